@@ -17,8 +17,10 @@ use crate::math::casting::Cast;
 use crate::math::constants::{
     CONCENTRATION_PRECISION, FEE_POOL_TO_REVENUE_POOL_THRESHOLD, K_BPS_UPDATE_SCALE,
     MAX_CONCENTRATION_COEFFICIENT, MAX_K_BPS_INCREASE, MAX_SQRT_K,
+    PRICE_TIMES_AMM_TO_QUOTE_PRECISION_RATIO, PRICE_TO_PEG_PRECISION_RATIO,
 };
 use crate::math::cp_curve::get_update_k_result;
+use crate::math::position::{calculate_base_asset_value, calculate_base_asset_value_and_pnl};
 use crate::math::repeg::get_total_fee_lower_bound;
 use crate::math::safe_math::SafeMath;
 use crate::math::spot_balance::get_token_amount;
@@ -27,7 +29,7 @@ use crate::math::spot_withdraw::{
 };
 use crate::math::{amm, amm_spread, bn, cp_curve, quote_asset::*};
 
-use crate::state::events::CurveRecord;
+use crate::state::events::{CurveRecord, CurveUpdateReason};
 use crate::state::oracle::OraclePriceData;
 use crate::state::perp_market::{PerpMarket, AMM};
 use crate::state::spot_market::{SpotBalance, SpotBalanceType, SpotMarket};
@@ -79,6 +81,10 @@ pub fn swap_base_asset(
     base_asset_swap_amount: u64,
     direction: SwapDirection,
 ) -> DriftResult<(u64, i64)> {
+    if market.is_oracle_quote_amm() {
+        return calculate_oracle_quote_swap_output(&market.amm, base_asset_swap_amount, direction);
+    }
+
     let (
         new_base_asset_reserve,
         new_quote_asset_reserve,
@@ -95,11 +101,118 @@ pub fn swap_base_asset(
     ))
 }
 
+/// Oracle quote amms fill at their bid/ask and leave the reserves centered on the oracle.
+/// The surplus is the spread captured vs the reserve price
+pub fn calculate_oracle_quote_swap_output(
+    amm: &AMM,
+    base_asset_swap_amount: u64,
+    direction: SwapDirection,
+) -> DriftResult<(u64, i64)> {
+    let reserve_price = amm.reserve_price()?;
+
+    let fill_price = match direction {
+        SwapDirection::Remove => amm.ask_price(reserve_price)?,
+        SwapDirection::Add => amm.bid_price(reserve_price)?,
+    };
+
+    let quote_asset_amount = base_asset_swap_amount
+        .cast::<u128>()?
+        .safe_mul(fill_price.cast()?)?;
+    // round in the amm's favor
+    let quote_asset_amount = match direction {
+        SwapDirection::Remove => {
+            quote_asset_amount.safe_div_ceil(PRICE_TIMES_AMM_TO_QUOTE_PRECISION_RATIO)?
+        }
+        SwapDirection::Add => {
+            quote_asset_amount.safe_div(PRICE_TIMES_AMM_TO_QUOTE_PRECISION_RATIO)?
+        }
+    };
+
+    let quote_asset_amount_at_reserve_price = base_asset_swap_amount
+        .cast::<u128>()?
+        .safe_mul(reserve_price.cast()?)?
+        .safe_div(PRICE_TIMES_AMM_TO_QUOTE_PRECISION_RATIO)?;
+
+    let quote_asset_amount_surplus = quote_asset_amount
+        .cast::<i128>()?
+        .safe_sub(quote_asset_amount_at_reserve_price.cast()?)?
+        .unsigned_abs();
+
+    Ok((
+        quote_asset_amount.cast::<u64>()?,
+        quote_asset_amount_surplus.cast::<i64>()?,
+    ))
+}
+
+/// Keeps an oracle quote amm's reserves balanced with the peg at the oracle price so the
+/// reserve price, and the bid/ask built from it, track the oracle. Moving the curve changes the
+/// exit price for the net market position like a repeg, so the cost is charged to the market.
+/// Returns None without moving the curve if the fee pool can't cover the cost
+pub fn update_oracle_quote_amm_peg(
+    market: &mut PerpMarket,
+    oracle_price: i64,
+) -> DriftResult<Option<i128>> {
+    validate!(
+        oracle_price > 0,
+        ErrorCode::InvalidOracle,
+        "oracle price {} <= 0",
+        oracle_price
+    )?;
+
+    let mut repegged_market = *market;
+
+    let net_market_value_before =
+        calculate_base_asset_value(market.amm.base_asset_amount_with_amm, &market.amm)?;
+
+    repegged_market.amm.peg_multiplier = oracle_price
+        .cast::<u128>()?
+        .safe_div(PRICE_TO_PEG_PRECISION_RATIO)?;
+    repegged_market.amm.base_asset_reserve = repegged_market.amm.sqrt_k;
+    repegged_market.amm.quote_asset_reserve = repegged_market.amm.sqrt_k;
+
+    let (_, cost) = calculate_base_asset_value_and_pnl(
+        market.amm.base_asset_amount_with_amm,
+        net_market_value_before,
+        &repegged_market.amm,
+    )?;
+
+    if !apply_cost_to_market(market, cost, true)? {
+        msg!(
+            "oracle quote amm peg not updated (cost {} not applied)",
+            cost
+        );
+        return Ok(None);
+    }
+
+    market.amm.peg_multiplier = repegged_market.amm.peg_multiplier;
+    market.amm.base_asset_reserve = repegged_market.amm.base_asset_reserve;
+    market.amm.quote_asset_reserve = repegged_market.amm.quote_asset_reserve;
+
+    let (terminal_quote_asset_reserve, terminal_base_asset_reserve) =
+        amm::calculate_terminal_reserves(&market.amm)?;
+    market.amm.terminal_quote_asset_reserve = terminal_quote_asset_reserve;
+
+    let (min_base_asset_reserve, max_base_asset_reserve) =
+        amm::calculate_bid_ask_bounds(market.amm.concentration_coef, terminal_base_asset_reserve)?;
+    market.amm.min_base_asset_reserve = min_base_asset_reserve;
+    market.amm.max_base_asset_reserve = max_base_asset_reserve;
+
+    Ok(Some(cost))
+}
+
 pub fn calculate_base_swap_output_with_spread(
     amm: &AMM,
     base_asset_swap_amount: u64,
     direction: SwapDirection,
 ) -> DriftResult<(u128, u128, u64, u64)> {
+    if amm.has_concentrated_band() {
+        return calculate_concentrated_base_swap_output_with_spread(
+            amm,
+            base_asset_swap_amount,
+            direction,
+        );
+    }
+
     // first do the swap with spread reserves to figure out how much base asset is acquired
     let (base_asset_reserve_with_spread, quote_asset_reserve_with_spread) = get_spread_reserves(
         amm,
@@ -152,6 +265,67 @@ pub fn calculate_base_swap_output_with_spread(
     ))
 }
 
+/// Same as calculate_base_swap_output_with_spread for a curve with a concentrated band.
+/// The reserve segments are found from the reserves without spread and applied to the spread reserves
+fn calculate_concentrated_base_swap_output_with_spread(
+    amm: &AMM,
+    base_asset_swap_amount: u64,
+    direction: SwapDirection,
+) -> DriftResult<(u128, u128, u64, u64)> {
+    let segments =
+        amm::calculate_base_reserve_segments(amm, base_asset_swap_amount.cast()?, direction)?;
+
+    let (base_asset_reserve_with_spread, _) = get_spread_reserves(
+        amm,
+        match direction {
+            SwapDirection::Add => PositionDirection::Short,
+            SwapDirection::Remove => PositionDirection::Long,
+        },
+    )?;
+
+    let (_, _, quote_asset_reserve_amount_with_spread) = amm::calculate_swap_output_for_segments(
+        &segments,
+        base_asset_reserve_with_spread,
+        direction,
+        amm.sqrt_k,
+    )?;
+
+    let quote_asset_amount = amm::calculate_quote_asset_amount_for_reserve_change(
+        quote_asset_reserve_amount_with_spread,
+        direction,
+        amm.peg_multiplier,
+    )?;
+
+    let (new_quote_asset_reserve, new_base_asset_reserve, quote_asset_reserve_amount) =
+        amm::calculate_swap_output_for_segments(
+            &segments,
+            amm.base_asset_reserve,
+            direction,
+            amm.sqrt_k,
+        )?;
+
+    let mut actual_quote_asset_amount =
+        reserve_to_asset_amount(quote_asset_reserve_amount, amm.peg_multiplier)?;
+
+    // Compensate for +1 quote asset amount added when removing base asset
+    if direction == SwapDirection::Remove {
+        actual_quote_asset_amount = actual_quote_asset_amount.safe_add(1)?;
+    }
+
+    let quote_asset_amount_surplus = if actual_quote_asset_amount > quote_asset_amount {
+        actual_quote_asset_amount.safe_sub(quote_asset_amount)?
+    } else {
+        quote_asset_amount.safe_sub(actual_quote_asset_amount)?
+    };
+
+    Ok((
+        new_base_asset_reserve,
+        new_quote_asset_reserve,
+        quote_asset_amount.cast::<u64>()?,
+        quote_asset_amount_surplus.cast::<u64>()?,
+    ))
+}
+
 pub fn update_spread_reserves(market: &mut PerpMarket) -> DriftResult {
     let (new_ask_base_asset_reserve, new_ask_quote_asset_reserve) =
         calculate_spread_reserves(market, PositionDirection::Long)?;
@@ -231,6 +405,8 @@ pub fn update_spreads(
             market.amm.max_base_asset_reserve,
             market.amm.mark_std,
             market.amm.oracle_std,
+            market.oracle_realized_vol_fast.cast()?,
+            market.oracle_realized_vol_slow.cast()?,
             market.amm.long_intensity_volume,
             market.amm.short_intensity_volume,
             market.amm.volume_24h,
@@ -259,6 +435,12 @@ pub fn update_spreads(
             .max(1);
     }
 
+    if market.is_amm_circuit_breaker_tripped() {
+        let half_max_spread = market.amm.max_spread.safe_div(2)?;
+        long_spread = half_max_spread;
+        short_spread = half_max_spread;
+    }
+
     market.amm.long_spread = long_spread;
     market.amm.short_spread = short_spread;
 
@@ -458,6 +640,7 @@ pub fn formulaic_update_k(
                 total_fee_minus_distributions: market.amm.total_fee_minus_distributions,
                 oracle_price: market.amm.historical_oracle_data.last_oracle_price,
                 fill_record: market.next_fill_record_id as u128,
+                reason: CurveUpdateReason::FormulaicUpdateK,
             });
         }
     }
@@ -1676,3 +1676,109 @@ mod revenue_pool_transfer_tests {
         assert_eq!(spot_market.revenue_pool.scaled_balance, 9870000000000);
     }
 }
+
+mod oracle_quote_amm {
+    use crate::controller::amm::{swap_base_asset, update_oracle_quote_amm_peg, SwapDirection};
+    use crate::controller::position::PositionDirection;
+    use crate::math::constants::{
+        BASE_PRECISION_I128, BASE_PRECISION_U64, PRICE_PRECISION_I64, QUOTE_PRECISION_I128,
+    };
+    use crate::state::perp_market::{AmmQuoteMode, PerpMarket};
+
+    #[test]
+    fn peg_tracks_oracle() {
+        let mut market = PerpMarket::default_test();
+        market.amm_quote_mode = AmmQuoteMode::Oracle;
+        market.amm.base_asset_reserve = market.amm.sqrt_k * 11 / 10;
+
+        update_oracle_quote_amm_peg(&mut market, 50 * PRICE_PRECISION_I64).unwrap();
+
+        assert_eq!(market.amm.base_asset_reserve, market.amm.sqrt_k);
+        assert_eq!(market.amm.quote_asset_reserve, market.amm.sqrt_k);
+        assert_eq!(market.amm.reserve_price().unwrap(), 50_000_000);
+
+        assert!(update_oracle_quote_amm_peg(&mut market, 0).is_err());
+    }
+
+    #[test]
+    fn peg_update_charged_to_market() {
+        let mut market = PerpMarket::default_test();
+        market.amm_quote_mode = AmmQuoteMode::Oracle;
+        // users are net long so the amm pays when the peg moves up
+        market.amm.base_asset_amount_with_amm = BASE_PRECISION_I128;
+
+        // fee pool can't cover it
+        let cost = update_oracle_quote_amm_peg(&mut market, 50 * PRICE_PRECISION_I64).unwrap();
+        assert_eq!(cost, None);
+        assert_eq!(
+            market.amm.reserve_price().unwrap(),
+            PRICE_PRECISION_I64 as u64
+        );
+        assert_eq!(market.amm.total_fee_minus_distributions, 0);
+
+        market.amm.total_fee_minus_distributions = 100 * QUOTE_PRECISION_I128;
+        let cost = update_oracle_quote_amm_peg(&mut market, 50 * PRICE_PRECISION_I64)
+            .unwrap()
+            .unwrap();
+        assert!(cost > 0);
+        assert_eq!(market.amm.reserve_price().unwrap(), 50_000_000);
+        assert_eq!(
+            market.amm.total_fee_minus_distributions,
+            100 * QUOTE_PRECISION_I128 - cost
+        );
+        assert_eq!(market.amm.net_revenue_since_last_funding, -(cost as i64));
+    }
+
+    #[test]
+    fn swap_at_bid_ask() {
+        let mut market = PerpMarket::default_test();
+        market.amm_quote_mode = AmmQuoteMode::Oracle;
+        update_oracle_quote_amm_peg(&mut market, 50 * PRICE_PRECISION_I64).unwrap();
+        market.amm.long_spread = 1000;
+        market.amm.short_spread = 1000;
+
+        let (quote_asset_amount, quote_asset_amount_surplus) =
+            swap_base_asset(&mut market, BASE_PRECISION_U64, SwapDirection::Remove).unwrap();
+        assert_eq!(quote_asset_amount, 50_050_000);
+        assert_eq!(quote_asset_amount_surplus, 50_000);
+
+        let (quote_asset_amount, quote_asset_amount_surplus) =
+            swap_base_asset(&mut market, BASE_PRECISION_U64, SwapDirection::Add).unwrap();
+        assert_eq!(quote_asset_amount, 49_950_000);
+        assert_eq!(quote_asset_amount_surplus, 50_000);
+
+        // reserves dont move
+        assert_eq!(market.amm.base_asset_reserve, market.amm.sqrt_k);
+        assert_eq!(market.amm.reserve_price().unwrap(), 50_000_000);
+    }
+
+    #[test]
+    fn available_liquidity_bounded_by_inventory_budget() {
+        let mut market = PerpMarket::default_test();
+        market.amm_quote_mode = AmmQuoteMode::Oracle;
+        market.oracle_quote_inventory_budget = 10 * BASE_PRECISION_U64;
+        // users are net long so the amm is short
+        market.amm.base_asset_amount_with_amm = 4 * BASE_PRECISION_I128;
+
+        assert_eq!(
+            market
+                .get_amm_available_liquidity(&PositionDirection::Long)
+                .unwrap(),
+            6 * BASE_PRECISION_U64
+        );
+        assert_eq!(
+            market
+                .get_amm_available_liquidity(&PositionDirection::Short)
+                .unwrap(),
+            14 * BASE_PRECISION_U64
+        );
+
+        market.amm.base_asset_amount_with_amm = 12 * BASE_PRECISION_I128;
+        assert_eq!(
+            market
+                .get_amm_available_liquidity(&PositionDirection::Long)
+                .unwrap(),
+            0
+        );
+    }
+}
@@ -11,7 +11,9 @@ use crate::controller::position::{
     get_position_index, update_position_and_market, update_quote_asset_amount,
     update_quote_asset_and_break_even_amount, PositionDirection,
 };
-use crate::controller::repeg::update_amm_and_check_validity;
+use crate::controller::repeg::{
+    load_perp_circuit_breaker, update_amm_and_check_validity, update_amm_circuit_breaker_after_fill,
+};
 use crate::controller::spot_balance::{
     update_revenue_pool_balances, update_spot_balances, update_spot_market_and_check_validity,
     update_spot_market_cumulative_interest,
@@ -59,6 +61,7 @@ use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarketI
 use crate::state::oracle_map::OracleMap;
 use crate::state::order_params::PlaceOrderOptions;
 use crate::state::paused_operations::{PerpOperation, SpotOperation};
+use crate::state::perp_circuit_breaker::PerpCircuitBreaker;
 use crate::state::perp_market::MarketStatus;
 use crate::state::perp_market_map::PerpMarketMap;
 use crate::state::spot_market::SpotBalanceType;
@@ -66,6 +69,7 @@ use crate::state::spot_market_map::SpotMarketMap;
 use crate::state::state::State;
 use crate::state::user::{MarketType, Order, OrderStatus, OrderType, User, UserStats};
 use crate::state::user_map::{UserMap, UserStatsMap};
+use crate::state::user_orders_extension::UserOrdersExtensionZeroCopyMut;
 use crate::{get_then_update_id, load_mut, LST_POOL_ID};
 use crate::{validate, LIQUIDATION_FEE_PRECISION};
 
@@ -88,6 +92,8 @@ pub fn liquidate_perp(
     slot: u64,
     now: i64,
     state: &State,
+    orders_extension: &mut Option<UserOrdersExtensionZeroCopyMut>,
+    circuit_breakers: &[AccountLoader<PerpCircuitBreaker>],
 ) -> DriftResult {
     let liquidation_margin_buffer_ratio = state.liquidation_margin_buffer_ratio;
     let initial_pct_to_liquidate = state.initial_pct_to_liquidate as u128;
@@ -182,7 +188,7 @@ pub fn liquidate_perp(
         ErrorCode::PositionDoesntHaveOpenPositionOrOrders
     )?;
 
-    let canceled_order_ids = orders::cancel_orders(
+    let canceled_order_ids = orders::cancel_orders_with_extension(
         user,
         user_key,
         Some(liquidator_key),
@@ -195,6 +201,7 @@ pub fn liquidate_perp(
         None,
         None,
         None,
+        orders_extension,
     )?;
 
     let mut market = perp_market_map.get_ref_mut(&market_index)?;
@@ -205,6 +212,7 @@ pub fn liquidate_perp(
         &state.oracle_guard_rails.validity,
     )?;
 
+    let mut circuit_breaker = load_perp_circuit_breaker(circuit_breakers, &market)?;
     update_amm_and_check_validity(
         &mut market,
         &mm_oracle_price_data,
@@ -212,7 +220,9 @@ pub fn liquidate_perp(
         now,
         slot,
         Some(DriftAction::Liquidate),
+        circuit_breaker.as_deref_mut(),
     )?;
+    drop(circuit_breaker);
 
     let oracle_price = if market.status == MarketStatus::Settlement {
         market.expiry_price
@@ -724,6 +734,8 @@ pub fn liquidate_perp_with_fill(
     oracle_map: &mut OracleMap,
     clock: &Clock,
     state: &State,
+    orders_extension: &mut Option<UserOrdersExtensionZeroCopyMut>,
+    circuit_breakers: &[AccountLoader<PerpCircuitBreaker>],
 ) -> DriftResult {
     let now = clock.unix_timestamp;
     let slot = clock.slot;
@@ -814,7 +826,7 @@ pub fn liquidate_perp_with_fill(
         ErrorCode::PositionDoesntHaveOpenPositionOrOrders
     )?;
 
-    let canceled_order_ids = orders::cancel_orders(
+    let canceled_order_ids = orders::cancel_orders_with_extension(
         &mut user,
         user_key,
         Some(liquidator_key),
@@ -827,6 +839,7 @@ pub fn liquidate_perp_with_fill(
         None,
         None,
         None,
+        orders_extension,
     )?;
 
     let mut market = perp_market_map.get_ref_mut(&market_index)?;
@@ -837,6 +850,7 @@ pub fn liquidate_perp_with_fill(
         &state.oracle_guard_rails.validity,
     )?;
 
+    let mut circuit_breaker = load_perp_circuit_breaker(circuit_breakers, &market)?;
     update_amm_and_check_validity(
         &mut market,
         &mm_oracle_price_data,
@@ -844,7 +858,9 @@ pub fn liquidate_perp_with_fill(
         now,
         slot,
         Some(DriftAction::Liquidate),
+        circuit_breaker.as_deref_mut(),
     )?;
+    drop(circuit_breaker);
 
     let oracle_price = if market.status == MarketStatus::Settlement {
         market.expiry_price
@@ -1063,6 +1079,16 @@ pub fn liquidate_perp_with_fill(
         FillMode::Liquidation,
         &mut None,
         false,
+        &[],
+    )?;
+
+    update_amm_circuit_breaker_after_fill(
+        market_index,
+        perp_market_map,
+        oracle_map,
+        state,
+        clock.slot,
+        circuit_breakers,
     )?;
 
     let mut user = load_mut!(user_loader)?;
@@ -1177,6 +1203,7 @@ pub fn liquidate_spot(
     now: i64,
     slot: u64,
     state: &State,
+    orders_extension: &mut Option<UserOrdersExtensionZeroCopyMut>,
 ) -> DriftResult {
     let liquidation_margin_buffer_ratio = state.liquidation_margin_buffer_ratio;
     let initial_pct_to_liquidate = state.initial_pct_to_liquidate as u128;
@@ -1405,7 +1432,7 @@ pub fn liquidate_spot(
     let liquidation_id = user.enter_liquidation(slot)?;
     let mut margin_freed = 0_u64;
 
-    let canceled_order_ids = orders::cancel_orders(
+    let canceled_order_ids = orders::cancel_orders_with_extension(
         user,
         user_key,
         Some(liquidator_key),
@@ -1418,6 +1445,7 @@ pub fn liquidate_spot(
         None,
         None,
         None,
+        orders_extension,
     )?;
 
     // check if user exited liquidation territory
@@ -1757,6 +1785,7 @@ pub fn liquidate_spot_with_swap_begin(
     now: i64,
     slot: u64,
     state: &State,
+    orders_extension: &mut Option<UserOrdersExtensionZeroCopyMut>,
 ) -> DriftResult {
     let liquidation_margin_buffer_ratio = state.liquidation_margin_buffer_ratio;
     let initial_pct_to_liquidate = state.initial_pct_to_liquidate as u128;
@@ -1933,7 +1962,7 @@ pub fn liquidate_spot_with_swap_begin(
 
     let liquidation_id = user.enter_liquidation(slot)?;
 
-    let canceled_order_ids = orders::cancel_orders(
+    let canceled_order_ids = orders::cancel_orders_with_extension(
         user,
         user_key,
         Some(liquidator_key),
@@ -1946,6 +1975,7 @@ pub fn liquidate_spot_with_swap_begin(
         None,
         None,
         None,
+        orders_extension,
     )?;
 
     // check if user exited liquidation territory
@@ -2305,6 +2335,7 @@ pub fn liquidate_borrow_for_perp_pnl(
     liquidation_margin_buffer_ratio: u32,
     initial_pct_to_liquidate: u128,
     liquidation_duration: u128,
+    orders_extension: &mut Option<UserOrdersExtensionZeroCopyMut>,
 ) -> DriftResult {
     // liquidator takes over a user borrow in exchange for that user's positive perpetual pnl
     // can only be done once a user's perpetual position size is 0
@@ -2505,7 +2536,7 @@ pub fn liquidate_borrow_for_perp_pnl(
     let liquidation_id = user.enter_liquidation(slot)?;
     let mut margin_freed = 0_u64;
 
-    let canceled_order_ids = orders::cancel_orders(
+    let canceled_order_ids = orders::cancel_orders_with_extension(
         user,
         user_key,
         Some(liquidator_key),
@@ -2518,6 +2549,7 @@ pub fn liquidate_borrow_for_perp_pnl(
         None,
         None,
         None,
+        orders_extension,
     )?;
 
     // check if user exited liquidation territory
@@ -2776,6 +2808,7 @@ pub fn liquidate_perp_pnl_for_deposit(
     liquidation_margin_buffer_ratio: u32,
     initial_pct_to_liquidate: u128,
     liquidation_duration: u128,
+    orders_extension: &mut Option<UserOrdersExtensionZeroCopyMut>,
 ) -> DriftResult {
     // liquidator takes over remaining negative perpetual pnl in exchange for a user deposit
     // can only be done once the perpetual position's size is 0
@@ -2984,7 +3017,7 @@ pub fn liquidate_perp_pnl_for_deposit(
     let liquidation_id = user.enter_liquidation(slot)?;
     let mut margin_freed = 0_u64;
 
-    let canceled_order_ids = orders::cancel_orders(
+    let canceled_order_ids = orders::cancel_orders_with_extension(
         user,
         user_key,
         Some(liquidator_key),
@@ -2997,6 +3030,7 @@ pub fn liquidate_perp_pnl_for_deposit(
         None,
         None,
         None,
+        orders_extension,
     )?;
 
     let (safest_tier_spot_liability, safest_tier_perp_liability) =
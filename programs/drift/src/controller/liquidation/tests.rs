@@ -161,6 +161,8 @@ pub mod liquidate_perp {
             slot,
             now,
             &state,
+            &mut None,
+            &[],
         )
         .unwrap();
 
@@ -311,6 +313,8 @@ pub mod liquidate_perp {
             slot,
             now,
             &state,
+            &mut None,
+            &[],
         )
         .unwrap();
 
@@ -459,6 +463,8 @@ pub mod liquidate_perp {
             slot,
             now,
             &state,
+            &mut None,
+            &[],
         )
         .unwrap();
 
@@ -595,6 +601,8 @@ pub mod liquidate_perp {
             slot,
             now,
             &state,
+            &mut None,
+            &[],
         )
         .unwrap();
 
@@ -746,6 +754,8 @@ pub mod liquidate_perp {
             slot,
             now,
             &state,
+            &mut None,
+            &[],
         )
         .unwrap();
 
@@ -976,6 +986,8 @@ pub mod liquidate_perp {
             slot,
             now,
             &state,
+            &mut None,
+            &[],
         )
         .unwrap();
 
@@ -1124,6 +1136,8 @@ pub mod liquidate_perp {
             slot,
             now,
             &state,
+            &mut None,
+            &[],
         );
 
         assert_eq!(result, Err(ErrorCode::LiquidationDoesntSatisfyLimitPrice));
@@ -1256,6 +1270,8 @@ pub mod liquidate_perp {
             slot,
             now,
             &state,
+            &mut None,
+            &[],
         );
 
         assert_eq!(result, Err(ErrorCode::LiquidationDoesntSatisfyLimitPrice));
@@ -1380,6 +1396,8 @@ pub mod liquidate_perp {
             slot,
             now,
             &state,
+            &mut None,
+            &[],
         )
         .unwrap();
 
@@ -1535,6 +1553,8 @@ pub mod liquidate_perp {
             slot,
             now,
             &state,
+            &mut None,
+            &[],
         )
         .unwrap();
 
@@ -1560,6 +1580,8 @@ pub mod liquidate_perp {
             slot,
             now,
             &state,
+            &mut None,
+            &[],
         )
         .unwrap();
 
@@ -1605,6 +1627,8 @@ pub mod liquidate_perp {
             slot,
             now,
             &state,
+            &mut None,
+            &[],
         )
         .unwrap();
 
@@ -1630,6 +1654,8 @@ pub mod liquidate_perp {
             slot,
             now,
             &state,
+            &mut None,
+            &[],
         )
         .unwrap();
 
@@ -1675,6 +1701,8 @@ pub mod liquidate_perp {
             slot,
             now,
             &state,
+            &mut None,
+            &[],
         )
         .unwrap();
 
@@ -1812,6 +1840,8 @@ pub mod liquidate_perp {
             slot,
             now,
             &state,
+            &mut None,
+            &[],
         )
         .unwrap();
 
@@ -1937,6 +1967,8 @@ pub mod liquidate_perp {
             slot,
             now,
             &state,
+            &mut None,
+            &[],
         )
         .unwrap();
 
@@ -2065,6 +2097,8 @@ pub mod liquidate_perp {
             slot,
             now,
             &state,
+            &mut None,
+            &[],
         )
         .unwrap();
 
@@ -2193,6 +2227,8 @@ pub mod liquidate_perp {
             slot,
             now,
             &state,
+            &mut None,
+            &[],
         )
         .unwrap();
 
@@ -2331,6 +2367,8 @@ pub mod liquidate_perp {
             slot,
             now,
             &state,
+            &mut None,
+            &[],
         )
         .unwrap();
 
@@ -2600,6 +2638,8 @@ pub mod liquidate_perp_with_fill {
             &mut oracle_map,
             &clock,
             &state,
+            &mut None,
+            &[],
         )
         .unwrap();
 
@@ -2809,6 +2849,8 @@ pub mod liquidate_perp_with_fill {
             &mut oracle_map,
             &clock,
             &state,
+            &mut None,
+            &[],
         )
         .unwrap();
 
@@ -2984,6 +3026,8 @@ pub mod liquidate_perp_with_fill {
             &mut oracle_map,
             &clock,
             &state,
+            &mut None,
+            &[],
         )
         .unwrap();
 
@@ -3155,6 +3199,8 @@ pub mod liquidate_perp_with_fill {
             &mut oracle_map,
             &clock,
             &state,
+            &mut None,
+            &[],
         )
         .unwrap();
 
@@ -3329,6 +3375,7 @@ pub mod liquidate_spot {
             now,
             slot,
             &state,
+            &mut None,
         )
         .unwrap();
 
@@ -3472,6 +3519,7 @@ pub mod liquidate_spot {
             now,
             slot,
             &state,
+            &mut None,
         )
         .is_err());
 
@@ -3500,6 +3548,7 @@ pub mod liquidate_spot {
             now,
             slot,
             &state,
+            &mut None,
         )
         .unwrap();
 
@@ -3643,6 +3692,7 @@ pub mod liquidate_spot {
             now,
             slot,
             &state,
+            &mut None,
         )
         .unwrap();
 
@@ -3861,6 +3911,7 @@ pub mod liquidate_spot {
             now,
             slot,
             &state,
+            &mut None,
         );
 
         assert_eq!(result, Err(ErrorCode::LiquidationDoesntSatisfyLimitPrice));
@@ -3988,6 +4039,7 @@ pub mod liquidate_spot {
             now,
             slot,
             &state,
+            &mut None,
         );
 
         assert_eq!(result, Ok(()));
@@ -4116,6 +4168,7 @@ pub mod liquidate_spot {
             now,
             slot,
             &state,
+            &mut None,
         )
         .unwrap();
 
@@ -4252,6 +4305,7 @@ pub mod liquidate_spot {
             now,
             slot,
             &state,
+            &mut None,
         )
         .unwrap();
 
@@ -4299,6 +4353,7 @@ pub mod liquidate_spot {
             now,
             slot,
             &state,
+            &mut None,
         )
         .unwrap();
 
@@ -4346,6 +4401,7 @@ pub mod liquidate_spot {
             now,
             slot,
             &state,
+            &mut None,
         )
         .unwrap();
 
@@ -4501,6 +4557,7 @@ pub mod liquidate_spot {
             now,
             slot,
             &state,
+            &mut None,
         )
         .unwrap();
 
@@ -4701,6 +4758,7 @@ pub mod liquidate_borrow_for_perp_pnl {
             10,
             PERCENTAGE_PRECISION,
             150,
+            &mut None,
         )
         .unwrap();
 
@@ -4858,6 +4916,7 @@ pub mod liquidate_borrow_for_perp_pnl {
             liquidation_buffer,
             PERCENTAGE_PRECISION,
             150,
+            &mut None,
         )
         .unwrap();
 
@@ -5055,6 +5114,7 @@ pub mod liquidate_borrow_for_perp_pnl {
             10,
             PERCENTAGE_PRECISION,
             150,
+            &mut None,
         )
         .unwrap();
 
@@ -5211,6 +5271,7 @@ pub mod liquidate_borrow_for_perp_pnl {
             10,
             PERCENTAGE_PRECISION,
             150,
+            &mut None,
         );
 
         assert_eq!(result, Err(ErrorCode::LiquidationDoesntSatisfyLimitPrice));
@@ -5358,6 +5419,7 @@ pub mod liquidate_borrow_for_perp_pnl {
             10,
             PERCENTAGE_PRECISION,
             150,
+            &mut None,
         );
 
         assert_eq!(result, Ok(()));
@@ -5506,6 +5568,7 @@ pub mod liquidate_borrow_for_perp_pnl {
             liquidation_buffer,
             PERCENTAGE_PRECISION,
             150,
+            &mut None,
         )
         .unwrap();
 
@@ -5663,6 +5726,7 @@ pub mod liquidate_borrow_for_perp_pnl {
             liquidation_buffer,
             LIQUIDATION_PCT_PRECISION / 10,
             150,
+            &mut None,
         )
         .unwrap();
 
@@ -5709,6 +5773,7 @@ pub mod liquidate_borrow_for_perp_pnl {
             liquidation_buffer,
             LIQUIDATION_PCT_PRECISION / 10,
             150,
+            &mut None,
         )
         .unwrap();
 
@@ -5755,6 +5820,7 @@ pub mod liquidate_borrow_for_perp_pnl {
             liquidation_buffer,
             LIQUIDATION_PCT_PRECISION / 10,
             150,
+            &mut None,
         )
         .unwrap();
 
@@ -5935,6 +6001,7 @@ pub mod liquidate_perp_pnl_for_deposit {
             10,
             PERCENTAGE_PRECISION,
             150,
+            &mut None,
         )
         .unwrap();
 
@@ -6091,6 +6158,7 @@ pub mod liquidate_perp_pnl_for_deposit {
             MARGIN_PRECISION / 50,
             PERCENTAGE_PRECISION,
             150,
+            &mut None,
         )
         .unwrap();
 
@@ -6249,6 +6317,7 @@ pub mod liquidate_perp_pnl_for_deposit {
             10,
             PERCENTAGE_PRECISION,
             150,
+            &mut None,
         )
         .unwrap();
 
@@ -6405,6 +6474,7 @@ pub mod liquidate_perp_pnl_for_deposit {
             10,
             PERCENTAGE_PRECISION,
             150,
+            &mut None,
         );
 
         assert_eq!(result, Err(ErrorCode::LiquidationDoesntSatisfyLimitPrice));
@@ -6552,6 +6622,7 @@ pub mod liquidate_perp_pnl_for_deposit {
             10,
             PERCENTAGE_PRECISION,
             150,
+            &mut None,
         );
 
         assert_eq!(result, Ok(()));
@@ -6699,6 +6770,7 @@ pub mod liquidate_perp_pnl_for_deposit {
             MARGIN_PRECISION / 50,
             PERCENTAGE_PRECISION,
             150,
+            &mut None,
         )
         .unwrap();
 
@@ -6856,6 +6928,7 @@ pub mod liquidate_perp_pnl_for_deposit {
             liquidation_buffer,
             LIQUIDATION_PCT_PRECISION / 10,
             150,
+            &mut None,
         )
         .unwrap();
 
@@ -6897,6 +6970,7 @@ pub mod liquidate_perp_pnl_for_deposit {
             liquidation_buffer,
             LIQUIDATION_PCT_PRECISION / 10,
             150,
+            &mut None,
         )
         .unwrap();
 
@@ -6938,6 +7012,7 @@ pub mod liquidate_perp_pnl_for_deposit {
             liquidation_buffer,
             LIQUIDATION_PCT_PRECISION / 10,
             150,
+            &mut None,
         )
         .unwrap();
 
@@ -7097,6 +7172,7 @@ pub mod liquidate_perp_pnl_for_deposit {
             10,
             PERCENTAGE_PRECISION,
             150,
+            &mut None,
         )
         .is_err());
 
@@ -7124,6 +7200,7 @@ pub mod liquidate_perp_pnl_for_deposit {
             now,
             slot,
             &state,
+            &mut None,
         )
         .unwrap();
 
@@ -7146,6 +7223,7 @@ pub mod liquidate_perp_pnl_for_deposit {
             10,
             PERCENTAGE_PRECISION,
             150,
+            &mut None,
         )
         .unwrap();
         assert_eq!(user.perp_positions[0].quote_asset_amount, -50000000);
@@ -7169,6 +7247,7 @@ pub mod liquidate_perp_pnl_for_deposit {
             10,
             PERCENTAGE_PRECISION,
             150,
+            &mut None,
         )
         .unwrap();
         assert_eq!(user.spot_positions[0].scaled_balance, 0);
@@ -7369,6 +7448,7 @@ pub mod liquidate_perp_pnl_for_deposit {
             10,
             PERCENTAGE_PRECISION,
             150,
+            &mut None,
         )
         .is_err());
         assert_eq!(user.perp_positions[0].quote_asset_amount, -100000000);
@@ -7390,6 +7470,7 @@ pub mod liquidate_perp_pnl_for_deposit {
             10,
             PERCENTAGE_PRECISION,
             150,
+            &mut None,
         )
         .unwrap();
         assert_eq!(user.perp_positions[0].quote_asset_amount, 0);
@@ -7411,6 +7492,7 @@ pub mod liquidate_perp_pnl_for_deposit {
             10,
             PERCENTAGE_PRECISION,
             150,
+            &mut None,
         )
         .unwrap();
 
@@ -8516,6 +8598,7 @@ pub mod liquidate_spot_with_swap {
             now,
             slot,
             &state,
+            &mut None,
         );
 
         assert_eq!(res, Err(ErrorCode::InvalidLiquidation));
@@ -8536,6 +8619,7 @@ pub mod liquidate_spot_with_swap {
             now,
             slot,
             &state,
+            &mut None,
         );
 
         assert_eq!(res, Ok(()));
@@ -8742,6 +8826,8 @@ mod liquidate_dust_prediction_market {
             clock_slot,
             now,
             &state,
+            &mut None,
+            &[],
         );
 
         assert_eq!(result, Ok(()));
@@ -8905,6 +8991,7 @@ mod liquidate_dust_spot_market {
             now,
             clock_slot,
             &state,
+            &mut None,
         );
 
         assert_eq!(result, Ok(()));
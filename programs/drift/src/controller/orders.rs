@@ -27,8 +27,8 @@ use crate::error::DriftResult;
 use crate::error::ErrorCode;
 use crate::get_struct_values;
 use crate::get_then_update_id;
+use crate::load;
 use crate::load_mut;
-use crate::math::amm::calculate_amm_available_liquidity;
 use crate::math::amm_jit::calculate_amm_jit_liquidity;
 use crate::math::auction::{calculate_auction_params_for_trigger_order, calculate_auction_prices};
 use crate::math::casting::Cast;
@@ -59,11 +59,13 @@ use crate::state::margin_calculation::{MarginCalculation, MarginContext};
 use crate::state::oracle::{OraclePriceData, StrictOraclePrice};
 use crate::state::oracle_map::OracleMap;
 use crate::state::order_params::{
-    ModifyOrderParams, OrderParams, PlaceOrderOptions, PostOnlyParam,
+    BracketOrderParams, CancelOrdersParams, ModifyOrderParams, OrderParams, OrderParamsBitFlag,
+    PlaceOrderOptions, PostOnlyParam, SignedMsgQuoteMessage,
 };
 use crate::state::paused_operations::{PerpOperation, SpotOperation};
 use crate::state::perp_market::{AMMAvailability, MarketStatus, PerpMarket};
 use crate::state::perp_market_map::PerpMarketMap;
+use crate::state::perp_order_book::PerpOrderBook;
 use crate::state::protected_maker_mode_config::ProtectedMakerParams;
 use crate::state::spot_fulfillment_params::{ExternalSpotFill, SpotFulfillmentParams};
 use crate::state::spot_market::{SpotBalanceType, SpotMarket};
@@ -72,10 +74,12 @@ use crate::state::state::FeeStructure;
 use crate::state::state::*;
 use crate::state::traits::Size;
 use crate::state::user::{
-    AssetType, Order, OrderBitFlag, OrderStatus, OrderTriggerCondition, OrderType, UserStats,
+    AssetType, Order, OrderBitFlag, OrderExtBitFlag, OrderStatus, OrderTriggerCondition, OrderType,
+    SelfTradePreventionMode, UserStats,
 };
 use crate::state::user::{MarketType, User};
 use crate::state::user_map::{UserMap, UserStatsMap};
+use crate::state::user_orders_extension::UserOrdersExtensionZeroCopyMut;
 use crate::validate;
 use crate::validation;
 use crate::validation::order::{
@@ -204,14 +208,22 @@ pub fn place_perp_order(
     // Increment open orders for existing position
     let (existing_position_direction, order_base_asset_amount) = {
         validate!(
-            params.base_asset_amount >= market.amm.order_step_size,
+            params.is_close_position() || params.base_asset_amount >= market.amm.order_step_size,
             ErrorCode::OrderAmountTooSmall,
             "params.base_asset_amount={} cannot be below market.amm.order_step_size={}",
             params.base_asset_amount,
             market.amm.order_step_size
         )?;
 
-        let base_asset_amount = if params.base_asset_amount == u64::MAX
+        let base_asset_amount = if params.is_close_position() {
+            validate!(
+                params.reduce_only,
+                ErrorCode::InvalidOrder,
+                "close position order must be reduce only"
+            )?;
+
+            u64::MAX
+        } else if params.base_asset_amount == u64::MAX
             && !(params.is_trigger_order() && params.reduce_only)
         {
             calculate_max_perp_order_size(
@@ -262,8 +274,10 @@ pub fn place_perp_order(
         state.min_perp_auction_duration,
     )?;
 
+    // orders waiting for activation get their default expiry once they're activated
     let max_ts = match params.max_ts {
         Some(max_ts) => max_ts,
+        None if params.is_pending_activation() => 0_i64,
         None => match params.order_type {
             OrderType::Market | OrderType::Oracle => now.safe_add(
                 30_i64.max(
@@ -306,7 +320,40 @@ pub fn place_perp_order(
         bit_flags = set_order_bit_flag(bit_flags, true, OrderBitFlag::HasBuilder);
     }
 
-    let new_order = Order {
+    if params.is_trailing_stop() {
+        validate!(
+            params.is_trigger_order(),
+            ErrorCode::InvalidOrderTrigger,
+            "trailing stop must be a trigger order"
+        )?;
+
+        bit_flags = set_order_bit_flag(bit_flags, true, OrderBitFlag::TrailingStop);
+        bit_flags = set_order_bit_flag(
+            bit_flags,
+            params.is_trailing_stop_percentage(),
+            OrderBitFlag::TrailingStopPercentage,
+        );
+    }
+
+    let auction_start_price = if params.is_iceberg() {
+        validate!(
+            params.order_type == OrderType::Limit
+                && params.post_only != PostOnlyParam::None
+                && auction_duration == 0,
+            ErrorCode::InvalidOrder,
+            "iceberg order must be a post only limit order"
+        )?;
+
+        standardize_base_asset_amount(
+            params.auction_start_price.unwrap_or(0).cast()?,
+            market.amm.order_step_size,
+        )?
+        .cast::<i64>()?
+    } else {
+        auction_start_price
+    };
+
+    let mut new_order = Order {
         status: OrderStatus::Open,
         order_type: params.order_type,
         market_type: params.market_type,
@@ -314,22 +361,27 @@ pub fn place_perp_order(
         order_id: get_then_update_id!(user, next_order_id),
         user_order_id: params.user_order_id,
         market_index: params.market_index,
-        price: get_price_for_perp_order(
-            params.price,
-            params.direction,
-            params.post_only,
-            &market.amm,
-        )?,
+        // oracle offset orders without a price cap keep a price of 0
+        price: if params.price == 0 && params.oracle_price_offset.unwrap_or(0) != 0 {
+            0
+        } else {
+            get_price_for_perp_order(
+                params.price,
+                params.direction,
+                params.post_only,
+                &market.amm,
+            )?
+        },
         existing_position_direction,
         base_asset_amount: order_base_asset_amount,
         base_asset_amount_filled: 0,
         quote_asset_amount_filled: 0,
         direction: params.direction,
         reduce_only,
-        trigger_price: standardize_price(
-            params.trigger_price.unwrap_or(0),
+        trigger_price: get_trigger_price_for_new_order(
+            &params,
             market.amm.order_tick_size,
-            params.direction,
+            market.amm.order_step_size,
         )?,
         trigger_condition: params.trigger_condition,
         post_only: params.post_only != PostOnlyParam::None,
@@ -341,9 +393,32 @@ pub fn place_perp_order(
         max_ts,
         posted_slot_tail: get_posted_slot_from_clock_slot(slot),
         bit_flags,
-        padding: [0; 1],
+        oco_group_id_and_bit_flags: options.oco_group_id,
     };
 
+    if params.is_iceberg() {
+        new_order.add_ext_bit_flag(OrderExtBitFlag::Iceberg);
+    }
+
+    if params.has_min_fill_size() {
+        new_order.add_ext_bit_flag(OrderExtBitFlag::MinFillSize);
+    }
+
+    if params.is_close_position() {
+        new_order.add_ext_bit_flag(OrderExtBitFlag::ClosePosition);
+    }
+
+    // trailing stops without an explicit trigger price start trailing from the current oracle
+    if new_order.is_trailing_stop() && new_order.trigger_price == 0 {
+        if let Some(trigger_price) = calculate_trailing_stop_trigger_price(
+            &new_order,
+            oracle_price_data.price.unsigned_abs(),
+            market.amm.order_tick_size,
+        )? {
+            new_order.trigger_price = trigger_price;
+        }
+    }
+
     let valid_oracle_price = Some(oracle_price_data.price);
     match validate_order(&new_order, market, valid_oracle_price, slot) {
         Ok(()) => {}
@@ -460,6 +535,222 @@ pub fn place_perp_order(
 
     user.update_last_active_slot(slot);
 
+    if options.update_heartbeat && !options.is_liquidation() {
+        user.update_last_heartbeat_ts(now)?;
+    }
+
+    Ok(())
+}
+
+/// Places an entry order along with reduce only take profit/stop loss orders in the same oco group.
+/// The protective orders stay empty until the entry fills and are then sized to the amount filled
+pub fn place_bracket_perp_order(
+    state: &State,
+    user: &mut User,
+    user_key: Pubkey,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    high_leverage_mode_config: &Option<AccountLoader<HighLeverageModeConfig>>,
+    clock: &Clock,
+    params: OrderParams,
+    bracket_params: BracketOrderParams,
+    options: PlaceOrderOptions,
+) -> DriftResult {
+    validate!(
+        params.market_type == MarketType::Perp,
+        ErrorCode::InvalidOrderMarketType,
+        "bracket orders must be perp orders"
+    )?;
+
+    validate!(
+        !params.is_trigger_order() && !params.reduce_only,
+        ErrorCode::InvalidOrder,
+        "bracket entry can not be a trigger or reduce only order"
+    )?;
+
+    validate!(
+        options.oco_group_id == 0,
+        ErrorCode::InvalidOrder,
+        "bracket entry can not join another oco group"
+    )?;
+
+    validate!(
+        !bracket_params.is_empty(),
+        ErrorCode::InvalidOrder,
+        "bracket must have a take profit or stop loss"
+    )?;
+
+    let oco_group_id = user.get_next_oco_group_id()?;
+
+    for leg_params in bracket_params.get_order_params(&params) {
+        let order_id = user.next_order_id;
+        place_perp_order(
+            state,
+            user,
+            user_key,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            &None,
+            clock,
+            leg_params,
+            PlaceOrderOptions {
+                try_expire_orders: false,
+                enforce_margin_check: false,
+                existing_position_direction_override: Some(params.direction),
+                oco_group_id,
+                ..PlaceOrderOptions::default()
+            },
+            &mut None,
+        )?;
+
+        let order_index = user.get_order_index(order_id)?;
+        user.orders[order_index].base_asset_amount = 0;
+    }
+
+    let order_id = user.next_order_id;
+    place_perp_order(
+        state,
+        user,
+        user_key,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        high_leverage_mode_config,
+        clock,
+        params,
+        PlaceOrderOptions {
+            oco_group_id,
+            ..options
+        },
+        &mut None,
+    )?;
+
+    match user.get_order_index(order_id) {
+        Ok(order_index) => user.orders[order_index].add_bit_flag(OrderBitFlag::BracketEntry),
+        Err(_) => {
+            msg!("bracket entry was not placed");
+            cancel_empty_bracket_orders(
+                oco_group_id,
+                user,
+                &user_key,
+                None,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                clock.unix_timestamp,
+                clock.slot,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Places a maker signed quote as a post only limit order in the maker's account. The nonce is
+/// only checked here, it's used up in `settle_signed_msg_quote_order` once the quote fills.
+/// Returns the quote's order id
+pub fn place_signed_msg_quote_order(
+    state: &State,
+    maker: &mut User,
+    maker_key: Pubkey,
+    maker_stats: &UserStats,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    clock: &Clock,
+    quote: &SignedMsgQuoteMessage,
+) -> DriftResult<u32> {
+    validate!(
+        quote.max_slot >= clock.slot,
+        ErrorCode::InvalidSignedMsgQuote,
+        "quote max slot {} < current slot {}",
+        quote.max_slot,
+        clock.slot
+    )?;
+
+    validate!(
+        quote.nonce > maker_stats.last_signed_quote_nonce,
+        ErrorCode::InvalidSignedMsgQuote,
+        "quote nonce {} <= last signed quote nonce {}",
+        quote.nonce,
+        maker_stats.last_signed_quote_nonce
+    )?;
+
+    let quote_order_params = OrderParams {
+        order_type: OrderType::Limit,
+        market_type: MarketType::Perp,
+        direction: quote.direction,
+        base_asset_amount: quote.base_asset_amount,
+        price: quote.price,
+        market_index: quote.market_index,
+        post_only: PostOnlyParam::MustPostOnly,
+        ..OrderParams::default()
+    };
+
+    place_perp_order(
+        state,
+        maker,
+        maker_key,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        &None,
+        clock,
+        quote_order_params,
+        PlaceOrderOptions {
+            update_heartbeat: false,
+            ..PlaceOrderOptions::default()
+        },
+        &mut None,
+    )?;
+
+    Ok(maker.get_last_order_id())
+}
+
+/// Quotes are firm for a single fill, so what's left of the quote order is canceled. The nonce is
+/// only used up if the quote filled, an unfilled quote can be submitted again until it expires
+pub fn settle_signed_msg_quote_order(
+    maker: &mut User,
+    maker_key: &Pubkey,
+    maker_stats: &mut UserStats,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    clock: &Clock,
+    quote_order_id: u32,
+    quote_nonce: u64,
+) -> DriftResult {
+    let order_index = maker
+        .orders
+        .iter()
+        .position(|order| order.order_id == quote_order_id)
+        .ok_or(ErrorCode::OrderDoesNotExist)?;
+
+    let quote_filled = maker.orders[order_index].base_asset_amount_filled > 0;
+
+    if maker.orders[order_index].status == OrderStatus::Open {
+        cancel_order(
+            order_index,
+            maker,
+            maker_key,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            clock.unix_timestamp,
+            clock.slot,
+            OrderActionExplanation::None,
+            None,
+            0,
+            false,
+        )?;
+    }
+
+    if quote_filled {
+        maker_stats.last_signed_quote_nonce = quote_nonce;
+    }
+
     Ok(())
 }
 
@@ -586,1255 +877,2757 @@ pub fn cancel_orders(
     Ok(canceled_order_ids)
 }
 
-pub fn cancel_order_by_order_id(
-    order_id: u32,
-    user: &AccountLoader<User>,
+pub fn cancel_orders_by_filter(
+    user: &mut User,
+    user_key: &Pubkey,
     perp_market_map: &PerpMarketMap,
     spot_market_map: &SpotMarketMap,
     oracle_map: &mut OracleMap,
-    clock: &Clock,
-) -> DriftResult {
-    let user_key = user.key();
-    let user = &mut load_mut!(user)?;
-    let order_index = match user.get_order_index(order_id) {
-        Ok(order_index) => order_index,
-        Err(_) => {
-            msg!("could not find order id {}", order_id);
-            return Ok(());
-        }
-    };
+    now: i64,
+    slot: u64,
+    params: &CancelOrdersParams,
+    orders_extension: &mut Option<UserOrdersExtensionZeroCopyMut>,
+) -> DriftResult<Vec<u32>> {
+    let mut canceled_order_ids: Vec<u32> = vec![];
+    let mut cancel_if_price_matches = |user: &mut User, order_index: usize| -> DriftResult {
+        if params.has_price_filter() {
+            let price = get_order_price_for_cancel_filter(
+                &user.orders[order_index],
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                slot,
+            )?;
 
-    cancel_order(
-        order_index,
-        user,
-        &user_key,
-        perp_market_map,
-        spot_market_map,
-        oracle_map,
-        clock.unix_timestamp,
-        clock.slot,
-        OrderActionExplanation::None,
-        None,
-        0,
-        false,
-    )?;
+            if !params.matches_price(price) {
+                return Ok(());
+            }
+        }
 
-    user.update_last_active_slot(clock.slot);
+        canceled_order_ids.push(user.orders[order_index].order_id);
+        cancel_order(
+            order_index,
+            user,
+            user_key,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            now,
+            slot,
+            OrderActionExplanation::None,
+            None,
+            0,
+            false,
+        )
+    };
 
-    Ok(())
-}
+    for order_index in 0..user.orders.len() {
+        if user.orders[order_index].status != OrderStatus::Open {
+            continue;
+        }
 
-pub fn cancel_order_by_user_order_id(
-    user_order_id: u8,
-    user: &AccountLoader<User>,
-    perp_market_map: &PerpMarketMap,
-    spot_market_map: &SpotMarketMap,
-    oracle_map: &mut OracleMap,
-    clock: &Clock,
-) -> DriftResult {
-    let user_key = user.key();
-    let user = &mut load_mut!(user)?;
-    let order_index = match user
-        .orders
-        .iter()
-        .position(|order| order.user_order_id == user_order_id)
-    {
-        Some(order_index) => order_index,
-        None => {
-            msg!("could not find user order id {}", user_order_id);
-            return Ok(());
+        if !params.matches(&user.orders[order_index], slot) {
+            continue;
         }
-    };
 
-    cancel_order(
-        order_index,
-        user,
-        &user_key,
-        perp_market_map,
-        spot_market_map,
-        oracle_map,
-        clock.unix_timestamp,
-        clock.slot,
-        OrderActionExplanation::None,
-        None,
-        0,
-        false,
-    )?;
+        cancel_if_price_matches(user, order_index)?;
+    }
 
-    user.update_last_active_slot(clock.slot);
+    if let Some(orders_extension) = orders_extension.as_mut() {
+        for_each_open_extension_order(
+            user,
+            orders_extension,
+            |order| params.matches(order, slot),
+            &mut cancel_if_price_matches,
+        )?;
+    }
 
-    Ok(())
+    user.update_last_active_slot(slot);
+
+    Ok(canceled_order_ids)
 }
 
-pub fn cancel_order(
-    order_index: usize,
-    user: &mut User,
-    user_key: &Pubkey,
+/// The price cancel filters compare against. Trigger orders that haven't triggered use their
+/// trigger price, everything else uses the limit price it would fill at now, including oracle
+/// offsets and auctions
+fn get_order_price_for_cancel_filter(
+    order: &Order,
     perp_market_map: &PerpMarketMap,
     spot_market_map: &SpotMarketMap,
     oracle_map: &mut OracleMap,
-    now: i64,
-    _slot: u64,
-    explanation: OrderActionExplanation,
-    filler_key: Option<&Pubkey>,
-    filler_reward: u64,
-    skip_log: bool,
-) -> DriftResult {
-    let (order_status, order_market_index, order_direction, order_market_type) = get_struct_values!(
-        user.orders[order_index],
-        status,
-        market_index,
-        direction,
-        market_type
-    );
-
-    let is_perp_order = order_market_type == MarketType::Perp;
+    slot: u64,
+) -> DriftResult<u64> {
+    if order.must_be_triggered() && !order.triggered() {
+        return Ok(order.trigger_price);
+    }
 
-    validate!(order_status == OrderStatus::Open, ErrorCode::OrderNotOpen)?;
+    get_order_limit_price(order, perp_market_map, spot_market_map, oracle_map, slot)
+}
 
-    let oracle_id = if is_perp_order {
-        perp_market_map.get_ref(&order_market_index)?.oracle_id()
-    } else {
-        spot_market_map.get_ref(&order_market_index)?.oracle_id()
+/// The limit price the order would fill at now, including oracle offsets and auctions
+fn get_order_limit_price(
+    order: &Order,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    slot: u64,
+) -> DriftResult<u64> {
+    let (oracle_id, tick_size, is_prediction_market) = match order.market_type {
+        MarketType::Perp => {
+            let market = perp_market_map.get_ref(&order.market_index)?;
+            (
+                market.oracle_id(),
+                market.amm.order_tick_size,
+                market.is_prediction_market(),
+            )
+        }
+        MarketType::Spot => {
+            let market = spot_market_map.get_ref(&order.market_index)?;
+            (market.oracle_id(), market.order_tick_size, false)
+        }
     };
 
-    if !skip_log {
-        let (taker, taker_order, maker, maker_order) =
-            get_taker_and_maker_for_order_record(user_key, &user.orders[order_index]);
+    let oracle_price = oracle_map.get_price_data(&oracle_id)?.price;
 
-        let order_action_record = get_order_action_record(
-            now,
-            OrderAction::Cancel,
-            explanation,
-            order_market_index,
-            filler_key.copied(),
-            None,
-            Some(filler_reward),
-            None,
-            None,
-            None,
-            None,
-            None,
-            None,
-            None,
-            taker,
-            taker_order,
-            maker,
-            maker_order,
-            oracle_map.get_price_data(&oracle_id)?.price,
-            0,
-            None,
-            None,
-            None,
-            None,
-            None,
+    Ok(order
+        .get_limit_price(
+            Some(oracle_price),
             None,
+            slot,
+            tick_size,
+            is_prediction_market,
             None,
-        )?;
-        emit_stack::<_, { OrderActionRecord::SIZE }>(order_action_record)?;
-    }
-
-    user.decrement_open_orders(user.orders[order_index].has_auction());
-    if is_perp_order {
-        // Decrement open orders for existing position
-        let position_index = get_position_index(&user.perp_positions, order_market_index)?;
+        )?
+        .unwrap_or(order.price))
+}
 
-        // only decrease open/bids ask if it's not a trigger order or if it's been triggered
-        let update_open_bids_and_asks = user.orders[order_index].update_open_bids_and_asks();
-        if update_open_bids_and_asks {
-            let base_asset_amount_unfilled =
-                user.orders[order_index].get_base_asset_amount_unfilled(None)?;
-            position::decrease_open_bids_and_asks(
-                &mut user.perp_positions[position_index],
-                &order_direction,
-                base_asset_amount_unfilled.cast()?,
-                update_open_bids_and_asks,
-            )?;
+/// Cancels the open orders parked in the user's orders extension account that match the filters.
+/// Should be called after cancel_orders so that no matching order is left in User.orders
+pub fn cancel_extension_orders(
+    user: &mut User,
+    user_key: &Pubkey,
+    filler_key: Option<&Pubkey>,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    now: i64,
+    slot: u64,
+    explanation: OrderActionExplanation,
+    market_type: Option<MarketType>,
+    market_index: Option<u16>,
+    direction: Option<PositionDirection>,
+    orders_extension: &mut UserOrdersExtensionZeroCopyMut,
+) -> DriftResult<Vec<u32>> {
+    let mut canceled_order_ids: Vec<u32> = vec![];
+    let should_cancel = |order: &Order| {
+        if let (Some(market_type), Some(market_index)) = (market_type, market_index) {
+            if order.market_type != market_type || order.market_index != market_index {
+                return false;
+            }
         }
 
-        user.perp_positions[position_index].open_orders -= 1;
-        user.orders[order_index].status = OrderStatus::Canceled;
-    } else {
-        let spot_position_index = user.get_spot_position_index(order_market_index)?;
-
-        // only decrease open/bids ask if it's not a trigger order or if it's been triggered
-        let update_open_bids_and_asks = user.orders[order_index].update_open_bids_and_asks();
-        if update_open_bids_and_asks {
-            let base_asset_amount_unfilled =
-                user.orders[order_index].get_base_asset_amount_unfilled(None)?;
-            decrease_spot_open_bids_and_asks(
-                &mut user.spot_positions[spot_position_index],
-                &order_direction,
-                base_asset_amount_unfilled,
-                update_open_bids_and_asks,
-            )?;
-        }
-        user.spot_positions[spot_position_index].open_orders -= 1;
-        user.orders[order_index].status = OrderStatus::Canceled;
-    }
+        direction.map_or(true, |direction| order.direction == direction)
+    };
 
-    Ok(())
-}
+    for_each_open_extension_order(
+        user,
+        orders_extension,
+        should_cancel,
+        |user, order_index| {
+            canceled_order_ids.push(user.orders[order_index].order_id);
+            cancel_order(
+                order_index,
+                user,
+                user_key,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                now,
+                slot,
+                explanation,
+                filler_key,
+                0,
+                false,
+            )
+        },
+    )?;
 
-pub enum ModifyOrderId {
-    UserOrderId(u8),
-    OrderId(u32),
+    Ok(canceled_order_ids)
 }
 
-pub fn modify_order(
-    order_id: ModifyOrderId,
-    modify_order_params: ModifyOrderParams,
-    user_loader: &AccountLoader<User>,
-    state: &State,
+/// Cancels the matching orders in User.orders and then the ones parked in the user's orders
+/// extension account, if it was passed in
+pub fn cancel_orders_with_extension(
+    user: &mut User,
+    user_key: &Pubkey,
+    filler_key: Option<&Pubkey>,
     perp_market_map: &PerpMarketMap,
     spot_market_map: &SpotMarketMap,
     oracle_map: &mut OracleMap,
-    clock: &Clock,
-) -> DriftResult {
-    let user_key = user_loader.key();
-    let mut user = load_mut!(user_loader)?;
-
-    let order_index = match order_id {
-        ModifyOrderId::UserOrderId(user_order_id) => {
-            match user.get_order_index_by_user_order_id(user_order_id) {
-                Ok(order_index) => order_index,
-                Err(e) => {
-                    msg!("User order id {} not found", user_order_id);
-                    if modify_order_params.must_modify() {
-                        return Err(e);
-                    } else {
-                        return Ok(());
-                    }
-                }
-            }
-        }
-        ModifyOrderId::OrderId(order_id) => match user.get_order_index(order_id) {
-            Ok(order_index) => order_index,
-            Err(e) => {
-                msg!("Order id {} not found", order_id);
-                if modify_order_params.must_modify() {
-                    return Err(e);
-                } else {
-                    return Ok(());
-                }
-            }
-        },
-    };
-
-    let existing_order = user.orders[order_index];
+    now: i64,
+    slot: u64,
+    explanation: OrderActionExplanation,
+    market_type: Option<MarketType>,
+    market_index: Option<u16>,
+    direction: Option<PositionDirection>,
+    orders_extension: &mut Option<UserOrdersExtensionZeroCopyMut>,
+) -> DriftResult<Vec<u32>> {
+    validate_orders_extension_passed(user, orders_extension)?;
 
-    cancel_order(
-        order_index,
-        &mut user,
-        &user_key,
+    let mut canceled_order_ids = cancel_orders(
+        user,
+        user_key,
+        filler_key,
         perp_market_map,
         spot_market_map,
         oracle_map,
-        clock.unix_timestamp,
-        clock.slot,
-        OrderActionExplanation::None,
-        None,
-        0,
-        false,
+        now,
+        slot,
+        explanation,
+        market_type,
+        market_index,
+        direction,
     )?;
 
-    user.update_last_active_slot(clock.slot);
+    if let Some(orders_extension) = orders_extension.as_mut() {
+        canceled_order_ids.extend(cancel_extension_orders(
+            user,
+            user_key,
+            filler_key,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            now,
+            slot,
+            explanation,
+            market_type,
+            market_index,
+            direction,
+            orders_extension,
+        )?);
+    }
 
-    let order_params =
-        merge_modify_order_params_with_existing_order(&existing_order, &modify_order_params)?;
+    Ok(canceled_order_ids)
+}
 
-    if let Some(order_params) = order_params {
-        if order_params.market_type == MarketType::Perp {
-            place_perp_order(
-                state,
-                &mut user,
+/// Orders parked in the user's orders extension account can't be canceled without it, so paths
+/// that have to cancel all of a user's orders require it once any order has been parked
+pub fn validate_orders_extension_passed(
+    user: &User,
+    orders_extension: &Option<UserOrdersExtensionZeroCopyMut>,
+) -> DriftResult {
+    validate!(
+        orders_extension.is_some() || user.extension_open_orders == 0,
+        ErrorCode::UserOrdersExtensionRequired,
+        "user has {} open orders in its orders extension",
+        user.extension_open_orders
+    )
+}
+
+/// Cancels the expired orders parked in the user's orders extension account
+pub fn expire_extension_orders(
+    user: &mut User,
+    user_key: &Pubkey,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    now: i64,
+    slot: u64,
+    orders_extension: &mut UserOrdersExtensionZeroCopyMut,
+) -> DriftResult {
+    let should_expire =
+        |order: &Order| order.max_ts != 0 && !order.must_be_triggered() && now > order.max_ts;
+
+    for_each_open_extension_order(
+        user,
+        orders_extension,
+        should_expire,
+        |user, order_index| {
+            cancel_order(
+                order_index,
+                user,
                 user_key,
                 perp_market_map,
                 spot_market_map,
                 oracle_map,
-                &None,
-                clock,
-                order_params,
-                PlaceOrderOptions::default(),
-                &mut None,
-            )?;
-        } else {
-            place_spot_order(
-                state,
-                &mut user,
-                user_key,
-                perp_market_map,
-                spot_market_map,
-                oracle_map,
-                clock,
-                order_params,
-                PlaceOrderOptions::default(),
-            )?;
+                now,
+                slot,
+                OrderActionExplanation::OrderExpired,
+                None,
+                0,
+                false,
+            )
+        },
+    )
+}
+
+/// Moves each open order in the user's orders extension account that passes `should_load` into an
+/// available User.orders slot, calls `f` with its order index and then parks whatever is left in
+/// the slot back in the extension. Errors if User.orders has no available slot to borrow
+fn for_each_open_extension_order(
+    user: &mut User,
+    orders_extension: &mut UserOrdersExtensionZeroCopyMut,
+    should_load: impl Fn(&Order) -> bool,
+    mut f: impl FnMut(&mut User, usize) -> DriftResult,
+) -> DriftResult {
+    for extension_order_index in 0..orders_extension.len() {
+        let order = orders_extension.get_order(extension_order_index)?;
+        if order.status != OrderStatus::Open || !should_load(order) {
+            continue;
         }
+
+        let order_index = match user.orders.iter().position(|order| order.is_available()) {
+            Some(order_index) => order_index,
+            None => {
+                msg!("no available order slot to load extension orders into");
+                return Err(ErrorCode::MaxNumberOfOrders);
+            }
+        };
+
+        swap_order_with_extension(user, order_index, orders_extension, extension_order_index)?;
+        f(user, order_index)?;
+        swap_order_with_extension(user, order_index, orders_extension, extension_order_index)?;
     }
 
     Ok(())
 }
 
-fn merge_modify_order_params_with_existing_order(
-    existing_order: &Order,
-    modify_order_params: &ModifyOrderParams,
-) -> DriftResult<Option<OrderParams>> {
-    let order_type = existing_order.order_type;
-    let market_type = existing_order.market_type;
-    let direction = modify_order_params
-        .direction
-        .unwrap_or(existing_order.direction);
-    let user_order_id = existing_order.user_order_id;
-    let base_asset_amount = match modify_order_params.base_asset_amount {
-        Some(base_asset_amount) if modify_order_params.exclude_previous_fill() => {
-            let base_asset_amount =
-                base_asset_amount.saturating_sub(existing_order.base_asset_amount_filled);
+/// Parks resting limit orders in the user's orders extension account until User.orders has
+/// `num_slots` available order slots
+pub fn free_order_slots_with_extension(
+    user: &mut User,
+    orders_extension: &mut UserOrdersExtensionZeroCopyMut,
+    num_slots: usize,
+) -> DriftResult {
+    while user
+        .orders
+        .iter()
+        .filter(|order| order.is_available())
+        .count()
+        < num_slots
+    {
+        let order_index = get_order_index_to_move_to_extension(user)?;
+        let extension_order_index = orders_extension.get_available_order_index()?;
+        swap_order_with_extension(user, order_index, orders_extension, extension_order_index)?;
+    }
 
-            if base_asset_amount == 0 {
-                return Ok(None);
-            }
+    Ok(())
+}
 
-            base_asset_amount
-        }
-        Some(base_asset_amount) => base_asset_amount,
-        None => existing_order.get_base_asset_amount_unfilled(None)?,
-    };
-    let price = modify_order_params.price.unwrap_or(existing_order.price);
-    let market_index = existing_order.market_index;
-    let reduce_only = modify_order_params
-        .reduce_only
-        .unwrap_or(existing_order.reduce_only);
-    let post_only = modify_order_params
-        .post_only
-        .unwrap_or(if existing_order.post_only {
-            PostOnlyParam::MustPostOnly
-        } else {
-            PostOnlyParam::None
-        });
-    let bit_flags = 0;
-    let max_ts = modify_order_params.max_ts.or(Some(existing_order.max_ts));
-    let trigger_price = modify_order_params
-        .trigger_price
-        .or(Some(existing_order.trigger_price));
-    let trigger_condition =
-        modify_order_params
-            .trigger_condition
-            .unwrap_or(match existing_order.trigger_condition {
-                OrderTriggerCondition::TriggeredAbove | OrderTriggerCondition::Above => {
-                    OrderTriggerCondition::Above
-                }
-                OrderTriggerCondition::TriggeredBelow | OrderTriggerCondition::Below => {
-                    OrderTriggerCondition::Below
-                }
-            });
-    let oracle_price_offset = modify_order_params
-        .oracle_price_offset
-        .or(Some(existing_order.oracle_price_offset));
-    let (auction_duration, auction_start_price, auction_end_price) =
-        if modify_order_params.auction_duration.is_some()
-            && modify_order_params.auction_start_price.is_some()
-            && modify_order_params.auction_end_price.is_some()
-        {
-            (
-                modify_order_params.auction_duration,
-                modify_order_params.auction_start_price,
-                modify_order_params.auction_end_price,
-            )
-        } else {
-            (None, None, None)
-        };
+/// Expires the orders parked in the user's orders extension account, if it was passed in, and then
+/// frees `num_slots` order slots in User.orders for the orders about to be placed
+pub fn expire_and_free_order_slots_with_extension(
+    user: &mut User,
+    user_key: &Pubkey,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    now: i64,
+    slot: u64,
+    orders_extension: &mut Option<UserOrdersExtensionZeroCopyMut>,
+    num_slots: usize,
+) -> DriftResult {
+    if let Some(orders_extension) = orders_extension.as_mut() {
+        expire_extension_orders(
+            user,
+            user_key,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            now,
+            slot,
+            orders_extension,
+        )?;
 
-    Ok(Some(OrderParams {
-        order_type,
-        market_type,
-        direction,
-        user_order_id,
-        base_asset_amount,
-        price,
-        market_index,
-        reduce_only,
-        post_only,
-        bit_flags,
-        max_ts,
-        trigger_price,
-        trigger_condition,
-        oracle_price_offset,
-        auction_duration,
-        auction_start_price,
-        auction_end_price,
-    }))
+        free_order_slots_with_extension(user, orders_extension, num_slots)?;
+    }
+
+    Ok(())
 }
 
-pub fn fill_perp_order(
+/// Moves an open order from the user's orders extension account back into User.orders so it
+/// can be filled or modified. Returns None if the order isn't in User.orders or the extension
+pub fn load_order_from_extension(
+    user: &mut User,
+    orders_extension: &mut UserOrdersExtensionZeroCopyMut,
     order_id: u32,
-    state: &State,
-    user: &AccountLoader<User>,
-    user_stats: &AccountLoader<UserStats>,
-    spot_market_map: &SpotMarketMap,
-    perp_market_map: &PerpMarketMap,
-    oracle_map: &mut OracleMap,
-    filler: &AccountLoader<User>,
-    filler_stats: &AccountLoader<UserStats>,
-    makers_and_referrer: &UserMap,
-    makers_and_referrer_stats: &UserStatsMap,
-    jit_maker_order_id: Option<u32>,
-    clock: &Clock,
-    fill_mode: FillMode,
-    rev_share_escrow: &mut Option<&mut RevenueShareEscrowZeroCopyMut>,
-    builder_referral_feature_enabled: bool,
-) -> DriftResult<(u64, u64)> {
-    let now = clock.unix_timestamp;
-    let slot = clock.slot;
+) -> DriftResult<Option<usize>> {
+    if let Ok(order_index) = user.get_order_index(order_id) {
+        return Ok(Some(order_index));
+    }
 
-    let filler_key = filler.key();
-    let user_key = user.key();
-    let user = &mut load_mut!(user)?;
-    let user_stats = &mut load_mut!(user_stats)?;
+    let extension_order_index = match orders_extension.get_order_index(order_id) {
+        Some(extension_order_index) => extension_order_index,
+        None => return Ok(None),
+    };
 
-    let order_index = user
-        .orders
-        .iter()
-        .position(|order| order.order_id == order_id && order.status == OrderStatus::Open)
-        .ok_or_else(print_error!(ErrorCode::OrderDoesNotExist))?;
+    let order_index = match user.orders.iter().position(|order| order.is_available()) {
+        Some(order_index) => order_index,
+        None => get_order_index_to_move_to_extension(user)?,
+    };
 
-    let (order_status, market_index, order_market_type, order_direction) = get_struct_values!(
-        user.orders[order_index],
-        status,
-        market_index,
-        market_type,
-        direction
-    );
+    swap_order_with_extension(user, order_index, orders_extension, extension_order_index)?;
 
-    validate!(
-        order_market_type == MarketType::Perp,
-        ErrorCode::InvalidOrderMarketType,
-        "must be perp order"
-    )?;
+    Ok(Some(order_index))
+}
 
-    // settle lp position so its tradeable
-    let mut market = perp_market_map.get_ref_mut(&market_index)?;
-    settle_funding_payment(user, &user_key, &mut market, now)?;
+/// Same as `load_order_from_extension` but finds the order by its user order id
+pub fn load_order_from_extension_by_user_order_id(
+    user: &mut User,
+    orders_extension: &mut UserOrdersExtensionZeroCopyMut,
+    user_order_id: u8,
+) -> DriftResult<Option<usize>> {
+    if let Ok(order_index) = user.get_order_index_by_user_order_id(user_order_id) {
+        return Ok(Some(order_index));
+    }
 
-    validate!(
-        matches!(
-            market.status,
-            MarketStatus::Active | MarketStatus::ReduceOnly
-        ),
-        ErrorCode::MarketFillOrderPaused,
-        "Market not active",
-    )?;
+    let order_id = match orders_extension.get_order_index_by_user_order_id(user_order_id) {
+        Some(extension_order_index) => orders_extension.get_order(extension_order_index)?.order_id,
+        None => return Ok(None),
+    };
 
-    validate!(
-        !market.is_operation_paused(PerpOperation::Fill),
-        ErrorCode::MarketFillOrderPaused,
-        "Market fills paused",
-    )?;
+    load_order_from_extension(user, orders_extension, order_id)
+}
 
-    drop(market);
+/// Moves a maker's open orders for a market and direction out of its orders extension account
+/// into the available User.orders slots, best priced first, so they can be matched by a taker
+pub fn load_maker_orders_from_extension(
+    maker: &mut User,
+    orders_extension: &mut UserOrdersExtensionZeroCopyMut,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    slot: u64,
+    market_type: MarketType,
+    market_index: u16,
+    maker_direction: PositionDirection,
+) -> DriftResult {
+    let mut extension_orders: Vec<(u32, u64)> = vec![];
+    for extension_order_index in 0..orders_extension.len() {
+        let order = orders_extension.get_order(extension_order_index)?;
+        if order.status != OrderStatus::Open
+            || order.market_type != market_type
+            || order.market_index != market_index
+            || order.direction != maker_direction
+        {
+            continue;
+        }
 
-    validate!(
-        order_status == OrderStatus::Open,
-        ErrorCode::OrderNotOpen,
-        "Order not open"
-    )?;
+        let price =
+            get_order_limit_price(order, perp_market_map, spot_market_map, oracle_map, slot)?;
 
-    validate!(
-        !user.orders[order_index].must_be_triggered() || user.orders[order_index].triggered(),
-        ErrorCode::OrderMustBeTriggeredFirst,
-        "Order must be triggered first"
-    )?;
+        extension_orders.push((extension_order_index, price));
+    }
 
-    if user.is_bankrupt() {
-        msg!("user is bankrupt");
-        return Ok((0, 0));
+    match maker_direction {
+        PositionDirection::Long => extension_orders.sort_by(|a, b| b.1.cmp(&a.1)),
+        PositionDirection::Short => extension_orders.sort_by(|a, b| a.1.cmp(&b.1)),
     }
 
-    if !fill_mode.is_liquidation() {
-        match validate_user_not_being_liquidated(
-            user,
-            perp_market_map,
-            spot_market_map,
-            oracle_map,
-            state.liquidation_margin_buffer_ratio,
-        ) {
-            Ok(_) => {}
-            Err(_) => {
-                msg!("user is being liquidated");
-                return Ok((0, 0));
-            }
-        }
+    for (extension_order_index, _) in extension_orders {
+        let order_index = match maker.orders.iter().position(|order| order.is_available()) {
+            Some(order_index) => order_index,
+            None => break,
+        };
+
+        swap_order_with_extension(maker, order_index, orders_extension, extension_order_index)?;
     }
 
-    let reserve_price_before: u64;
-    let safe_oracle_validity: OracleValidity;
-    let oracle_price: i64;
-    let oracle_twap_5min: i64;
-    let perp_market_index: u16;
-    let user_can_skip_duration: bool;
-    let amm_can_skip_duration: bool;
-    let amm_has_low_enough_inventory: bool;
-    let oracle_valid_for_amm_fill: bool;
-    let oracle_stale_for_margin: bool;
-    let min_auction_duration: u8;
-    let mut amm_is_available = !state.amm_paused()?;
-    {
-        let market = &mut perp_market_map.get_ref_mut(&market_index)?;
-        validation::perp_market::validate_perp_market(market)?;
-        validate!(
-            !market.is_in_settlement(now),
-            ErrorCode::MarketFillOrderPaused,
-            "Market is in settlement mode",
-        )?;
-
-        let oracle_price_data = oracle_map.get_price_data(&market.oracle_id())?;
-        let mm_oracle_price_data = market.get_mm_oracle_price_data(
-            *oracle_price_data,
-            slot,
-            &state.oracle_guard_rails.validity,
-        )?;
-        let safe_oracle_price_data = mm_oracle_price_data.get_safe_oracle_price_data();
-        safe_oracle_validity = oracle_validity(
-            MarketType::Perp,
-            market.market_index,
-            market.amm.historical_oracle_data.last_oracle_price_twap,
-            &safe_oracle_price_data,
-            &state.oracle_guard_rails.validity,
-            market.get_max_confidence_interval_multiplier()?,
-            &market.amm.oracle_source,
-            oracle::LogMode::SafeMMOracle,
-            market.amm.oracle_slot_delay_override,
-        )?;
+    Ok(())
+}
 
-        oracle_valid_for_amm_fill =
-            is_oracle_valid_for_action(safe_oracle_validity, Some(DriftAction::FillOrderAmm))?;
+/// Only the oldest resting limit orders are moved out of User.orders. Orders with auctions, triggers,
+/// oco groups or builder fees stay since other logic finds them by scanning User.orders
+fn get_order_index_to_move_to_extension(user: &User) -> DriftResult<usize> {
+    user.orders
+        .iter()
+        .enumerate()
+        .filter(|(_, order)| {
+            order.status == OrderStatus::Open
+                && order.order_type == OrderType::Limit
+                && !order.has_auction()
+                && !order.has_oco_group()
+                && !order.is_has_builder()
+        })
+        .min_by_key(|(_, order)| order.slot)
+        .map(|(order_index, _)| order_index)
+        .ok_or(ErrorCode::MaxNumberOfOrders)
+}
 
-        oracle_stale_for_margin = mm_oracle_price_data.get_delay()
-            > state
-                .oracle_guard_rails
-                .validity
-                .slots_before_stale_for_margin;
+fn swap_order_with_extension(
+    user: &mut User,
+    order_index: usize,
+    orders_extension: &mut UserOrdersExtensionZeroCopyMut,
+    extension_order_index: u32,
+) -> DriftResult {
+    let extension_order = orders_extension.get_order_mut(extension_order_index)?;
 
-        amm_is_available &= oracle_valid_for_amm_fill;
-        amm_is_available &= !market.is_operation_paused(PerpOperation::AmmFill);
-        amm_is_available &= !market.has_too_much_drawdown()?;
+    if user.orders[order_index].status == OrderStatus::Open {
+        user.extension_open_orders = user.extension_open_orders.safe_add(1)?;
+    }
 
-        // We are already using safe oracle data from MM oracle.
-        // But AMM isnt available if we could have used MM oracle but fell back due to price diff
-        let amm_available_mm_oracle_recent_but_volatile =
-            if mm_oracle_price_data.is_enabled() && mm_oracle_price_data.is_mm_oracle_as_recent() {
-                let amm_available = !mm_oracle_price_data.is_mm_exchange_diff_bps_high();
-                amm_available
-            } else {
-                true
-            };
-        amm_is_available &= amm_available_mm_oracle_recent_but_volatile;
+    if extension_order.status == OrderStatus::Open {
+        user.extension_open_orders = user.extension_open_orders.safe_sub(1)?;
+    }
 
-        let amm_wants_to_jit_make = market.amm.amm_wants_to_jit_make(order_direction)?;
-        amm_has_low_enough_inventory = market
-            .amm
-            .amm_has_low_enough_inventory(amm_wants_to_jit_make)?;
-        amm_can_skip_duration =
-            market.can_skip_auction_duration(&state, amm_has_low_enough_inventory)?;
+    std::mem::swap(&mut user.orders[order_index], extension_order);
 
-        user_can_skip_duration = user.can_skip_auction_duration(user_stats)?;
+    Ok(())
+}
 
-        reserve_price_before = market.amm.reserve_price()?;
-        oracle_price = mm_oracle_price_data.get_price();
-        oracle_twap_5min = market
-            .amm
-            .historical_oracle_data
-            .last_oracle_price_twap_5min;
-        perp_market_index = market.market_index;
+/// Cancels the other open orders in an order's oco group after it has been filled or triggered
+pub fn cancel_oco_group_orders(
+    order_index: usize,
+    user: &mut User,
+    user_key: &Pubkey,
+    filler_key: Option<&Pubkey>,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    now: i64,
+    slot: u64,
+) -> DriftResult {
+    let oco_group_id = user.orders[order_index].oco_group_id();
+    let order_id = user.orders[order_index].order_id;
 
-        min_auction_duration =
-            market.get_min_perp_auction_duration(state.min_perp_auction_duration);
+    if oco_group_id == 0 {
+        return Ok(());
     }
 
-    // allow oracle price to be used to calculate limit price if it's valid or stale for amm
-    let valid_oracle_price =
-        if is_oracle_valid_for_action(safe_oracle_validity, Some(DriftAction::OracleOrderPrice))? {
-            Some(oracle_price)
-        } else {
-            msg!("Perp market = {} oracle deemed invalid", perp_market_index);
-            None
-        };
-
-    let is_filler_taker = user_key == filler_key;
-    let is_filler_maker = makers_and_referrer.0.contains_key(&filler_key);
-    let (mut filler, mut filler_stats) = if !is_filler_maker && !is_filler_taker {
-        let filler = load_mut!(filler)?;
+    for i in 0..user.orders.len() {
+        if user.orders[i].status != OrderStatus::Open
+            || user.orders[i].oco_group_id() != oco_group_id
+            || user.orders[i].order_id == order_id
+        {
+            continue;
+        }
 
-        validate!(
-            filler.pool_id == 0,
-            ErrorCode::InvalidPoolId,
-            "filler pool id ({}) != 0",
-            filler.pool_id
+        cancel_order(
+            i,
+            user,
+            user_key,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            now,
+            slot,
+            OrderActionExplanation::OcoOrderFilledOrTriggered,
+            filler_key,
+            0,
+            false,
         )?;
+    }
 
-        if filler.authority != user.authority {
-            (Some(filler), Some(load_mut!(filler_stats)?))
-        } else {
-            (None, None)
-        }
-    } else {
-        (None, None)
-    };
-
-    let maker_orders_info = get_maker_orders_info(
-        perp_market_map,
-        spot_market_map,
-        oracle_map,
-        makers_and_referrer,
-        &user_key,
-        &user.orders[order_index],
-        &mut filler.as_deref_mut(),
-        &filler_key,
-        state.perp_fee_structure.flat_filler_fee,
-        oracle_price,
-        jit_maker_order_id,
-        now,
-        slot,
-        user_can_skip_duration,
-        state.min_perp_auction_duration as u64,
-    )?;
+    Ok(())
+}
 
-    // no referrer bonus for liquidations
-    let referrer_info = if !fill_mode.is_liquidation() {
-        get_referrer_info(
-            user_stats,
-            &user_key,
-            makers_and_referrer,
-            makers_and_referrer_stats,
+/// Sizes a bracket's protective orders to what its entry has filled. Fills of any other order in
+/// an oco group cancel the rest of the group
+pub fn update_oco_group_after_fill(
+    order_index: usize,
+    user: &mut User,
+    user_key: &Pubkey,
+    filler_key: Option<&Pubkey>,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    now: i64,
+    slot: u64,
+) -> DriftResult {
+    if !user.orders[order_index].is_bracket_entry() {
+        return cancel_oco_group_orders(
+            order_index,
+            user,
+            user_key,
+            filler_key,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            now,
             slot,
-        )?
-    } else {
-        None
-    };
-
-    let oracle_too_divergent_with_twap_5min = is_oracle_too_divergent_with_twap_5min(
-        oracle_price,
-        oracle_twap_5min,
-        state
-            .oracle_guard_rails
-            .max_oracle_twap_5min_percent_divergence()
-            .cast()?,
-    )?;
+        );
+    }
 
-    if oracle_too_divergent_with_twap_5min {
-        // update filler last active so tx doesn't revert
-        if let Some(filler) = filler.as_deref_mut() {
-            filler.update_last_active_slot(slot);
-        }
+    let oco_group_id = user.orders[order_index].oco_group_id();
+    let base_asset_amount_filled = user.orders[order_index].base_asset_amount_filled;
 
-        if !perp_market_map
-            .get_ref_mut(&market_index)?
-            .is_prediction_market()
+    for order in user.orders.iter_mut() {
+        if order.status == OrderStatus::Open
+            && order.oco_group_id() == oco_group_id
+            && !order.is_bracket_entry()
         {
-            return Ok((0, 0));
+            order.base_asset_amount = base_asset_amount_filled;
         }
     }
 
-    let should_expire_order = should_expire_order_before_fill(user, order_index, now)?;
-
-    let position_index =
-        get_position_index(&user.perp_positions, user.orders[order_index].market_index)?;
-    let existing_base_asset_amount = user.perp_positions[position_index].base_asset_amount;
-    let should_cancel_reduce_only = should_cancel_reduce_only_order(
-        &user.orders[order_index],
-        existing_base_asset_amount,
-        perp_market_map
-            .get_ref_mut(&market_index)?
-            .amm
-            .order_step_size,
-    )?;
-
-    if should_expire_order || should_cancel_reduce_only {
-        let filler_reward = {
-            let mut market = perp_market_map.get_ref_mut(&market_index)?;
-            pay_keeper_flat_reward_for_perps(
-                user,
-                filler.as_deref_mut(),
-                market.deref_mut(),
-                state.perp_fee_structure.flat_filler_fee,
-                slot,
-            )?
-        };
+    Ok(())
+}
 
-        let explanation = if should_expire_order {
-            OrderActionExplanation::OrderExpired
-        } else {
-            OrderActionExplanation::ReduceOnlyOrderIncreasedPosition
-        };
+/// Cancels the protective orders of a bracket that are still waiting on their entry to fill
+fn cancel_empty_bracket_orders(
+    oco_group_id: u8,
+    user: &mut User,
+    user_key: &Pubkey,
+    filler_key: Option<&Pubkey>,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    now: i64,
+    slot: u64,
+) -> DriftResult {
+    for i in 0..user.orders.len() {
+        if user.orders[i].status != OrderStatus::Open
+            || user.orders[i].oco_group_id() != oco_group_id
+            || user.orders[i].base_asset_amount != 0
+        {
+            continue;
+        }
 
         cancel_order(
-            order_index,
+            i,
             user,
-            &user_key,
+            user_key,
             perp_market_map,
             spot_market_map,
             oracle_map,
             now,
             slot,
-            explanation,
-            Some(&filler_key),
-            filler_reward,
+            OrderActionExplanation::BracketEntryCanceled,
+            filler_key,
+            0,
             false,
         )?;
-
-        return Ok((0, 0));
     }
 
-    let amm_availability = if amm_is_available {
-        if amm_can_skip_duration && user_can_skip_duration {
-            AMMAvailability::Immediate
-        } else {
-            AMMAvailability::AfterMinDuration
-        }
-    } else {
-        AMMAvailability::Unavailable
-    };
-
-    let (base_asset_amount, quote_asset_amount) = fulfill_perp_order(
-        user,
-        order_index,
-        &user_key,
-        user_stats,
-        makers_and_referrer,
-        makers_and_referrer_stats,
-        &maker_orders_info,
-        &mut filler.as_deref_mut(),
-        &filler_key,
-        &mut filler_stats.as_deref_mut(),
-        referrer_info,
-        spot_market_map,
-        perp_market_map,
-        oracle_map,
-        &state.perp_fee_structure,
-        reserve_price_before,
-        valid_oracle_price,
-        now,
-        slot,
-        min_auction_duration,
-        amm_availability,
-        fill_mode,
-        oracle_stale_for_margin,
-        rev_share_escrow,
-        builder_referral_feature_enabled,
-    )?;
-
-    if base_asset_amount != 0 {
-        let fill_price =
-            calculate_fill_price(quote_asset_amount, base_asset_amount, BASE_PRECISION_U64)?;
-
-        let mut perp_market = perp_market_map.get_ref_mut(&market_index)?;
-        validate_fill_price_within_price_bands(
-            fill_price,
-            oracle_price,
-            oracle_twap_5min,
-            perp_market.margin_ratio_initial,
-            state
-                .oracle_guard_rails
-                .max_oracle_twap_5min_percent_divergence(),
-            perp_market.is_prediction_market(),
-            None,
-        )?;
-
-        perp_market.last_fill_price = fill_price;
-    }
-
-    let base_asset_amount_after = user.perp_positions[position_index].base_asset_amount;
-    let should_cancel_reduce_only = should_cancel_reduce_only_order(
-        &user.orders[order_index],
-        base_asset_amount_after,
-        perp_market_map
-            .get_ref_mut(&market_index)?
-            .amm
-            .order_step_size,
-    )?;
-
-    if should_cancel_reduce_only {
-        let filler_reward = {
-            let mut market = perp_market_map.get_ref_mut(&market_index)?;
-            pay_keeper_flat_reward_for_perps(
-                user,
-                filler.as_deref_mut(),
-                market.deref_mut(),
-                state.perp_fee_structure.flat_filler_fee,
-                slot,
-            )?
-        };
+    Ok(())
+}
 
-        let explanation = OrderActionExplanation::ReduceOnlyOrderIncreasedPosition;
+/// Cancels the rest of a spread once one of its legs is canceled
+fn cancel_spread_legs(
+    oco_group_id: u8,
+    user: &mut User,
+    user_key: &Pubkey,
+    filler_key: Option<&Pubkey>,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    now: i64,
+    slot: u64,
+) -> DriftResult {
+    for i in 0..user.orders.len() {
+        if user.orders[i].status != OrderStatus::Open
+            || !user.orders[i].is_spread_leg()
+            || user.orders[i].oco_group_id() != oco_group_id
+        {
+            continue;
+        }
 
         cancel_order(
-            order_index,
+            i,
             user,
-            &user_key,
+            user_key,
             perp_market_map,
             spot_market_map,
             oracle_map,
             now,
             slot,
-            explanation,
-            Some(&filler_key),
-            filler_reward,
+            OrderActionExplanation::SpreadLegCanceled,
+            filler_key,
+            0,
             false,
-        )?
-    }
-
-    if base_asset_amount == 0 {
-        return Ok((base_asset_amount, quote_asset_amount));
+        )?;
     }
 
-    {
-        let market = perp_market_map.get_ref(&market_index)?;
+    Ok(())
+}
 
-        let open_interest = market.get_open_interest();
-        let max_open_interest = market.amm.max_open_interest;
+/// Links orders placed together into a spread. The legs rest until [`fill_spread_order`] fills
+/// all of them at once
+pub fn link_spread_order_legs(
+    user: &mut User,
+    order_ids: &[u32],
+    max_net_quote_asset_amount: i64,
+) -> DriftResult {
+    let oco_group_id = user.get_next_oco_group_id()?;
+    for order_id in order_ids.iter() {
+        let order_index = user.get_order_index(*order_id)?;
+        let order = &mut user.orders[order_index];
 
         validate!(
-            max_open_interest == 0 || max_open_interest > open_interest,
-            ErrorCode::MaxOpenInterest,
-            "open interest ({}) > max open interest ({})",
-            open_interest,
-            max_open_interest
+            !order.has_oco_group() && !order.has_auction(),
+            ErrorCode::InvalidOrder,
+            "spread leg can not have an oco group or an auction"
         )?;
+
+        order.oco_group_id_and_bit_flags |= oco_group_id;
+        order.add_ext_bit_flag(OrderExtBitFlag::SpreadLeg);
+        order.auction_end_price = max_net_quote_asset_amount;
     }
 
-    // Try to update the funding rate at the end of every trade
-    {
-        let market = &mut perp_market_map.get_ref_mut(&market_index)?;
-        let funding_paused =
-            state.funding_paused()? || market.is_operation_paused(PerpOperation::UpdateFunding);
+    Ok(())
+}
 
-        controller::funding::update_funding_rate(
-            market_index,
-            market,
-            oracle_map,
-            now,
-            slot,
-            &state.oracle_guard_rails,
-            funding_paused,
-            Some(reserve_price_before),
+/// Fills every leg of the spread the order belongs to, all-or-none. The legs are unlinked and each
+/// filled as a plain limit order; a leg left unfilled or a net cost above the spread's limit fails
+/// the fill and with it the transaction.
+///
+/// The net cost sums each leg's quote filled, i.e. its fill price weighted by its base filled:
+/// positive for legs going long (paid) and negative for legs going short (received). Returns it
+/// (precision: QUOTE_PRECISION)
+pub fn fill_spread_order(
+    order_id: u32,
+    state: &State,
+    user: &AccountLoader<User>,
+    user_stats: &AccountLoader<UserStats>,
+    spot_market_map: &SpotMarketMap,
+    perp_market_map: &PerpMarketMap,
+    oracle_map: &mut OracleMap,
+    filler: &AccountLoader<User>,
+    filler_stats: &AccountLoader<UserStats>,
+    makers_and_referrer: &UserMap,
+    makers_and_referrer_stats: &UserStatsMap,
+    clock: &Clock,
+    fill_mode: FillMode,
+    mut spot_fulfillment_params: Option<&mut dyn SpotFulfillmentParams>,
+    rev_share_escrow: &mut Option<&mut RevenueShareEscrowZeroCopyMut>,
+    builder_referral_feature_enabled: bool,
+    perp_order_books: &[&PerpOrderBook],
+) -> DriftResult<i64> {
+    let (legs, max_net_quote_asset_amount) = {
+        let user = &mut load_mut!(user)?;
+        let order_index = user.get_order_index(order_id)?;
+
+        validate!(
+            user.orders[order_index].is_spread_leg(),
+            ErrorCode::InvalidOrder,
+            "order {} is not a spread leg",
+            order_id
         )?;
-    }
 
-    user.update_last_active_slot(slot);
+        let oco_group_id = user.orders[order_index].oco_group_id();
+        let max_net_quote_asset_amount =
+            user.orders[order_index].get_spread_max_net_quote_asset_amount();
 
-    Ok((base_asset_amount, quote_asset_amount))
-}
+        let mut legs = Vec::with_capacity(4);
+        for order in user.orders.iter_mut() {
+            if order.status == OrderStatus::Open
+                && order.is_spread_leg()
+                && order.oco_group_id() == oco_group_id
+            {
+                legs.push((order.order_id, order.market_type, order.direction));
+                order.unlink_spread_leg();
+            }
+        }
 
-pub fn validate_market_within_price_band(
-    market: &PerpMarket,
-    state: &State,
-    oracle_price: i64,
-) -> DriftResult<bool> {
-    if market.is_prediction_market() {
-        return Ok(true);
+        (legs, max_net_quote_asset_amount)
+    };
+
+    let mut net_quote_asset_amount = 0_i64;
+    for (leg_order_id, market_type, direction) in legs {
+        if market_type == MarketType::Perp {
+            fill_perp_order(
+                leg_order_id,
+                state,
+                user,
+                user_stats,
+                spot_market_map,
+                perp_market_map,
+                oracle_map,
+                filler,
+                filler_stats,
+                makers_and_referrer,
+                makers_and_referrer_stats,
+                None,
+                clock,
+                fill_mode,
+                rev_share_escrow,
+                builder_referral_feature_enabled,
+                perp_order_books,
+            )?;
+        } else {
+            let fulfillment_params = spot_fulfillment_params.as_deref_mut().ok_or_else(|| {
+                msg!("spot leg {} needs spot fulfillment params", leg_order_id);
+                ErrorCode::InvalidOrder
+            })?;
+
+            fill_spot_order(
+                leg_order_id,
+                state,
+                user,
+                user_stats,
+                spot_market_map,
+                perp_market_map,
+                oracle_map,
+                filler,
+                filler_stats,
+                makers_and_referrer,
+                makers_and_referrer_stats,
+                None,
+                clock,
+                fulfillment_params,
+            )?;
+        }
+
+        // the filled order's slot is free again, so its fill is read before the next leg
+        let user = load!(user)?;
+        let order = user
+            .orders
+            .iter()
+            .find(|order| order.order_id == leg_order_id)
+            .ok_or(ErrorCode::OrderDoesNotExist)?;
+
+        validate!(
+            order.status == OrderStatus::Filled
+                && order.base_asset_amount_filled == order.base_asset_amount,
+            ErrorCode::SpreadOrderNotFilled,
+            "spread leg {} filled {} of {}",
+            leg_order_id,
+            order.base_asset_amount_filled,
+            order.base_asset_amount
+        )?;
+
+        let quote_asset_amount_filled = order.quote_asset_amount_filled.cast::<i64>()?;
+        net_quote_asset_amount = match direction {
+            PositionDirection::Long => {
+                net_quote_asset_amount.safe_add(quote_asset_amount_filled)?
+            }
+            PositionDirection::Short => {
+                net_quote_asset_amount.safe_sub(quote_asset_amount_filled)?
+            }
+        };
     }
 
-    let reserve_price = market.amm.reserve_price()?;
+    validate!(
+        net_quote_asset_amount <= max_net_quote_asset_amount,
+        ErrorCode::SpreadOrderNotFilled,
+        "spread net quote {} above limit {}",
+        net_quote_asset_amount,
+        max_net_quote_asset_amount
+    )?;
 
-    let reserve_spread_pct =
-        amm::calculate_oracle_twap_5min_price_spread_pct(&market.amm, reserve_price)?;
+    Ok(net_quote_asset_amount)
+}
 
-    let oracle_spread_pct =
-        amm::calculate_oracle_twap_5min_price_spread_pct(&market.amm, oracle_price.unsigned_abs())?;
+/// trigger_price holds the trigger price for trigger orders, the start ts/slot for orders pending
+/// activation and the min fill size for other orders
+fn get_trigger_price_for_new_order(
+    params: &OrderParams,
+    tick_size: u64,
+    step_size: u64,
+) -> DriftResult<u64> {
+    let trigger_price = params.trigger_price.unwrap_or(0);
 
-    if reserve_spread_pct.abs() > oracle_spread_pct.abs() {
-        let is_reserve_too_divergent = amm::is_oracle_mark_too_divergent(
-            reserve_spread_pct,
-            &state.oracle_guard_rails.price_divergence,
+    if params.is_trigger_order() {
+        validate!(
+            !params.has_min_fill_size(),
+            ErrorCode::InvalidOrderMinFillSize,
+            "trigger orders can not have a min fill size"
         )?;
 
-        // if oracle-mark divergence pushed outside limit, block order
-        if is_reserve_too_divergent {
-            msg!("Perp market = {} price pushed outside bounds: last_oracle_price_twap_5min={} vs reserve_price={},(breach spread {})",
-                market.market_index,
-                market.amm.historical_oracle_data.last_oracle_price_twap_5min,
-                reserve_price,
-                reserve_spread_pct,
-            );
-            return Err(ErrorCode::PriceBandsBreached);
-        }
+        standardize_price(trigger_price, tick_size, params.direction)
+    } else if params.is_pending_activation() {
+        validate!(
+            !params.has_min_fill_size(),
+            ErrorCode::InvalidOrderMinFillSize,
+            "orders pending activation can not have a min fill size"
+        )?;
+
+        Ok(trigger_price)
+    } else if params.has_min_fill_size() {
+        standardize_base_asset_amount(trigger_price, step_size)
     } else {
-        let is_oracle_too_divergent = amm::is_oracle_mark_too_divergent(
-            oracle_spread_pct,
-            &state.oracle_guard_rails.price_divergence,
+        validate!(
+            trigger_price == 0,
+            ErrorCode::InvalidOrderTrigger,
+            "non trigger order should not have trigger price"
         )?;
 
-        // if oracle-mark divergence pushed outside limit, block order
-        if is_oracle_too_divergent {
-            msg!("Perp market = {} price pushed outside bounds: last_oracle_price_twap_5min={} vs oracle_price={},(breach spread {})",
-                market.market_index,
-                market.amm.historical_oracle_data.last_oracle_price_twap_5min,
-                oracle_price,
-                oracle_spread_pct,
-            );
-            return Err(ErrorCode::PriceBandsBreached);
-        }
+        Ok(0)
     }
-
-    Ok(true)
 }
 
-#[allow(clippy::type_complexity)]
-fn get_maker_orders_info(
-    perp_market_map: &PerpMarketMap,
+pub fn cancel_order_by_order_id(
+    order_id: u32,
+    user: &AccountLoader<User>,
+    perp_market_map: &PerpMarketMap,
     spot_market_map: &SpotMarketMap,
     oracle_map: &mut OracleMap,
-    makers_and_referrer: &UserMap,
-    taker_key: &Pubkey,
-    taker_order: &Order,
-    filler: &mut Option<&mut User>,
-    filler_key: &Pubkey,
-    filler_reward: u64,
-    oracle_price: i64,
-    jit_maker_order_id: Option<u32>,
-    now: i64,
-    slot: u64,
-    user_can_skip_duration: bool,
-    protected_maker_min_age: u64,
-) -> DriftResult<Vec<(Pubkey, usize, u64)>> {
-    let maker_direction = taker_order.direction.opposite();
-
-    let mut maker_orders_info = Vec::with_capacity(16);
-
-    let taker_order_age = slot.safe_sub(taker_order.slot)?;
-
-    for (maker_key, user_account_loader) in makers_and_referrer.0.iter() {
-        if maker_key == taker_key {
-            continue;
+    clock: &Clock,
+) -> DriftResult {
+    let user_key = user.key();
+    let user = &mut load_mut!(user)?;
+    let order_index = match user.get_order_index(order_id) {
+        Ok(order_index) => order_index,
+        Err(_) => {
+            msg!("could not find order id {}", order_id);
+            return Ok(());
         }
+    };
 
-        let mut maker = load_mut!(user_account_loader)?;
-
-        if maker.is_being_liquidated() || maker.is_bankrupt() {
-            continue;
-        }
+    cancel_order(
+        order_index,
+        user,
+        &user_key,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        clock.unix_timestamp,
+        clock.slot,
+        OrderActionExplanation::None,
+        None,
+        0,
+        false,
+    )?;
 
-        let is_protected_maker = maker.is_protected_maker();
+    user.update_last_active_slot(clock.slot);
 
-        let mut market = perp_market_map.get_ref_mut(&taker_order.market_index)?;
-        let maker_order_price_and_indexes = find_maker_orders(
-            &maker,
-            &maker_direction,
-            &MarketType::Perp,
-            taker_order.market_index,
-            Some(oracle_price),
-            slot,
-            market.amm.order_tick_size,
-            market.is_prediction_market(),
-            get_protected_maker_params(
-                is_protected_maker,
-                jit_maker_order_id.is_some(),
-                user_can_skip_duration,
-                taker_order_age,
-                protected_maker_min_age,
-                market.deref(),
-            ),
-        )?;
+    Ok(())
+}
 
-        if maker_order_price_and_indexes.is_empty() {
-            continue;
+pub fn cancel_order_by_user_order_id(
+    user_order_id: u8,
+    user: &AccountLoader<User>,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    clock: &Clock,
+) -> DriftResult {
+    let user_key = user.key();
+    let user = &mut load_mut!(user)?;
+    let order_index = match user
+        .orders
+        .iter()
+        .position(|order| order.user_order_id == user_order_id)
+    {
+        Some(order_index) => order_index,
+        None => {
+            msg!("could not find user order id {}", user_order_id);
+            return Ok(());
         }
+    };
 
-        maker.update_last_active_slot(slot);
+    cancel_order(
+        order_index,
+        user,
+        &user_key,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        clock.unix_timestamp,
+        clock.slot,
+        OrderActionExplanation::None,
+        None,
+        0,
+        false,
+    )?;
 
-        settle_funding_payment(&mut maker, maker_key, &mut market, now)?;
+    user.update_last_active_slot(clock.slot);
 
-        let initial_margin_ratio = market.margin_ratio_initial;
-        let step_size = market.amm.order_step_size;
+    Ok(())
+}
 
-        drop(market);
+pub fn cancel_order(
+    order_index: usize,
+    user: &mut User,
+    user_key: &Pubkey,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    now: i64,
+    _slot: u64,
+    explanation: OrderActionExplanation,
+    filler_key: Option<&Pubkey>,
+    filler_reward: u64,
+    skip_log: bool,
+) -> DriftResult {
+    let (order_status, order_market_index, order_direction, order_market_type) = get_struct_values!(
+        user.orders[order_index],
+        status,
+        market_index,
+        direction,
+        market_type
+    );
 
-        for (maker_order_index, maker_order_price) in maker_order_price_and_indexes.iter() {
-            let maker_order_index = *maker_order_index;
-            let maker_order_price = *maker_order_price;
+    let is_perp_order = order_market_type == MarketType::Perp;
 
-            let maker_order = &maker.orders[maker_order_index];
-            if !is_maker_for_taker(maker_order, taker_order, slot)? {
-                continue;
-            }
+    validate!(order_status == OrderStatus::Open, ErrorCode::OrderNotOpen)?;
 
-            if !are_orders_same_market_but_different_sides(maker_order, taker_order) {
-                continue;
-            }
+    let oracle_id = if is_perp_order {
+        perp_market_map.get_ref(&order_market_index)?.oracle_id()
+    } else {
+        spot_market_map.get_ref(&order_market_index)?.oracle_id()
+    };
 
-            if let Some(jit_maker_order_id) = jit_maker_order_id {
-                // if jit maker order id exists, must only use that order
-                if maker_order.order_id != jit_maker_order_id {
-                    continue;
-                }
-            }
+    if !skip_log {
+        let (taker, taker_order, maker, maker_order) =
+            get_taker_and_maker_for_order_record(user_key, &user.orders[order_index]);
 
-            let breaches_oracle_price_limits = {
-                limit_price_breaches_maker_oracle_price_bands(
-                    maker_order_price,
-                    maker_order.direction,
-                    oracle_price,
-                    initial_margin_ratio,
-                )?
-            };
+        let order_action_record = get_order_action_record(
+            now,
+            OrderAction::Cancel,
+            explanation,
+            order_market_index,
+            filler_key.copied(),
+            None,
+            Some(filler_reward),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            taker,
+            taker_order,
+            maker,
+            maker_order,
+            oracle_map.get_price_data(&oracle_id)?.price,
+            0,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )?;
+        emit_stack::<_, { OrderActionRecord::SIZE }>(order_action_record)?;
+    }
 
-            let should_expire_order = should_expire_order(&maker, maker_order_index, now)?;
+    user.decrement_open_orders(user.orders[order_index].has_auction());
+    if is_perp_order {
+        // Decrement open orders for existing position
+        let position_index = get_position_index(&user.perp_positions, order_market_index)?;
 
-            let existing_base_asset_amount = maker
-                .get_perp_position(maker.orders[maker_order_index].market_index)?
-                .base_asset_amount;
-            let should_cancel_reduce_only_order = should_cancel_reduce_only_order(
-                &maker.orders[maker_order_index],
-                existing_base_asset_amount,
-                step_size,
+        // only decrease open/bids ask if it's not a trigger order or if it's been triggered
+        let update_open_bids_and_asks = user.orders[order_index].update_open_bids_and_asks();
+        if update_open_bids_and_asks {
+            let base_asset_amount_unfilled =
+                user.orders[order_index].get_base_asset_amount_unfilled(None)?;
+            position::decrease_open_bids_and_asks(
+                &mut user.perp_positions[position_index],
+                &order_direction,
+                base_asset_amount_unfilled.cast()?,
+                update_open_bids_and_asks,
             )?;
+        }
 
-            if breaches_oracle_price_limits
-                || should_expire_order
-                || should_cancel_reduce_only_order
-            {
-                let filler_reward = {
-                    let mut market = perp_market_map
-                        .get_ref_mut(&maker.orders[maker_order_index].market_index)?;
-                    pay_keeper_flat_reward_for_perps(
-                        &mut maker,
-                        filler.as_deref_mut(),
-                        market.deref_mut(),
-                        filler_reward,
-                        slot,
-                    )?
-                };
+        user.perp_positions[position_index].open_orders -= 1;
+        user.orders[order_index].status = OrderStatus::Canceled;
+    } else {
+        let spot_position_index = user.get_spot_position_index(order_market_index)?;
 
-                let explanation = if breaches_oracle_price_limits {
-                    OrderActionExplanation::OraclePriceBreachedLimitPrice
-                } else if should_expire_order {
-                    OrderActionExplanation::OrderExpired
-                } else {
-                    OrderActionExplanation::ReduceOnlyOrderIncreasedPosition
-                };
-
-                cancel_order(
-                    maker_order_index,
-                    maker.deref_mut(),
-                    maker_key,
-                    perp_market_map,
-                    spot_market_map,
-                    oracle_map,
-                    now,
-                    slot,
-                    explanation,
-                    Some(filler_key),
-                    filler_reward,
-                    false,
-                )?;
+        // only decrease open/bids ask if it's not a trigger order or if it's been triggered
+        let update_open_bids_and_asks = user.orders[order_index].update_open_bids_and_asks();
+        if update_open_bids_and_asks {
+            let base_asset_amount_unfilled =
+                user.orders[order_index].get_base_asset_amount_unfilled(None)?;
+            decrease_spot_open_bids_and_asks(
+                &mut user.spot_positions[spot_position_index],
+                &order_direction,
+                base_asset_amount_unfilled,
+                update_open_bids_and_asks,
+            )?;
+        }
+        user.spot_positions[spot_position_index].open_orders -= 1;
+        user.orders[order_index].status = OrderStatus::Canceled;
+    }
 
-                continue;
-            }
+    if user.orders[order_index].is_bracket_entry() {
+        cancel_empty_bracket_orders(
+            user.orders[order_index].oco_group_id(),
+            user,
+            user_key,
+            filler_key,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            now,
+            _slot,
+        )?;
+    }
 
-            insert_maker_order_info(
-                &mut maker_orders_info,
-                (*maker_key, maker_order_index, maker_order_price),
-                maker_direction,
-            );
-        }
+    if user.orders[order_index].is_spread_leg() {
+        cancel_spread_legs(
+            user.orders[order_index].oco_group_id(),
+            user,
+            user_key,
+            filler_key,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            now,
+            _slot,
+        )?;
     }
 
-    Ok(maker_orders_info)
+    Ok(())
 }
 
-#[inline(always)]
-fn get_protected_maker_params(
-    is_protected_maker: bool,
-    jit_maker: bool,
-    user_can_skip_duration: bool,
-    taker_order_age: u64,
-    protected_maker_min_age: u64,
-    market: &PerpMarket,
-) -> Option<ProtectedMakerParams> {
-    if is_protected_maker
-        && !jit_maker
-        && !user_can_skip_duration
-        && taker_order_age < protected_maker_min_age
-    {
-        Some(market.get_protected_maker_params())
-    } else {
-        None
-    }
+pub enum ModifyOrderId {
+    UserOrderId(u8),
+    OrderId(u32),
 }
 
-#[inline(always)]
-fn insert_maker_order_info(
-    maker_orders_info: &mut Vec<(Pubkey, usize, u64)>,
-    maker_order_info: (Pubkey, usize, u64),
-    direction: PositionDirection,
-) {
-    let price = maker_order_info.2;
-    let index = match maker_orders_info.binary_search_by(|item| match direction {
-        PositionDirection::Short => item.2.cmp(&price),
-        PositionDirection::Long => price.cmp(&item.2),
-    }) {
-        Ok(index) => index,
-        Err(index) => index,
+pub fn modify_order(
+    order_id: ModifyOrderId,
+    modify_order_params: ModifyOrderParams,
+    user_loader: &AccountLoader<User>,
+    state: &State,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    clock: &Clock,
+) -> DriftResult {
+    let user_key = user_loader.key();
+    let mut user = load_mut!(user_loader)?;
+
+    let order_index = match order_id {
+        ModifyOrderId::UserOrderId(user_order_id) => {
+            match user.get_order_index_by_user_order_id(user_order_id) {
+                Ok(order_index) => order_index,
+                Err(e) => {
+                    msg!("User order id {} not found", user_order_id);
+                    if modify_order_params.must_modify() {
+                        return Err(e);
+                    } else {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        ModifyOrderId::OrderId(order_id) => match user.get_order_index(order_id) {
+            Ok(order_index) => order_index,
+            Err(e) => {
+                msg!("Order id {} not found", order_id);
+                if modify_order_params.must_modify() {
+                    return Err(e);
+                } else {
+                    return Ok(());
+                }
+            }
+        },
     };
 
-    if index < maker_orders_info.capacity() {
-        maker_orders_info.insert(index, maker_order_info);
+    let existing_order = user.orders[order_index];
+
+    validate!(
+        !existing_order.is_bracket_entry() && !existing_order.is_spread_leg(),
+        ErrorCode::InvalidOrder,
+        "bracket entry or spread leg order {} can not be modified",
+        existing_order.order_id
+    )?;
+
+    if amend_order(
+        order_index,
+        &modify_order_params,
+        &mut user,
+        &user_key,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        clock.unix_timestamp,
+        clock.slot,
+    )? {
+        return Ok(());
+    }
+
+    cancel_order(
+        order_index,
+        &mut user,
+        &user_key,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        clock.unix_timestamp,
+        clock.slot,
+        OrderActionExplanation::None,
+        None,
+        0,
+        false,
+    )?;
+
+    user.update_last_active_slot(clock.slot);
+
+    let order_params =
+        merge_modify_order_params_with_existing_order(&existing_order, &modify_order_params)?;
+
+    if let Some(order_params) = order_params {
+        if order_params.market_type == MarketType::Perp {
+            place_perp_order(
+                state,
+                &mut user,
+                user_key,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                &None,
+                clock,
+                order_params,
+                PlaceOrderOptions {
+                    oco_group_id: existing_order.oco_group_id(),
+                    ..PlaceOrderOptions::default()
+                },
+                &mut None,
+            )?;
+        } else {
+            place_spot_order(
+                state,
+                &mut user,
+                user_key,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                clock,
+                order_params,
+                PlaceOrderOptions::default(),
+            )?;
+        }
     }
+
+    Ok(())
 }
 
-fn get_referrer_info(
-    user_stats: &UserStats,
+/// Amends the order in place so it keeps its slot and queue priority. Only size decreases and
+/// max_ts/reduce_only changes can be amended, returns false if the order has to be re-placed
+fn amend_order(
+    order_index: usize,
+    modify_order_params: &ModifyOrderParams,
+    user: &mut User,
     user_key: &Pubkey,
-    makers_and_referrer: &UserMap,
-    makers_and_referrer_stats: &UserStatsMap,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    now: i64,
     slot: u64,
-) -> DriftResult<Option<(Pubkey, Pubkey)>> {
-    if user_stats.referrer.eq(&Pubkey::default()) {
-        return Ok(None);
-    }
+) -> DriftResult<bool> {
+    let existing_order = user.orders[order_index];
 
-    validate!(
-        makers_and_referrer_stats
-            .0
-            .contains_key(&user_stats.referrer),
-        ErrorCode::ReferrerStatsNotFound
-    )?;
+    if !modify_order_params.only_amends(&existing_order) {
+        return Ok(false);
+    }
 
-    let referrer_authority_key = user_stats.referrer;
-    let mut referrer_user_key = Pubkey::default();
-    for (referrer_key, referrer) in makers_and_referrer.0.iter() {
-        // if user is in makers and referrer map, skip to avoid invalid borrow
-        if referrer_key == user_key {
-            continue;
+    let base_asset_amount_unfilled = existing_order.get_base_asset_amount_unfilled(None)?;
+    let new_base_asset_amount_unfilled = match modify_order_params.base_asset_amount {
+        Some(_) if existing_order.is_close_position() => return Ok(false),
+        Some(base_asset_amount) if modify_order_params.exclude_previous_fill() => {
+            base_asset_amount.saturating_sub(existing_order.base_asset_amount_filled)
         }
+        Some(base_asset_amount) => base_asset_amount,
+        None => base_asset_amount_unfilled,
+    };
 
-        let mut referrer = load_mut!(referrer)?;
-        if referrer.authority != referrer_authority_key {
-            continue;
-        }
+    let (market_index, market_type, direction) =
+        get_struct_values!(existing_order, market_index, market_type, direction);
 
-        if referrer.sub_account_id == 0 {
-            if referrer.pool_id != 0 {
-                return Ok(None);
-            }
+    let step_size = match market_type {
+        MarketType::Perp => perp_market_map.get_ref(&market_index)?.amm.order_step_size,
+        MarketType::Spot => spot_market_map.get_ref(&market_index)?.order_step_size,
+    };
 
-            referrer.update_last_active_slot(slot);
-            referrer_user_key = *referrer_key;
-            break;
-        }
+    if new_base_asset_amount_unfilled == 0
+        || new_base_asset_amount_unfilled > base_asset_amount_unfilled
+        || !is_multiple_of_step_size(new_base_asset_amount_unfilled, step_size)?
+    {
+        return Ok(false);
     }
 
-    if referrer_user_key == Pubkey::default() {
-        return Err(ErrorCode::ReferrerNotFound);
+    let max_ts = modify_order_params.max_ts.unwrap_or(existing_order.max_ts);
+    if max_ts != 0 && max_ts < now {
+        return Ok(false);
     }
 
-    Ok(Some((referrer_authority_key, referrer_user_key)))
-}
+    // removing reduce only can increase risk so it goes through the margin checks in place order
+    let reduce_only = modify_order_params
+        .reduce_only
+        .unwrap_or(existing_order.reduce_only);
+    if existing_order.reduce_only && !reduce_only {
+        return Ok(false);
+    }
 
-#[inline(always)]
-fn get_builder_escrow_info(
-    escrow_opt: &mut Option<&mut RevenueShareEscrowZeroCopyMut>,
-    sub_account_id: u16,
-    order_id: u32,
-    market_index: u16,
-    builder_referral_feature_enabled: bool,
-) -> (Option<u32>, Option<u32>, Option<u16>, Option<u8>) {
-    if let Some(escrow) = escrow_opt {
-        let builder_order_idx = escrow.find_order_index(sub_account_id, order_id);
-        let referrer_builder_order_idx = if builder_referral_feature_enabled {
-            escrow.find_or_create_referral_index(market_index)
-        } else {
-            None
-        };
+    let base_asset_amount_decrease =
+        base_asset_amount_unfilled.safe_sub(new_base_asset_amount_unfilled)?;
+
+    let mut amended_order = existing_order;
+    amended_order.base_asset_amount = amended_order
+        .base_asset_amount
+        .safe_sub(base_asset_amount_decrease)?;
+    amended_order.max_ts = max_ts;
+    amended_order.reduce_only = reduce_only;
+
+    let update_open_bids_and_asks = existing_order.update_open_bids_and_asks();
+    if amended_order.update_open_bids_and_asks() != update_open_bids_and_asks
+        || amended_order.get_min_fill_size() > amended_order.base_asset_amount
+        || (amended_order.is_iceberg()
+            && amended_order.get_iceberg_display_size()? >= amended_order.base_asset_amount)
+    {
+        return Ok(false);
+    }
 
-        let builder_order = builder_order_idx.and_then(|idx| escrow.get_order(idx).ok());
-        let builder_order_fee_bps = builder_order.map(|order| order.fee_tenth_bps);
-        let builder_idx = builder_order.map(|order| order.builder_idx);
+    let oracle_id = match market_type {
+        MarketType::Perp => {
+            let position_index = get_position_index(&user.perp_positions, market_index)?;
+            position::decrease_open_bids_and_asks(
+                &mut user.perp_positions[position_index],
+                &direction,
+                base_asset_amount_decrease.cast()?,
+                update_open_bids_and_asks,
+            )?;
 
-        (
-            builder_order_idx,
-            referrer_builder_order_idx,
-            builder_order_fee_bps,
-            builder_idx,
-        )
-    } else {
-        (None, None, None, None)
+            perp_market_map.get_ref(&market_index)?.oracle_id()
+        }
+        MarketType::Spot => {
+            let spot_position_index = user.get_spot_position_index(market_index)?;
+            decrease_spot_open_bids_and_asks(
+                &mut user.spot_positions[spot_position_index],
+                &direction,
+                base_asset_amount_decrease,
+                update_open_bids_and_asks,
+            )?;
+
+            spot_market_map.get_ref(&market_index)?.oracle_id()
+        }
+    };
+
+    user.orders[order_index] = amended_order;
+
+    let (taker, taker_order, maker, maker_order) =
+        get_taker_and_maker_for_order_record(user_key, &amended_order);
+
+    let order_action_record = get_order_action_record(
+        now,
+        OrderAction::Amend,
+        OrderActionExplanation::None,
+        market_index,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        taker,
+        taker_order,
+        maker,
+        maker_order,
+        oracle_map.get_price_data(&oracle_id)?.price,
+        0,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+    )?;
+    emit_stack::<_, { OrderActionRecord::SIZE }>(order_action_record)?;
+
+    user.update_last_active_slot(slot);
+
+    Ok(true)
+}
+
+fn merge_modify_order_params_with_existing_order(
+    existing_order: &Order,
+    modify_order_params: &ModifyOrderParams,
+) -> DriftResult<Option<OrderParams>> {
+    let order_type = existing_order.order_type;
+    let market_type = existing_order.market_type;
+    let direction = modify_order_params
+        .direction
+        .unwrap_or(existing_order.direction);
+    let user_order_id = existing_order.user_order_id;
+    let base_asset_amount = match modify_order_params.base_asset_amount {
+        Some(base_asset_amount) if modify_order_params.exclude_previous_fill() => {
+            let base_asset_amount =
+                base_asset_amount.saturating_sub(existing_order.base_asset_amount_filled);
+
+            if base_asset_amount == 0 {
+                return Ok(None);
+            }
+
+            base_asset_amount
+        }
+        Some(base_asset_amount) => base_asset_amount,
+        None => existing_order.get_base_asset_amount_unfilled(None)?,
+    };
+    let price = modify_order_params.price.unwrap_or(existing_order.price);
+    let market_index = existing_order.market_index;
+    let reduce_only = modify_order_params
+        .reduce_only
+        .unwrap_or(existing_order.reduce_only);
+    let post_only = modify_order_params
+        .post_only
+        .unwrap_or(if existing_order.post_only {
+            PostOnlyParam::MustPostOnly
+        } else {
+            PostOnlyParam::None
+        });
+    let mut bit_flags = 0;
+    if existing_order.is_trailing_stop() && !existing_order.triggered() {
+        bit_flags |= OrderParamsBitFlag::TrailingStop as u8;
+        if existing_order.is_bit_flag_set(OrderBitFlag::TrailingStopPercentage) {
+            bit_flags |= OrderParamsBitFlag::TrailingStopPercentage as u8;
+        }
+    }
+    if existing_order.has_min_fill_size() {
+        bit_flags |= OrderParamsBitFlag::MinFillSize as u8;
+    }
+    if existing_order.is_close_position() {
+        bit_flags |= OrderParamsBitFlag::ClosePosition as u8;
     }
+    let keep_iceberg = existing_order.is_iceberg() && post_only != PostOnlyParam::None;
+    if keep_iceberg {
+        bit_flags |= OrderParamsBitFlag::Iceberg as u8;
+    }
+    let max_ts = modify_order_params.max_ts.or(Some(existing_order.max_ts));
+    let trigger_price = modify_order_params
+        .trigger_price
+        .or(Some(existing_order.trigger_price));
+    let trigger_condition =
+        modify_order_params
+            .trigger_condition
+            .unwrap_or(match existing_order.trigger_condition {
+                OrderTriggerCondition::TriggeredAbove | OrderTriggerCondition::Above => {
+                    OrderTriggerCondition::Above
+                }
+                OrderTriggerCondition::TriggeredBelow | OrderTriggerCondition::Below => {
+                    OrderTriggerCondition::Below
+                }
+                OrderTriggerCondition::TriggeredMarginRatioBelow
+                | OrderTriggerCondition::MarginRatioBelow => {
+                    OrderTriggerCondition::MarginRatioBelow
+                }
+                OrderTriggerCondition::TriggeredTotalCollateralBelow
+                | OrderTriggerCondition::TotalCollateralBelow => {
+                    OrderTriggerCondition::TotalCollateralBelow
+                }
+                OrderTriggerCondition::ActivateAfterTs => OrderTriggerCondition::ActivateAfterTs,
+                OrderTriggerCondition::ActivateAfterSlot => {
+                    OrderTriggerCondition::ActivateAfterSlot
+                }
+            });
+    let oracle_price_offset = modify_order_params
+        .oracle_price_offset
+        .or(Some(existing_order.oracle_price_offset));
+    let (auction_duration, auction_start_price, auction_end_price) =
+        if modify_order_params.auction_duration.is_some()
+            && modify_order_params.auction_start_price.is_some()
+            && modify_order_params.auction_end_price.is_some()
+        {
+            (
+                modify_order_params.auction_duration,
+                modify_order_params.auction_start_price,
+                modify_order_params.auction_end_price,
+            )
+        } else if keep_iceberg {
+            (None, Some(existing_order.auction_start_price), None)
+        } else {
+            (None, None, None)
+        };
+
+    Ok(Some(OrderParams {
+        order_type,
+        market_type,
+        direction,
+        user_order_id,
+        base_asset_amount,
+        price,
+        market_index,
+        reduce_only,
+        post_only,
+        bit_flags,
+        max_ts,
+        trigger_price,
+        trigger_condition,
+        oracle_price_offset,
+        auction_duration,
+        auction_start_price,
+        auction_end_price,
+    }))
 }
 
-fn fulfill_perp_order(
-    user: &mut User,
-    user_order_index: usize,
-    user_key: &Pubkey,
-    user_stats: &mut UserStats,
-    makers_and_referrer: &UserMap,
-    makers_and_referrer_stats: &UserStatsMap,
-    maker_orders_info: &[(Pubkey, usize, u64)],
-    filler: &mut Option<&mut User>,
-    filler_key: &Pubkey,
-    filler_stats: &mut Option<&mut UserStats>,
-    referrer_info: Option<(Pubkey, Pubkey)>,
+pub fn fill_perp_order(
+    order_id: u32,
+    state: &State,
+    user: &AccountLoader<User>,
+    user_stats: &AccountLoader<UserStats>,
     spot_market_map: &SpotMarketMap,
     perp_market_map: &PerpMarketMap,
     oracle_map: &mut OracleMap,
-    fee_structure: &FeeStructure,
-    reserve_price_before: u64,
-    valid_oracle_price: Option<i64>,
-    now: i64,
-    slot: u64,
-    min_auction_duration: u8,
-    amm_availability: AMMAvailability,
+    filler: &AccountLoader<User>,
+    filler_stats: &AccountLoader<UserStats>,
+    makers_and_referrer: &UserMap,
+    makers_and_referrer_stats: &UserStatsMap,
+    jit_maker_order_id: Option<u32>,
+    clock: &Clock,
     fill_mode: FillMode,
-    oracle_stale_for_margin: bool,
     rev_share_escrow: &mut Option<&mut RevenueShareEscrowZeroCopyMut>,
     builder_referral_feature_enabled: bool,
+    perp_order_books: &[&PerpOrderBook],
 ) -> DriftResult<(u64, u64)> {
-    let market_index = user.orders[user_order_index].market_index;
+    let now = clock.unix_timestamp;
+    let slot = clock.slot;
 
-    let user_order_position_decreasing =
-        determine_if_user_order_is_position_decreasing(user, market_index, user_order_index)?;
+    let filler_key = filler.key();
+    let user_key = user.key();
+    let user = &mut load_mut!(user)?;
+    let user_stats = &mut load_mut!(user_stats)?;
 
-    let perp_market = perp_market_map.get_ref(&market_index)?;
-    let limit_price = fill_mode.get_limit_price(
-        &user.orders[user_order_index],
-        valid_oracle_price,
-        slot,
-        perp_market.amm.order_tick_size,
-        perp_market.is_prediction_market(),
-    )?;
-    let perp_market_oi_before = perp_market.get_open_interest();
-    drop(perp_market);
+    let order_index = user
+        .orders
+        .iter()
+        .position(|order| order.order_id == order_id && order.status == OrderStatus::Open)
+        .ok_or_else(print_error!(ErrorCode::OrderDoesNotExist))?;
 
-    let fulfillment_methods = {
-        let market = perp_market_map.get_ref(&market_index)?;
-        let oracle_price = oracle_map.get_price_data(&market.oracle_id())?.price;
+    let (order_status, market_index, order_market_type, order_direction) = get_struct_values!(
+        user.orders[order_index],
+        status,
+        market_index,
+        market_type,
+        direction
+    );
 
-        determine_perp_fulfillment_methods(
-            &user.orders[user_order_index],
-            maker_orders_info,
-            &market.amm,
-            reserve_price_before,
-            Some(oracle_price),
-            limit_price,
-            amm_availability,
-            slot,
-            min_auction_duration,
-            fill_mode,
-        )?
-    };
+    validate!(
+        order_market_type == MarketType::Perp,
+        ErrorCode::InvalidOrderMarketType,
+        "must be perp order"
+    )?;
 
-    if fulfillment_methods.is_empty() {
-        return Ok((0, 0));
+    // settle lp position so its tradeable
+    let mut market = perp_market_map.get_ref_mut(&market_index)?;
+    settle_funding_payment(user, &user_key, &mut market, now)?;
+
+    validate!(
+        matches!(
+            market.status,
+            MarketStatus::Active | MarketStatus::ReduceOnly
+        ),
+        ErrorCode::MarketFillOrderPaused,
+        "Market not active",
+    )?;
+
+    validate!(
+        !market.is_operation_paused(PerpOperation::Fill),
+        ErrorCode::MarketFillOrderPaused,
+        "Market fills paused",
+    )?;
+
+    validate!(
+        !market.is_batch_auction_enabled() || fill_mode.is_liquidation(),
+        ErrorCode::MarketFillsInBatchAuction,
+        "Market fills clear in batch auctions",
+    )?;
+
+    drop(market);
+
+    validate!(
+        order_status == OrderStatus::Open,
+        ErrorCode::OrderNotOpen,
+        "Order not open"
+    )?;
+
+    validate!(
+        !user.orders[order_index].must_be_triggered() || user.orders[order_index].triggered(),
+        ErrorCode::OrderMustBeTriggeredFirst,
+        "Order must be triggered first"
+    )?;
+
+    validate!(
+        !user.orders[order_index].is_pending_activation(),
+        ErrorCode::OrderNotActivated,
+        "Order must be activated first"
+    )?;
+
+    validate!(
+        !user.orders[order_index].is_spread_leg(),
+        ErrorCode::InvalidOrder,
+        "Spread legs are filled with their spread"
+    )?;
+
+    if user.is_bankrupt() {
+        msg!("user is bankrupt");
+        return Ok((0, 0));
+    }
+
+    if !fill_mode.is_liquidation() {
+        match validate_user_not_being_liquidated(
+            user,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            state.liquidation_margin_buffer_ratio,
+        ) {
+            Ok(_) => {}
+            Err(_) => {
+                msg!("user is being liquidated");
+                return Ok((0, 0));
+            }
+        }
+    }
+
+    let reserve_price_before: u64;
+    let safe_oracle_validity: OracleValidity;
+    let oracle_price: i64;
+    let oracle_twap_5min: i64;
+    let perp_market_index: u16;
+    let user_can_skip_duration: bool;
+    let amm_can_skip_duration: bool;
+    let amm_has_low_enough_inventory: bool;
+    let oracle_valid_for_amm_fill: bool;
+    let oracle_stale_for_margin: bool;
+    let min_auction_duration: u8;
+    let mut amm_is_available = !state.amm_paused()?;
+    {
+        let market = &mut perp_market_map.get_ref_mut(&market_index)?;
+        validation::perp_market::validate_perp_market(market)?;
+        validate!(
+            !market.is_in_settlement(now),
+            ErrorCode::MarketFillOrderPaused,
+            "Market is in settlement mode",
+        )?;
+
+        let oracle_price_data = oracle_map.get_price_data(&market.oracle_id())?;
+        let mm_oracle_price_data = market.get_mm_oracle_price_data(
+            *oracle_price_data,
+            slot,
+            &state.oracle_guard_rails.validity,
+        )?;
+        let safe_oracle_price_data = mm_oracle_price_data.get_safe_oracle_price_data();
+        safe_oracle_validity = oracle_validity(
+            MarketType::Perp,
+            market.market_index,
+            market.amm.historical_oracle_data.last_oracle_price_twap,
+            &safe_oracle_price_data,
+            &state.oracle_guard_rails.validity,
+            market.get_max_confidence_interval_multiplier()?,
+            &market.amm.oracle_source,
+            oracle::LogMode::SafeMMOracle,
+            market.amm.oracle_slot_delay_override,
+        )?;
+
+        oracle_valid_for_amm_fill =
+            is_oracle_valid_for_action(safe_oracle_validity, Some(DriftAction::FillOrderAmm))?;
+
+        oracle_stale_for_margin = mm_oracle_price_data.get_delay()
+            > state
+                .oracle_guard_rails
+                .validity
+                .slots_before_stale_for_margin;
+
+        amm_is_available &= oracle_valid_for_amm_fill;
+        amm_is_available &= !market.is_operation_paused(PerpOperation::AmmFill);
+        amm_is_available &= !market.has_too_much_drawdown()?;
+
+        // We are already using safe oracle data from MM oracle.
+        // But AMM isnt available if we could have used MM oracle but fell back due to price diff
+        let amm_available_mm_oracle_recent_but_volatile =
+            if mm_oracle_price_data.is_enabled() && mm_oracle_price_data.is_mm_oracle_as_recent() {
+                let amm_available = !mm_oracle_price_data.is_mm_exchange_diff_bps_high();
+                amm_available
+            } else {
+                true
+            };
+        amm_is_available &= amm_available_mm_oracle_recent_but_volatile;
+
+        let amm_wants_to_jit_make = market.amm.amm_wants_to_jit_make(order_direction)?;
+        amm_has_low_enough_inventory = market
+            .amm
+            .amm_has_low_enough_inventory(amm_wants_to_jit_make)?;
+        amm_can_skip_duration =
+            market.can_skip_auction_duration(&state, amm_has_low_enough_inventory)?;
+
+        user_can_skip_duration = user.can_skip_auction_duration(user_stats)?;
+
+        reserve_price_before = market.amm.reserve_price()?;
+        oracle_price = mm_oracle_price_data.get_price();
+        oracle_twap_5min = market
+            .amm
+            .historical_oracle_data
+            .last_oracle_price_twap_5min;
+        perp_market_index = market.market_index;
+
+        min_auction_duration =
+            market.get_min_perp_auction_duration(state.min_perp_auction_duration);
+    }
+
+    // allow oracle price to be used to calculate limit price if it's valid or stale for amm
+    let valid_oracle_price =
+        if is_oracle_valid_for_action(safe_oracle_validity, Some(DriftAction::OracleOrderPrice))? {
+            Some(oracle_price)
+        } else {
+            msg!("Perp market = {} oracle deemed invalid", perp_market_index);
+            None
+        };
+
+    let is_filler_taker = user_key == filler_key;
+    let is_filler_maker = makers_and_referrer.0.contains_key(&filler_key);
+    let (mut filler, mut filler_stats) = if !is_filler_maker && !is_filler_taker {
+        let filler = load_mut!(filler)?;
+
+        validate!(
+            filler.pool_id == 0,
+            ErrorCode::InvalidPoolId,
+            "filler pool id ({}) != 0",
+            filler.pool_id
+        )?;
+
+        if filler.authority != user.authority {
+            (Some(filler), Some(load_mut!(filler_stats)?))
+        } else {
+            (None, None)
+        }
+    } else {
+        (None, None)
+    };
+
+    let maker_orders_info = get_maker_orders_info(
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        makers_and_referrer,
+        &user_key,
+        &user.orders[order_index],
+        &mut filler.as_deref_mut(),
+        &filler_key,
+        state.perp_fee_structure.flat_filler_fee,
+        oracle_price,
+        jit_maker_order_id,
+        now,
+        slot,
+        user_can_skip_duration,
+        state.min_perp_auction_duration as u64,
+        perp_order_books,
+    )?;
+
+    // no referrer bonus for liquidations
+    let referrer_info = if !fill_mode.is_liquidation() {
+        get_referrer_info(
+            user_stats,
+            &user_key,
+            makers_and_referrer,
+            makers_and_referrer_stats,
+            slot,
+        )?
+    } else {
+        None
+    };
+
+    let oracle_too_divergent_with_twap_5min = is_oracle_too_divergent_with_twap_5min(
+        oracle_price,
+        oracle_twap_5min,
+        state
+            .oracle_guard_rails
+            .max_oracle_twap_5min_percent_divergence()
+            .cast()?,
+    )?;
+
+    if oracle_too_divergent_with_twap_5min {
+        // update filler last active so tx doesn't revert
+        if let Some(filler) = filler.as_deref_mut() {
+            filler.update_last_active_slot(slot);
+        }
+
+        if !perp_market_map
+            .get_ref_mut(&market_index)?
+            .is_prediction_market()
+        {
+            return Ok((0, 0));
+        }
+    }
+
+    let should_expire_order = should_expire_order_before_fill(user, order_index, now)?;
+
+    let position_index =
+        get_position_index(&user.perp_positions, user.orders[order_index].market_index)?;
+    let existing_base_asset_amount = user.perp_positions[position_index].base_asset_amount;
+    let should_cancel_reduce_only = should_cancel_reduce_only_order(
+        &user.orders[order_index],
+        existing_base_asset_amount,
+        perp_market_map
+            .get_ref_mut(&market_index)?
+            .amm
+            .order_step_size,
+    )?;
+
+    if should_expire_order || should_cancel_reduce_only {
+        let filler_reward = {
+            let mut market = perp_market_map.get_ref_mut(&market_index)?;
+            pay_keeper_flat_reward_for_perps(
+                user,
+                filler.as_deref_mut(),
+                market.deref_mut(),
+                state.perp_fee_structure.flat_filler_fee,
+                slot,
+            )?
+        };
+
+        let explanation = if should_expire_order {
+            OrderActionExplanation::OrderExpired
+        } else {
+            OrderActionExplanation::ReduceOnlyOrderIncreasedPosition
+        };
+
+        cancel_order(
+            order_index,
+            user,
+            &user_key,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            now,
+            slot,
+            explanation,
+            Some(&filler_key),
+            filler_reward,
+            false,
+        )?;
+
+        return Ok((0, 0));
+    }
+
+    let taker_limit_price = {
+        let market = perp_market_map.get_ref(&market_index)?;
+        user.orders[order_index].get_limit_price(
+            valid_oracle_price,
+            None,
+            slot,
+            market.amm.order_tick_size,
+            market.is_prediction_market(),
+            None,
+        )?
+    };
+
+    let maker_orders_info = apply_self_trade_prevention(
+        user,
+        order_index,
+        &user_key,
+        taker_limit_price,
+        maker_orders_info,
+        makers_and_referrer,
+        &filler_key,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        now,
+        slot,
+    )?;
+
+    if user.orders[order_index].status != OrderStatus::Open {
+        return Ok((0, 0));
+    }
+
+    let amm_availability = if amm_is_available {
+        if amm_can_skip_duration && user_can_skip_duration {
+            AMMAvailability::Immediate
+        } else {
+            AMMAvailability::AfterMinDuration
+        }
+    } else {
+        AMMAvailability::Unavailable
+    };
+
+    let (base_asset_amount, quote_asset_amount) = fulfill_perp_order(
+        user,
+        order_index,
+        &user_key,
+        user_stats,
+        makers_and_referrer,
+        makers_and_referrer_stats,
+        &maker_orders_info,
+        &mut filler.as_deref_mut(),
+        &filler_key,
+        &mut filler_stats.as_deref_mut(),
+        referrer_info,
+        spot_market_map,
+        perp_market_map,
+        oracle_map,
+        &state.perp_fee_structure,
+        reserve_price_before,
+        valid_oracle_price,
+        now,
+        slot,
+        min_auction_duration,
+        amm_availability,
+        fill_mode,
+        oracle_stale_for_margin,
+        rev_share_escrow,
+        builder_referral_feature_enabled,
+    )?;
+
+    if base_asset_amount != 0 {
+        let fill_price =
+            calculate_fill_price(quote_asset_amount, base_asset_amount, BASE_PRECISION_U64)?;
+
+        let mut perp_market = perp_market_map.get_ref_mut(&market_index)?;
+        validate_fill_price_within_price_bands(
+            fill_price,
+            oracle_price,
+            oracle_twap_5min,
+            perp_market.margin_ratio_initial,
+            state
+                .oracle_guard_rails
+                .max_oracle_twap_5min_percent_divergence(),
+            perp_market.is_prediction_market(),
+            None,
+        )?;
+
+        perp_market.last_fill_price = fill_price;
+    }
+
+    let base_asset_amount_after = user.perp_positions[position_index].base_asset_amount;
+    let should_cancel_reduce_only = should_cancel_reduce_only_order(
+        &user.orders[order_index],
+        base_asset_amount_after,
+        perp_market_map
+            .get_ref_mut(&market_index)?
+            .amm
+            .order_step_size,
+    )?;
+
+    if should_cancel_reduce_only {
+        let filler_reward = {
+            let mut market = perp_market_map.get_ref_mut(&market_index)?;
+            pay_keeper_flat_reward_for_perps(
+                user,
+                filler.as_deref_mut(),
+                market.deref_mut(),
+                state.perp_fee_structure.flat_filler_fee,
+                slot,
+            )?
+        };
+
+        let explanation = OrderActionExplanation::ReduceOnlyOrderIncreasedPosition;
+
+        cancel_order(
+            order_index,
+            user,
+            &user_key,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            now,
+            slot,
+            explanation,
+            Some(&filler_key),
+            filler_reward,
+            false,
+        )?
+    }
+
+    if base_asset_amount == 0 {
+        return Ok((base_asset_amount, quote_asset_amount));
+    }
+
+    update_oco_group_after_fill(
+        order_index,
+        user,
+        &user_key,
+        Some(&filler_key),
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        now,
+        slot,
+    )?;
+
+    for (maker_key, maker_order_index, _) in maker_orders_info.iter() {
+        let mut maker = makers_and_referrer.get_ref_mut(maker_key)?;
+        if maker.orders[*maker_order_index].has_oco_group()
+            && maker.orders[*maker_order_index].base_asset_amount_filled > 0
+        {
+            update_oco_group_after_fill(
+                *maker_order_index,
+                &mut maker,
+                maker_key,
+                Some(&filler_key),
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                now,
+                slot,
+            )?;
+        }
+    }
+
+    {
+        let market = perp_market_map.get_ref(&market_index)?;
+
+        let open_interest = market.get_open_interest();
+        let max_open_interest = market.amm.max_open_interest;
+
+        validate!(
+            max_open_interest == 0 || max_open_interest > open_interest,
+            ErrorCode::MaxOpenInterest,
+            "open interest ({}) > max open interest ({})",
+            open_interest,
+            max_open_interest
+        )?;
+    }
+
+    // Try to update the funding rate at the end of every trade
+    {
+        let market = &mut perp_market_map.get_ref_mut(&market_index)?;
+        let funding_paused =
+            state.funding_paused()? || market.is_operation_paused(PerpOperation::UpdateFunding);
+
+        controller::funding::update_funding_rate(
+            market_index,
+            market,
+            oracle_map,
+            now,
+            slot,
+            &state.oracle_guard_rails,
+            funding_paused,
+            Some(reserve_price_before),
+        )?;
+    }
+
+    user.update_last_active_slot(slot);
+
+    Ok((base_asset_amount, quote_asset_amount))
+}
+
+pub fn validate_market_within_price_band(
+    market: &PerpMarket,
+    state: &State,
+    oracle_price: i64,
+) -> DriftResult<bool> {
+    if market.is_prediction_market() {
+        return Ok(true);
+    }
+
+    let reserve_price = market.amm.reserve_price()?;
+
+    let reserve_spread_pct =
+        amm::calculate_oracle_twap_5min_price_spread_pct(&market.amm, reserve_price)?;
+
+    let oracle_spread_pct =
+        amm::calculate_oracle_twap_5min_price_spread_pct(&market.amm, oracle_price.unsigned_abs())?;
+
+    if reserve_spread_pct.abs() > oracle_spread_pct.abs() {
+        let is_reserve_too_divergent = amm::is_oracle_mark_too_divergent(
+            reserve_spread_pct,
+            &state.oracle_guard_rails.price_divergence,
+        )?;
+
+        // if oracle-mark divergence pushed outside limit, block order
+        if is_reserve_too_divergent {
+            msg!("Perp market = {} price pushed outside bounds: last_oracle_price_twap_5min={} vs reserve_price={},(breach spread {})",
+                market.market_index,
+                market.amm.historical_oracle_data.last_oracle_price_twap_5min,
+                reserve_price,
+                reserve_spread_pct,
+            );
+            return Err(ErrorCode::PriceBandsBreached);
+        }
+    } else {
+        let is_oracle_too_divergent = amm::is_oracle_mark_too_divergent(
+            oracle_spread_pct,
+            &state.oracle_guard_rails.price_divergence,
+        )?;
+
+        // if oracle-mark divergence pushed outside limit, block order
+        if is_oracle_too_divergent {
+            msg!("Perp market = {} price pushed outside bounds: last_oracle_price_twap_5min={} vs oracle_price={},(breach spread {})",
+                market.market_index,
+                market.amm.historical_oracle_data.last_oracle_price_twap_5min,
+                oracle_price,
+                oracle_spread_pct,
+            );
+            return Err(ErrorCode::PriceBandsBreached);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Applies the taker's self trade prevention mode to crossing maker orders from the same authority.
+/// Returns the maker orders that can still fill the taker
+#[allow(clippy::type_complexity)]
+fn apply_self_trade_prevention(
+    user: &mut User,
+    user_order_index: usize,
+    user_key: &Pubkey,
+    taker_limit_price: Option<u64>,
+    maker_orders_info: Vec<(Pubkey, usize, u64)>,
+    makers_and_referrer: &UserMap,
+    filler_key: &Pubkey,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    now: i64,
+    slot: u64,
+) -> DriftResult<Vec<(Pubkey, usize, u64)>> {
+    let self_trade_prevention_mode = user.self_trade_prevention_mode;
+    if self_trade_prevention_mode == SelfTradePreventionMode::None {
+        return Ok(maker_orders_info);
+    }
+
+    let maker_direction = user.orders[user_order_index].direction.opposite();
+    let mut remaining_maker_orders_info = Vec::with_capacity(maker_orders_info.len());
+    for (maker_key, maker_order_index, maker_price) in maker_orders_info {
+        let mut maker = makers_and_referrer.get_ref_mut(&maker_key)?;
+
+        let orders_cross = taker_limit_price.map_or(true, |taker_price| {
+            do_orders_cross(maker_direction, maker_price, taker_price)
+        });
+
+        if maker.authority != user.authority
+            || !orders_cross
+            || user.orders[user_order_index].status != OrderStatus::Open
+        {
+            remaining_maker_orders_info.push((maker_key, maker_order_index, maker_price));
+            continue;
+        }
+
+        let (cancel_maker, cancel_taker) = get_self_trade_prevention_cancels(
+            self_trade_prevention_mode,
+            user,
+            user_order_index,
+            &mut maker,
+            maker_order_index,
+        )?;
+
+        if cancel_maker {
+            cancel_order(
+                maker_order_index,
+                &mut maker,
+                &maker_key,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                now,
+                slot,
+                OrderActionExplanation::SelfTradePrevention,
+                Some(filler_key),
+                0,
+                false,
+            )?;
+        } else {
+            remaining_maker_orders_info.push((maker_key, maker_order_index, maker_price));
+        }
+
+        drop(maker);
+
+        if cancel_taker {
+            cancel_order(
+                user_order_index,
+                user,
+                user_key,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                now,
+                slot,
+                OrderActionExplanation::SelfTradePrevention,
+                Some(filler_key),
+                0,
+                false,
+            )?;
+        }
+    }
+
+    Ok(remaining_maker_orders_info)
+}
+
+/// Applies a self trade prevention mode to a crossing taker and maker order from the same
+/// authority. Returns whether the maker and taker orders should be canceled
+fn get_self_trade_prevention_cancels(
+    self_trade_prevention_mode: SelfTradePreventionMode,
+    taker: &mut User,
+    taker_order_index: usize,
+    maker: &mut User,
+    maker_order_index: usize,
+) -> DriftResult<(bool, bool)> {
+    let cancels = match self_trade_prevention_mode {
+        SelfTradePreventionMode::None => (false, false),
+        SelfTradePreventionMode::CancelMaker => (true, false),
+        SelfTradePreventionMode::CancelTaker => (false, true),
+        SelfTradePreventionMode::CancelBoth => (true, true),
+        SelfTradePreventionMode::DecrementAndCancel => {
+            let maker_base_asset_amount =
+                get_order_base_asset_amount_unfilled(maker, maker_order_index)?;
+            let taker_base_asset_amount =
+                get_order_base_asset_amount_unfilled(taker, taker_order_index)?;
+            let decrement = maker_base_asset_amount.min(taker_base_asset_amount);
+
+            if maker_base_asset_amount > decrement {
+                decrement_order_base_asset_amount(maker, maker_order_index, decrement)?;
+            }
+
+            if taker_base_asset_amount > decrement {
+                decrement_order_base_asset_amount(taker, taker_order_index, decrement)?;
+            }
+
+            (
+                maker_base_asset_amount == decrement,
+                taker_base_asset_amount == decrement,
+            )
+        }
+    };
+
+    Ok(cancels)
+}
+
+/// Close position orders are sized from the position, so their unfilled amount depends on it
+fn get_order_base_asset_amount_unfilled(user: &User, order_index: usize) -> DriftResult<u64> {
+    let order = &user.orders[order_index];
+    let existing_position = if order.is_close_position() {
+        Some(
+            user.get_perp_position(order.market_index)?
+                .base_asset_amount,
+        )
+    } else {
+        None
+    };
+
+    order.get_base_asset_amount_unfilled(existing_position)
+}
+
+/// Close position orders keep tracking the position, so they aren't decremented
+fn decrement_order_base_asset_amount(
+    user: &mut User,
+    order_index: usize,
+    base_asset_amount: u64,
+) -> DriftResult {
+    if user.orders[order_index].is_close_position() {
+        return Ok(());
+    }
+
+    let (market_type, market_index, direction) = get_struct_values!(
+        user.orders[order_index],
+        market_type,
+        market_index,
+        direction
+    );
+
+    let update_open_bids_and_asks = user.orders[order_index].update_open_bids_and_asks();
+    if market_type == MarketType::Perp {
+        let position_index = get_position_index(&user.perp_positions, market_index)?;
+        position::decrease_open_bids_and_asks(
+            &mut user.perp_positions[position_index],
+            &direction,
+            base_asset_amount,
+            update_open_bids_and_asks,
+        )?;
+    } else {
+        let spot_position_index = user.get_spot_position_index(market_index)?;
+        decrease_spot_open_bids_and_asks(
+            &mut user.spot_positions[spot_position_index],
+            &direction,
+            base_asset_amount,
+            update_open_bids_and_asks,
+        )?;
+    }
+
+    user.orders[order_index].base_asset_amount = user.orders[order_index]
+        .base_asset_amount
+        .safe_sub(base_asset_amount)?;
+
+    Ok(())
+}
+
+#[allow(clippy::type_complexity)]
+fn get_maker_orders_info(
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    makers_and_referrer: &UserMap,
+    taker_key: &Pubkey,
+    taker_order: &Order,
+    filler: &mut Option<&mut User>,
+    filler_key: &Pubkey,
+    filler_reward: u64,
+    oracle_price: i64,
+    jit_maker_order_id: Option<u32>,
+    now: i64,
+    slot: u64,
+    user_can_skip_duration: bool,
+    protected_maker_min_age: u64,
+    perp_order_books: &[&PerpOrderBook],
+) -> DriftResult<Vec<(Pubkey, usize, u64)>> {
+    let maker_direction = taker_order.direction.opposite();
+
+    let mut maker_orders_info = Vec::with_capacity(16);
+
+    // when the market's order book is passed in, fills can't go past a book entry whose maker
+    // wasn't passed in. entries of makers that were passed in but no longer rest are skipped
+    let order_book_price_limit = match perp_order_books
+        .iter()
+        .find(|perp_order_book| perp_order_book.market_index == taker_order.market_index)
+    {
+        Some(perp_order_book) if jit_maker_order_id.is_none() => perp_order_book
+            .get_best_unloaded_price(maker_direction, |user| {
+                user == taker_key || makers_and_referrer.0.contains_key(user)
+            }),
+        _ => None,
+    };
+
+    let taker_order_age = slot.safe_sub(taker_order.slot)?;
+
+    for (maker_key, user_account_loader) in makers_and_referrer.0.iter() {
+        if maker_key == taker_key {
+            continue;
+        }
+
+        let mut maker = load_mut!(user_account_loader)?;
+
+        if maker.is_being_liquidated() || maker.is_bankrupt() {
+            continue;
+        }
+
+        let is_protected_maker = maker.is_protected_maker();
+
+        let mut market = perp_market_map.get_ref_mut(&taker_order.market_index)?;
+        let maker_order_price_and_indexes = find_maker_orders(
+            &maker,
+            &maker_direction,
+            &MarketType::Perp,
+            taker_order.market_index,
+            Some(oracle_price),
+            slot,
+            market.amm.order_tick_size,
+            market.is_prediction_market(),
+            get_protected_maker_params(
+                is_protected_maker,
+                jit_maker_order_id.is_some(),
+                user_can_skip_duration,
+                taker_order_age,
+                protected_maker_min_age,
+                market.deref(),
+            ),
+        )?;
+
+        if maker_order_price_and_indexes.is_empty() {
+            continue;
+        }
+
+        maker.update_last_active_slot(slot);
+
+        settle_funding_payment(&mut maker, maker_key, &mut market, now)?;
+
+        let initial_margin_ratio = market.margin_ratio_initial;
+        let step_size = market.amm.order_step_size;
+
+        drop(market);
+
+        for (maker_order_index, maker_order_price) in maker_order_price_and_indexes.iter() {
+            let maker_order_index = *maker_order_index;
+            let maker_order_price = *maker_order_price;
+
+            let maker_order = &maker.orders[maker_order_index];
+            if !is_maker_for_taker(maker_order, taker_order, slot)? {
+                continue;
+            }
+
+            if !are_orders_same_market_but_different_sides(maker_order, taker_order) {
+                continue;
+            }
+
+            if let Some(jit_maker_order_id) = jit_maker_order_id {
+                // if jit maker order id exists, must only use that order
+                if maker_order.order_id != jit_maker_order_id {
+                    continue;
+                }
+            }
+
+            if let Some(order_book_price_limit) = order_book_price_limit {
+                let is_worse_than_book = match maker_direction {
+                    PositionDirection::Long => maker_order_price < order_book_price_limit,
+                    PositionDirection::Short => maker_order_price > order_book_price_limit,
+                };
+
+                if is_worse_than_book {
+                    continue;
+                }
+            }
+
+            let breaches_oracle_price_limits = {
+                limit_price_breaches_maker_oracle_price_bands(
+                    maker_order_price,
+                    maker_order.direction,
+                    oracle_price,
+                    initial_margin_ratio,
+                )?
+            };
+
+            let should_expire_order = should_expire_order(&maker, maker_order_index, now)?;
+
+            let existing_base_asset_amount = maker
+                .get_perp_position(maker.orders[maker_order_index].market_index)?
+                .base_asset_amount;
+            let should_cancel_reduce_only_order = should_cancel_reduce_only_order(
+                &maker.orders[maker_order_index],
+                existing_base_asset_amount,
+                step_size,
+            )?;
+
+            if breaches_oracle_price_limits
+                || should_expire_order
+                || should_cancel_reduce_only_order
+            {
+                let filler_reward = {
+                    let mut market = perp_market_map
+                        .get_ref_mut(&maker.orders[maker_order_index].market_index)?;
+                    pay_keeper_flat_reward_for_perps(
+                        &mut maker,
+                        filler.as_deref_mut(),
+                        market.deref_mut(),
+                        filler_reward,
+                        slot,
+                    )?
+                };
+
+                let explanation = if breaches_oracle_price_limits {
+                    OrderActionExplanation::OraclePriceBreachedLimitPrice
+                } else if should_expire_order {
+                    OrderActionExplanation::OrderExpired
+                } else {
+                    OrderActionExplanation::ReduceOnlyOrderIncreasedPosition
+                };
+
+                cancel_order(
+                    maker_order_index,
+                    maker.deref_mut(),
+                    maker_key,
+                    perp_market_map,
+                    spot_market_map,
+                    oracle_map,
+                    now,
+                    slot,
+                    explanation,
+                    Some(filler_key),
+                    filler_reward,
+                    false,
+                )?;
+
+                continue;
+            }
+
+            insert_maker_order_info(
+                &mut maker_orders_info,
+                (*maker_key, maker_order_index, maker_order_price),
+                maker_direction,
+            );
+        }
+    }
+
+    Ok(maker_orders_info)
+}
+
+#[inline(always)]
+fn get_protected_maker_params(
+    is_protected_maker: bool,
+    jit_maker: bool,
+    user_can_skip_duration: bool,
+    taker_order_age: u64,
+    protected_maker_min_age: u64,
+    market: &PerpMarket,
+) -> Option<ProtectedMakerParams> {
+    if is_protected_maker
+        && !jit_maker
+        && !user_can_skip_duration
+        && taker_order_age < protected_maker_min_age
+    {
+        Some(market.get_protected_maker_params())
+    } else {
+        None
+    }
+}
+
+#[inline(always)]
+fn insert_maker_order_info(
+    maker_orders_info: &mut Vec<(Pubkey, usize, u64)>,
+    maker_order_info: (Pubkey, usize, u64),
+    direction: PositionDirection,
+) {
+    let price = maker_order_info.2;
+    let index = match maker_orders_info.binary_search_by(|item| match direction {
+        PositionDirection::Short => item.2.cmp(&price),
+        PositionDirection::Long => price.cmp(&item.2),
+    }) {
+        Ok(index) => index,
+        Err(index) => index,
+    };
+
+    if index < maker_orders_info.capacity() {
+        maker_orders_info.insert(index, maker_order_info);
+    }
+}
+
+fn get_referrer_info(
+    user_stats: &UserStats,
+    user_key: &Pubkey,
+    makers_and_referrer: &UserMap,
+    makers_and_referrer_stats: &UserStatsMap,
+    slot: u64,
+) -> DriftResult<Option<(Pubkey, Pubkey)>> {
+    if user_stats.referrer.eq(&Pubkey::default()) {
+        return Ok(None);
+    }
+
+    validate!(
+        makers_and_referrer_stats
+            .0
+            .contains_key(&user_stats.referrer),
+        ErrorCode::ReferrerStatsNotFound
+    )?;
+
+    let referrer_authority_key = user_stats.referrer;
+    let mut referrer_user_key = Pubkey::default();
+    for (referrer_key, referrer) in makers_and_referrer.0.iter() {
+        // if user is in makers and referrer map, skip to avoid invalid borrow
+        if referrer_key == user_key {
+            continue;
+        }
+
+        let mut referrer = load_mut!(referrer)?;
+        if referrer.authority != referrer_authority_key {
+            continue;
+        }
+
+        if referrer.sub_account_id == 0 {
+            if referrer.pool_id != 0 {
+                return Ok(None);
+            }
+
+            referrer.update_last_active_slot(slot);
+            referrer_user_key = *referrer_key;
+            break;
+        }
+    }
+
+    if referrer_user_key == Pubkey::default() {
+        return Err(ErrorCode::ReferrerNotFound);
+    }
+
+    Ok(Some((referrer_authority_key, referrer_user_key)))
+}
+
+#[inline(always)]
+fn get_builder_escrow_info(
+    escrow_opt: &mut Option<&mut RevenueShareEscrowZeroCopyMut>,
+    sub_account_id: u16,
+    order_id: u32,
+    market_index: u16,
+    builder_referral_feature_enabled: bool,
+) -> (Option<u32>, Option<u32>, Option<u16>, Option<u8>) {
+    if let Some(escrow) = escrow_opt {
+        let builder_order_idx = escrow.find_order_index(sub_account_id, order_id);
+        let referrer_builder_order_idx = if builder_referral_feature_enabled {
+            escrow.find_or_create_referral_index(market_index)
+        } else {
+            None
+        };
+
+        let builder_order = builder_order_idx.and_then(|idx| escrow.get_order(idx).ok());
+        let builder_order_fee_bps = builder_order.map(|order| order.fee_tenth_bps);
+        let builder_idx = builder_order.map(|order| order.builder_idx);
+
+        (
+            builder_order_idx,
+            referrer_builder_order_idx,
+            builder_order_fee_bps,
+            builder_idx,
+        )
+    } else {
+        (None, None, None, None)
+    }
+}
+
+/// For a run of makers resting at the same price, caps each maker's fill so they share the
+/// taker's remaining size pro-rata, with the oldest order getting the top of book bonus first.
+/// Returns the index after the run and the index of the top of book order
+fn calculate_pro_rata_maker_fill_caps(
+    fulfillment_methods: &[PerpFulfillmentMethod],
+    run_start: usize,
+    taker: &User,
+    taker_order_index: usize,
+    makers_and_referrer: &UserMap,
+    step_size: u64,
+    top_of_book_bonus: u8,
+    maker_fill_caps: &mut [Option<u64>],
+) -> DriftResult<(usize, Option<usize>)> {
+    let run_price = match fulfillment_methods[run_start] {
+        PerpFulfillmentMethod::Match(_, _, maker_price) => maker_price,
+        PerpFulfillmentMethod::AMM(_) => return Ok((run_start + 1, None)),
+    };
+
+    let mut run_orders = vec![];
+    for (method_index, fulfillment_method) in fulfillment_methods.iter().enumerate().skip(run_start)
+    {
+        match fulfillment_method {
+            PerpFulfillmentMethod::Match(maker_key, maker_order_index, maker_price)
+                if *maker_price == run_price =>
+            {
+                let maker = makers_and_referrer.get_ref(maker_key)?;
+                let maker_order = &maker.orders[*maker_order_index as usize];
+                let maker_existing_position = maker
+                    .get_perp_position(maker_order.market_index)?
+                    .base_asset_amount;
+                let maker_base_asset_amount =
+                    maker_order.get_base_asset_amount_displayed(Some(maker_existing_position))?;
+
+                run_orders.push((method_index, maker_order.slot, maker_base_asset_amount));
+            }
+            _ => break,
+        }
+    }
+
+    let run_end = run_start.safe_add(run_orders.len())?;
+
+    // caps from an earlier pass over the run are replaced
+    for maker_fill_cap in maker_fill_caps[run_start..run_end].iter_mut() {
+        *maker_fill_cap = None;
+    }
+
+    if run_orders.len() < 2 {
+        return Ok((run_end, None));
+    }
+
+    // oldest order is top of book
+    run_orders.sort_by_key(|(method_index, order_slot, _)| (*order_slot, *method_index));
+    let top_of_book_method_index = run_orders[0].0;
+
+    let taker_order = &taker.orders[taker_order_index];
+    let taker_existing_position = taker
+        .get_perp_position(taker_order.market_index)?
+        .base_asset_amount;
+    let taker_base_asset_amount =
+        taker_order.get_base_asset_amount_unfilled(Some(taker_existing_position))?;
+
+    let sizes: Vec<u64> = run_orders.iter().map(|(_, _, size)| *size).collect();
+    let total_size = sizes
+        .iter()
+        .try_fold(0_u64, |total, size| total.safe_add(*size))?;
+
+    // every maker in the run is filled completely
+    if taker_base_asset_amount >= total_size {
+        return Ok((run_end, Some(top_of_book_method_index)));
+    }
+
+    let allocations = calculate_pro_rata_maker_allocations(
+        &sizes,
+        taker_base_asset_amount,
+        step_size,
+        top_of_book_bonus,
+    )?;
+
+    for ((method_index, _, _), allocation) in run_orders.iter().zip(allocations) {
+        maker_fill_caps[*method_index] = Some(allocation);
+    }
+
+    Ok((run_end, Some(top_of_book_method_index)))
+}
+
+fn fulfill_perp_order(
+    user: &mut User,
+    user_order_index: usize,
+    user_key: &Pubkey,
+    user_stats: &mut UserStats,
+    makers_and_referrer: &UserMap,
+    makers_and_referrer_stats: &UserStatsMap,
+    maker_orders_info: &[(Pubkey, usize, u64)],
+    filler: &mut Option<&mut User>,
+    filler_key: &Pubkey,
+    filler_stats: &mut Option<&mut UserStats>,
+    referrer_info: Option<(Pubkey, Pubkey)>,
+    spot_market_map: &SpotMarketMap,
+    perp_market_map: &PerpMarketMap,
+    oracle_map: &mut OracleMap,
+    fee_structure: &FeeStructure,
+    reserve_price_before: u64,
+    valid_oracle_price: Option<i64>,
+    now: i64,
+    slot: u64,
+    min_auction_duration: u8,
+    amm_availability: AMMAvailability,
+    fill_mode: FillMode,
+    oracle_stale_for_margin: bool,
+    rev_share_escrow: &mut Option<&mut RevenueShareEscrowZeroCopyMut>,
+    builder_referral_feature_enabled: bool,
+) -> DriftResult<(u64, u64)> {
+    let market_index = user.orders[user_order_index].market_index;
+
+    let user_order_position_decreasing =
+        determine_if_user_order_is_position_decreasing(user, market_index, user_order_index)?;
+
+    let perp_market = perp_market_map.get_ref(&market_index)?;
+    let limit_price = fill_mode.get_limit_price(
+        &user.orders[user_order_index],
+        valid_oracle_price,
+        slot,
+        perp_market.amm.order_tick_size,
+        perp_market.is_prediction_market(),
+    )?;
+    let perp_market_oi_before = perp_market.get_open_interest();
+    let pro_rata_top_of_book_bonus = if perp_market.is_pro_rata_allocation_enabled() {
+        Some(perp_market.pro_rata_top_of_book_bonus)
+    } else {
+        None
+    };
+    let order_step_size = perp_market.amm.order_step_size;
+    drop(perp_market);
+
+    let fulfillment_methods = {
+        let market = perp_market_map.get_ref(&market_index)?;
+        let oracle_price = oracle_map.get_price_data(&market.oracle_id())?.price;
+
+        determine_perp_fulfillment_methods(
+            &user.orders[user_order_index],
+            maker_orders_info,
+            &market.amm,
+            reserve_price_before,
+            Some(oracle_price),
+            limit_price,
+            amm_availability,
+            slot,
+            min_auction_duration,
+            fill_mode,
+        )?
+    };
+
+    if fulfillment_methods.is_empty() {
+        return Ok((0, 0));
     }
 
     let mut base_asset_amount = 0_u64;
     let mut quote_asset_amount = 0_u64;
     let mut maker_fills: BTreeMap<Pubkey, i64> = BTreeMap::new();
     let maker_direction = user.orders[user_order_index].direction.opposite();
-    for fulfillment_method in fulfillment_methods.iter() {
+    let mut maker_fill_caps: Vec<Option<u64>> = vec![None; fulfillment_methods.len()];
+    let mut pro_rata_run_end = 0_usize;
+    let mut pro_rata_top_of_book_method_index: Option<usize> = None;
+    for (method_index, fulfillment_method) in fulfillment_methods.iter().enumerate() {
         if user.orders[user_order_index].status != OrderStatus::Open {
             break;
         }
+
+        if let Some(top_of_book_bonus) = pro_rata_top_of_book_bonus {
+            if method_index >= pro_rata_run_end {
+                let (run_end, top_of_book_method_index) = calculate_pro_rata_maker_fill_caps(
+                    &fulfillment_methods,
+                    method_index,
+                    user,
+                    user_order_index,
+                    makers_and_referrer,
+                    order_step_size,
+                    top_of_book_bonus,
+                    &mut maker_fill_caps,
+                )?;
+                pro_rata_run_end = run_end;
+                pro_rata_top_of_book_method_index = top_of_book_method_index;
+            }
+
+            if maker_fill_caps[method_index] == Some(0) {
+                continue;
+            }
+        }
+
         let mut market = perp_market_map.get_ref_mut(&market_index)?;
         let user_order_direction: PositionDirection = user.orders[user_order_index].direction;
 
@@ -1874,419 +3667,998 @@ fn fulfill_perp_order(
                         now,
                         slot,
                         user_key,
-                        filler_key,
+                        filler_key,
+                        filler,
+                        filler_stats,
+                        &mut maker.as_deref_mut(),
+                        &mut maker_stats.as_deref_mut(),
+                        &mut referrer.as_deref_mut(),
+                        &mut referrer_stats.as_deref_mut(),
+                        fee_structure,
+                        limit_price,
+                        None,
+                        *maker_price,
+                        fill_mode.is_liquidation(),
+                        rev_share_escrow,
+                        builder_referral_feature_enabled,
+                    )?;
+
+                (fill_base_asset_amount, fill_quote_asset_amount)
+            }
+            PerpFulfillmentMethod::Match(maker_key, maker_order_index, maker_price) => {
+                let mut maker = makers_and_referrer.get_ref_mut(maker_key)?;
+                let mut maker_stats = if maker.authority == user.authority {
+                    None
+                } else {
+                    Some(makers_and_referrer_stats.get_ref_mut(&maker.authority)?)
+                };
+
+                let (mut referrer, mut referrer_stats) = get_referrer(
+                    &referrer_info,
+                    makers_and_referrer,
+                    makers_and_referrer_stats,
+                    Some(&maker),
+                )?;
+
+                let (fill_base_asset_amount, fill_quote_asset_amount, maker_fill_base_asset_amount) =
+                    fulfill_perp_order_with_match(
+                        market.deref_mut(),
+                        user,
+                        user_stats,
+                        user_order_index,
+                        user_key,
+                        &mut maker,
+                        &mut maker_stats.as_deref_mut(),
+                        *maker_order_index as usize,
+                        maker_key,
                         filler,
                         filler_stats,
-                        &mut maker.as_deref_mut(),
-                        &mut maker_stats.as_deref_mut(),
+                        filler_key,
                         &mut referrer.as_deref_mut(),
                         &mut referrer_stats.as_deref_mut(),
-                        fee_structure,
+                        reserve_price_before,
+                        valid_oracle_price,
                         limit_price,
-                        None,
                         *maker_price,
+                        maker_fill_caps[method_index],
+                        now,
+                        slot,
+                        fee_structure,
+                        oracle_map,
                         fill_mode.is_liquidation(),
                         rev_share_escrow,
                         builder_referral_feature_enabled,
                     )?;
 
+                if maker_fill_base_asset_amount != 0 {
+                    update_maker_fills_map(
+                        &mut maker_fills,
+                        maker_key,
+                        maker_direction,
+                        maker_fill_base_asset_amount,
+                    )?;
+                }
+
                 (fill_base_asset_amount, fill_quote_asset_amount)
             }
-            PerpFulfillmentMethod::Match(maker_key, maker_order_index, maker_price) => {
-                let mut maker = makers_and_referrer.get_ref_mut(maker_key)?;
-                let mut maker_stats = if maker.authority == user.authority {
-                    None
+        };
+
+        base_asset_amount = base_asset_amount.safe_add(fill_base_asset_amount)?;
+        quote_asset_amount = quote_asset_amount.safe_add(fill_quote_asset_amount)?;
+        market
+            .amm
+            .update_volume_24h(fill_quote_asset_amount, user_order_direction, now)?;
+
+        // a maker that fills less than its share (e.g. below its min fill size) leaves the
+        // shortfall to be shared by the makers left in the run
+        if let (Some(top_of_book_bonus), Some(maker_fill_cap)) =
+            (pro_rata_top_of_book_bonus, maker_fill_caps[method_index])
+        {
+            let next_method_index = method_index.safe_add(1)?;
+            if fill_base_asset_amount < maker_fill_cap && next_method_index < pro_rata_run_end {
+                let top_of_book_bonus = if pro_rata_top_of_book_method_index
+                    .map_or(false, |top_of_book_method_index| {
+                        top_of_book_method_index > method_index
+                    }) {
+                    top_of_book_bonus
                 } else {
-                    Some(makers_and_referrer_stats.get_ref_mut(&maker.authority)?)
+                    0
                 };
 
-                let (mut referrer, mut referrer_stats) = get_referrer(
-                    &referrer_info,
-                    makers_and_referrer,
-                    makers_and_referrer_stats,
-                    Some(&maker),
-                )?;
+                calculate_pro_rata_maker_fill_caps(
+                    &fulfillment_methods,
+                    next_method_index,
+                    user,
+                    user_order_index,
+                    makers_and_referrer,
+                    order_step_size,
+                    top_of_book_bonus,
+                    &mut maker_fill_caps,
+                )?;
+            }
+        }
+    }
+
+    validate!(
+        (base_asset_amount > 0) == (quote_asset_amount > 0),
+        ErrorCode::DefaultError,
+        "invalid fill base = {} quote = {}",
+        base_asset_amount,
+        quote_asset_amount
+    )?;
+
+    let total_maker_fill = maker_fills.values().sum::<i64>();
+
+    validate!(
+        total_maker_fill.unsigned_abs() <= base_asset_amount,
+        ErrorCode::DefaultError,
+        "invalid total maker fill {} total fill {}",
+        total_maker_fill,
+        base_asset_amount
+    )?;
+
+    if !fill_mode.is_liquidation() {
+        // if the maker is long, the user sold so
+        let taker_base_asset_amount_delta = if maker_direction == PositionDirection::Long {
+            base_asset_amount as i64
+        } else {
+            -(base_asset_amount as i64)
+        };
+
+        let mut context = MarginContext::standard(if user_order_position_decreasing {
+            MarginRequirementType::Maintenance
+        } else {
+            MarginRequirementType::Fill
+        })
+        .fuel_perp_delta(market_index, taker_base_asset_amount_delta)
+        .fuel_numerator(user, now);
+
+        if oracle_stale_for_margin && !user_order_position_decreasing {
+            context = context.margin_ratio_override(MARGIN_PRECISION);
+        }
+
+        let taker_margin_calculation =
+            calculate_margin_requirement_and_total_collateral_and_liability_info(
+                user,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                context,
+            )?;
+
+        user_stats.update_fuel_bonus(
+            user,
+            taker_margin_calculation.fuel_deposits,
+            taker_margin_calculation.fuel_borrows,
+            taker_margin_calculation.fuel_positions,
+            now,
+        )?;
+
+        if !taker_margin_calculation.meets_margin_requirement() {
+            msg!(
+                "taker breached fill requirements (margin requirement {}) (total_collateral {})",
+                taker_margin_calculation.margin_requirement,
+                taker_margin_calculation.total_collateral
+            );
+            return Err(ErrorCode::InsufficientCollateral);
+        }
+    }
+
+    for (maker_key, maker_base_asset_amount_filled) in maker_fills {
+        let mut maker = makers_and_referrer.get_ref_mut(&maker_key)?;
+
+        let maker_stats = if maker.authority == user.authority {
+            None
+        } else {
+            Some(makers_and_referrer_stats.get_ref_mut(&maker.authority)?)
+        };
+
+        let (margin_type, maker_risk_increasing) = select_margin_type_for_perp_maker(
+            &maker,
+            maker_base_asset_amount_filled,
+            market_index,
+        )?;
+
+        let mut context = MarginContext::standard(margin_type)
+            .fuel_perp_delta(market_index, -maker_base_asset_amount_filled)
+            .fuel_numerator(&maker, now);
+
+        if oracle_stale_for_margin {
+            validate!(
+                user_order_position_decreasing || !maker_risk_increasing,
+                ErrorCode::InvalidOracle,
+                "taker or maker must be reducing position if oracle stale for margin"
+            )?;
+
+            if maker_risk_increasing {
+                context = context.margin_ratio_override(MARGIN_PRECISION);
+            }
+        }
+
+        let maker_margin_calculation =
+            calculate_margin_requirement_and_total_collateral_and_liability_info(
+                &maker,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                context,
+            )?;
+
+        if let Some(mut maker_stats) = maker_stats {
+            maker_stats.update_fuel_bonus(
+                &mut maker,
+                maker_margin_calculation.fuel_deposits,
+                maker_margin_calculation.fuel_borrows,
+                maker_margin_calculation.fuel_positions,
+                now,
+            )?;
+        }
+
+        if !maker_margin_calculation.meets_margin_requirement() {
+            msg!(
+                "maker ({}) breached fill requirements (margin requirement {}) (total_collateral {})",
+                maker_key,
+                maker_margin_calculation.margin_requirement,
+                maker_margin_calculation.total_collateral
+            );
+            return Err(ErrorCode::InsufficientCollateral);
+        }
+    }
+
+    if oracle_stale_for_margin {
+        let perp_market_oi_after = perp_market_map.get_ref(&market_index)?.get_open_interest();
+        validate!(
+            perp_market_oi_after <= perp_market_oi_before,
+            ErrorCode::InvalidOracle,
+            "oracle stale for margin but open interest increased"
+        )?;
+    }
+
+    Ok((base_asset_amount, quote_asset_amount))
+}
+
+#[allow(clippy::type_complexity)]
+fn get_referrer<'a>(
+    referrer_info: &'a Option<(Pubkey, Pubkey)>,
+    makers_and_referrer: &'a UserMap,
+    makers_and_referrer_stats: &'a UserStatsMap,
+    maker: Option<&User>,
+) -> DriftResult<(Option<RefMut<'a, User>>, Option<RefMut<'a, UserStats>>)> {
+    let (referrer_authority_key, referrer_user_key) = match referrer_info {
+        Some(referrer_keys) => referrer_keys,
+        None => return Ok((None, None)),
+    };
+
+    if let Some(maker) = maker {
+        if &maker.authority == referrer_authority_key {
+            return Ok((None, None));
+        }
+    }
+
+    let referrer = makers_and_referrer.get_ref_mut(referrer_user_key)?;
+    let referrer_stats = makers_and_referrer_stats.get_ref_mut(referrer_authority_key)?;
+
+    Ok((Some(referrer), Some(referrer_stats)))
+}
+
+#[inline(always)]
+fn update_maker_fills_map(
+    map: &mut BTreeMap<Pubkey, i64>,
+    maker_key: &Pubkey,
+    maker_direction: PositionDirection,
+    fill: u64,
+) -> DriftResult {
+    let signed_fill = match maker_direction {
+        PositionDirection::Long => fill.cast::<i64>()?,
+        PositionDirection::Short => -fill.cast::<i64>()?,
+    };
+
+    if let Some(maker_filled) = map.get_mut(maker_key) {
+        *maker_filled = maker_filled.safe_add(signed_fill)?;
+    } else {
+        map.insert(*maker_key, signed_fill);
+    }
+
+    Ok(())
+}
+
+fn determine_if_user_order_is_position_decreasing(
+    user: &User,
+    market_index: u16,
+    order_index: usize,
+) -> DriftResult<bool> {
+    let position_index = get_position_index(&user.perp_positions, market_index)?;
+    let order_direction = user.orders[order_index].direction;
+    let position_base_asset_amount_before = user.perp_positions[position_index].base_asset_amount;
+    is_order_position_reducing(
+        &order_direction,
+        user.orders[order_index]
+            .get_base_asset_amount_unfilled(Some(position_base_asset_amount_before))?,
+        position_base_asset_amount_before.cast()?,
+    )
+}
+
+pub fn fulfill_perp_order_with_amm(
+    user: &mut User,
+    user_stats: &mut UserStats,
+    order_index: usize,
+    market: &mut PerpMarket,
+    oracle_map: &mut OracleMap,
+    reserve_price_before: u64,
+    now: i64,
+    slot: u64,
+    user_key: &Pubkey,
+    filler_key: &Pubkey,
+    filler: &mut Option<&mut User>,
+    filler_stats: &mut Option<&mut UserStats>,
+    maker: &mut Option<&mut User>,
+    maker_stats: &mut Option<&mut UserStats>,
+    referrer: &mut Option<&mut User>,
+    referrer_stats: &mut Option<&mut UserStats>,
+    fee_structure: &FeeStructure,
+    limit_price: Option<u64>,
+    override_base_asset_amount: Option<u64>,
+    override_fill_price: Option<u64>,
+    is_liquidation: bool,
+    rev_share_escrow: &mut Option<&mut RevenueShareEscrowZeroCopyMut>,
+    builder_referral_feature_enabled: bool,
+) -> DriftResult<(u64, u64)> {
+    let position_index = get_position_index(&user.perp_positions, market.market_index)?;
+    let existing_base_asset_amount = user.perp_positions[position_index].base_asset_amount;
+
+    // Determine the base asset amount the market can fill
+    let (base_asset_amount, limit_price, fill_price) = match override_base_asset_amount {
+        Some(override_base_asset_amount) => {
+            (override_base_asset_amount, limit_price, override_fill_price)
+        }
+        None => {
+            let fee_tier = determine_user_fee_tier(
+                user_stats,
+                fee_structure,
+                &MarketType::Perp,
+                user.is_high_leverage_mode(MarginRequirementType::Initial),
+            )?;
+            let (base_asset_amount, limit_price) = calculate_base_asset_amount_for_amm_to_fulfill(
+                &user.orders[order_index],
+                market,
+                limit_price,
+                override_fill_price,
+                existing_base_asset_amount,
+                &fee_tier,
+            )?;
 
-                let (fill_base_asset_amount, fill_quote_asset_amount, maker_fill_base_asset_amount) =
-                    fulfill_perp_order_with_match(
-                        market.deref_mut(),
-                        user,
-                        user_stats,
-                        user_order_index,
-                        user_key,
-                        &mut maker,
-                        &mut maker_stats.as_deref_mut(),
-                        *maker_order_index as usize,
-                        maker_key,
-                        filler,
-                        filler_stats,
-                        filler_key,
-                        &mut referrer.as_deref_mut(),
-                        &mut referrer_stats.as_deref_mut(),
-                        reserve_price_before,
-                        valid_oracle_price,
-                        limit_price,
-                        *maker_price,
-                        now,
-                        slot,
-                        fee_structure,
-                        oracle_map,
-                        fill_mode.is_liquidation(),
-                        rev_share_escrow,
-                        builder_referral_feature_enabled,
-                    )?;
+            let fill_price = if user.orders[order_index].post_only {
+                limit_price
+            } else {
+                None
+            };
 
-                if maker_fill_base_asset_amount != 0 {
-                    update_maker_fills_map(
-                        &mut maker_fills,
-                        maker_key,
-                        maker_direction,
-                        maker_fill_base_asset_amount,
-                    )?;
-                }
+            (base_asset_amount, limit_price, fill_price)
+        }
+    };
 
-                (fill_base_asset_amount, fill_quote_asset_amount)
-            }
-        };
+    // if user position is less than min order size, step size is the threshold
+    let amm_size_threshold = if !user.orders[order_index].reduce_only
+        && existing_base_asset_amount.unsigned_abs() > market.amm.min_order_size
+    {
+        market.amm.min_order_size
+    } else {
+        market.amm.order_step_size
+    };
 
-        base_asset_amount = base_asset_amount.safe_add(fill_base_asset_amount)?;
-        quote_asset_amount = quote_asset_amount.safe_add(fill_quote_asset_amount)?;
-        market
-            .amm
-            .update_volume_24h(fill_quote_asset_amount, user_order_direction, now)?;
+    if base_asset_amount < amm_size_threshold {
+        // if is an actual swap (and not amm jit order) then msg!
+        if override_base_asset_amount.is_none() {
+            msg!(
+                "Amm cant fulfill order. market index {} base asset amount {} market.amm.min_order_size {}",
+                market.market_index,
+                base_asset_amount,
+                market.amm.min_order_size
+            );
+        }
+        return Ok((0, 0));
     }
 
-    validate!(
-        (base_asset_amount > 0) == (quote_asset_amount > 0),
-        ErrorCode::DefaultError,
-        "invalid fill base = {} quote = {}",
+    if is_fill_below_min_fill_size(
+        &user.orders[order_index],
         base_asset_amount,
-        quote_asset_amount
-    )?;
-
-    let total_maker_fill = maker_fills.values().sum::<i64>();
+        Some(existing_base_asset_amount),
+    )? {
+        msg!(
+            "Amm fill {} below order min fill size {}",
+            base_asset_amount,
+            user.orders[order_index].get_min_fill_size()
+        );
+        return Ok((0, 0));
+    }
 
-    validate!(
-        total_maker_fill.unsigned_abs() <= base_asset_amount,
-        ErrorCode::DefaultError,
-        "invalid total maker fill {} total fill {}",
-        total_maker_fill,
-        base_asset_amount
-    )?;
+    let (order_post_only, order_slot, order_direction, order_id) = get_struct_values!(
+        user.orders[order_index],
+        post_only,
+        slot,
+        direction,
+        order_id
+    );
+    let user_order_has_builder = user.orders[order_index].is_has_builder();
+    if user_order_has_builder && rev_share_escrow.is_none() {
+        msg!("Order has builder but no escrow account included, in the future this will fail.");
+    }
 
-    if !fill_mode.is_liquidation() {
-        // if the maker is long, the user sold so
-        let taker_base_asset_amount_delta = if maker_direction == PositionDirection::Long {
-            base_asset_amount as i64
-        } else {
-            -(base_asset_amount as i64)
-        };
+    validation::perp_market::validate_amm_account_for_fill(&market.amm, order_direction)?;
 
-        let mut context = MarginContext::standard(if user_order_position_decreasing {
-            MarginRequirementType::Maintenance
-        } else {
-            MarginRequirementType::Fill
-        })
-        .fuel_perp_delta(market_index, taker_base_asset_amount_delta)
-        .fuel_numerator(user, now);
+    let existing_position_params_for_order_action = user.perp_positions[position_index]
+        .get_existing_position_params_for_order_action(order_direction);
 
-        if oracle_stale_for_margin && !user_order_position_decreasing {
-            context = context.margin_ratio_override(MARGIN_PRECISION);
-        }
+    let market_side_price = match order_direction {
+        PositionDirection::Long => market.amm.ask_price(reserve_price_before)?,
+        PositionDirection::Short => market.amm.bid_price(reserve_price_before)?,
+    };
 
-        let taker_margin_calculation =
-            calculate_margin_requirement_and_total_collateral_and_liability_info(
-                user,
-                perp_market_map,
-                spot_market_map,
-                oracle_map,
-                context,
-            )?;
+    let sanitize_clamp_denominator = market.get_sanitize_clamp_denominator()?;
+    amm::update_mark_twap_from_estimates(
+        &mut market.amm,
+        now,
+        Some(market_side_price),
+        Some(order_direction),
+        sanitize_clamp_denominator,
+    )?;
 
-        user_stats.update_fuel_bonus(
+    let (quote_asset_amount, quote_asset_amount_surplus, _) =
+        controller::position::update_position_with_base_asset_amount(
+            base_asset_amount,
+            order_direction,
+            market,
             user,
-            taker_margin_calculation.fuel_deposits,
-            taker_margin_calculation.fuel_borrows,
-            taker_margin_calculation.fuel_positions,
-            now,
+            position_index,
+            fill_price,
         )?;
 
-        if !taker_margin_calculation.meets_margin_requirement() {
-            msg!(
-                "taker breached fill requirements (margin requirement {}) (total_collateral {})",
-                taker_margin_calculation.margin_requirement,
-                taker_margin_calculation.total_collateral
-            );
-            return Err(ErrorCode::InsufficientCollateral);
-        }
+    if let Some(limit_price) = limit_price {
+        validate_fill_price(
+            quote_asset_amount,
+            base_asset_amount,
+            BASE_PRECISION_U64,
+            order_direction,
+            limit_price,
+            !order_post_only,
+        )?;
     }
 
-    for (maker_key, maker_base_asset_amount_filled) in maker_fills {
-        let mut maker = makers_and_referrer.get_ref_mut(&maker_key)?;
+    let reward_referrer = can_reward_user_with_referral_reward(
+        referrer,
+        market.market_index,
+        rev_share_escrow,
+        builder_referral_feature_enabled,
+    );
+    let reward_filler = can_reward_user_with_perp_pnl(filler, market.market_index)
+        || can_reward_user_with_perp_pnl(maker, market.market_index);
+
+    let (builder_order_idx, referrer_builder_order_idx, builder_order_fee_bps, builder_idx) =
+        get_builder_escrow_info(
+            rev_share_escrow,
+            user.sub_account_id,
+            order_id,
+            market.market_index,
+            builder_referral_feature_enabled,
+        );
+
+    let FillFees {
+        user_fee,
+        fee_to_market,
+        filler_reward,
+        referee_discount,
+        referrer_reward,
+        fee_to_market_for_lp: _fee_to_market_for_lp,
+        maker_rebate,
+        builder_fee: builder_fee_option,
+    } = fees::calculate_fee_for_fulfillment_with_amm(
+        user_stats,
+        quote_asset_amount,
+        fee_structure,
+        order_slot,
+        slot,
+        reward_filler,
+        reward_referrer,
+        referrer_stats,
+        quote_asset_amount_surplus,
+        order_post_only,
+        market.fee_adjustment,
+        user.is_high_leverage_mode(MarginRequirementType::Initial),
+        builder_order_fee_bps,
+    )?;
+
+    let builder_fee = builder_fee_option.unwrap_or(0);
 
-        let maker_stats = if maker.authority == user.authority {
-            None
+    if builder_fee != 0 {
+        if let (Some(idx), Some(escrow)) = (builder_order_idx, rev_share_escrow.as_mut()) {
+            let order = escrow.get_order_mut(idx)?;
+            order.fees_accrued = order.fees_accrued.safe_add(builder_fee)?;
         } else {
-            Some(makers_and_referrer_stats.get_ref_mut(&maker.authority)?)
-        };
-
-        let (margin_type, maker_risk_increasing) = select_margin_type_for_perp_maker(
-            &maker,
-            maker_base_asset_amount_filled,
-            market_index,
-        )?;
+            msg!("Order has builder fee but no escrow account found, in the future this tx will fail.");
+        }
+    }
 
-        let mut context = MarginContext::standard(margin_type)
-            .fuel_perp_delta(market_index, -maker_base_asset_amount_filled)
-            .fuel_numerator(&maker, now);
+    // Increment the protocol's total fee variables
+    market.amm.total_fee = market.amm.total_fee.safe_add(fee_to_market.cast()?)?;
+    market.amm.total_exchange_fee = market.amm.total_exchange_fee.safe_add(user_fee.cast()?)?;
+    market.amm.total_mm_fee = market
+        .amm
+        .total_mm_fee
+        .safe_add(quote_asset_amount_surplus.cast()?)?;
+    market.amm.total_fee_minus_distributions = market
+        .amm
+        .total_fee_minus_distributions
+        .safe_add(fee_to_market.cast()?)?;
+    market.amm.net_revenue_since_last_funding = market
+        .amm
+        .net_revenue_since_last_funding
+        .safe_add(fee_to_market)?;
 
-        if oracle_stale_for_margin {
-            validate!(
-                user_order_position_decreasing || !maker_risk_increasing,
-                ErrorCode::InvalidOracle,
-                "taker or maker must be reducing position if oracle stale for margin"
-            )?;
+    // Increment the user's total fee variables
+    user_stats.increment_total_fees(user_fee)?;
+    user_stats.increment_total_rebate(maker_rebate)?;
+    user_stats.increment_total_referee_discount(referee_discount)?;
 
-            if maker_risk_increasing {
-                context = context.margin_ratio_override(MARGIN_PRECISION);
+    if let (Some(idx), Some(escrow)) = (referrer_builder_order_idx, rev_share_escrow.as_mut()) {
+        let order = escrow.get_order_mut(idx)?;
+        order.fees_accrued = order.fees_accrued.safe_add(referrer_reward)?;
+    } else if let (Some(referrer), Some(referrer_stats)) =
+        (referrer.as_mut(), referrer_stats.as_mut())
+    {
+        if let Ok(referrer_position) = referrer.force_get_perp_position_mut(market.market_index) {
+            if referrer_reward > 0 {
+                update_quote_asset_amount(referrer_position, market, referrer_reward.cast()?)?;
             }
+            referrer_stats.increment_total_referrer_reward(referrer_reward, now)?;
         }
+    }
 
-        let maker_margin_calculation =
-            calculate_margin_requirement_and_total_collateral_and_liability_info(
-                &maker,
-                perp_market_map,
-                spot_market_map,
-                oracle_map,
-                context,
-            )?;
-
-        if let Some(mut maker_stats) = maker_stats {
-            maker_stats.update_fuel_bonus(
-                &mut maker,
-                maker_margin_calculation.fuel_deposits,
-                maker_margin_calculation.fuel_borrows,
-                maker_margin_calculation.fuel_positions,
-                now,
-            )?;
-        }
+    let position_index = get_position_index(&user.perp_positions, market.market_index)?;
 
-        if !maker_margin_calculation.meets_margin_requirement() {
-            msg!(
-                "maker ({}) breached fill requirements (margin requirement {}) (total_collateral {})",
-                maker_key,
-                maker_margin_calculation.margin_requirement,
-                maker_margin_calculation.total_collateral
-            );
-            return Err(ErrorCode::InsufficientCollateral);
-        }
+    if user_fee != 0 || builder_fee != 0 {
+        controller::position::update_quote_asset_and_break_even_amount(
+            &mut user.perp_positions[position_index],
+            market,
+            -(user_fee.safe_add(builder_fee)?).cast()?,
+        )?;
     }
 
-    if oracle_stale_for_margin {
-        let perp_market_oi_after = perp_market_map.get_ref(&market_index)?.get_open_interest();
-        validate!(
-            perp_market_oi_after <= perp_market_oi_before,
-            ErrorCode::InvalidOracle,
-            "oracle stale for margin but open interest increased"
+    if maker_rebate != 0 {
+        controller::position::update_quote_asset_and_break_even_amount(
+            &mut user.perp_positions[position_index],
+            market,
+            maker_rebate.cast()?,
         )?;
     }
 
-    Ok((base_asset_amount, quote_asset_amount))
-}
+    if order_post_only {
+        user_stats.update_maker_volume_30d(market.fuel_boost_maker, quote_asset_amount, now)?;
+    } else {
+        user_stats.update_taker_volume_30d(market.fuel_boost_taker, quote_asset_amount, now)?;
+    }
 
-#[allow(clippy::type_complexity)]
-fn get_referrer<'a>(
-    referrer_info: &'a Option<(Pubkey, Pubkey)>,
-    makers_and_referrer: &'a UserMap,
-    makers_and_referrer_stats: &'a UserStatsMap,
-    maker: Option<&User>,
-) -> DriftResult<(Option<RefMut<'a, User>>, Option<RefMut<'a, UserStats>>)> {
-    let (referrer_authority_key, referrer_user_key) = match referrer_info {
-        Some(referrer_keys) => referrer_keys,
-        None => return Ok((None, None)),
-    };
+    if let Some(filler) = filler.as_mut() {
+        credit_filler_perp_pnl(
+            filler,
+            filler_stats,
+            market,
+            filler_reward,
+            quote_asset_amount,
+            now,
+            slot,
+        )?;
+    } else if let Some(maker) = maker.as_mut() {
+        credit_filler_perp_pnl(
+            maker,
+            maker_stats,
+            market,
+            filler_reward,
+            quote_asset_amount,
+            now,
+            slot,
+        )?;
+    }
 
-    if let Some(maker) = maker {
-        if &maker.authority == referrer_authority_key {
-            return Ok((None, None));
+    let is_filled = update_order_after_fill(
+        &mut user.orders[order_index],
+        base_asset_amount,
+        quote_asset_amount,
+    )?;
+    if is_filled {
+        if let (Some(idx), Some(escrow)) = (builder_order_idx, rev_share_escrow.as_mut()) {
+            let _ = escrow
+                .get_order_mut(idx)
+                .map(|order| order.add_bit_flag(RevenueShareOrderBitFlag::Completed));
         }
     }
 
-    let referrer = makers_and_referrer.get_ref_mut(referrer_user_key)?;
-    let referrer_stats = makers_and_referrer_stats.get_ref_mut(referrer_authority_key)?;
+    decrease_open_bids_and_asks(
+        &mut user.perp_positions[position_index],
+        &order_direction,
+        base_asset_amount,
+        user.orders[order_index].update_open_bids_and_asks(),
+    )?;
 
-    Ok((Some(referrer), Some(referrer_stats)))
+    let (taker, taker_order, maker, maker_order) =
+        get_taker_and_maker_for_order_record(user_key, &user.orders[order_index]);
+
+    let fill_record_id = get_then_update_id!(market, next_fill_record_id);
+    let order_action_explanation = match (override_base_asset_amount, override_fill_price) {
+        _ if is_liquidation => OrderActionExplanation::Liquidation,
+        (Some(_), Some(_)) => OrderActionExplanation::OrderFilledWithAMMJit,
+        _ => OrderActionExplanation::OrderFilledWithAMM,
+    };
+    let mut order_action_bit_flags: u8 = 0;
+    order_action_bit_flags = set_order_bit_flag(
+        order_action_bit_flags,
+        user.orders[order_index].is_signed_msg(),
+        OrderBitFlag::SignedMessage,
+    );
+    let (
+        taker_existing_quote_entry_amount,
+        taker_existing_base_asset_amount,
+        maker_existing_quote_entry_amount,
+        maker_existing_base_asset_amount,
+    ) = {
+        let (existing_quote_entry_amount, existing_base_asset_amount) =
+            calculate_existing_position_fields_for_order_action(
+                base_asset_amount,
+                existing_position_params_for_order_action,
+            )?;
+        if taker.is_some() {
+            (
+                existing_quote_entry_amount,
+                existing_base_asset_amount,
+                None,
+                None,
+            )
+        } else {
+            (
+                None,
+                None,
+                existing_quote_entry_amount,
+                existing_base_asset_amount,
+            )
+        }
+    };
+    let order_action_record = get_order_action_record(
+        now,
+        OrderAction::Fill,
+        order_action_explanation,
+        market.market_index,
+        Some(*filler_key),
+        Some(fill_record_id),
+        Some(filler_reward),
+        Some(base_asset_amount),
+        Some(quote_asset_amount),
+        Some(user_fee.safe_add(builder_fee)?),
+        if maker_rebate != 0 {
+            Some(maker_rebate)
+        } else {
+            None
+        },
+        Some(referrer_reward),
+        Some(quote_asset_amount_surplus),
+        None,
+        taker,
+        taker_order,
+        maker,
+        maker_order,
+        oracle_map.get_price_data(&market.oracle_id())?.price,
+        order_action_bit_flags,
+        taker_existing_quote_entry_amount,
+        taker_existing_base_asset_amount,
+        maker_existing_quote_entry_amount,
+        maker_existing_base_asset_amount,
+        None,
+        builder_idx,
+        builder_fee_option,
+    )?;
+    emit_stack::<_, { OrderActionRecord::SIZE }>(order_action_record)?;
+
+    // Cant reset order until after its logged
+    if user.orders[order_index].is_filled(user.perp_positions[position_index].base_asset_amount)? {
+        user.decrement_open_orders(user.orders[order_index].has_auction());
+        user.orders[order_index].status = OrderStatus::Filled;
+        let market_position = &mut user.perp_positions[position_index];
+        market_position.open_orders -= 1;
+    }
+
+    Ok((base_asset_amount, quote_asset_amount))
 }
 
-#[inline(always)]
-fn update_maker_fills_map(
-    map: &mut BTreeMap<Pubkey, i64>,
-    maker_key: &Pubkey,
-    maker_direction: PositionDirection,
-    fill: u64,
+pub fn credit_filler_perp_pnl(
+    filler: &mut User,
+    filler_stats: &mut Option<&mut UserStats>,
+    market: &mut PerpMarket,
+    filler_reward: u64,
+    quote_asset_amount: u64,
+    now: i64,
+    slot: u64,
 ) -> DriftResult {
-    let signed_fill = match maker_direction {
-        PositionDirection::Long => fill.cast::<i64>()?,
-        PositionDirection::Short => -fill.cast::<i64>()?,
-    };
+    if filler_reward > 0 {
+        let position_index = get_position_index(&filler.perp_positions, market.market_index)
+            .or_else(|_| add_new_position(&mut filler.perp_positions, market.market_index))?;
 
-    if let Some(maker_filled) = map.get_mut(maker_key) {
-        *maker_filled = maker_filled.safe_add(signed_fill)?;
-    } else {
-        map.insert(*maker_key, signed_fill);
+        controller::position::update_quote_asset_amount(
+            &mut filler.perp_positions[position_index],
+            market,
+            filler_reward.cast()?,
+        )?;
+
+        filler_stats
+            .as_mut()
+            .safe_unwrap()?
+            .update_filler_volume(quote_asset_amount, now)?;
     }
 
-    Ok(())
-}
+    filler.update_last_active_slot(slot);
 
-fn determine_if_user_order_is_position_decreasing(
-    user: &User,
-    market_index: u16,
-    order_index: usize,
-) -> DriftResult<bool> {
-    let position_index = get_position_index(&user.perp_positions, market_index)?;
-    let order_direction = user.orders[order_index].direction;
-    let position_base_asset_amount_before = user.perp_positions[position_index].base_asset_amount;
-    is_order_position_reducing(
-        &order_direction,
-        user.orders[order_index]
-            .get_base_asset_amount_unfilled(Some(position_base_asset_amount_before))?,
-        position_base_asset_amount_before.cast()?,
-    )
+    Ok(())
 }
 
-pub fn fulfill_perp_order_with_amm(
-    user: &mut User,
-    user_stats: &mut UserStats,
-    order_index: usize,
+pub fn fulfill_perp_order_with_match(
     market: &mut PerpMarket,
-    oracle_map: &mut OracleMap,
-    reserve_price_before: u64,
-    now: i64,
-    slot: u64,
-    user_key: &Pubkey,
-    filler_key: &Pubkey,
+    taker: &mut User,
+    taker_stats: &mut UserStats,
+    taker_order_index: usize,
+    taker_key: &Pubkey,
+    maker: &mut User,
+    maker_stats: &mut Option<&mut UserStats>,
+    maker_order_index: usize,
+    maker_key: &Pubkey,
     filler: &mut Option<&mut User>,
     filler_stats: &mut Option<&mut UserStats>,
-    maker: &mut Option<&mut User>,
-    maker_stats: &mut Option<&mut UserStats>,
+    filler_key: &Pubkey,
     referrer: &mut Option<&mut User>,
     referrer_stats: &mut Option<&mut UserStats>,
+    reserve_price_before: u64,
+    valid_oracle_price: Option<i64>,
+    taker_limit_price: Option<u64>,
+    maker_price: u64,
+    max_base_asset_amount: Option<u64>,
+    now: i64,
+    slot: u64,
     fee_structure: &FeeStructure,
-    limit_price: Option<u64>,
-    override_base_asset_amount: Option<u64>,
-    override_fill_price: Option<u64>,
+    oracle_map: &mut OracleMap,
     is_liquidation: bool,
     rev_share_escrow: &mut Option<&mut RevenueShareEscrowZeroCopyMut>,
     builder_referral_feature_enabled: bool,
-) -> DriftResult<(u64, u64)> {
-    let position_index = get_position_index(&user.perp_positions, market.market_index)?;
-    let existing_base_asset_amount = user.perp_positions[position_index].base_asset_amount;
+) -> DriftResult<(u64, u64, u64)> {
+    if !are_orders_same_market_but_different_sides(
+        &maker.orders[maker_order_index],
+        &taker.orders[taker_order_index],
+    ) {
+        return Ok((0_u64, 0_u64, 0_u64));
+    }
 
-    // Determine the base asset amount the market can fill
-    let (base_asset_amount, limit_price, fill_price) = match override_base_asset_amount {
-        Some(override_base_asset_amount) => {
-            (override_base_asset_amount, limit_price, override_fill_price)
-        }
-        None => {
-            let fee_tier = determine_user_fee_tier(
-                user_stats,
-                fee_structure,
-                &MarketType::Perp,
-                user.is_high_leverage_mode(MarginRequirementType::Initial),
-            )?;
-            let (base_asset_amount, limit_price) = calculate_base_asset_amount_for_amm_to_fulfill(
-                &user.orders[order_index],
+    let oracle_price = oracle_map.get_price_data(&market.oracle_id())?.price;
+    let taker_direction: PositionDirection = taker.orders[taker_order_index].direction;
+    let taker_order_has_builder = taker.orders[taker_order_index].is_has_builder();
+    if taker_order_has_builder && rev_share_escrow.is_none() {
+        msg!("Order has builder but no escrow account included, in the future this will fail.");
+    }
+
+    let taker_price = if let Some(taker_limit_price) = taker_limit_price {
+        taker_limit_price
+    } else {
+        let amm_available_liquidity = market.get_amm_available_liquidity(&taker_direction)?;
+        market.amm.get_fallback_price(
+            &taker_direction,
+            amm_available_liquidity,
+            oracle_price,
+            taker.orders[taker_order_index].seconds_til_expiry(now),
+        )?
+    };
+
+    let taker_existing_position = taker
+        .get_perp_position(market.market_index)?
+        .base_asset_amount;
+    let taker_base_asset_amount = taker.orders[taker_order_index]
+        .get_base_asset_amount_unfilled(Some(taker_existing_position))?;
+
+    let maker_direction = maker.orders[maker_order_index].direction;
+    let (maker_existing_position, maker_existing_position_params_for_order_action) = {
+        let maker_position = maker.get_perp_position(market.market_index)?;
+
+        (
+            maker_position.base_asset_amount,
+            maker_position.get_existing_position_params_for_order_action(maker_direction),
+        )
+    };
+    let maker_base_asset_amount = maker.orders[maker_order_index]
+        .get_base_asset_amount_displayed(Some(maker_existing_position))?;
+    // allocated fills (e.g. batch auctions) can cap how much of the maker order is filled
+    let maker_base_asset_amount = match max_base_asset_amount {
+        Some(max_base_asset_amount) => maker_base_asset_amount.min(max_base_asset_amount),
+        None => maker_base_asset_amount,
+    };
+
+    let orders_cross = do_orders_cross(maker_direction, maker_price, taker_price);
+
+    if !orders_cross {
+        msg!(
+            "orders dont cross. maker price {} taker price {}",
+            maker_price,
+            taker_price
+        );
+        return Ok((0_u64, 0_u64, 0_u64));
+    }
+
+    let (base_asset_amount, _) = calculate_fill_for_matched_orders(
+        maker_base_asset_amount,
+        maker_price,
+        taker_base_asset_amount,
+        PERP_DECIMALS,
+        maker_direction,
+    )?;
+
+    if base_asset_amount == 0 {
+        return Ok((0_u64, 0_u64, 0_u64));
+    }
+
+    if is_fill_below_min_fill_size(
+        &taker.orders[taker_order_index],
+        base_asset_amount,
+        Some(taker_existing_position),
+    )? || is_fill_below_min_fill_size(
+        &maker.orders[maker_order_index],
+        base_asset_amount,
+        Some(maker_existing_position),
+    )? {
+        msg!("fill {} below min fill size", base_asset_amount);
+        return Ok((0_u64, 0_u64, 0_u64));
+    }
+
+    let sanitize_clamp_denominator = market.get_sanitize_clamp_denominator()?;
+    amm::update_mark_twap_from_estimates(
+        &mut market.amm,
+        now,
+        Some(maker_price),
+        Some(taker_direction),
+        sanitize_clamp_denominator,
+    )?;
+
+    let mut total_quote_asset_amount = 0_u64;
+    let mut total_base_asset_amount = 0_u64;
+
+    // amm jit would split the checked fill into pieces that could be below a min fill size
+    // or take from a capped allocation
+    let jit_base_asset_amount = if taker.orders[taker_order_index].has_min_fill_size()
+        || maker.orders[maker_order_index].has_min_fill_size()
+        || max_base_asset_amount.is_some()
+    {
+        0
+    } else {
+        calculate_amm_jit_liquidity(
+            market,
+            taker_direction,
+            maker_price,
+            valid_oracle_price,
+            base_asset_amount,
+            taker_base_asset_amount,
+            maker_base_asset_amount,
+            taker.orders[taker_order_index].has_limit_price(slot)?,
+        )?
+    };
+
+    if jit_base_asset_amount > 0 {
+        let (base_asset_amount_filled_by_amm, quote_asset_amount_filled_by_amm) =
+            fulfill_perp_order_with_amm(
+                taker,
+                taker_stats,
+                taker_order_index,
                 market,
-                limit_price,
-                override_fill_price,
-                existing_base_asset_amount,
-                &fee_tier,
+                oracle_map,
+                reserve_price_before,
+                now,
+                slot,
+                taker_key,
+                filler_key,
+                filler,
+                filler_stats,
+                &mut None,
+                &mut None,
+                &mut None,
+                &mut None,
+                fee_structure,
+                taker_limit_price,
+                Some(jit_base_asset_amount),
+                Some(maker_price), // match the makers price
+                is_liquidation,
+                rev_share_escrow,
+                builder_referral_feature_enabled,
             )?;
 
-            let fill_price = if user.orders[order_index].post_only {
-                limit_price
-            } else {
-                None
-            };
+        total_base_asset_amount = base_asset_amount_filled_by_amm;
+        total_quote_asset_amount = quote_asset_amount_filled_by_amm
+    }
+
+    let (taker_existing_position, taker_existing_position_params_for_order_action) = {
+        let taker_position = taker.get_perp_position(market.market_index)?;
+
+        (
+            taker_position.base_asset_amount,
+            taker_position.get_existing_position_params_for_order_action(taker_direction),
+        )
+    };
+
+    let taker_base_asset_amount = taker.orders[taker_order_index]
+        .get_base_asset_amount_unfilled(Some(taker_existing_position))?;
+
+    let (base_asset_amount_fulfilled_by_maker, quote_asset_amount) =
+        calculate_fill_for_matched_orders(
+            maker_base_asset_amount,
+            maker_price,
+            taker_base_asset_amount,
+            PERP_DECIMALS,
+            maker_direction,
+        )?;
 
-            (base_asset_amount, limit_price, fill_price)
-        }
-    };
+    validate_fill_price(
+        quote_asset_amount,
+        base_asset_amount_fulfilled_by_maker,
+        BASE_PRECISION_U64,
+        taker_direction,
+        taker_price,
+        true,
+    )?;
 
-    // if user position is less than min order size, step size is the threshold
-    let amm_size_threshold = if !user.orders[order_index].reduce_only
-        && existing_base_asset_amount.unsigned_abs() > market.amm.min_order_size
-    {
-        market.amm.min_order_size
-    } else {
-        market.amm.order_step_size
-    };
+    validate_fill_price(
+        quote_asset_amount,
+        base_asset_amount_fulfilled_by_maker,
+        BASE_PRECISION_U64,
+        maker_direction,
+        maker_price,
+        false,
+    )?;
 
-    if base_asset_amount < amm_size_threshold {
-        // if is an actual swap (and not amm jit order) then msg!
-        if override_base_asset_amount.is_none() {
-            msg!(
-                "Amm cant fulfill order. market index {} base asset amount {} market.amm.min_order_size {}",
-                market.market_index,
-                base_asset_amount,
-                market.amm.min_order_size
-            );
-        }
-        return Ok((0, 0));
-    }
+    total_base_asset_amount =
+        total_base_asset_amount.safe_add(base_asset_amount_fulfilled_by_maker)?;
+    total_quote_asset_amount = total_quote_asset_amount.safe_add(quote_asset_amount)?;
 
-    let (order_post_only, order_slot, order_direction, order_id) = get_struct_values!(
-        user.orders[order_index],
-        post_only,
-        slot,
-        direction,
-        order_id
-    );
-    let user_order_has_builder = user.orders[order_index].is_has_builder();
-    if user_order_has_builder && rev_share_escrow.is_none() {
-        msg!("Order has builder but no escrow account included, in the future this will fail.");
-    }
+    let maker_position_index = get_position_index(
+        &maker.perp_positions,
+        maker.orders[maker_order_index].market_index,
+    )?;
 
-    validation::perp_market::validate_amm_account_for_fill(&market.amm, order_direction)?;
+    let maker_position_delta = get_position_delta_for_fill(
+        base_asset_amount_fulfilled_by_maker,
+        quote_asset_amount,
+        maker.orders[maker_order_index].direction,
+    )?;
 
-    let existing_position_params_for_order_action = user.perp_positions[position_index]
-        .get_existing_position_params_for_order_action(order_direction);
+    update_position_and_market(
+        &mut maker.perp_positions[maker_position_index],
+        market,
+        &maker_position_delta,
+    )?;
 
-    let market_side_price = match order_direction {
-        PositionDirection::Long => market.amm.ask_price(reserve_price_before)?,
-        PositionDirection::Short => market.amm.bid_price(reserve_price_before)?,
+    // if maker is none, makes maker and taker authority was the same
+    if let Some(maker_stats) = maker_stats {
+        maker_stats.update_maker_volume_30d(market.fuel_boost_maker, quote_asset_amount, now)?;
+    } else {
+        taker_stats.update_maker_volume_30d(market.fuel_boost_maker, quote_asset_amount, now)?;
     };
 
-    let sanitize_clamp_denominator = market.get_sanitize_clamp_denominator()?;
-    amm::update_mark_twap_from_estimates(
-        &mut market.amm,
-        now,
-        Some(market_side_price),
-        Some(order_direction),
-        sanitize_clamp_denominator,
+    let taker_position_index = get_position_index(
+        &taker.perp_positions,
+        taker.orders[taker_order_index].market_index,
     )?;
 
-    let (quote_asset_amount, quote_asset_amount_surplus, _) =
-        controller::position::update_position_with_base_asset_amount(
-            base_asset_amount,
-            order_direction,
-            market,
-            user,
-            position_index,
-            fill_price,
-        )?;
+    let taker_position_delta = get_position_delta_for_fill(
+        base_asset_amount_fulfilled_by_maker,
+        quote_asset_amount,
+        taker.orders[taker_order_index].direction,
+    )?;
 
-    if let Some(limit_price) = limit_price {
-        validate_fill_price(
-            quote_asset_amount,
-            base_asset_amount,
-            BASE_PRECISION_U64,
-            order_direction,
-            limit_price,
-            !order_post_only,
-        )?;
-    }
+    update_position_and_market(
+        &mut taker.perp_positions[taker_position_index],
+        market,
+        &taker_position_delta,
+    )?;
+
+    taker_stats.update_taker_volume_30d(market.fuel_boost_taker, quote_asset_amount, now)?;
 
     let reward_referrer = can_reward_user_with_referral_reward(
         referrer,
@@ -2294,47 +4666,51 @@ pub fn fulfill_perp_order_with_amm(
         rev_share_escrow,
         builder_referral_feature_enabled,
     );
-    let reward_filler = can_reward_user_with_perp_pnl(filler, market.market_index)
-        || can_reward_user_with_perp_pnl(maker, market.market_index);
+    let reward_filler = can_reward_user_with_perp_pnl(filler, market.market_index);
 
     let (builder_order_idx, referrer_builder_order_idx, builder_order_fee_bps, builder_idx) =
         get_builder_escrow_info(
             rev_share_escrow,
-            user.sub_account_id,
-            order_id,
+            taker.sub_account_id,
+            taker.orders[taker_order_index].order_id,
             market.market_index,
             builder_referral_feature_enabled,
         );
 
+    let filler_multiplier = if reward_filler {
+        calculate_filler_multiplier_for_matched_orders(maker_price, maker_direction, oracle_price)?
+    } else {
+        0
+    };
+
     let FillFees {
-        user_fee,
+        user_fee: taker_fee,
+        maker_rebate,
         fee_to_market,
         filler_reward,
-        referee_discount,
         referrer_reward,
-        fee_to_market_for_lp: _fee_to_market_for_lp,
-        maker_rebate,
+        referee_discount,
         builder_fee: builder_fee_option,
-    } = fees::calculate_fee_for_fulfillment_with_amm(
-        user_stats,
+        ..
+    } = fees::calculate_fee_for_fulfillment_with_match(
+        taker_stats,
+        maker_stats,
         quote_asset_amount,
         fee_structure,
-        order_slot,
+        taker.orders[taker_order_index].slot,
         slot,
-        reward_filler,
+        filler_multiplier,
         reward_referrer,
         referrer_stats,
-        quote_asset_amount_surplus,
-        order_post_only,
+        &MarketType::Perp,
         market.fee_adjustment,
-        user.is_high_leverage_mode(MarginRequirementType::Initial),
+        taker.is_high_leverage_mode(MarginRequirementType::Initial),
         builder_order_fee_bps,
     )?;
-
     let builder_fee = builder_fee_option.unwrap_or(0);
 
     if builder_fee != 0 {
-        if let (Some(idx), Some(escrow)) = (builder_order_idx, rev_share_escrow.as_mut()) {
+        if let (Some(idx), Some(escrow)) = (builder_order_idx, rev_share_escrow.as_deref_mut()) {
             let order = escrow.get_order_mut(idx)?;
             order.fees_accrued = order.fees_accrued.safe_add(builder_fee)?;
         } else {
@@ -2342,13 +4718,12 @@ pub fn fulfill_perp_order_with_amm(
         }
     }
 
-    // Increment the protocol's total fee variables
+    // Increment the markets house's total fee variables
     market.amm.total_fee = market.amm.total_fee.safe_add(fee_to_market.cast()?)?;
-    market.amm.total_exchange_fee = market.amm.total_exchange_fee.safe_add(user_fee.cast()?)?;
-    market.amm.total_mm_fee = market
+    market.amm.total_exchange_fee = market
         .amm
-        .total_mm_fee
-        .safe_add(quote_asset_amount_surplus.cast()?)?;
+        .total_exchange_fee
+        .safe_add(fee_to_market.cast()?)?;
     market.amm.total_fee_minus_distributions = market
         .amm
         .total_fee_minus_distributions
@@ -2358,133 +4733,121 @@ pub fn fulfill_perp_order_with_amm(
         .net_revenue_since_last_funding
         .safe_add(fee_to_market)?;
 
-    // Increment the user's total fee variables
-    user_stats.increment_total_fees(user_fee)?;
-    user_stats.increment_total_rebate(maker_rebate)?;
-    user_stats.increment_total_referee_discount(referee_discount)?;
+    controller::position::update_quote_asset_and_break_even_amount(
+        &mut taker.perp_positions[taker_position_index],
+        market,
+        -(taker_fee.safe_add(builder_fee)?).cast()?,
+    )?;
 
-    if let (Some(idx), Some(escrow)) = (referrer_builder_order_idx, rev_share_escrow.as_mut()) {
-        let order = escrow.get_order_mut(idx)?;
-        order.fees_accrued = order.fees_accrued.safe_add(referrer_reward)?;
-    } else if let (Some(referrer), Some(referrer_stats)) =
-        (referrer.as_mut(), referrer_stats.as_mut())
-    {
-        if let Ok(referrer_position) = referrer.force_get_perp_position_mut(market.market_index) {
-            if referrer_reward > 0 {
-                update_quote_asset_amount(referrer_position, market, referrer_reward.cast()?)?;
-            }
-            referrer_stats.increment_total_referrer_reward(referrer_reward, now)?;
-        }
-    }
+    taker_stats.increment_total_fees(taker_fee)?;
+    taker_stats.increment_total_referee_discount(referee_discount)?;
 
-    let position_index = get_position_index(&user.perp_positions, market.market_index)?;
+    controller::position::update_quote_asset_and_break_even_amount(
+        &mut maker.perp_positions[maker_position_index],
+        market,
+        maker_rebate.cast()?,
+    )?;
 
-    if user_fee != 0 || builder_fee != 0 {
-        controller::position::update_quote_asset_and_break_even_amount(
-            &mut user.perp_positions[position_index],
-            market,
-            -(user_fee.safe_add(builder_fee)?).cast()?,
-        )?;
+    if let Some(maker_stats) = maker_stats {
+        maker_stats.increment_total_rebate(maker_rebate)?;
+    } else {
+        taker_stats.increment_total_rebate(maker_rebate)?;
     }
 
-    if maker_rebate != 0 {
-        controller::position::update_quote_asset_and_break_even_amount(
-            &mut user.perp_positions[position_index],
-            market,
-            maker_rebate.cast()?,
-        )?;
-    }
+    if let Some(filler) = filler {
+        if filler_reward > 0 {
+            let filler_position_index =
+                get_position_index(&filler.perp_positions, market.market_index).or_else(|_| {
+                    add_new_position(&mut filler.perp_positions, market.market_index)
+                })?;
 
-    if order_post_only {
-        user_stats.update_maker_volume_30d(market.fuel_boost_maker, quote_asset_amount, now)?;
-    } else {
-        user_stats.update_taker_volume_30d(market.fuel_boost_taker, quote_asset_amount, now)?;
-    }
+            controller::position::update_quote_asset_amount(
+                &mut filler.perp_positions[filler_position_index],
+                market,
+                filler_reward.cast()?,
+            )?;
 
-    if let Some(filler) = filler.as_mut() {
-        credit_filler_perp_pnl(
-            filler,
-            filler_stats,
-            market,
-            filler_reward,
-            quote_asset_amount,
-            now,
-            slot,
-        )?;
-    } else if let Some(maker) = maker.as_mut() {
-        credit_filler_perp_pnl(
-            maker,
-            maker_stats,
-            market,
-            filler_reward,
-            quote_asset_amount,
-            now,
-            slot,
-        )?;
+            filler_stats
+                .as_mut()
+                .safe_unwrap()?
+                .update_filler_volume(quote_asset_amount, now)?;
+        }
+        filler.update_last_active_slot(slot);
+    }
+
+    if let (Some(idx), Some(escrow)) = (referrer_builder_order_idx, rev_share_escrow.as_deref_mut())
+    {
+        let order = escrow.get_order_mut(idx)?;
+        order.fees_accrued = order.fees_accrued.safe_add(referrer_reward)?;
+    } else if let (Some(referrer), Some(referrer_stats)) =
+        (referrer.as_mut(), referrer_stats.as_mut())
+    {
+        if let Ok(referrer_position) = referrer.force_get_perp_position_mut(market.market_index) {
+            if referrer_reward > 0 {
+                update_quote_asset_amount(referrer_position, market, referrer_reward.cast()?)?;
+            }
+            referrer_stats.increment_total_referrer_reward(referrer_reward, now)?;
+        }
     }
 
     let is_filled = update_order_after_fill(
-        &mut user.orders[order_index],
-        base_asset_amount,
+        &mut taker.orders[taker_order_index],
+        base_asset_amount_fulfilled_by_maker,
         quote_asset_amount,
     )?;
+
     if is_filled {
-        if let (Some(idx), Some(escrow)) = (builder_order_idx, rev_share_escrow.as_mut()) {
-            let _ = escrow
-                .get_order_mut(idx)
-                .map(|order| order.add_bit_flag(RevenueShareOrderBitFlag::Completed));
+        if let (Some(idx), Some(escrow)) = (builder_order_idx, rev_share_escrow.as_deref_mut()) {
+            escrow
+                .get_order_mut(idx)?
+                .add_bit_flag(RevenueShareOrderBitFlag::Completed);
         }
     }
 
     decrease_open_bids_and_asks(
-        &mut user.perp_positions[position_index],
-        &order_direction,
-        base_asset_amount,
-        user.orders[order_index].update_open_bids_and_asks(),
+        &mut taker.perp_positions[taker_position_index],
+        &taker.orders[taker_order_index].direction,
+        base_asset_amount_fulfilled_by_maker,
+        taker.orders[taker_order_index].update_open_bids_and_asks(),
     )?;
 
-    let (taker, taker_order, maker, maker_order) =
-        get_taker_and_maker_for_order_record(user_key, &user.orders[order_index]);
+    update_order_after_fill(
+        &mut maker.orders[maker_order_index],
+        base_asset_amount_fulfilled_by_maker,
+        quote_asset_amount,
+    )?;
+
+    decrease_open_bids_and_asks(
+        &mut maker.perp_positions[maker_position_index],
+        &maker.orders[maker_order_index].direction,
+        base_asset_amount_fulfilled_by_maker,
+        maker.orders[maker_order_index].update_open_bids_and_asks(),
+    )?;
 
     let fill_record_id = get_then_update_id!(market, next_fill_record_id);
-    let order_action_explanation = match (override_base_asset_amount, override_fill_price) {
-        _ if is_liquidation => OrderActionExplanation::Liquidation,
-        (Some(_), Some(_)) => OrderActionExplanation::OrderFilledWithAMMJit,
-        _ => OrderActionExplanation::OrderFilledWithAMM,
+    let order_action_explanation = if is_liquidation {
+        OrderActionExplanation::Liquidation
+    } else if maker.orders[maker_order_index].is_jit_maker() {
+        OrderActionExplanation::OrderFilledWithMatchJit
+    } else {
+        OrderActionExplanation::OrderFilledWithMatch
     };
-    let mut order_action_bit_flags: u8 = 0;
+    let mut order_action_bit_flags = 0;
     order_action_bit_flags = set_order_bit_flag(
         order_action_bit_flags,
-        user.orders[order_index].is_signed_msg(),
+        taker.orders[taker_order_index].is_signed_msg(),
         OrderBitFlag::SignedMessage,
     );
-    let (
-        taker_existing_quote_entry_amount,
-        taker_existing_base_asset_amount,
-        maker_existing_quote_entry_amount,
-        maker_existing_base_asset_amount,
-    ) = {
-        let (existing_quote_entry_amount, existing_base_asset_amount) =
-            calculate_existing_position_fields_for_order_action(
-                base_asset_amount,
-                existing_position_params_for_order_action,
-            )?;
-        if taker.is_some() {
-            (
-                existing_quote_entry_amount,
-                existing_base_asset_amount,
-                None,
-                None,
-            )
-        } else {
-            (
-                None,
-                None,
-                existing_quote_entry_amount,
-                existing_base_asset_amount,
-            )
-        }
-    };
+    let (taker_existing_quote_entry_amount, taker_existing_base_asset_amount) =
+        calculate_existing_position_fields_for_order_action(
+            base_asset_amount_fulfilled_by_maker,
+            taker_existing_position_params_for_order_action,
+        )?;
+    let (maker_existing_quote_entry_amount, maker_existing_base_asset_amount) =
+        calculate_existing_position_fields_for_order_action(
+            base_asset_amount_fulfilled_by_maker,
+            maker_existing_position_params_for_order_action,
+        )?;
     let order_action_record = get_order_action_record(
         now,
         OrderAction::Fill,
@@ -2493,21 +4856,17 @@ pub fn fulfill_perp_order_with_amm(
         Some(*filler_key),
         Some(fill_record_id),
         Some(filler_reward),
-        Some(base_asset_amount),
+        Some(base_asset_amount_fulfilled_by_maker),
         Some(quote_asset_amount),
-        Some(user_fee.safe_add(builder_fee)?),
-        if maker_rebate != 0 {
-            Some(maker_rebate)
-        } else {
-            None
-        },
+        Some(taker_fee.safe_add(builder_fee)?),
+        Some(maker_rebate),
         Some(referrer_reward),
-        Some(quote_asset_amount_surplus),
         None,
-        taker,
-        taker_order,
-        maker,
-        maker_order,
+        None,
+        Some(*taker_key),
+        Some(taker.orders[taker_order_index]),
+        Some(*maker_key),
+        Some(maker.orders[maker_order_index]),
         oracle_map.get_price_data(&market.oracle_id())?.price,
         order_action_bit_flags,
         taker_existing_quote_entry_amount,
@@ -2520,521 +4879,538 @@ pub fn fulfill_perp_order_with_amm(
     )?;
     emit_stack::<_, { OrderActionRecord::SIZE }>(order_action_record)?;
 
-    // Cant reset order until after its logged
-    if user.orders[order_index].get_base_asset_amount_unfilled(None)? == 0 {
-        user.decrement_open_orders(user.orders[order_index].has_auction());
-        user.orders[order_index].status = OrderStatus::Filled;
-        let market_position = &mut user.perp_positions[position_index];
+    if taker.orders[taker_order_index]
+        .is_filled(taker.perp_positions[taker_position_index].base_asset_amount)?
+    {
+        taker.decrement_open_orders(taker.orders[taker_order_index].has_auction());
+        taker.orders[taker_order_index].status = OrderStatus::Filled;
+        let market_position = &mut taker.perp_positions[taker_position_index];
         market_position.open_orders -= 1;
     }
 
-    Ok((base_asset_amount, quote_asset_amount))
-}
-
-pub fn credit_filler_perp_pnl(
-    filler: &mut User,
-    filler_stats: &mut Option<&mut UserStats>,
-    market: &mut PerpMarket,
-    filler_reward: u64,
-    quote_asset_amount: u64,
-    now: i64,
-    slot: u64,
-) -> DriftResult {
-    if filler_reward > 0 {
-        let position_index = get_position_index(&filler.perp_positions, market.market_index)
-            .or_else(|_| add_new_position(&mut filler.perp_positions, market.market_index))?;
-
-        controller::position::update_quote_asset_amount(
-            &mut filler.perp_positions[position_index],
-            market,
-            filler_reward.cast()?,
-        )?;
-
-        filler_stats
-            .as_mut()
-            .safe_unwrap()?
-            .update_filler_volume(quote_asset_amount, now)?;
-    }
-
-    filler.update_last_active_slot(slot);
-
-    Ok(())
-}
-
-pub fn fulfill_perp_order_with_match(
-    market: &mut PerpMarket,
-    taker: &mut User,
-    taker_stats: &mut UserStats,
-    taker_order_index: usize,
-    taker_key: &Pubkey,
-    maker: &mut User,
-    maker_stats: &mut Option<&mut UserStats>,
-    maker_order_index: usize,
-    maker_key: &Pubkey,
-    filler: &mut Option<&mut User>,
-    filler_stats: &mut Option<&mut UserStats>,
-    filler_key: &Pubkey,
-    referrer: &mut Option<&mut User>,
-    referrer_stats: &mut Option<&mut UserStats>,
-    reserve_price_before: u64,
-    valid_oracle_price: Option<i64>,
-    taker_limit_price: Option<u64>,
-    maker_price: u64,
-    now: i64,
-    slot: u64,
-    fee_structure: &FeeStructure,
-    oracle_map: &mut OracleMap,
-    is_liquidation: bool,
-    rev_share_escrow: &mut Option<&mut RevenueShareEscrowZeroCopyMut>,
-    builder_referral_feature_enabled: bool,
-) -> DriftResult<(u64, u64, u64)> {
-    if !are_orders_same_market_but_different_sides(
-        &maker.orders[maker_order_index],
-        &taker.orders[taker_order_index],
-    ) {
-        return Ok((0_u64, 0_u64, 0_u64));
-    }
-
-    let oracle_price = oracle_map.get_price_data(&market.oracle_id())?.price;
-    let taker_direction: PositionDirection = taker.orders[taker_order_index].direction;
-    let taker_order_has_builder = taker.orders[taker_order_index].is_has_builder();
-    if taker_order_has_builder && rev_share_escrow.is_none() {
-        msg!("Order has builder but no escrow account included, in the future this will fail.");
-    }
-
-    let taker_price = if let Some(taker_limit_price) = taker_limit_price {
-        taker_limit_price
-    } else {
-        let amm_available_liquidity =
-            calculate_amm_available_liquidity(&market.amm, &taker_direction)?;
-        market.amm.get_fallback_price(
-            &taker_direction,
-            amm_available_liquidity,
-            oracle_price,
-            taker.orders[taker_order_index].seconds_til_expiry(now),
-        )?
-    };
-
-    let taker_existing_position = taker
-        .get_perp_position(market.market_index)?
-        .base_asset_amount;
-    let taker_base_asset_amount = taker.orders[taker_order_index]
-        .get_base_asset_amount_unfilled(Some(taker_existing_position))?;
-
-    let maker_direction = maker.orders[maker_order_index].direction;
-    let (maker_existing_position, maker_existing_position_params_for_order_action) = {
-        let maker_position = maker.get_perp_position(market.market_index)?;
-
-        (
-            maker_position.base_asset_amount,
-            maker_position.get_existing_position_params_for_order_action(maker_direction),
-        )
-    };
-    let maker_base_asset_amount = maker.orders[maker_order_index]
-        .get_base_asset_amount_unfilled(Some(maker_existing_position))?;
-
-    let orders_cross = do_orders_cross(maker_direction, maker_price, taker_price);
-
-    if !orders_cross {
-        msg!(
-            "orders dont cross. maker price {} taker price {}",
-            maker_price,
-            taker_price
-        );
-        return Ok((0_u64, 0_u64, 0_u64));
-    }
-
-    let (base_asset_amount, _) = calculate_fill_for_matched_orders(
-        maker_base_asset_amount,
-        maker_price,
-        taker_base_asset_amount,
-        PERP_DECIMALS,
-        maker_direction,
-    )?;
-
-    if base_asset_amount == 0 {
-        return Ok((0_u64, 0_u64, 0_u64));
+    if maker.orders[maker_order_index]
+        .is_filled(maker.perp_positions[maker_position_index].base_asset_amount)?
+    {
+        maker.decrement_open_orders(maker.orders[maker_order_index].has_auction());
+        maker.orders[maker_order_index].status = OrderStatus::Filled;
+        let market_position = &mut maker.perp_positions[maker_position_index];
+        market_position.open_orders -= 1;
     }
 
-    let sanitize_clamp_denominator = market.get_sanitize_clamp_denominator()?;
-    amm::update_mark_twap_from_estimates(
-        &mut market.amm,
-        now,
-        Some(maker_price),
-        Some(taker_direction),
-        sanitize_clamp_denominator,
+    Ok((
+        total_base_asset_amount,
+        total_quote_asset_amount,
+        base_asset_amount_fulfilled_by_maker,
+    ))
+}
+
+struct BatchAuctionOrder {
+    user_key: Pubkey,
+    authority: Pubkey,
+    order_index: usize,
+    order_slot: u64,
+    limit_price: u64,
+    base_asset_amount: u64,
+}
+
+/// Syncs the order book with the users passed in and checks that none of its resting orders are
+/// left out, so the crank can't clear the batch at a price set by a subset of the book
+fn validate_perp_order_book_for_batch_auction(
+    perp_order_book: &mut PerpOrderBook,
+    market_index: u16,
+    users: &UserMap,
+) -> DriftResult {
+    validate!(
+        perp_order_book.market_index == market_index,
+        ErrorCode::InvalidPerpOrderBook,
+        "order book market index ({}) != market index ({})",
+        perp_order_book.market_index,
+        market_index
     )?;
 
-    let mut total_quote_asset_amount = 0_u64;
-    let mut total_base_asset_amount = 0_u64;
+    sync_perp_order_book(perp_order_book, users)?;
 
-    let jit_base_asset_amount = calculate_amm_jit_liquidity(
-        market,
-        taker_direction,
-        maker_price,
-        valid_oracle_price,
-        base_asset_amount,
-        taker_base_asset_amount,
-        maker_base_asset_amount,
-        taker.orders[taker_order_index].has_limit_price(slot)?,
+    validate!(
+        !perp_order_book.has_full_side(),
+        ErrorCode::InvalidPerpOrderBook,
+        "order book for market {} is full and may be missing resting orders",
+        market_index
     )?;
 
-    if jit_base_asset_amount > 0 {
-        let (base_asset_amount_filled_by_amm, quote_asset_amount_filled_by_amm) =
-            fulfill_perp_order_with_amm(
-                taker,
-                taker_stats,
-                taker_order_index,
-                market,
-                oracle_map,
-                reserve_price_before,
-                now,
-                slot,
-                taker_key,
-                filler_key,
-                filler,
-                filler_stats,
-                &mut None,
-                &mut None,
-                &mut None,
-                &mut None,
-                fee_structure,
-                taker_limit_price,
-                Some(jit_base_asset_amount),
-                Some(maker_price), // match the makers price
-                is_liquidation,
-                rev_share_escrow,
-                builder_referral_feature_enabled,
-            )?;
+    for entry in perp_order_book
+        .bids()
+        .iter()
+        .chain(perp_order_book.asks().iter())
+    {
+        validate!(
+            users.0.contains_key(&entry.user),
+            ErrorCode::InvalidPerpOrderBook,
+            "user {} with resting order {} not passed in",
+            entry.user,
+            entry.order_id
+        )?;
+    }
 
-        total_base_asset_amount = base_asset_amount_filled_by_amm;
-        total_quote_asset_amount = quote_asset_amount_filled_by_amm
+    Ok(())
+}
+
+fn sync_perp_order_book(perp_order_book: &mut PerpOrderBook, users: &UserMap) -> DriftResult {
+    for (user_key, user) in users.0.iter() {
+        perp_order_book.update_for_user(user_key, &load!(user)?)?;
     }
 
-    let (taker_existing_position, taker_existing_position_params_for_order_action) = {
-        let taker_position = taker.get_perp_position(market.market_index)?;
+    Ok(())
+}
 
-        (
-            taker_position.base_asset_amount,
-            taker_position.get_existing_position_params_for_order_action(taker_direction),
-        )
-    };
+/// Clears the open orders of a batch auction market at a single price, with the side that has
+/// more size filled pro-rata. Only the orders of the users passed in take part, so every user with
+/// a resting order in the market's order book has to be passed in for the batch to clear.
+/// Orders never match against the same user. Orders from the same authority match unless the
+/// later order's user has a self trade prevention mode, which is applied instead
+pub fn clear_perp_batch_auction(
+    market_index: u16,
+    state: &State,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    users: &UserMap,
+    users_stats: &UserStatsMap,
+    perp_order_book: &mut PerpOrderBook,
+    filler_key: &Pubkey,
+    clock: &Clock,
+) -> DriftResult<(u64, u64)> {
+    let now = clock.unix_timestamp;
+    let slot = clock.slot;
 
-    let taker_base_asset_amount = taker.orders[taker_order_index]
-        .get_base_asset_amount_unfilled(Some(taker_existing_position))?;
+    validate_perp_order_book_for_batch_auction(perp_order_book, market_index, users)?;
 
-    let (base_asset_amount_fulfilled_by_maker, quote_asset_amount) =
-        calculate_fill_for_matched_orders(
-            maker_base_asset_amount,
-            maker_price,
-            taker_base_asset_amount,
-            PERP_DECIMALS,
-            maker_direction,
+    let (oracle_price, oracle_twap_5min, reserve_price_before, tick_size, step_size) = {
+        let market = &mut perp_market_map.get_ref_mut(&market_index)?;
+        validation::perp_market::validate_perp_market(market)?;
+
+        validate!(
+            matches!(
+                market.status,
+                MarketStatus::Active | MarketStatus::ReduceOnly
+            ),
+            ErrorCode::MarketFillOrderPaused,
+            "Market not active",
         )?;
 
-    validate_fill_price(
-        quote_asset_amount,
-        base_asset_amount_fulfilled_by_maker,
-        BASE_PRECISION_U64,
-        taker_direction,
-        taker_price,
-        true,
-    )?;
+        validate!(
+            !market.is_operation_paused(PerpOperation::Fill),
+            ErrorCode::MarketFillOrderPaused,
+            "Market fills paused",
+        )?;
 
-    validate_fill_price(
-        quote_asset_amount,
-        base_asset_amount_fulfilled_by_maker,
-        BASE_PRECISION_U64,
-        maker_direction,
-        maker_price,
-        false,
-    )?;
+        validate!(
+            !market.is_in_settlement(now),
+            ErrorCode::MarketFillOrderPaused,
+            "Market is in settlement mode",
+        )?;
 
-    total_base_asset_amount =
-        total_base_asset_amount.safe_add(base_asset_amount_fulfilled_by_maker)?;
-    total_quote_asset_amount = total_quote_asset_amount.safe_add(quote_asset_amount)?;
+        validate!(
+            market.can_clear_batch_auction(slot)?,
+            ErrorCode::BatchAuctionNotReady,
+            "batch auction duration = {} last batch auction slot = {} slot = {}",
+            market.batch_auction_duration,
+            market.last_batch_auction_slot,
+            slot
+        )?;
 
-    let maker_position_index = get_position_index(
-        &maker.perp_positions,
-        maker.orders[maker_order_index].market_index,
-    )?;
+        let (oracle_price_data, oracle_validity) = oracle_map.get_price_data_and_validity(
+            MarketType::Perp,
+            market.market_index,
+            &market.oracle_id(),
+            market.amm.historical_oracle_data.last_oracle_price_twap,
+            market.get_max_confidence_interval_multiplier()?,
+            0,
+        )?;
 
-    let maker_position_delta = get_position_delta_for_fill(
-        base_asset_amount_fulfilled_by_maker,
-        quote_asset_amount,
-        maker.orders[maker_order_index].direction,
-    )?;
+        validate!(
+            is_oracle_valid_for_action(oracle_validity, Some(DriftAction::FillOrderMatch))?,
+            ErrorCode::InvalidOracle,
+            "oracle invalid for batch auction"
+        )?;
 
-    update_position_and_market(
-        &mut maker.perp_positions[maker_position_index],
-        market,
-        &maker_position_delta,
-    )?;
+        market.last_batch_auction_slot = slot;
 
-    // if maker is none, makes maker and taker authority was the same
-    if let Some(maker_stats) = maker_stats {
-        maker_stats.update_maker_volume_30d(market.fuel_boost_maker, quote_asset_amount, now)?;
-    } else {
-        taker_stats.update_maker_volume_30d(market.fuel_boost_maker, quote_asset_amount, now)?;
+        (
+            oracle_price_data.price,
+            market
+                .amm
+                .historical_oracle_data
+                .last_oracle_price_twap_5min,
+            market.amm.reserve_price()?,
+            market.amm.order_tick_size,
+            market.amm.order_step_size,
+        )
     };
 
-    let taker_position_index = get_position_index(
-        &taker.perp_positions,
-        taker.orders[taker_order_index].market_index,
-    )?;
+    let is_prediction_market = perp_market_map
+        .get_ref(&market_index)?
+        .is_prediction_market();
 
-    let taker_position_delta = get_position_delta_for_fill(
-        base_asset_amount_fulfilled_by_maker,
-        quote_asset_amount,
-        taker.orders[taker_order_index].direction,
-    )?;
+    let mut bids: Vec<BatchAuctionOrder> = vec![];
+    let mut asks: Vec<BatchAuctionOrder> = vec![];
+    for (user_key, user) in users.0.iter() {
+        let mut user = load_mut!(user)?;
 
-    update_position_and_market(
-        &mut taker.perp_positions[taker_position_index],
-        market,
-        &taker_position_delta,
-    )?;
+        if user.is_bankrupt() {
+            continue;
+        }
 
-    taker_stats.update_taker_volume_30d(market.fuel_boost_taker, quote_asset_amount, now)?;
+        if validate_user_not_being_liquidated(
+            &mut user,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            state.liquidation_margin_buffer_ratio,
+        )
+        .is_err()
+        {
+            msg!("user {} is being liquidated", user_key);
+            continue;
+        }
 
-    let reward_referrer = can_reward_user_with_referral_reward(
-        referrer,
-        market.market_index,
-        rev_share_escrow,
-        builder_referral_feature_enabled,
-    );
-    let reward_filler = can_reward_user_with_perp_pnl(filler, market.market_index);
+        settle_funding_payment(
+            &mut user,
+            user_key,
+            &mut perp_market_map.get_ref_mut(&market_index)?,
+            now,
+        )?;
 
-    let (builder_order_idx, referrer_builder_order_idx, builder_order_fee_bps, builder_idx) =
-        get_builder_escrow_info(
-            rev_share_escrow,
-            taker.sub_account_id,
-            taker.orders[taker_order_index].order_id,
-            market.market_index,
-            builder_referral_feature_enabled,
-        );
+        let existing_position = user
+            .get_perp_position(market_index)
+            .map_or(0, |position| position.base_asset_amount);
+
+        for (order_index, order) in user.orders.iter().enumerate() {
+            if order.status != OrderStatus::Open
+                || order.market_type != MarketType::Perp
+                || order.market_index != market_index
+                || (order.must_be_triggered() && !order.triggered())
+                || order.is_pending_activation()
+                || order.is_spread_leg()
+                || should_expire_order(&user, order_index, now)?
+            {
+                continue;
+            }
 
-    let filler_multiplier = if reward_filler {
-        calculate_filler_multiplier_for_matched_orders(maker_price, maker_direction, oracle_price)?
-    } else {
-        0
-    };
+            let limit_price = match order.get_limit_price(
+                Some(oracle_price),
+                None,
+                slot,
+                tick_size,
+                is_prediction_market,
+                None,
+            )? {
+                Some(limit_price) => limit_price,
+                None => continue,
+            };
 
-    let FillFees {
-        user_fee: taker_fee,
-        maker_rebate,
-        fee_to_market,
-        filler_reward,
-        referrer_reward,
-        referee_discount,
-        builder_fee: builder_fee_option,
-        ..
-    } = fees::calculate_fee_for_fulfillment_with_match(
-        taker_stats,
-        maker_stats,
-        quote_asset_amount,
-        fee_structure,
-        taker.orders[taker_order_index].slot,
-        slot,
-        filler_multiplier,
-        reward_referrer,
-        referrer_stats,
-        &MarketType::Perp,
-        market.fee_adjustment,
-        taker.is_high_leverage_mode(MarginRequirementType::Initial),
-        builder_order_fee_bps,
-    )?;
-    let builder_fee = builder_fee_option.unwrap_or(0);
+            let base_asset_amount =
+                order.get_base_asset_amount_unfilled(Some(existing_position))?;
+            if base_asset_amount == 0 {
+                continue;
+            }
+
+            let batch_auction_order = BatchAuctionOrder {
+                user_key: *user_key,
+                authority: user.authority,
+                order_index,
+                order_slot: order.slot,
+                limit_price,
+                base_asset_amount,
+            };
 
-    if builder_fee != 0 {
-        if let (Some(idx), Some(escrow)) = (builder_order_idx, rev_share_escrow.as_deref_mut()) {
-            let order = escrow.get_order_mut(idx)?;
-            order.fees_accrued = order.fees_accrued.safe_add(builder_fee)?;
-        } else {
-            msg!("Order has builder fee but no escrow account found, in the future this tx will fail.");
+            match order.direction {
+                PositionDirection::Long => bids.push(batch_auction_order),
+                PositionDirection::Short => asks.push(batch_auction_order),
+            }
         }
     }
 
-    // Increment the markets house's total fee variables
-    market.amm.total_fee = market.amm.total_fee.safe_add(fee_to_market.cast()?)?;
-    market.amm.total_exchange_fee = market
-        .amm
-        .total_exchange_fee
-        .safe_add(fee_to_market.cast()?)?;
-    market.amm.total_fee_minus_distributions = market
-        .amm
-        .total_fee_minus_distributions
-        .safe_add(fee_to_market.cast()?)?;
-    market.amm.net_revenue_since_last_funding = market
-        .amm
-        .net_revenue_since_last_funding
-        .safe_add(fee_to_market)?;
+    let (clearing_price, base_asset_amount_to_clear) = match calculate_batch_auction_clearing_price(
+        &bids
+            .iter()
+            .map(|order| (order.limit_price, order.base_asset_amount))
+            .collect::<Vec<_>>(),
+        &asks
+            .iter()
+            .map(|order| (order.limit_price, order.base_asset_amount))
+            .collect::<Vec<_>>(),
+        oracle_price.unsigned_abs(),
+    )? {
+        Some(clearing_price_and_base_asset_amount) => clearing_price_and_base_asset_amount,
+        None => {
+            msg!(
+                "batch auction for market {} has no crossing orders",
+                market_index
+            );
+            return Ok((0, 0));
+        }
+    };
 
-    controller::position::update_quote_asset_and_break_even_amount(
-        &mut taker.perp_positions[taker_position_index],
-        market,
-        -(taker_fee.safe_add(builder_fee)?).cast()?,
+    validate_fill_price_within_price_bands(
+        clearing_price,
+        oracle_price,
+        oracle_twap_5min,
+        perp_market_map.get_ref(&market_index)?.margin_ratio_initial,
+        state
+            .oracle_guard_rails
+            .max_oracle_twap_5min_percent_divergence(),
+        is_prediction_market,
+        None,
     )?;
 
-    taker_stats.increment_total_fees(taker_fee)?;
-    taker_stats.increment_total_referee_discount(referee_discount)?;
+    // the earliest orders get what's left from rounding the pro-rata allocations
+    bids.retain(|order| order.limit_price >= clearing_price);
+    bids.sort_by_key(|order| order.order_slot);
+    asks.retain(|order| order.limit_price <= clearing_price);
+    asks.sort_by_key(|order| order.order_slot);
 
-    controller::position::update_quote_asset_and_break_even_amount(
-        &mut maker.perp_positions[maker_position_index],
-        market,
-        maker_rebate.cast()?,
+    let mut bid_allocations = calculate_pro_rata_allocations(
+        &bids
+            .iter()
+            .map(|order| order.base_asset_amount)
+            .collect::<Vec<_>>(),
+        base_asset_amount_to_clear,
+        step_size,
+    )?;
+    let mut ask_allocations = calculate_pro_rata_allocations(
+        &asks
+            .iter()
+            .map(|order| order.base_asset_amount)
+            .collect::<Vec<_>>(),
+        base_asset_amount_to_clear,
+        step_size,
     )?;
 
-    if let Some(maker_stats) = maker_stats {
-        maker_stats.increment_total_rebate(maker_rebate)?;
-    } else {
-        taker_stats.increment_total_rebate(maker_rebate)?;
-    }
+    let mut base_asset_amount = 0_u64;
+    let mut quote_asset_amount = 0_u64;
+    let mut fills: BTreeMap<Pubkey, i64> = BTreeMap::new();
+    let mut filled_orders: Vec<(Pubkey, usize)> = vec![];
+    let mut bid_index = 0_usize;
+    while bid_index < bids.len() {
+        if bid_allocations[bid_index] == 0 {
+            bid_index += 1;
+            continue;
+        }
 
-    if let Some(filler) = filler {
-        if filler_reward > 0 {
-            let filler_position_index =
-                get_position_index(&filler.perp_positions, market.market_index).or_else(|_| {
-                    add_new_position(&mut filler.perp_positions, market.market_index)
-                })?;
+        let bid = &bids[bid_index];
 
-            controller::position::update_quote_asset_amount(
-                &mut filler.perp_positions[filler_position_index],
-                market,
-                filler_reward.cast()?,
+        // self matches are skipped without using up either allocation, so the bid is matched with
+        // the first ask left that isn't from the same user
+        let ask_index = match (0..asks.len()).find(|ask_index| {
+            ask_allocations[*ask_index] > 0 && asks[*ask_index].user_key != bid.user_key
+        }) {
+            Some(ask_index) => ask_index,
+            None => {
+                bid_index += 1;
+                continue;
+            }
+        };
+        let ask = &asks[ask_index];
+
+        // the later order pays the taker fee
+        let bid_is_taker = bid.order_slot >= ask.order_slot;
+        let (taker_order, maker_order) = if bid_is_taker { (bid, ask) } else { (ask, bid) };
+
+        let mut taker = users.get_ref_mut(&taker_order.user_key)?;
+        let mut maker = users.get_ref_mut(&maker_order.user_key)?;
+
+        let self_trade_prevention_mode = taker.self_trade_prevention_mode;
+        if bid.authority == ask.authority
+            && self_trade_prevention_mode != SelfTradePreventionMode::None
+        {
+            let (cancel_maker, cancel_taker) = get_self_trade_prevention_cancels(
+                self_trade_prevention_mode,
+                &mut taker,
+                taker_order.order_index,
+                &mut maker,
+                maker_order.order_index,
             )?;
 
-            filler_stats
-                .as_mut()
-                .safe_unwrap()?
-                .update_filler_volume(quote_asset_amount, now)?;
+            let (taker_allocation, maker_allocation) = if bid_is_taker {
+                (
+                    &mut bid_allocations[bid_index],
+                    &mut ask_allocations[ask_index],
+                )
+            } else {
+                (
+                    &mut ask_allocations[ask_index],
+                    &mut bid_allocations[bid_index],
+                )
+            };
+
+            for (user, order, cancel, allocation) in [
+                (&mut maker, maker_order, cancel_maker, maker_allocation),
+                (&mut taker, taker_order, cancel_taker, taker_allocation),
+            ] {
+                if cancel {
+                    cancel_order(
+                        order.order_index,
+                        user,
+                        &order.user_key,
+                        perp_market_map,
+                        spot_market_map,
+                        oracle_map,
+                        now,
+                        slot,
+                        OrderActionExplanation::SelfTradePrevention,
+                        Some(filler_key),
+                        0,
+                        false,
+                    )?;
+                    *allocation = 0;
+                } else {
+                    *allocation = (*allocation).min(get_order_base_asset_amount_unfilled(
+                        user,
+                        order.order_index,
+                    )?);
+                }
+            }
+
+            continue;
         }
-        filler.update_last_active_slot(slot);
-    }
 
-    if let (Some(idx), Some(escrow)) = (referrer_builder_order_idx, rev_share_escrow.as_deref_mut())
-    {
-        let order = escrow.get_order_mut(idx)?;
-        order.fees_accrued = order.fees_accrued.safe_add(referrer_reward)?;
-    } else if let (Some(referrer), Some(referrer_stats)) =
-        (referrer.as_mut(), referrer_stats.as_mut())
-    {
-        if let Ok(referrer_position) = referrer.force_get_perp_position_mut(market.market_index) {
-            if referrer_reward > 0 {
-                update_quote_asset_amount(referrer_position, market, referrer_reward.cast()?)?;
+        let fill_base_asset_amount = bid_allocations[bid_index].min(ask_allocations[ask_index]);
+        bid_allocations[bid_index] = bid_allocations[bid_index].safe_sub(fill_base_asset_amount)?;
+        ask_allocations[ask_index] = ask_allocations[ask_index].safe_sub(fill_base_asset_amount)?;
+
+        let mut taker_stats = users_stats.get_ref_mut(&taker.authority)?;
+        let mut maker_stats = if maker.authority == taker.authority {
+            None
+        } else {
+            Some(users_stats.get_ref_mut(&maker.authority)?)
+        };
+
+        let mut market = perp_market_map.get_ref_mut(&market_index)?;
+        let taker_direction = taker.orders[taker_order.order_index].direction;
+
+        let (fill_base_asset_amount, fill_quote_asset_amount, _) = fulfill_perp_order_with_match(
+            market.deref_mut(),
+            &mut taker,
+            &mut taker_stats,
+            taker_order.order_index,
+            &taker_order.user_key,
+            &mut maker,
+            &mut maker_stats.as_deref_mut(),
+            maker_order.order_index,
+            &maker_order.user_key,
+            &mut None,
+            &mut None,
+            filler_key,
+            &mut None,
+            &mut None,
+            reserve_price_before,
+            Some(oracle_price),
+            Some(taker_order.limit_price),
+            clearing_price,
+            Some(fill_base_asset_amount),
+            now,
+            slot,
+            &state.perp_fee_structure,
+            oracle_map,
+            false,
+            &mut None,
+            false,
+        )?;
+
+        if fill_base_asset_amount == 0 {
+            continue;
+        }
+
+        market
+            .amm
+            .update_volume_24h(fill_quote_asset_amount, taker_direction, now)?;
+
+        base_asset_amount = base_asset_amount.safe_add(fill_base_asset_amount)?;
+        quote_asset_amount = quote_asset_amount.safe_add(fill_quote_asset_amount)?;
+
+        let bid_fill = fills.entry(bid.user_key).or_insert(0);
+        *bid_fill = bid_fill.safe_add(fill_base_asset_amount.cast()?)?;
+        let ask_fill = fills.entry(ask.user_key).or_insert(0);
+        *ask_fill = ask_fill.safe_sub(fill_base_asset_amount.cast()?)?;
+
+        for order in [bid, ask] {
+            if !filled_orders.contains(&(order.user_key, order.order_index)) {
+                filled_orders.push((order.user_key, order.order_index));
             }
-            referrer_stats.increment_total_referrer_reward(referrer_reward, now)?;
         }
     }
 
-    let is_filled = update_order_after_fill(
-        &mut taker.orders[taker_order_index],
-        base_asset_amount_fulfilled_by_maker,
-        quote_asset_amount,
-    )?;
+    if base_asset_amount == 0 {
+        sync_perp_order_book(perp_order_book, users)?;
+        return Ok((0, 0));
+    }
 
-    if is_filled {
-        if let (Some(idx), Some(escrow)) = (builder_order_idx, rev_share_escrow.as_deref_mut()) {
-            escrow
-                .get_order_mut(idx)?
-                .add_bit_flag(RevenueShareOrderBitFlag::Completed);
+    perp_market_map.get_ref_mut(&market_index)?.last_fill_price = clearing_price;
+
+    for (user_key, order_index) in filled_orders {
+        let mut user = users.get_ref_mut(&user_key)?;
+        if user.orders[order_index].has_oco_group() {
+            update_oco_group_after_fill(
+                order_index,
+                &mut user,
+                &user_key,
+                Some(filler_key),
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                now,
+                slot,
+            )?;
         }
     }
 
-    decrease_open_bids_and_asks(
-        &mut taker.perp_positions[taker_position_index],
-        &taker.orders[taker_order_index].direction,
-        base_asset_amount_fulfilled_by_maker,
-        taker.orders[taker_order_index].update_open_bids_and_asks(),
-    )?;
+    for (user_key, base_asset_amount_filled) in fills {
+        let mut user = users.get_ref_mut(&user_key)?;
 
-    update_order_after_fill(
-        &mut maker.orders[maker_order_index],
-        base_asset_amount_fulfilled_by_maker,
-        quote_asset_amount,
-    )?;
+        // fills are checked like maker fills since every order rested for the batch
+        let (margin_type, _) =
+            select_margin_type_for_perp_maker(&user, base_asset_amount_filled, market_index)?;
 
-    decrease_open_bids_and_asks(
-        &mut maker.perp_positions[maker_position_index],
-        &maker.orders[maker_order_index].direction,
-        base_asset_amount_fulfilled_by_maker,
-        maker.orders[maker_order_index].update_open_bids_and_asks(),
-    )?;
+        let margin_calculation =
+            calculate_margin_requirement_and_total_collateral_and_liability_info(
+                &user,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                MarginContext::standard(margin_type),
+            )?;
 
-    let fill_record_id = get_then_update_id!(market, next_fill_record_id);
-    let order_action_explanation = if is_liquidation {
-        OrderActionExplanation::Liquidation
-    } else if maker.orders[maker_order_index].is_jit_maker() {
-        OrderActionExplanation::OrderFilledWithMatchJit
-    } else {
-        OrderActionExplanation::OrderFilledWithMatch
-    };
-    let mut order_action_bit_flags = 0;
-    order_action_bit_flags = set_order_bit_flag(
-        order_action_bit_flags,
-        taker.orders[taker_order_index].is_signed_msg(),
-        OrderBitFlag::SignedMessage,
-    );
-    let (taker_existing_quote_entry_amount, taker_existing_base_asset_amount) =
-        calculate_existing_position_fields_for_order_action(
-            base_asset_amount_fulfilled_by_maker,
-            taker_existing_position_params_for_order_action,
-        )?;
-    let (maker_existing_quote_entry_amount, maker_existing_base_asset_amount) =
-        calculate_existing_position_fields_for_order_action(
-            base_asset_amount_fulfilled_by_maker,
-            maker_existing_position_params_for_order_action,
-        )?;
-    let order_action_record = get_order_action_record(
-        now,
-        OrderAction::Fill,
-        order_action_explanation,
-        market.market_index,
-        Some(*filler_key),
-        Some(fill_record_id),
-        Some(filler_reward),
-        Some(base_asset_amount_fulfilled_by_maker),
-        Some(quote_asset_amount),
-        Some(taker_fee.safe_add(builder_fee)?),
-        Some(maker_rebate),
-        Some(referrer_reward),
-        None,
-        None,
-        Some(*taker_key),
-        Some(taker.orders[taker_order_index]),
-        Some(*maker_key),
-        Some(maker.orders[maker_order_index]),
-        oracle_map.get_price_data(&market.oracle_id())?.price,
-        order_action_bit_flags,
-        taker_existing_quote_entry_amount,
-        taker_existing_base_asset_amount,
-        maker_existing_quote_entry_amount,
-        maker_existing_base_asset_amount,
-        None,
-        builder_idx,
-        builder_fee_option,
-    )?;
-    emit_stack::<_, { OrderActionRecord::SIZE }>(order_action_record)?;
+        if !margin_calculation.meets_margin_requirement() {
+            msg!(
+                "user ({}) breached fill requirements (margin requirement {}) (total_collateral {})",
+                user_key,
+                margin_calculation.margin_requirement,
+                margin_calculation.total_collateral
+            );
+            return Err(ErrorCode::InsufficientCollateral);
+        }
 
-    if taker.orders[taker_order_index].get_base_asset_amount_unfilled(None)? == 0 {
-        taker.decrement_open_orders(taker.orders[taker_order_index].has_auction());
-        taker.orders[taker_order_index].status = OrderStatus::Filled;
-        let market_position = &mut taker.perp_positions[taker_position_index];
-        market_position.open_orders -= 1;
+        user.update_last_active_slot(slot);
+    }
+
+    {
+        let market = perp_market_map.get_ref(&market_index)?;
+
+        let open_interest = market.get_open_interest();
+        let max_open_interest = market.amm.max_open_interest;
+
+        validate!(
+            max_open_interest == 0 || max_open_interest > open_interest,
+            ErrorCode::MaxOpenInterest,
+            "open interest ({}) > max open interest ({})",
+            open_interest,
+            max_open_interest
+        )?;
     }
 
-    if maker.orders[maker_order_index].get_base_asset_amount_unfilled(None)? == 0 {
-        maker.decrement_open_orders(maker.orders[maker_order_index].has_auction());
-        maker.orders[maker_order_index].status = OrderStatus::Filled;
-        let market_position = &mut maker.perp_positions[maker_position_index];
-        market_position.open_orders -= 1;
-    }
+    sync_perp_order_book(perp_order_book, users)?;
 
-    Ok((
-        total_base_asset_amount,
-        total_quote_asset_amount,
-        base_asset_amount_fulfilled_by_maker,
-    ))
+    Ok((base_asset_amount, quote_asset_amount))
 }
 
 pub fn update_order_after_fill(
@@ -3117,6 +5493,12 @@ pub fn trigger_order(
         "Order must be a perp order"
     )?;
 
+    validate!(
+        !user.is_bracket_order_waiting_for_entry(order_index),
+        ErrorCode::OrderNotTriggerable,
+        "Bracket order is waiting for its entry to fill"
+    )?;
+
     validate_user_not_being_liquidated(
         user,
         perp_market_map,
@@ -3127,6 +5509,14 @@ pub fn trigger_order(
 
     validate!(!user.is_bankrupt(), ErrorCode::UserBankrupt)?;
 
+    let account_trigger_condition_satisfied = user_satisfies_account_trigger_condition(
+        user,
+        order_index,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+    )?;
+
     let mut perp_market = perp_market_map.get_ref_mut(&market_index)?;
     let (oracle_price_data, oracle_validity) = oracle_map.get_price_data_and_validity(
         MarketType::Perp,
@@ -3167,7 +5557,27 @@ pub fn trigger_order(
 
     let trigger_price =
         perp_market.get_trigger_price(oracle_price, now, state.use_median_trigger_price())?;
-    let can_trigger = order_satisfies_trigger_condition(&user.orders[order_index], trigger_price)?;
+    let can_trigger = match account_trigger_condition_satisfied {
+        Some(account_trigger_condition_satisfied) => account_trigger_condition_satisfied,
+        None => order_satisfies_trigger_condition(&user.orders[order_index], trigger_price)?,
+    };
+
+    if !can_trigger && user.orders[order_index].is_trailing_stop() {
+        if let Some(new_trigger_price) = calculate_trailing_stop_trigger_price(
+            &user.orders[order_index],
+            trigger_price,
+            perp_market.amm.order_tick_size,
+        )? {
+            msg!(
+                "Updating trailing stop trigger price from {} to {}",
+                user.orders[order_index].trigger_price,
+                new_trigger_price
+            );
+            user.orders[order_index].trigger_price = new_trigger_price;
+        }
+
+        return Ok(());
+    }
 
     validate!(
         can_trigger,
@@ -3208,25 +5618,292 @@ pub fn trigger_order(
         )?;
     }
 
-    let is_filler_taker = user_key == filler_key;
-    let mut filler = if !is_filler_taker {
-        Some(load_mut!(filler)?)
-    } else {
-        None
+    let is_filler_taker = user_key == filler_key;
+    let mut filler = if !is_filler_taker {
+        Some(load_mut!(filler)?)
+    } else {
+        None
+    };
+
+    let filler_reward = pay_keeper_flat_reward_for_perps(
+        user,
+        filler.as_deref_mut(),
+        &mut perp_market,
+        state.perp_fee_structure.flat_filler_fee,
+        slot,
+    )?;
+
+    let order_action_record = get_order_action_record(
+        now,
+        OrderAction::Trigger,
+        OrderActionExplanation::None,
+        market_index,
+        Some(filler_key),
+        None,
+        Some(filler_reward),
+        None,
+        None,
+        Some(filler_reward),
+        None,
+        None,
+        None,
+        None,
+        Some(user_key),
+        Some(user.orders[order_index]),
+        None,
+        None,
+        oracle_price,
+        0,
+        None,
+        None,
+        None,
+        None,
+        Some(trigger_price),
+        None,
+        None,
+    )?;
+    emit!(order_action_record);
+
+    let (_, worst_case_liability_value_after) = user
+        .get_perp_position(market_index)?
+        .worst_case_liability_value(oracle_price, perp_market.contract_type)?;
+
+    let is_risk_increasing = worst_case_liability_value_after > worst_case_liability_value_before;
+
+    drop(perp_market);
+
+    // If order increases risk and user is below initial margin, cancel it
+    if is_risk_increasing && !user.orders[order_index].reduce_only {
+        let meets_initial_margin_requirement =
+            meets_initial_margin_requirement(user, perp_market_map, spot_market_map, oracle_map)?;
+
+        if !meets_initial_margin_requirement {
+            cancel_order(
+                order_index,
+                user,
+                &user_key,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                now,
+                slot,
+                OrderActionExplanation::InsufficientFreeCollateral,
+                Some(&filler_key),
+                0,
+                false,
+            )?;
+        }
+    }
+
+    if user.orders[order_index].status == OrderStatus::Open {
+        cancel_oco_group_orders(
+            order_index,
+            user,
+            &user_key,
+            Some(&filler_key),
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            now,
+            slot,
+        )?;
+    }
+
+    user.update_last_active_slot(slot);
+
+    Ok(())
+}
+
+/// Returns None if the order triggers on the oracle price. Account trigger conditions are
+/// checked against the user's maintenance margin requirement
+fn user_satisfies_account_trigger_condition(
+    user: &User,
+    order_index: usize,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+) -> DriftResult<Option<bool>> {
+    if !user.orders[order_index].has_account_trigger_condition() {
+        return Ok(None);
+    }
+
+    let margin_calculation = calculate_margin_requirement_and_total_collateral_and_liability_info(
+        user,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        MarginContext::standard(MarginRequirementType::Maintenance),
+    )?;
+
+    msg!(
+        "total collateral {} margin requirement {}",
+        margin_calculation.total_collateral,
+        margin_calculation.margin_requirement
+    );
+
+    order_satisfies_account_trigger_condition(&user.orders[order_index], &margin_calculation)
+        .map(Some)
+}
+
+fn update_trigger_order_params(
+    order: &mut Order,
+    oracle_price_data: &OraclePriceData,
+    slot: u64,
+    min_auction_duration: u8,
+    perp_market: Option<&PerpMarket>,
+) -> DriftResult {
+    order.trigger_condition = match order.trigger_condition {
+        OrderTriggerCondition::Above => OrderTriggerCondition::TriggeredAbove,
+        OrderTriggerCondition::Below => OrderTriggerCondition::TriggeredBelow,
+        OrderTriggerCondition::MarginRatioBelow => OrderTriggerCondition::TriggeredMarginRatioBelow,
+        OrderTriggerCondition::TotalCollateralBelow => {
+            OrderTriggerCondition::TriggeredTotalCollateralBelow
+        }
+        _ => {
+            return Err(print_error!(ErrorCode::InvalidTriggerOrderCondition)());
+        }
+    };
+
+    if slot.saturating_sub(order.slot) > 150 && order.reduce_only {
+        order.add_bit_flag(OrderBitFlag::SafeTriggerOrder);
+    }
+
+    order.slot = slot;
+
+    let (auction_duration, auction_start_price, auction_end_price) =
+        calculate_auction_params_for_trigger_order(
+            order,
+            oracle_price_data,
+            min_auction_duration,
+            perp_market,
+        )?;
+
+    msg!(
+        "new auction duration {} start price {} end price {}",
+        auction_duration,
+        auction_start_price,
+        auction_end_price
+    );
+
+    order.auction_duration = auction_duration;
+    order.auction_start_price = auction_start_price;
+    order.auction_end_price = auction_end_price;
+
+    // trail is no longer needed once triggered and must not be read as a limit price offset
+    if order.is_trailing_stop() {
+        order.oracle_price_offset = 0;
+    }
+
+    if matches!(order.order_type, OrderType::TriggerMarket) {
+        order.add_bit_flag(OrderBitFlag::OracleTriggerMarket);
+    }
+
+    Ok(())
+}
+
+pub fn activate_order(
+    order_id: u32,
+    state: &State,
+    user: &AccountLoader<User>,
+    spot_market_map: &SpotMarketMap,
+    perp_market_map: &PerpMarketMap,
+    oracle_map: &mut OracleMap,
+    filler: &AccountLoader<User>,
+    clock: &Clock,
+) -> DriftResult {
+    let now = clock.unix_timestamp;
+    let slot = clock.slot;
+
+    let filler_key = filler.key();
+    let user_key = user.key();
+    let user = &mut load_mut!(user)?;
+
+    let order_index = user
+        .orders
+        .iter()
+        .position(|order| order.order_id == order_id && order.status == OrderStatus::Open)
+        .ok_or_else(print_error!(ErrorCode::OrderDoesNotExist))?;
+
+    let (market_index, market_type) =
+        get_struct_values!(user.orders[order_index], market_index, market_type);
+
+    validate!(
+        user.orders[order_index].is_pending_activation(),
+        ErrorCode::OrderNotPendingActivation,
+        "Order is not pending activation"
+    )?;
+
+    validate!(
+        user.orders[order_index].can_be_activated(now, slot)?,
+        ErrorCode::OrderActivationNotReached,
+        "Order start not reached. trigger_condition: {:?} start: {} now: {} slot: {}",
+        &user.orders[order_index].trigger_condition,
+        user.orders[order_index].trigger_price,
+        now,
+        slot
+    )?;
+
+    validate!(!user.is_bankrupt(), ErrorCode::UserBankrupt)?;
+
+    let is_filler_taker = user_key == filler_key;
+    let mut filler = if !is_filler_taker {
+        Some(load_mut!(filler)?)
+    } else {
+        None
+    };
+
+    let (oracle_price, filler_reward) = match market_type {
+        MarketType::Perp => {
+            let mut perp_market = perp_market_map.get_ref_mut(&market_index)?;
+            let oracle_price_data = oracle_map.get_price_data(&perp_market.oracle_id())?;
+
+            update_activated_order_params(
+                &mut user.orders[order_index],
+                oracle_price_data,
+                now,
+                slot,
+                Some(&perp_market),
+            )?;
+
+            let filler_reward = pay_keeper_flat_reward_for_perps(
+                user,
+                filler.as_deref_mut(),
+                &mut perp_market,
+                state.perp_fee_structure.flat_filler_fee,
+                slot,
+            )?;
+
+            (oracle_price_data.price, filler_reward)
+        }
+        MarketType::Spot => {
+            let spot_market = spot_market_map.get_ref(&market_index)?;
+            let oracle_price_data = oracle_map.get_price_data(&spot_market.oracle_id())?;
+
+            update_activated_order_params(
+                &mut user.orders[order_index],
+                oracle_price_data,
+                now,
+                slot,
+                None,
+            )?;
+
+            let mut quote_market = spot_market_map.get_quote_spot_market_mut()?;
+            let filler_reward = pay_keeper_flat_reward_for_spot(
+                user,
+                filler.as_deref_mut(),
+                &mut quote_market,
+                state.spot_fee_structure.flat_filler_fee,
+                slot,
+            )?;
+
+            (oracle_price_data.price, filler_reward)
+        }
     };
 
-    let filler_reward = pay_keeper_flat_reward_for_perps(
-        user,
-        filler.as_deref_mut(),
-        &mut perp_market,
-        state.perp_fee_structure.flat_filler_fee,
-        slot,
-    )?;
-
     let order_action_record = get_order_action_record(
         now,
         OrderAction::Trigger,
-        OrderActionExplanation::None,
+        OrderActionExplanation::OrderActivated,
         market_index,
         Some(filler_key),
         None,
@@ -3248,90 +5925,60 @@ pub fn trigger_order(
         None,
         None,
         None,
-        Some(trigger_price),
+        None,
         None,
         None,
     )?;
     emit!(order_action_record);
 
-    let (_, worst_case_liability_value_after) = user
-        .get_perp_position(market_index)?
-        .worst_case_liability_value(oracle_price, perp_market.contract_type)?;
-
-    let is_risk_increasing = worst_case_liability_value_after > worst_case_liability_value_before;
-
-    drop(perp_market);
-
-    // If order increases risk and user is below initial margin, cancel it
-    if is_risk_increasing && !user.orders[order_index].reduce_only {
-        let meets_initial_margin_requirement =
-            meets_initial_margin_requirement(user, perp_market_map, spot_market_map, oracle_map)?;
-
-        if !meets_initial_margin_requirement {
-            cancel_order(
-                order_index,
-                user,
-                &user_key,
-                perp_market_map,
-                spot_market_map,
-                oracle_map,
-                now,
-                slot,
-                OrderActionExplanation::InsufficientFreeCollateral,
-                Some(&filler_key),
-                0,
-                false,
-            )?;
-        }
-    }
-
     user.update_last_active_slot(slot);
 
     Ok(())
 }
 
-fn update_trigger_order_params(
+/// Clears the start condition and re-stamps the order as if it was placed in the activation slot
+fn update_activated_order_params(
     order: &mut Order,
     oracle_price_data: &OraclePriceData,
+    now: i64,
     slot: u64,
-    min_auction_duration: u8,
     perp_market: Option<&PerpMarket>,
 ) -> DriftResult {
-    order.trigger_condition = match order.trigger_condition {
-        OrderTriggerCondition::Above => OrderTriggerCondition::TriggeredAbove,
-        OrderTriggerCondition::Below => OrderTriggerCondition::TriggeredBelow,
-        _ => {
-            return Err(print_error!(ErrorCode::InvalidTriggerOrderCondition)());
-        }
-    };
-
-    if slot.saturating_sub(order.slot) > 150 && order.reduce_only {
-        order.add_bit_flag(OrderBitFlag::SafeTriggerOrder);
-    }
-
+    order.trigger_condition = OrderTriggerCondition::Above;
+    order.trigger_price = 0;
     order.slot = slot;
 
-    let (auction_duration, auction_start_price, auction_end_price) =
-        calculate_auction_params_for_trigger_order(
-            order,
-            oracle_price_data,
-            min_auction_duration,
-            perp_market,
-        )?;
+    // market and limit auction prices are absolute and were set against the oracle at placement.
+    // oracle auction prices are offsets so they only need the new slot
+    if order.has_auction() && matches!(order.order_type, OrderType::Market | OrderType::Limit) {
+        let (auction_duration, auction_start_price, auction_end_price) =
+            calculate_auction_params_for_trigger_order(
+                order,
+                oracle_price_data,
+                order.auction_duration,
+                perp_market,
+            )?;
 
-    msg!(
-        "new auction duration {} start price {} end price {}",
-        auction_duration,
-        auction_start_price,
-        auction_end_price
-    );
+        msg!(
+            "new auction duration {} start price {} end price {}",
+            auction_duration,
+            auction_start_price,
+            auction_end_price
+        );
 
-    order.auction_duration = auction_duration;
-    order.auction_start_price = auction_start_price;
-    order.auction_end_price = auction_end_price;
+        order.auction_duration = auction_duration;
+        order.auction_start_price = auction_start_price;
+        order.auction_end_price = auction_end_price;
+    }
 
-    if matches!(order.order_type, OrderType::TriggerMarket) {
-        order.add_bit_flag(OrderBitFlag::OracleTriggerMarket);
+    if order.max_ts == 0 && matches!(order.order_type, OrderType::Market | OrderType::Oracle) {
+        order.max_ts = now.safe_add(
+            30_i64.max(
+                (order.auction_duration.safe_div(2)?)
+                    .cast::<i64>()?
+                    .safe_add(10_i64)?,
+            ),
+        )?;
     }
 
     Ok(())
@@ -3345,6 +5992,7 @@ pub fn force_cancel_orders(
     oracle_map: &mut OracleMap,
     filler: &AccountLoader<User>,
     clock: &Clock,
+    orders_extension: &mut Option<UserOrdersExtensionZeroCopyMut>,
 ) -> DriftResult {
     let now = clock.unix_timestamp;
     let slot = clock.slot;
@@ -3361,6 +6009,8 @@ pub fn force_cancel_orders(
 
     validate!(!user.is_bankrupt(), ErrorCode::UserBankrupt)?;
 
+    validate_orders_extension_passed(user, orders_extension)?;
+
     let margin_calc = calculate_margin_requirement_and_total_collateral_and_liability_info(
         user,
         perp_market_map,
@@ -3378,11 +6028,7 @@ pub fn force_cancel_orders(
 
     let mut total_fee = 0_u64;
 
-    for order_index in 0..user.orders.len() {
-        if user.orders[order_index].status != OrderStatus::Open {
-            continue;
-        }
-
+    let mut force_cancel_order = |user: &mut User, order_index: usize| -> DriftResult {
         let market_index = user.orders[order_index].market_index;
         let market_type = user.orders[order_index].market_type;
 
@@ -3399,7 +6045,7 @@ pub fn force_cancel_orders(
                     token_amount,
                 )?;
                 if is_position_reducing {
-                    continue;
+                    return Ok(());
                 }
 
                 state.spot_fee_structure.flat_filler_fee
@@ -3413,7 +6059,7 @@ pub fn force_cancel_orders(
                     base_asset_amount,
                 )?;
                 if is_position_reducing {
-                    continue;
+                    return Ok(());
                 }
 
                 state.perp_fee_structure.flat_filler_fee
@@ -3435,7 +6081,19 @@ pub fn force_cancel_orders(
             Some(&filler_key),
             fee,
             false,
-        )?;
+        )
+    };
+
+    for order_index in 0..user.orders.len() {
+        if user.orders[order_index].status != OrderStatus::Open {
+            continue;
+        }
+
+        force_cancel_order(user, order_index)?;
+    }
+
+    if let Some(orders_extension) = orders_extension.as_mut() {
+        for_each_open_extension_order(user, orders_extension, |_| true, &mut force_cancel_order)?;
     }
 
     pay_keeper_flat_reward_for_spot(
@@ -3451,6 +6109,113 @@ pub fn force_cancel_orders(
     Ok(())
 }
 
+pub fn cancel_orders_after_heartbeat_expired(
+    state: &State,
+    user_account_loader: &AccountLoader<User>,
+    spot_market_map: &SpotMarketMap,
+    perp_market_map: &PerpMarketMap,
+    oracle_map: &mut OracleMap,
+    filler: &AccountLoader<User>,
+    clock: &Clock,
+    orders_extension: &mut Option<UserOrdersExtensionZeroCopyMut>,
+) -> DriftResult {
+    let now = clock.unix_timestamp;
+    let slot = clock.slot;
+
+    let filler_key = filler.key();
+    let user_key = user_account_loader.key();
+    let user = &mut load_mut!(user_account_loader)?;
+    let filler = &mut load_mut!(filler)?;
+
+    validate!(
+        !user.is_being_liquidated(),
+        ErrorCode::UserIsBeingLiquidated
+    )?;
+
+    validate!(!user.is_bankrupt(), ErrorCode::UserBankrupt)?;
+
+    validate!(
+        user.is_heartbeat_expired(now)?,
+        ErrorCode::HeartbeatNotExpired,
+        "last heartbeat {} timeout {} now {}",
+        user.last_heartbeat_ts,
+        user.heartbeat_timeout,
+        now
+    )?;
+
+    validate_orders_extension_passed(user, orders_extension)?;
+
+    // the filler is paid for the orders that are actually canceled
+    let open_orders_fee_before = get_open_orders_flat_filler_fee(state, user, orders_extension)?;
+
+    cancel_orders_with_extension(
+        user,
+        &user_key,
+        Some(&filler_key),
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        now,
+        slot,
+        OrderActionExplanation::HeartbeatExpired,
+        None,
+        None,
+        None,
+        orders_extension,
+    )?;
+
+    let total_fee = open_orders_fee_before.safe_sub(get_open_orders_flat_filler_fee(
+        state,
+        user,
+        orders_extension,
+    )?)?;
+
+    pay_keeper_flat_reward_for_spot(
+        user,
+        Some(filler),
+        spot_market_map.get_quote_spot_market_mut()?.deref_mut(),
+        total_fee,
+        slot,
+    )?;
+
+    Ok(())
+}
+
+/// Sum of the flat filler fees for the user's open orders, including those parked in its orders
+/// extension account
+fn get_open_orders_flat_filler_fee(
+    state: &State,
+    user: &User,
+    orders_extension: &Option<UserOrdersExtensionZeroCopyMut>,
+) -> DriftResult<u64> {
+    let get_flat_filler_fee = |order: &Order| match order.market_type {
+        MarketType::Spot => state.spot_fee_structure.flat_filler_fee,
+        MarketType::Perp => state.perp_fee_structure.flat_filler_fee,
+    };
+
+    let mut total_fee = 0_u64;
+    for order in user.orders.iter() {
+        if order.status != OrderStatus::Open {
+            continue;
+        }
+
+        total_fee = total_fee.safe_add(get_flat_filler_fee(order))?;
+    }
+
+    if let Some(orders_extension) = orders_extension.as_ref() {
+        for extension_order_index in 0..orders_extension.len() {
+            let order = orders_extension.get_order(extension_order_index)?;
+            if order.status != OrderStatus::Open {
+                continue;
+            }
+
+            total_fee = total_fee.safe_add(get_flat_filler_fee(order))?;
+        }
+    }
+
+    Ok(total_fee)
+}
+
 pub fn can_reward_user_with_perp_pnl(user: &mut Option<&mut User>, market_index: u16) -> bool {
     match user.as_mut() {
         Some(user) => user.force_get_perp_position_mut(market_index).is_ok(),
@@ -3591,8 +6356,27 @@ pub fn place_spot_order(
         )?;
     }
 
+    validate!(
+        !params.is_trailing_stop(),
+        ErrorCode::InvalidOrderTrigger,
+        "trailing stop orders are only supported for perp markets"
+    )?;
+
+    validate!(
+        options.oco_group_id == 0,
+        ErrorCode::InvalidOrder,
+        "oco orders are only supported for perp markets"
+    )?;
+
+    validate!(
+        !params.is_iceberg(),
+        ErrorCode::InvalidOrder,
+        "iceberg orders are only supported for perp markets"
+    )?;
+
     let max_ts = match params.max_ts {
         Some(max_ts) => max_ts,
+        None if params.is_pending_activation() => 0_i64,
         None => match params.order_type {
             OrderType::Market | OrderType::Oracle => now.safe_add(30)?,
             _ => 0_i64,
@@ -3703,6 +6487,12 @@ pub fn place_spot_order(
 
     validate!(spot_market.orders_enabled, ErrorCode::SpotOrdersDisabled)?;
 
+    validate!(
+        !params.is_close_position(),
+        ErrorCode::InvalidOrder,
+        "close position orders are only supported for perp markets"
+    )?;
+
     validate!(
         params.market_index != QUOTE_SPOT_MARKET_INDEX,
         ErrorCode::InvalidOrderBaseQuoteAsset,
@@ -3724,7 +6514,7 @@ pub fn place_spot_order(
         OrderBitFlag::NewTriggerReduceOnly,
     );
 
-    let new_order = Order {
+    let mut new_order = Order {
         status: OrderStatus::Open,
         order_type: params.order_type,
         market_type: params.market_type,
@@ -3739,10 +6529,10 @@ pub fn place_spot_order(
         quote_asset_amount_filled: 0,
         direction: params.direction,
         reduce_only,
-        trigger_price: standardize_price(
-            params.trigger_price.unwrap_or(0),
+        trigger_price: get_trigger_price_for_new_order(
+            &params,
             spot_market.order_tick_size,
-            params.direction,
+            spot_market.order_step_size,
         )?,
         trigger_condition: params.trigger_condition,
         post_only: params.post_only != PostOnlyParam::None,
@@ -3754,9 +6544,13 @@ pub fn place_spot_order(
         max_ts,
         posted_slot_tail: get_posted_slot_from_clock_slot(slot),
         bit_flags,
-        padding: [0; 1],
+        oco_group_id_and_bit_flags: 0,
     };
 
+    if params.has_min_fill_size() {
+        new_order.add_ext_bit_flag(OrderExtBitFlag::MinFillSize);
+    }
+
     validate_spot_order(
         &new_order,
         spot_market.order_step_size,
@@ -3843,6 +6637,7 @@ pub fn place_spot_order(
     emit_stack::<_, { OrderRecord::SIZE }>(order_record)?;
 
     user.update_last_active_slot(slot);
+    user.update_last_heartbeat_ts(now)?;
 
     Ok(())
 }
@@ -3912,6 +6707,18 @@ pub fn fill_spot_order(
         "Order must be triggered first"
     )?;
 
+    validate!(
+        !user.orders[order_index].is_pending_activation(),
+        ErrorCode::OrderNotActivated,
+        "Order must be activated first"
+    )?;
+
+    validate!(
+        !user.orders[order_index].is_spread_leg(),
+        ErrorCode::InvalidOrder,
+        "Spread legs are filled with their spread"
+    )?;
+
     if user.is_bankrupt() {
         msg!("User is bankrupt");
         return Ok(0);
@@ -4066,6 +6873,37 @@ pub fn fill_spot_order(
         return Ok(0);
     }
 
+    let taker_limit_price = {
+        let spot_market = spot_market_map.get_ref(&order_market_index)?;
+        user.orders[order_index].get_limit_price(
+            Some(oracle_price),
+            None,
+            slot,
+            spot_market.order_tick_size,
+            false,
+            None,
+        )?
+    };
+
+    let maker_order_info = apply_self_trade_prevention(
+        user,
+        order_index,
+        &user_key,
+        taker_limit_price,
+        maker_order_info,
+        makers_and_referrer,
+        &filler_key,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        now,
+        slot,
+    )?;
+
+    if user.orders[order_index].status != OrderStatus::Open {
+        return Ok(0);
+    }
+
     if fulfillment_params.is_external() {
         let exchange_status = state.get_exchange_status()?;
 
@@ -4864,6 +7702,19 @@ pub fn fulfill_spot_order_with_match(
         return Ok((0_u64, 0_u64));
     }
 
+    if is_fill_below_min_fill_size(
+        &taker.orders[taker_order_index],
+        base_asset_amount,
+        Some(taker_token_amount.cast()?),
+    )? || is_fill_below_min_fill_size(
+        &maker.orders[maker_order_index],
+        base_asset_amount,
+        Some(maker_token_amount.cast()?),
+    )? {
+        msg!("fill {} below min fill size", base_asset_amount);
+        return Ok((0_u64, 0_u64));
+    }
+
     let base_precision = base_market.get_precision();
     validate_fill_price(
         quote_asset_amount,
@@ -5164,6 +8015,13 @@ pub fn fulfill_spot_order_with_external_market(
         }
     };
 
+    // external markets can partially fill any amount, so an order with a min fill size
+    // can't be sent there without risking a fill below its minimum
+    if taker.orders[taker_order_index].has_min_fill_size() {
+        msg!("External markets can't guarantee a min fill size");
+        return Ok((0, 0));
+    }
+
     let ExternalSpotFill {
         base_asset_amount_filled,
         base_update_direction,
@@ -5427,6 +8285,14 @@ pub fn trigger_spot_order(
 
     validate!(!user.is_bankrupt(), ErrorCode::UserBankrupt)?;
 
+    let account_trigger_condition_satisfied = user_satisfies_account_trigger_condition(
+        user,
+        order_index,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+    )?;
+
     let spot_market = spot_market_map.get_ref(&market_index)?;
     let (oracle_price_data, oracle_validity) = oracle_map.get_price_data_and_validity(
         MarketType::Spot,
@@ -5471,10 +8337,13 @@ pub fn trigger_spot_order(
         "oracle price vs twap too divergent"
     )?;
 
-    let can_trigger = order_satisfies_trigger_condition(
-        &user.orders[order_index],
-        oracle_price.unsigned_abs().cast()?,
-    )?;
+    let can_trigger = match account_trigger_condition_satisfied {
+        Some(account_trigger_condition_satisfied) => account_trigger_condition_satisfied,
+        None => order_satisfies_trigger_condition(
+            &user.orders[order_index],
+            oracle_price.unsigned_abs().cast()?,
+        )?,
+    };
     validate!(can_trigger, ErrorCode::OrderDidNotSatisfyTriggerCondition)?;
 
     let position_index = user.get_spot_position_index(market_index)?;
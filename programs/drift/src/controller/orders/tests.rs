@@ -266,6 +266,7 @@ pub mod fill_order_protected_maker {
             FillMode::Fill,
             &mut None,
             false,
+            &[],
         )
         .unwrap();
 
@@ -377,6 +378,7 @@ pub mod fill_order_protected_maker {
             FillMode::Fill,
             &mut None,
             false,
+            &[],
         )
         .unwrap();
 
@@ -485,6 +487,7 @@ pub mod fulfill_order_with_maker_order {
             None,
             taker_limit_price,
             maker_price,
+            None,
             now,
             slot,
             &fee_structure,
@@ -610,6 +613,7 @@ pub mod fulfill_order_with_maker_order {
             None,
             taker_limit_price,
             maker_price,
+            None,
             now,
             slot,
             &fee_structure,
@@ -735,6 +739,7 @@ pub mod fulfill_order_with_maker_order {
             None,
             taker_limit_price,
             maker_price,
+            None,
             now,
             slot,
             &fee_structure,
@@ -860,6 +865,7 @@ pub mod fulfill_order_with_maker_order {
             None,
             taker_limit_price,
             maker_price,
+            None,
             now,
             slot,
             &fee_structure,
@@ -984,6 +990,7 @@ pub mod fulfill_order_with_maker_order {
             None,
             taker_limit_price,
             maker_price,
+            None,
             now,
             slot,
             &fee_structure,
@@ -1075,6 +1082,7 @@ pub mod fulfill_order_with_maker_order {
             None,
             taker_limit_price,
             maker_price,
+            None,
             now,
             slot,
             &fee_structure,
@@ -1167,6 +1175,7 @@ pub mod fulfill_order_with_maker_order {
             None,
             taker_limit_price,
             maker_price,
+            None,
             now,
             slot,
             &fee_structure,
@@ -1259,6 +1268,7 @@ pub mod fulfill_order_with_maker_order {
             None,
             taker_limit_price,
             maker_price,
+            None,
             now,
             slot,
             &fee_structure,
@@ -1351,6 +1361,7 @@ pub mod fulfill_order_with_maker_order {
             None,
             taker_limit_price,
             maker_price,
+            None,
             now,
             slot,
             &fee_structure,
@@ -1463,6 +1474,7 @@ pub mod fulfill_order_with_maker_order {
             None,
             taker_limit_price,
             maker_price,
+            None,
             now,
             slot,
             &fee_structure,
@@ -1580,6 +1592,7 @@ pub mod fulfill_order_with_maker_order {
             None,
             taker_limit_price,
             maker_price,
+            None,
             now,
             slot,
             &fee_structure,
@@ -1702,6 +1715,7 @@ pub mod fulfill_order_with_maker_order {
             None,
             taker_limit_price,
             maker_price,
+            None,
             now,
             slot,
             &fee_structure,
@@ -1825,6 +1839,7 @@ pub mod fulfill_order_with_maker_order {
             None,
             taker_limit_price,
             maker_price,
+            None,
             now,
             slot,
             &fee_structure,
@@ -1972,6 +1987,7 @@ pub mod fulfill_order_with_maker_order {
             None,
             taker_limit_price,
             maker_price,
+            None,
             now,
             slot,
             &fee_structure,
@@ -2094,6 +2110,7 @@ pub mod fulfill_order_with_maker_order {
             None,
             taker_limit_price,
             maker_price,
+            None,
             now,
             slot,
             &fee_structure,
@@ -2226,6 +2243,7 @@ pub mod fulfill_order_with_maker_order {
             Some(oracle_price),
             taker_limit_price,
             maker_price,
+            None,
             now,
             slot,
             &fee_structure,
@@ -2379,6 +2397,7 @@ pub mod fulfill_order_with_maker_order {
             Some(oracle_price),
             taker_price,
             maker_price,
+            None,
             now,
             slot,
             &fee_structure,
@@ -2530,6 +2549,7 @@ pub mod fulfill_order_with_maker_order {
             None,
             taker_limit_price,
             maker_price,
+            None,
             now,
             slot,
             &fee_structure,
@@ -2682,6 +2702,7 @@ pub mod fulfill_order_with_maker_order {
             None,
             taker_price,
             maker_price,
+            None,
             now,
             slot,
             &fee_structure,
@@ -2815,6 +2836,7 @@ pub mod fulfill_order_with_maker_order {
             None,
             taker_limit_price,
             maker_price,
+            None,
             now,
             slot,
             &fee_structure,
@@ -2947,6 +2969,7 @@ pub mod fulfill_order_with_maker_order {
             None,
             taker_limit_price,
             maker_price,
+            None,
             now,
             slot,
             &fee_structure,
@@ -3015,12 +3038,14 @@ pub mod fulfill_order {
     use crate::state::fill_mode::FillMode;
     use crate::state::margin_calculation::MarginContext;
     use crate::state::oracle::{HistoricalOracleData, OracleSource};
-    use crate::state::perp_market::{PerpMarket, AMM};
+    use crate::state::perp_market::{MakerAllocationPolicy, PerpMarket, AMM};
     use crate::state::perp_market_map::PerpMarketMap;
     use crate::state::spot_market::{SpotBalanceType, SpotMarket};
     use crate::state::spot_market_map::SpotMarketMap;
     use crate::state::state::{OracleGuardRails, State, ValidityGuardRails};
-    use crate::state::user::{OrderStatus, OrderType, SpotPosition, User, UserStats};
+    use crate::state::user::{
+        OrderExtBitFlag, OrderStatus, OrderType, SpotPosition, User, UserStats,
+    };
     use crate::state::user_map::{UserMap, UserStatsMap};
     use crate::test_utils::*;
     use crate::test_utils::{get_orders, get_positions, get_pyth_price, get_spot_positions};
@@ -3613,6 +3638,220 @@ pub mod fulfill_order {
         assert_eq!(maker_position.open_asks, 0);
     }
 
+    #[test]
+    fn fulfill_with_pro_rata_makers_redistributes_shortfall() {
+        let now = 0_i64;
+        let slot = 0_u64;
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_slippage_ratio: 50,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 1000,
+                order_tick_size: 1,
+                oracle: oracle_price_key,
+                base_spread: 0,
+                historical_oracle_data: HistoricalOracleData {
+                    last_oracle_price: (100 * PRICE_PRECISION) as i64,
+                    last_oracle_price_twap: (100 * PRICE_PRECISION) as i64,
+                    last_oracle_price_twap_5min: (100 * PRICE_PRECISION) as i64,
+
+                    ..HistoricalOracleData::default()
+                },
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Initialized,
+            maker_allocation_policy: MakerAllocationPolicy::ProRata,
+            ..PerpMarket::default_test()
+        };
+        market.amm.max_base_asset_reserve = u128::MAX;
+        market.amm.min_base_asset_reserve = 0;
+
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let mut taker = User {
+            orders: get_orders(Order {
+                market_index: 0,
+                status: OrderStatus::Open,
+                order_type: OrderType::Market,
+                direction: PositionDirection::Long,
+                base_asset_amount: BASE_PRECISION_U64,
+                slot: 0,
+                auction_start_price: 0,
+                auction_end_price: 100 * PRICE_PRECISION_I64,
+                auction_duration: 0,
+                price: 150 * PRICE_PRECISION_U64,
+                ..Order::default()
+            }),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 1,
+                open_bids: BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
+
+        let maker_key = Pubkey::default();
+        let maker_authority =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let mut maker = User {
+            authority: maker_authority,
+            orders: get_orders!(
+                // pro-rata share of 0.5 is below the min fill size
+                Order {
+                    market_index: 0,
+                    status: OrderStatus::Open,
+                    post_only: true,
+                    order_type: OrderType::Limit,
+                    direction: PositionDirection::Short,
+                    base_asset_amount: BASE_PRECISION_U64,
+                    price: 90 * PRICE_PRECISION_U64,
+                    trigger_price: BASE_PRECISION_U64,
+                    oco_group_id_and_bit_flags: OrderExtBitFlag::MinFillSize as u8,
+                    slot: 0,
+                    ..Order::default()
+                },
+                Order {
+                    market_index: 0,
+                    status: OrderStatus::Open,
+                    post_only: true,
+                    order_type: OrderType::Limit,
+                    direction: PositionDirection::Short,
+                    base_asset_amount: BASE_PRECISION_U64,
+                    price: 90 * PRICE_PRECISION_U64,
+                    slot: 1,
+                    ..Order::default()
+                }
+            ),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 2,
+                open_asks: -2 * BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
+        create_anchor_account_info!(maker, User, maker_account_info);
+        let makers_and_referrers = UserMap::load_one(&maker_account_info).unwrap();
+
+        let mut filler = User::default();
+
+        let fee_structure = get_fee_structure();
+
+        let (taker_key, _, filler_key) = get_user_keys();
+
+        let mut taker_stats = UserStats::default();
+
+        let mut maker_stats = UserStats {
+            authority: maker_authority,
+            ..UserStats::default()
+        };
+        create_anchor_account_info!(maker_stats, UserStats, maker_stats_account_info);
+        let maker_and_referrer_stats = UserStatsMap::load_one(&maker_stats_account_info).unwrap();
+
+        let mut filler_stats = UserStats::default();
+
+        let (base_asset_amount, _) = fulfill_perp_order(
+            &mut taker,
+            0,
+            &taker_key,
+            &mut taker_stats,
+            &makers_and_referrers,
+            &maker_and_referrer_stats,
+            &[
+                (maker_key, 0, 90 * PRICE_PRECISION_U64),
+                (maker_key, 1, 90 * PRICE_PRECISION_U64),
+            ],
+            &mut Some(&mut filler),
+            &filler_key,
+            &mut Some(&mut filler_stats),
+            None,
+            &spot_market_map,
+            &market_map,
+            &mut oracle_map,
+            &fee_structure,
+            100 * PRICE_PRECISION_U64,
+            Some(market.amm.historical_oracle_data.last_oracle_price),
+            now,
+            slot,
+            10,
+            crate::state::perp_market::AMMAvailability::AfterMinDuration,
+            FillMode::Fill,
+            false,
+            &mut None,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(base_asset_amount, BASE_PRECISION_U64);
+
+        // the second maker takes the first maker's unfilled share instead of the amm
+        let taker_position = &taker.perp_positions[0];
+        assert_eq!(taker_position.base_asset_amount, BASE_PRECISION_I64);
+        assert_eq!(taker_position.quote_entry_amount, -90 * QUOTE_PRECISION_I64);
+
+        let maker = makers_and_referrers.get_ref_mut(&maker_key).unwrap();
+        assert_eq!(maker.orders[0].base_asset_amount_filled, 0);
+        assert_eq!(maker.orders[0].status, OrderStatus::Open);
+        assert_eq!(maker.orders[1].base_asset_amount_filled, BASE_PRECISION_U64);
+        assert_eq!(
+            maker.perp_positions[0].base_asset_amount,
+            -BASE_PRECISION_I64
+        );
+
+        let market_after = market_map.get_ref(&0).unwrap();
+        assert_eq!(market_after.amm.base_asset_amount_with_amm, 0);
+    }
+
     #[test]
     fn fulfill_with_maker_then_amm() {
         let now = 0_i64;
@@ -5036,6 +5275,7 @@ pub mod fulfill_order {
             FillMode::Fill,
             &mut None,
             false,
+            &[],
         )
         .unwrap();
 
@@ -5063,6 +5303,7 @@ pub mod fulfill_order {
             FillMode::Fill,
             &mut None,
             false,
+            &[],
         )
         .unwrap();
 
@@ -5954,6 +6195,7 @@ pub mod fill_order {
             FillMode::Fill,
             &mut None,
             false,
+            &[],
         )
         .unwrap();
 
@@ -6158,6 +6400,7 @@ pub mod fill_order {
             FillMode::Fill,
             &mut None,
             false,
+            &[],
         )
         .unwrap();
 
@@ -6289,6 +6532,7 @@ pub mod fill_order {
             FillMode::Fill,
             &mut None,
             false,
+            &[],
         )
         .unwrap();
 
@@ -6453,6 +6697,7 @@ pub mod fill_order {
             FillMode::Fill,
             &mut None,
             false,
+            &[],
         );
 
         assert_eq!(err, Err(ErrorCode::MaxOpenInterest));
@@ -10383,6 +10628,7 @@ pub mod force_cancel_orders {
             &mut oracle_map,
             &filler_account_loader,
             &clock,
+            &mut None,
         )
         .unwrap();
 
@@ -10457,6 +10703,7 @@ pub mod get_maker_orders_info {
     use crate::state::oracle::OracleSource;
     use crate::state::perp_market::{PerpMarket, AMM};
     use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::perp_order_book::{OrderBookEntry, PerpOrderBook};
     use crate::state::spot_market::{SpotBalanceType, SpotMarket};
     use crate::state::spot_market_map::SpotMarketMap;
     use crate::state::user::{OrderStatus, OrderType, SpotPosition, User};
@@ -10468,6 +10715,8 @@ pub mod get_maker_orders_info {
     use crate::{create_account_info, get_orders};
     use crate::{create_anchor_account_info, QUOTE_PRECISION_I64};
 
+    use bytemuck::Zeroable;
+
     use super::*;
 
     #[test]
@@ -10651,6 +10900,7 @@ pub mod get_maker_orders_info {
             clock.slot,
             true,
             10,
+            &[],
         )
         .unwrap();
 
@@ -10842,6 +11092,7 @@ pub mod get_maker_orders_info {
             clock.slot,
             true,
             10,
+            &[],
         )
         .unwrap();
 
@@ -11022,6 +11273,7 @@ pub mod get_maker_orders_info {
             clock.slot,
             true,
             10,
+            &[],
         )
         .unwrap();
 
@@ -11265,6 +11517,7 @@ pub mod get_maker_orders_info {
             clock.slot,
             true,
             10,
+            &[],
         )
         .unwrap();
 
@@ -11277,22 +11530,62 @@ pub mod get_maker_orders_info {
                 (second_maker_key, 1, 103000000),
             ],
         );
-    }
 
-    #[test]
-    fn jit_maker_order_id() {
-        let clock = Clock {
-            slot: 6,
-            epoch_start_timestamp: 0,
-            epoch: 0,
-            leader_schedule_epoch: 0,
-            unix_timestamp: 0,
-        };
+        // a better priced book entry whose maker wasn't passed in can't be skipped
+        let mut perp_order_book = PerpOrderBook::zeroed();
+        perp_order_book.insert(
+            PositionDirection::Short,
+            OrderBookEntry {
+                user: Pubkey::new_unique(),
+                price: 101500000,
+                order_id: 1,
+                padding: [0; 4],
+            },
+        );
 
-        let mut pyth_price = get_pyth_price(100, 6);
-        let oracle_price = 100 * PRICE_PRECISION_I64;
-        let oracle_price_key =
-            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let maker_order_price_and_indexes = get_maker_orders_info(
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &makers_and_referrers,
+            &taker_key,
+            &user.orders[0],
+            &mut Some(&mut filler),
+            &filler_key,
+            0,
+            oracle_price,
+            None,
+            clock.unix_timestamp,
+            clock.slot,
+            true,
+            10,
+            &[&perp_order_book],
+        )
+        .unwrap();
+
+        assert_eq!(
+            maker_order_price_and_indexes,
+            vec![
+                (first_maker_key, 0, 100000000),
+                (second_maker_key, 0, 101000000),
+            ],
+        );
+    }
+
+    #[test]
+    fn jit_maker_order_id() {
+        let clock = Clock {
+            slot: 6,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 0,
+        };
+
+        let mut pyth_price = get_pyth_price(100, 6);
+        let oracle_price = 100 * PRICE_PRECISION_I64;
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
         let pyth_program = crate::ids::pyth_program::id();
         create_account_info!(
             pyth_price,
@@ -11463,6 +11756,7 @@ pub mod get_maker_orders_info {
             clock.slot,
             true,
             10,
+            &[],
         )
         .unwrap();
 
@@ -11683,6 +11977,7 @@ pub mod get_maker_orders_info {
             clock.slot,
             true,
             10,
+            &[],
         )
         .unwrap();
 
@@ -12881,3 +13176,3572 @@ mod update_maker_fills_map {
         assert_eq!(*map.get(&maker_key).unwrap(), -2 * fill as i64);
     }
 }
+
+mod decrement_order_base_asset_amount {
+    use crate::controller::orders::decrement_order_base_asset_amount;
+    use crate::controller::position::PositionDirection;
+    use crate::state::user::{MarketType, Order, OrderStatus, OrderType, PerpPosition, User};
+    use crate::test_utils::{get_orders, get_positions};
+    use crate::BASE_PRECISION_U64;
+
+    #[test]
+    fn test() {
+        let mut user = User {
+            orders: get_orders(Order {
+                status: OrderStatus::Open,
+                market_type: MarketType::Perp,
+                order_type: OrderType::Limit,
+                direction: PositionDirection::Long,
+                base_asset_amount: 10 * BASE_PRECISION_U64,
+                base_asset_amount_filled: 2 * BASE_PRECISION_U64,
+                ..Order::default()
+            }),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 1,
+                open_bids: 8 * BASE_PRECISION_U64 as i64,
+                ..PerpPosition::default()
+            }),
+            ..User::default()
+        };
+
+        decrement_order_base_asset_amount(&mut user, 0, 3 * BASE_PRECISION_U64).unwrap();
+
+        assert_eq!(user.orders[0].base_asset_amount, 7 * BASE_PRECISION_U64);
+        assert_eq!(
+            user.orders[0].get_base_asset_amount_unfilled(None).unwrap(),
+            5 * BASE_PRECISION_U64
+        );
+        assert_eq!(
+            user.perp_positions[0].open_bids,
+            5 * BASE_PRECISION_U64 as i64
+        );
+    }
+}
+
+mod free_order_slots_with_extension {
+    use std::cell::{RefCell, RefMut};
+    use std::str::FromStr;
+
+    use anchor_lang::prelude::Pubkey;
+
+    use crate::controller::orders::{
+        cancel_extension_orders, cancel_orders_with_extension, expire_extension_orders,
+        free_order_slots_with_extension, load_maker_orders_from_extension,
+        load_order_from_extension, load_order_from_extension_by_user_order_id,
+    };
+    use crate::controller::position::PositionDirection;
+    use crate::create_account_info;
+    use crate::create_anchor_account_info;
+    use crate::error::ErrorCode;
+    use crate::math::constants::PRICE_PRECISION_U64;
+    use crate::state::events::OrderActionExplanation;
+    use crate::state::oracle::HistoricalOracleData;
+    use crate::state::oracle_map::OracleMap;
+    use crate::state::perp_market::{PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::user::{MarketType, Order, OrderStatus, OrderType, User};
+    use crate::state::user_orders_extension::{
+        UserOrdersExtensionFixed, UserOrdersExtensionZeroCopyMut,
+    };
+    use crate::test_utils::{create_account_info, get_orders, get_pyth_price};
+
+    #[test]
+    fn parks_oldest_resting_limit_order() {
+        let mut user = User {
+            orders: get_orders(Order {
+                status: OrderStatus::Open,
+                order_type: OrderType::Limit,
+                post_only: true,
+                slot: 10,
+                ..Order::default()
+            }),
+            open_orders: 32,
+            ..User::default()
+        };
+        for (i, order) in user.orders.iter_mut().enumerate() {
+            order.order_id = i as u32 + 1;
+        }
+        user.orders[5].slot = 1;
+        // trigger orders stay in User.orders
+        user.orders[6].order_type = OrderType::TriggerLimit;
+        user.orders[6].slot = 0;
+
+        let fixed = RefCell::new(UserOrdersExtensionFixed {
+            len: 2,
+            ..UserOrdersExtensionFixed::default()
+        });
+        let data = RefCell::new([Order::default(); 2]);
+        let mut orders_extension = UserOrdersExtensionZeroCopyMut {
+            fixed: fixed.borrow_mut(),
+            data: RefMut::map(data.borrow_mut(), |orders| {
+                bytemuck::cast_slice_mut(&mut orders[..])
+            }),
+        };
+
+        free_order_slots_with_extension(&mut user, &mut orders_extension, 1).unwrap();
+
+        assert!(user.orders[5].is_available());
+        assert_eq!(orders_extension.get_order(0).unwrap().order_id, 6);
+        assert_eq!(user.extension_open_orders, 1);
+        assert_eq!(user.open_orders, 32);
+
+        // already has an available slot
+        free_order_slots_with_extension(&mut user, &mut orders_extension, 1).unwrap();
+        assert_eq!(user.extension_open_orders, 1);
+
+        free_order_slots_with_extension(&mut user, &mut orders_extension, 2).unwrap();
+        assert_eq!(user.extension_open_orders, 2);
+
+        let result = free_order_slots_with_extension(&mut user, &mut orders_extension, 3);
+        assert_eq!(result, Err(ErrorCode::UserOrdersExtensionFull));
+
+        // loading an order back uses an available slot
+        let order_index = load_order_from_extension(&mut user, &mut orders_extension, 6)
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.orders[order_index].order_id, 6);
+        assert_eq!(user.orders[order_index].status, OrderStatus::Open);
+        assert_eq!(user.extension_open_orders, 1);
+
+        assert_eq!(
+            load_order_from_extension(&mut user, &mut orders_extension, 100).unwrap(),
+            None
+        );
+
+        // orders can also be loaded back by their user order id
+        orders_extension.get_order_mut(1).unwrap().user_order_id = 7;
+        let order_id = orders_extension.get_order(1).unwrap().order_id;
+        let order_index =
+            load_order_from_extension_by_user_order_id(&mut user, &mut orders_extension, 7)
+                .unwrap()
+                .unwrap();
+        assert_eq!(user.orders[order_index].order_id, order_id);
+        assert_eq!(user.orders[order_index].user_order_id, 7);
+        assert_eq!(user.extension_open_orders, 0);
+
+        assert_eq!(
+            load_order_from_extension_by_user_order_id(&mut user, &mut orders_extension, 8)
+                .unwrap(),
+            None
+        );
+    }
+
+    fn get_full_user() -> User {
+        let mut user = User {
+            orders: get_orders(Order {
+                status: OrderStatus::Open,
+                order_type: OrderType::Limit,
+                market_index: 1,
+                ..Order::default()
+            }),
+            open_orders: 32,
+            ..User::default()
+        };
+        for (i, order) in user.orders.iter_mut().enumerate() {
+            order.order_id = i as u32 + 1;
+        }
+        user
+    }
+
+    #[test]
+    fn loads_best_priced_maker_orders() {
+        let mut maker = get_full_user();
+        maker.orders[3] = Order::default();
+        maker.orders[7] = Order::default();
+        maker.open_orders = 30;
+        maker.extension_open_orders = 4;
+
+        let fixed = RefCell::new(UserOrdersExtensionFixed {
+            len: 4,
+            ..UserOrdersExtensionFixed::default()
+        });
+        let ask = Order {
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Short,
+            ..Order::default()
+        };
+        let data = RefCell::new([
+            Order {
+                order_id: 100,
+                price: 102 * PRICE_PRECISION_U64,
+                ..ask
+            },
+            Order {
+                order_id: 101,
+                price: 100 * PRICE_PRECISION_U64,
+                ..ask
+            },
+            // oracle offset ask at 101
+            Order {
+                order_id: 102,
+                oracle_price_offset: PRICE_PRECISION_U64 as i32,
+                ..ask
+            },
+            // bids stay in the extension
+            Order {
+                order_id: 103,
+                price: 99 * PRICE_PRECISION_U64,
+                direction: PositionDirection::Long,
+                ..ask
+            },
+        ]);
+        let mut orders_extension = UserOrdersExtensionZeroCopyMut {
+            fixed: fixed.borrow_mut(),
+            data: RefMut::map(data.borrow_mut(), |orders| {
+                bytemuck::cast_slice_mut(&mut orders[..])
+            }),
+        };
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, 0, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                order_tick_size: 1,
+                oracle: oracle_price_key,
+                historical_oracle_data: HistoricalOracleData {
+                    last_oracle_price: oracle_price.agg.price,
+                    ..HistoricalOracleData::default()
+                },
+                ..AMM::default()
+            },
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let perp_market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        load_maker_orders_from_extension(
+            &mut maker,
+            &mut orders_extension,
+            &perp_market_map,
+            &SpotMarketMap::empty(),
+            &mut oracle_map,
+            0,
+            MarketType::Perp,
+            0,
+            PositionDirection::Short,
+        )
+        .unwrap();
+
+        assert_eq!(maker.orders[3].order_id, 101);
+        assert_eq!(maker.orders[7].order_id, 102);
+        assert_eq!(
+            orders_extension.get_order(0).unwrap().status,
+            OrderStatus::Open
+        );
+        assert_eq!(
+            orders_extension.get_order(3).unwrap().status,
+            OrderStatus::Open
+        );
+        assert_eq!(maker.extension_open_orders, 2);
+    }
+
+    #[test]
+    fn cancel_needs_an_available_order_slot() {
+        let mut user = get_full_user();
+        user.extension_open_orders = 1;
+
+        let fixed = RefCell::new(UserOrdersExtensionFixed {
+            len: 1,
+            ..UserOrdersExtensionFixed::default()
+        });
+        let data = RefCell::new([Order {
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            order_id: 100,
+            max_ts: 10,
+            ..Order::default()
+        }]);
+        let mut orders_extension = UserOrdersExtensionZeroCopyMut {
+            fixed: fixed.borrow_mut(),
+            data: RefMut::map(data.borrow_mut(), |orders| {
+                bytemuck::cast_slice_mut(&mut orders[..])
+            }),
+        };
+
+        let perp_market_map = PerpMarketMap::empty();
+        let spot_market_map = SpotMarketMap::empty();
+        let mut oracle_map = OracleMap::empty();
+
+        // orders that aren't expired are skipped without needing a slot
+        expire_extension_orders(
+            &mut user,
+            &Pubkey::default(),
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            10,
+            0,
+            &mut orders_extension,
+        )
+        .unwrap();
+
+        let result = cancel_extension_orders(
+            &mut user,
+            &Pubkey::default(),
+            None,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            0,
+            0,
+            OrderActionExplanation::None,
+            None,
+            None,
+            None,
+            &mut orders_extension,
+        );
+        assert_eq!(result, Err(ErrorCode::MaxNumberOfOrders));
+
+        assert_eq!(orders_extension.get_order(0).unwrap().order_id, 100);
+        assert_eq!(user.extension_open_orders, 1);
+    }
+
+    #[test]
+    fn cancel_all_requires_extension_with_parked_orders() {
+        let mut user = get_full_user();
+        user.extension_open_orders = 1;
+
+        let perp_market_map = PerpMarketMap::empty();
+        let spot_market_map = SpotMarketMap::empty();
+        let mut oracle_map = OracleMap::empty();
+
+        let result = cancel_orders_with_extension(
+            &mut user,
+            &Pubkey::default(),
+            None,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            0,
+            0,
+            OrderActionExplanation::Liquidation,
+            None,
+            None,
+            None,
+            &mut None,
+        );
+        assert_eq!(result, Err(ErrorCode::UserOrdersExtensionRequired));
+
+        // nothing was canceled
+        assert!(user
+            .orders
+            .iter()
+            .all(|order| order.status == OrderStatus::Open));
+    }
+}
+
+pub mod oco_group {
+    use std::str::FromStr;
+
+    use anchor_lang::prelude::{AccountLoader, Clock};
+
+    use crate::controller::orders::{fill_perp_order, trigger_order};
+    use crate::controller::position::PositionDirection;
+    use crate::create_anchor_account_info;
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, BASE_PRECISION_I64, BASE_PRECISION_U64, PEG_PRECISION,
+        PRICE_PRECISION_I64, PRICE_PRECISION_U64, SPOT_BALANCE_PRECISION_U64,
+        SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
+    };
+    use crate::state::oracle::HistoricalOracleData;
+    use crate::state::oracle::OracleSource;
+    use crate::state::paused_operations::PerpOperation;
+    use crate::state::perp_market::{PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::state::State;
+    use crate::state::user::{
+        MarketType, OrderStatus, OrderTriggerCondition, OrderType, SpotPosition, User, UserStats,
+    };
+    use crate::test_utils::*;
+    use crate::test_utils::{
+        create_account_info, get_positions, get_pyth_price, get_spot_positions,
+    };
+    use crate::{create_account_info, get_orders, QUOTE_PRECISION_I64};
+
+    use super::*;
+    use crate::state::fill_mode::FillMode;
+    use crate::state::user_map::{UserMap, UserStatsMap};
+
+    #[test]
+    fn fill_cancels_rest_of_group() {
+        let clock = Clock {
+            slot: 56,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 0,
+        };
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            paused_operations: PerpOperation::AmmFill as u8,
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                terminal_quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_slippage_ratio: 100,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 1000,
+                order_tick_size: 1,
+                oracle: oracle_price_key,
+                historical_oracle_data: HistoricalOracleData {
+                    last_oracle_price_twap: oracle_price.twap,
+                    last_oracle_price_twap_5min: oracle_price.twap,
+                    last_oracle_price: oracle_price.agg.price,
+                    ..HistoricalOracleData::default()
+                },
+                ..AMM::default()
+            },
+            margin_ratio_initial: 2000,
+            margin_ratio_maintenance: 1000,
+            status: MarketStatus::Active,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let mut user = User {
+            authority: Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap(),
+            orders: get_orders!(
+                Order {
+                    market_index: 0,
+                    order_id: 1,
+                    status: OrderStatus::Open,
+                    order_type: OrderType::Market,
+                    direction: PositionDirection::Long,
+                    market_type: MarketType::Perp,
+                    base_asset_amount: BASE_PRECISION_U64,
+                    slot: clock.slot - 1,
+                    auction_start_price: 0,
+                    auction_end_price: 100 * PRICE_PRECISION_I64,
+                    auction_duration: 1,
+                    price: 100 * PRICE_PRECISION_U64,
+                    oco_group_id_and_bit_flags: 1,
+                    ..Order::default()
+                },
+                Order {
+                    market_index: 0,
+                    order_id: 2,
+                    status: OrderStatus::Open,
+                    order_type: OrderType::Limit,
+                    direction: PositionDirection::Long,
+                    market_type: MarketType::Perp,
+                    base_asset_amount: BASE_PRECISION_U64,
+                    slot: clock.slot - 1,
+                    price: 90 * PRICE_PRECISION_U64,
+                    oco_group_id_and_bit_flags: 1,
+                    ..Order::default()
+                }
+            ),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 2,
+                open_bids: 2 * BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            open_orders: 2,
+            ..User::default()
+        };
+        create_anchor_account_info!(user, User, user_account_info);
+        let user_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&user_account_info).unwrap();
+
+        create_anchor_account_info!(UserStats::default(), UserStats, user_stats_account_info);
+        let user_stats_account_loader: AccountLoader<UserStats> =
+            AccountLoader::try_from(&user_stats_account_info).unwrap();
+
+        let maker_key = Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
+        let maker_authority =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let mut maker = User {
+            authority: maker_authority,
+            orders: get_orders(Order {
+                market_index: 0,
+                order_id: 1,
+                status: OrderStatus::Open,
+                order_type: OrderType::Limit,
+                market_type: MarketType::Perp,
+                direction: PositionDirection::Short,
+                base_asset_amount: BASE_PRECISION_U64,
+                slot: clock.slot - 3,
+                price: 100 * PRICE_PRECISION_U64,
+                post_only: true,
+                ..Order::default()
+            }),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 1,
+                open_asks: -BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
+        create_anchor_account_info!(maker, &maker_key, User, maker_account_info);
+        let makers_and_referrers = UserMap::load_one(&maker_account_info).unwrap();
+
+        let mut maker_stats = UserStats {
+            authority: maker_authority,
+            ..UserStats::default()
+        };
+        create_anchor_account_info!(maker_stats, UserStats, maker_stats_account_info);
+        let maker_and_referrer_stats = UserStatsMap::load_one(&maker_stats_account_info).unwrap();
+
+        let filler_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
+        create_anchor_account_info!(User::default(), &filler_key, User, filler_account_info);
+        let filler_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&filler_account_info).unwrap();
+
+        create_anchor_account_info!(UserStats::default(), UserStats, filler_stats_account_info);
+        let filler_stats_account_loader: AccountLoader<UserStats> =
+            AccountLoader::try_from(&filler_stats_account_info).unwrap();
+
+        let state = State {
+            min_perp_auction_duration: 1,
+            default_market_order_time_in_force: 10,
+            ..State::default()
+        };
+
+        let (base_asset_amount, _) = fill_perp_order(
+            1,
+            &state,
+            &user_account_loader,
+            &user_stats_account_loader,
+            &spot_market_map,
+            &market_map,
+            &mut oracle_map,
+            &filler_account_loader,
+            &filler_stats_account_loader,
+            &makers_and_referrers,
+            &maker_and_referrer_stats,
+            None,
+            &clock,
+            FillMode::Fill,
+            &mut None,
+            false,
+            &[],
+        )
+        .unwrap();
+
+        assert_eq!(base_asset_amount, BASE_PRECISION_U64);
+
+        let user_after = user_account_loader.load().unwrap();
+        assert_eq!(user_after.orders[0].status, OrderStatus::Filled);
+        assert_eq!(user_after.orders[1].status, OrderStatus::Canceled);
+        assert_eq!(user_after.perp_positions[0].open_orders, 0);
+        assert_eq!(user_after.perp_positions[0].open_bids, 0);
+        assert_eq!(user_after.open_orders, 0);
+    }
+
+    #[test]
+    fn trigger_cancels_rest_of_group() {
+        let clock = Clock {
+            slot: 56,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 0,
+        };
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                terminal_quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                order_step_size: 1000,
+                order_tick_size: 1,
+                oracle: oracle_price_key,
+                historical_oracle_data: HistoricalOracleData {
+                    last_oracle_price_twap: oracle_price.twap,
+                    last_oracle_price_twap_5min: oracle_price.twap,
+                    last_oracle_price: oracle_price.agg.price,
+                    ..HistoricalOracleData::default()
+                },
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Active,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        // stop loss and take profit on a long position
+        let mut user = User {
+            orders: get_orders!(
+                Order {
+                    market_index: 0,
+                    order_id: 1,
+                    status: OrderStatus::Open,
+                    order_type: OrderType::TriggerMarket,
+                    direction: PositionDirection::Short,
+                    market_type: MarketType::Perp,
+                    base_asset_amount: BASE_PRECISION_U64,
+                    trigger_price: 105 * PRICE_PRECISION_U64,
+                    trigger_condition: OrderTriggerCondition::Below,
+                    reduce_only: true,
+                    oco_group_id_and_bit_flags: 1,
+                    ..Order::default()
+                },
+                Order {
+                    market_index: 0,
+                    order_id: 2,
+                    status: OrderStatus::Open,
+                    order_type: OrderType::TriggerMarket,
+                    direction: PositionDirection::Short,
+                    market_type: MarketType::Perp,
+                    base_asset_amount: BASE_PRECISION_U64,
+                    trigger_price: 120 * PRICE_PRECISION_U64,
+                    trigger_condition: OrderTriggerCondition::Above,
+                    reduce_only: true,
+                    oco_group_id_and_bit_flags: 1,
+                    ..Order::default()
+                }
+            ),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: BASE_PRECISION_I64,
+                quote_asset_amount: -100 * QUOTE_PRECISION_I64,
+                quote_entry_amount: -100 * QUOTE_PRECISION_I64,
+                quote_break_even_amount: -100 * QUOTE_PRECISION_I64,
+                open_orders: 2,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            open_orders: 2,
+            ..User::default()
+        };
+        create_anchor_account_info!(user, User, user_account_info);
+        let user_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&user_account_info).unwrap();
+
+        let filler_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
+        create_anchor_account_info!(User::default(), &filler_key, User, filler_account_info);
+        let filler_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&filler_account_info).unwrap();
+
+        let state = State::default();
+
+        trigger_order(
+            1,
+            &state,
+            &user_account_loader,
+            &spot_market_map,
+            &market_map,
+            &mut oracle_map,
+            &filler_account_loader,
+            &clock,
+        )
+        .unwrap();
+
+        let user_after = user_account_loader.load().unwrap();
+        assert!(user_after.orders[0].triggered());
+        assert_eq!(user_after.orders[0].status, OrderStatus::Open);
+        assert_eq!(user_after.orders[1].status, OrderStatus::Canceled);
+        assert_eq!(user_after.perp_positions[0].open_orders, 1);
+        assert_eq!(user_after.open_orders, 1);
+    }
+}
+
+pub mod bracket_order {
+    use std::str::FromStr;
+
+    use anchor_lang::prelude::{AccountLoader, Clock};
+
+    use crate::controller::orders::{cancel_order, fill_perp_order};
+    use crate::controller::position::PositionDirection;
+    use crate::create_anchor_account_info;
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, BASE_PRECISION_I64, BASE_PRECISION_U64, PEG_PRECISION,
+        PRICE_PRECISION_I64, PRICE_PRECISION_U64, SPOT_BALANCE_PRECISION_U64,
+        SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
+    };
+    use crate::state::events::OrderActionExplanation;
+    use crate::state::oracle::HistoricalOracleData;
+    use crate::state::oracle::OracleSource;
+    use crate::state::paused_operations::PerpOperation;
+    use crate::state::perp_market::{PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::state::State;
+    use crate::state::user::{
+        MarketType, OrderBitFlag, OrderStatus, OrderTriggerCondition, OrderType, SpotPosition,
+        User, UserStats,
+    };
+    use crate::test_utils::*;
+    use crate::test_utils::{
+        create_account_info, get_positions, get_pyth_price, get_spot_positions,
+    };
+    use crate::{create_account_info, get_orders, QUOTE_PRECISION_I64};
+
+    use super::*;
+    use crate::state::fill_mode::FillMode;
+    use crate::state::user_map::{UserMap, UserStatsMap};
+
+    fn get_bracket_orders(slot: u64) -> [Order; 32] {
+        let mut entry = Order {
+            market_index: 0,
+            order_id: 3,
+            status: OrderStatus::Open,
+            order_type: OrderType::Market,
+            direction: PositionDirection::Long,
+            market_type: MarketType::Perp,
+            base_asset_amount: BASE_PRECISION_U64,
+            slot,
+            auction_start_price: 0,
+            auction_end_price: 100 * PRICE_PRECISION_I64,
+            auction_duration: 1,
+            price: 100 * PRICE_PRECISION_U64,
+            oco_group_id_and_bit_flags: 1,
+            ..Order::default()
+        };
+        entry.add_bit_flag(OrderBitFlag::BracketEntry);
+
+        get_orders!(
+            Order {
+                market_index: 0,
+                order_id: 1,
+                status: OrderStatus::Open,
+                order_type: OrderType::TriggerMarket,
+                direction: PositionDirection::Short,
+                market_type: MarketType::Perp,
+                base_asset_amount: 0,
+                trigger_price: 90 * PRICE_PRECISION_U64,
+                trigger_condition: OrderTriggerCondition::Below,
+                reduce_only: true,
+                oco_group_id_and_bit_flags: 1,
+                ..Order::default()
+            },
+            Order {
+                market_index: 0,
+                order_id: 2,
+                status: OrderStatus::Open,
+                order_type: OrderType::TriggerMarket,
+                direction: PositionDirection::Short,
+                market_type: MarketType::Perp,
+                base_asset_amount: 0,
+                trigger_price: 110 * PRICE_PRECISION_U64,
+                trigger_condition: OrderTriggerCondition::Above,
+                reduce_only: true,
+                oco_group_id_and_bit_flags: 1,
+                ..Order::default()
+            },
+            entry
+        )
+    }
+
+    #[test]
+    fn legs_sized_to_entry_fill() {
+        let clock = Clock {
+            slot: 56,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 0,
+        };
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            paused_operations: PerpOperation::AmmFill as u8,
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                terminal_quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_slippage_ratio: 100,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 1000,
+                order_tick_size: 1,
+                oracle: oracle_price_key,
+                historical_oracle_data: HistoricalOracleData {
+                    last_oracle_price_twap: oracle_price.twap,
+                    last_oracle_price_twap_5min: oracle_price.twap,
+                    last_oracle_price: oracle_price.agg.price,
+                    ..HistoricalOracleData::default()
+                },
+                ..AMM::default()
+            },
+            margin_ratio_initial: 2000,
+            margin_ratio_maintenance: 1000,
+            status: MarketStatus::Active,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let mut user = User {
+            authority: Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap(),
+            orders: get_bracket_orders(clock.slot - 1),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 3,
+                open_bids: BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            open_orders: 3,
+            next_order_id: 4,
+            ..User::default()
+        };
+        create_anchor_account_info!(user, User, user_account_info);
+        let user_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&user_account_info).unwrap();
+
+        create_anchor_account_info!(UserStats::default(), UserStats, user_stats_account_info);
+        let user_stats_account_loader: AccountLoader<UserStats> =
+            AccountLoader::try_from(&user_stats_account_info).unwrap();
+
+        // maker only fills half the entry
+        let maker_key = Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
+        let maker_authority =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let mut maker = User {
+            authority: maker_authority,
+            orders: get_orders(Order {
+                market_index: 0,
+                order_id: 1,
+                status: OrderStatus::Open,
+                order_type: OrderType::Limit,
+                market_type: MarketType::Perp,
+                direction: PositionDirection::Short,
+                base_asset_amount: BASE_PRECISION_U64 / 2,
+                slot: clock.slot - 3,
+                price: 100 * PRICE_PRECISION_U64,
+                post_only: true,
+                ..Order::default()
+            }),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 1,
+                open_asks: -BASE_PRECISION_I64 / 2,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
+        create_anchor_account_info!(maker, &maker_key, User, maker_account_info);
+        let makers_and_referrers = UserMap::load_one(&maker_account_info).unwrap();
+
+        let mut maker_stats = UserStats {
+            authority: maker_authority,
+            ..UserStats::default()
+        };
+        create_anchor_account_info!(maker_stats, UserStats, maker_stats_account_info);
+        let maker_and_referrer_stats = UserStatsMap::load_one(&maker_stats_account_info).unwrap();
+
+        let filler_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
+        create_anchor_account_info!(User::default(), &filler_key, User, filler_account_info);
+        let filler_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&filler_account_info).unwrap();
+
+        create_anchor_account_info!(UserStats::default(), UserStats, filler_stats_account_info);
+        let filler_stats_account_loader: AccountLoader<UserStats> =
+            AccountLoader::try_from(&filler_stats_account_info).unwrap();
+
+        let state = State {
+            min_perp_auction_duration: 1,
+            default_market_order_time_in_force: 10,
+            ..State::default()
+        };
+
+        let (base_asset_amount, _) = fill_perp_order(
+            3,
+            &state,
+            &user_account_loader,
+            &user_stats_account_loader,
+            &spot_market_map,
+            &market_map,
+            &mut oracle_map,
+            &filler_account_loader,
+            &filler_stats_account_loader,
+            &makers_and_referrers,
+            &maker_and_referrer_stats,
+            None,
+            &clock,
+            FillMode::Fill,
+            &mut None,
+            false,
+            &[],
+        )
+        .unwrap();
+
+        assert_eq!(base_asset_amount, BASE_PRECISION_U64 / 2);
+
+        let mut user = user_account_loader.load_mut().unwrap();
+        assert_eq!(user.orders[2].status, OrderStatus::Open);
+        assert_eq!(user.orders[0].base_asset_amount, BASE_PRECISION_U64 / 2);
+        assert_eq!(user.orders[1].base_asset_amount, BASE_PRECISION_U64 / 2);
+        assert!(!user.is_bracket_order_waiting_for_entry(0));
+
+        // canceling the rest of the entry leaves the sized legs protecting the position
+        cancel_order(
+            2,
+            &mut user,
+            &Pubkey::default(),
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            clock.unix_timestamp,
+            clock.slot,
+            OrderActionExplanation::None,
+            None,
+            0,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(user.orders[2].status, OrderStatus::Canceled);
+        assert_eq!(user.orders[0].status, OrderStatus::Open);
+        assert_eq!(user.orders[1].status, OrderStatus::Open);
+        assert_eq!(user.perp_positions[0].open_orders, 2);
+    }
+
+    #[test]
+    fn canceling_unfilled_entry_cancels_legs() {
+        let slot = 56;
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                oracle: oracle_price_key,
+                ..AMM::default()
+            },
+            status: MarketStatus::Active,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let mut user = User {
+            orders: get_bracket_orders(slot - 1),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 3,
+                open_bids: BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            open_orders: 3,
+            next_order_id: 4,
+            ..User::default()
+        };
+
+        assert!(user.is_bracket_order_waiting_for_entry(0));
+        assert!(user.is_bracket_order_waiting_for_entry(1));
+        assert!(!user.is_bracket_order_waiting_for_entry(2));
+
+        cancel_order(
+            2,
+            &mut user,
+            &Pubkey::default(),
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            0,
+            slot,
+            OrderActionExplanation::None,
+            None,
+            0,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(user.orders[0].status, OrderStatus::Canceled);
+        assert_eq!(user.orders[1].status, OrderStatus::Canceled);
+        assert_eq!(user.orders[2].status, OrderStatus::Canceled);
+        assert_eq!(user.perp_positions[0].open_orders, 0);
+        assert_eq!(user.perp_positions[0].open_bids, 0);
+        assert_eq!(user.open_orders, 0);
+    }
+}
+
+pub mod self_trade_prevention {
+    use std::str::FromStr;
+
+    use anchor_lang::prelude::{AccountLoader, Clock};
+
+    use crate::controller::orders::{fill_perp_order, fill_spot_order};
+    use crate::controller::position::PositionDirection;
+    use crate::create_anchor_account_info;
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, BASE_PRECISION_I64, BASE_PRECISION_U64, LAMPORTS_PER_SOL_I64,
+        LAMPORTS_PER_SOL_U64, PEG_PRECISION, PRICE_PRECISION_I64, PRICE_PRECISION_U64,
+        SPOT_BALANCE_PRECISION, SPOT_BALANCE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION,
+        SPOT_WEIGHT_PRECISION,
+    };
+    use crate::state::fill_mode::FillMode;
+    use crate::state::oracle::HistoricalOracleData;
+    use crate::state::oracle::OracleSource;
+    use crate::state::paused_operations::PerpOperation;
+    use crate::state::perp_market::{PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_fulfillment_params::TestFulfillmentParams;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::state::State;
+    use crate::state::user::{
+        MarketType, OrderExtBitFlag, OrderStatus, OrderType, SelfTradePreventionMode, SpotPosition,
+        User, UserStats,
+    };
+    use crate::state::user_map::{UserMap, UserStatsMap};
+    use crate::test_utils::*;
+    use crate::test_utils::{create_account_info, get_positions, get_pyth_price};
+    use crate::{create_account_info, QUOTE_PRECISION_I64};
+
+    use super::*;
+
+    fn get_taker_order(base_asset_amount: u64, slot: u64) -> Order {
+        Order {
+            market_index: 0,
+            order_id: 1,
+            status: OrderStatus::Open,
+            order_type: OrderType::Market,
+            direction: PositionDirection::Long,
+            market_type: MarketType::Perp,
+            base_asset_amount,
+            slot: slot - 1,
+            auction_start_price: 0,
+            auction_end_price: 100 * PRICE_PRECISION_I64,
+            auction_duration: 1,
+            price: 100 * PRICE_PRECISION_U64,
+            ..Order::default()
+        }
+    }
+
+    /// Fills the taker's order against a crossing maker order from a subaccount of the same authority
+    fn fill_perp_order_against_self(
+        self_trade_prevention_mode: SelfTradePreventionMode,
+        taker_order: Order,
+        taker_position: PerpPosition,
+        maker_base_asset_amount: u64,
+    ) -> (u64, User, User) {
+        let clock = Clock {
+            slot: 56,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 0,
+        };
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            paused_operations: PerpOperation::AmmFill as u8,
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                terminal_quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_slippage_ratio: 100,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 1000,
+                order_tick_size: 1,
+                oracle: oracle_price_key,
+                historical_oracle_data: HistoricalOracleData {
+                    last_oracle_price_twap: oracle_price.twap,
+                    last_oracle_price_twap_5min: oracle_price.twap,
+                    last_oracle_price: oracle_price.agg.price,
+                    ..HistoricalOracleData::default()
+                },
+                ..AMM::default()
+            },
+            margin_ratio_initial: 2000,
+            margin_ratio_maintenance: 1000,
+            status: MarketStatus::Active,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let authority = Pubkey::from_str("My11111111111111111111111111111111111111112").unwrap();
+
+        let mut user = User {
+            authority,
+            orders: get_orders(taker_order),
+            perp_positions: get_positions(taker_position),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            open_orders: 1,
+            self_trade_prevention_mode,
+            ..User::default()
+        };
+        create_anchor_account_info!(user, User, user_account_info);
+        let user_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&user_account_info).unwrap();
+
+        create_anchor_account_info!(UserStats::default(), UserStats, user_stats_account_info);
+        let user_stats_account_loader: AccountLoader<UserStats> =
+            AccountLoader::try_from(&user_stats_account_info).unwrap();
+
+        let maker_key = Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
+        let mut maker = User {
+            authority,
+            orders: get_orders(Order {
+                market_index: 0,
+                order_id: 1,
+                status: OrderStatus::Open,
+                order_type: OrderType::Limit,
+                market_type: MarketType::Perp,
+                direction: PositionDirection::Short,
+                base_asset_amount: maker_base_asset_amount,
+                slot: clock.slot - 3,
+                price: 100 * PRICE_PRECISION_U64,
+                post_only: true,
+                ..Order::default()
+            }),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 1,
+                open_asks: -(maker_base_asset_amount as i64),
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            open_orders: 1,
+            ..User::default()
+        };
+        create_anchor_account_info!(maker, &maker_key, User, maker_account_info);
+        let makers_and_referrers = UserMap::load_one(&maker_account_info).unwrap();
+
+        let mut maker_stats = UserStats {
+            authority,
+            ..UserStats::default()
+        };
+        create_anchor_account_info!(maker_stats, UserStats, maker_stats_account_info);
+        let maker_and_referrer_stats = UserStatsMap::load_one(&maker_stats_account_info).unwrap();
+
+        let filler_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
+        create_anchor_account_info!(User::default(), &filler_key, User, filler_account_info);
+        let filler_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&filler_account_info).unwrap();
+
+        create_anchor_account_info!(UserStats::default(), UserStats, filler_stats_account_info);
+        let filler_stats_account_loader: AccountLoader<UserStats> =
+            AccountLoader::try_from(&filler_stats_account_info).unwrap();
+
+        let state = State {
+            min_perp_auction_duration: 1,
+            default_market_order_time_in_force: 10,
+            ..State::default()
+        };
+
+        let (base_asset_amount, _) = fill_perp_order(
+            1,
+            &state,
+            &user_account_loader,
+            &user_stats_account_loader,
+            &spot_market_map,
+            &market_map,
+            &mut oracle_map,
+            &filler_account_loader,
+            &filler_stats_account_loader,
+            &makers_and_referrers,
+            &maker_and_referrer_stats,
+            None,
+            &clock,
+            FillMode::Fill,
+            &mut None,
+            false,
+            &[],
+        )
+        .unwrap();
+
+        let user_after = *user_account_loader.load().unwrap();
+        let maker_after = *makers_and_referrers.get_ref(&maker_key).unwrap();
+
+        (base_asset_amount, user_after, maker_after)
+    }
+
+    fn get_taker_position() -> PerpPosition {
+        PerpPosition {
+            market_index: 0,
+            open_orders: 1,
+            open_bids: BASE_PRECISION_I64,
+            ..PerpPosition::default()
+        }
+    }
+
+    #[test]
+    fn cancel_maker() {
+        let (base_asset_amount, taker, maker) = fill_perp_order_against_self(
+            SelfTradePreventionMode::CancelMaker,
+            get_taker_order(BASE_PRECISION_U64, 56),
+            get_taker_position(),
+            BASE_PRECISION_U64,
+        );
+
+        assert_eq!(base_asset_amount, 0);
+        assert_eq!(taker.orders[0].status, OrderStatus::Open);
+        assert_eq!(taker.perp_positions[0].open_bids, BASE_PRECISION_I64);
+        assert_eq!(maker.orders[0].status, OrderStatus::Canceled);
+        assert_eq!(maker.perp_positions[0].open_asks, 0);
+        assert_eq!(maker.perp_positions[0].open_orders, 0);
+    }
+
+    #[test]
+    fn cancel_taker() {
+        let (base_asset_amount, taker, maker) = fill_perp_order_against_self(
+            SelfTradePreventionMode::CancelTaker,
+            get_taker_order(BASE_PRECISION_U64, 56),
+            get_taker_position(),
+            BASE_PRECISION_U64,
+        );
+
+        assert_eq!(base_asset_amount, 0);
+        assert_eq!(taker.orders[0].status, OrderStatus::Canceled);
+        assert_eq!(taker.perp_positions[0].open_bids, 0);
+        assert_eq!(taker.perp_positions[0].open_orders, 0);
+        assert_eq!(maker.orders[0].status, OrderStatus::Open);
+        assert_eq!(maker.perp_positions[0].open_asks, -BASE_PRECISION_I64);
+    }
+
+    #[test]
+    fn cancel_both() {
+        let (base_asset_amount, taker, maker) = fill_perp_order_against_self(
+            SelfTradePreventionMode::CancelBoth,
+            get_taker_order(BASE_PRECISION_U64, 56),
+            get_taker_position(),
+            BASE_PRECISION_U64,
+        );
+
+        assert_eq!(base_asset_amount, 0);
+        assert_eq!(taker.orders[0].status, OrderStatus::Canceled);
+        assert_eq!(taker.perp_positions[0].open_bids, 0);
+        assert_eq!(maker.orders[0].status, OrderStatus::Canceled);
+        assert_eq!(maker.perp_positions[0].open_asks, 0);
+    }
+
+    #[test]
+    fn decrement_and_cancel() {
+        let (base_asset_amount, taker, maker) = fill_perp_order_against_self(
+            SelfTradePreventionMode::DecrementAndCancel,
+            get_taker_order(BASE_PRECISION_U64, 56),
+            get_taker_position(),
+            2 * BASE_PRECISION_U64,
+        );
+
+        assert_eq!(base_asset_amount, 0);
+        assert_eq!(taker.orders[0].status, OrderStatus::Canceled);
+        assert_eq!(taker.perp_positions[0].open_bids, 0);
+        assert_eq!(maker.orders[0].status, OrderStatus::Open);
+        assert_eq!(maker.orders[0].base_asset_amount, BASE_PRECISION_U64);
+        assert_eq!(maker.perp_positions[0].open_asks, -BASE_PRECISION_I64);
+    }
+
+    #[test]
+    fn decrement_and_cancel_close_position() {
+        let taker_order = Order {
+            reduce_only: true,
+            oco_group_id_and_bit_flags: OrderExtBitFlag::ClosePosition as u8,
+            ..get_taker_order(u64::MAX, 56)
+        };
+        let taker_position = PerpPosition {
+            market_index: 0,
+            base_asset_amount: -BASE_PRECISION_I64,
+            quote_asset_amount: 100 * QUOTE_PRECISION_I64,
+            quote_entry_amount: 100 * QUOTE_PRECISION_I64,
+            quote_break_even_amount: 100 * QUOTE_PRECISION_I64,
+            open_orders: 1,
+            ..PerpPosition::default()
+        };
+
+        let (base_asset_amount, taker, maker) = fill_perp_order_against_self(
+            SelfTradePreventionMode::DecrementAndCancel,
+            taker_order,
+            taker_position,
+            2 * BASE_PRECISION_U64,
+        );
+
+        // the close order is only as big as the position it closes
+        assert_eq!(base_asset_amount, 0);
+        assert_eq!(taker.orders[0].status, OrderStatus::Canceled);
+        assert_eq!(taker.perp_positions[0].open_orders, 0);
+        assert_eq!(maker.orders[0].status, OrderStatus::Open);
+        assert_eq!(maker.orders[0].base_asset_amount, BASE_PRECISION_U64);
+        assert_eq!(maker.perp_positions[0].open_asks, -BASE_PRECISION_I64);
+    }
+
+    /// Fills a spot bid against a crossing ask from a subaccount of the same authority
+    fn fill_spot_order_against_self(
+        self_trade_prevention_mode: SelfTradePreventionMode,
+        taker_base_asset_amount: u64,
+        maker_base_asset_amount: u64,
+    ) -> (u64, User, User) {
+        let clock = Clock {
+            slot: 11,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 11,
+        };
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let perp_market_map = PerpMarketMap::empty();
+
+        let mut base_market = SpotMarket {
+            deposit_balance: 10 * SPOT_BALANCE_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_price(100 * PRICE_PRECISION_I64),
+            ..SpotMarket::default_base_market()
+        };
+        create_anchor_account_info!(base_market, SpotMarket, base_market_account_info);
+        let mut quote_market = SpotMarket {
+            deposit_balance: 1000 * SPOT_BALANCE_PRECISION,
+            ..SpotMarket::default_quote_market()
+        };
+        create_anchor_account_info!(quote_market, SpotMarket, quote_market_account_info);
+        let spot_market_map = SpotMarketMap::load_multiple(
+            vec![&base_market_account_info, &quote_market_account_info],
+            true,
+        )
+        .unwrap();
+
+        let authority = Pubkey::from_str("My11111111111111111111111111111111111111112").unwrap();
+
+        let mut taker_spot_positions = [SpotPosition::default(); 8];
+        taker_spot_positions[0] = SpotPosition {
+            market_index: 0,
+            scaled_balance: 1000 * SPOT_BALANCE_PRECISION_U64,
+            balance_type: SpotBalanceType::Deposit,
+            ..SpotPosition::default()
+        };
+        taker_spot_positions[1] = SpotPosition {
+            market_index: 1,
+            open_orders: 1,
+            open_bids: taker_base_asset_amount as i64,
+            ..SpotPosition::default()
+        };
+        let mut taker = User {
+            authority,
+            orders: get_orders(Order {
+                order_id: 1,
+                market_index: 1,
+                market_type: MarketType::Spot,
+                order_type: OrderType::Limit,
+                status: OrderStatus::Open,
+                direction: PositionDirection::Long,
+                base_asset_amount: taker_base_asset_amount,
+                slot: 0,
+                price: 100 * PRICE_PRECISION_U64,
+                ..Order::default()
+            }),
+            spot_positions: taker_spot_positions,
+            open_orders: 1,
+            self_trade_prevention_mode,
+            ..User::default()
+        };
+        create_anchor_account_info!(taker, User, taker_account_info);
+        let taker_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&taker_account_info).unwrap();
+
+        create_anchor_account_info!(UserStats::default(), UserStats, taker_stats_account_info);
+        let taker_stats_account_loader: AccountLoader<UserStats> =
+            AccountLoader::try_from(&taker_stats_account_info).unwrap();
+
+        let mut maker_spot_positions = [SpotPosition::default(); 8];
+        maker_spot_positions[1] = SpotPosition {
+            market_index: 1,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 10 * SPOT_BALANCE_PRECISION_U64,
+            open_orders: 1,
+            open_asks: -(maker_base_asset_amount as i64),
+            ..SpotPosition::default()
+        };
+        let maker_key = Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
+        let mut maker = User {
+            authority,
+            orders: get_orders(Order {
+                order_id: 1,
+                market_index: 1,
+                post_only: true,
+                market_type: MarketType::Spot,
+                order_type: OrderType::Limit,
+                status: OrderStatus::Open,
+                direction: PositionDirection::Short,
+                base_asset_amount: maker_base_asset_amount,
+                price: 100 * PRICE_PRECISION_U64,
+                ..Order::default()
+            }),
+            spot_positions: maker_spot_positions,
+            open_orders: 1,
+            ..User::default()
+        };
+        create_anchor_account_info!(maker, &maker_key, User, maker_account_info);
+        let makers_and_referrers = UserMap::load_one(&maker_account_info).unwrap();
+
+        create_anchor_account_info!(UserStats::default(), UserStats, maker_stats_account_info);
+        let maker_and_referrer_stats = UserStatsMap::load_one(&maker_stats_account_info).unwrap();
+
+        let filler_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
+        create_anchor_account_info!(User::default(), &filler_key, User, filler_account_info);
+        let filler_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&filler_account_info).unwrap();
+
+        create_anchor_account_info!(UserStats::default(), UserStats, filler_stats_account_info);
+        let filler_stats_account_loader: AccountLoader<UserStats> =
+            AccountLoader::try_from(&filler_stats_account_info).unwrap();
+
+        let state = State {
+            default_spot_auction_duration: 1,
+            ..State::default()
+        };
+
+        let base_asset_amount = fill_spot_order(
+            1,
+            &state,
+            &taker_account_loader,
+            &taker_stats_account_loader,
+            &spot_market_map,
+            &perp_market_map,
+            &mut oracle_map,
+            &filler_account_loader,
+            &filler_stats_account_loader,
+            &makers_and_referrers,
+            &maker_and_referrer_stats,
+            None,
+            &clock,
+            &mut TestFulfillmentParams {},
+        )
+        .unwrap();
+
+        let taker_after = *taker_account_loader.load().unwrap();
+        let maker_after = *makers_and_referrers.get_ref(&maker_key).unwrap();
+
+        (base_asset_amount, taker_after, maker_after)
+    }
+
+    #[test]
+    fn spot_cancel_maker() {
+        let (base_asset_amount, taker, maker) = fill_spot_order_against_self(
+            SelfTradePreventionMode::CancelMaker,
+            LAMPORTS_PER_SOL_U64,
+            LAMPORTS_PER_SOL_U64,
+        );
+
+        assert_eq!(base_asset_amount, 0);
+        assert_eq!(taker.orders[0].status, OrderStatus::Open);
+        assert_eq!(maker.orders[0].status, OrderStatus::Canceled);
+        assert_eq!(maker.spot_positions[1].open_asks, 0);
+    }
+
+    #[test]
+    fn spot_cancel_taker() {
+        let (base_asset_amount, taker, maker) = fill_spot_order_against_self(
+            SelfTradePreventionMode::CancelTaker,
+            LAMPORTS_PER_SOL_U64,
+            LAMPORTS_PER_SOL_U64,
+        );
+
+        assert_eq!(base_asset_amount, 0);
+        assert_eq!(taker.orders[0].status, OrderStatus::Canceled);
+        assert_eq!(taker.spot_positions[1].open_bids, 0);
+        assert_eq!(maker.orders[0].status, OrderStatus::Open);
+    }
+
+    #[test]
+    fn spot_cancel_both() {
+        let (base_asset_amount, taker, maker) = fill_spot_order_against_self(
+            SelfTradePreventionMode::CancelBoth,
+            LAMPORTS_PER_SOL_U64,
+            LAMPORTS_PER_SOL_U64,
+        );
+
+        assert_eq!(base_asset_amount, 0);
+        assert_eq!(taker.orders[0].status, OrderStatus::Canceled);
+        assert_eq!(maker.orders[0].status, OrderStatus::Canceled);
+    }
+
+    #[test]
+    fn spot_decrement_and_cancel() {
+        let (base_asset_amount, taker, maker) = fill_spot_order_against_self(
+            SelfTradePreventionMode::DecrementAndCancel,
+            2 * LAMPORTS_PER_SOL_U64,
+            LAMPORTS_PER_SOL_U64,
+        );
+
+        assert_eq!(base_asset_amount, 0);
+        assert_eq!(taker.orders[0].status, OrderStatus::Open);
+        assert_eq!(taker.orders[0].base_asset_amount, LAMPORTS_PER_SOL_U64);
+        assert_eq!(taker.spot_positions[1].open_bids, LAMPORTS_PER_SOL_I64);
+        assert_eq!(maker.orders[0].status, OrderStatus::Canceled);
+        assert_eq!(maker.spot_positions[1].open_asks, 0);
+    }
+}
+
+pub mod spread_order {
+    use std::str::FromStr;
+
+    use anchor_lang::prelude::{AccountLoader, Clock};
+
+    use crate::controller::orders::{
+        cancel_order, fill_perp_order, fill_spread_order, link_spread_order_legs,
+    };
+    use crate::controller::position::PositionDirection;
+    use crate::create_anchor_account_info;
+    use crate::error::{DriftResult, ErrorCode};
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, BASE_PRECISION_I64, BASE_PRECISION_U64, PEG_PRECISION,
+        PRICE_PRECISION_U64, QUOTE_PRECISION_I64, SPOT_BALANCE_PRECISION_U64,
+        SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
+    };
+    use crate::state::events::OrderActionExplanation;
+    use crate::state::fill_mode::FillMode;
+    use crate::state::oracle::{HistoricalOracleData, OracleSource};
+    use crate::state::paused_operations::PerpOperation;
+    use crate::state::perp_market::{PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::state::State;
+    use crate::state::user::{OrderStatus, OrderType, SpotPosition, User, UserStats};
+    use crate::state::user_map::{UserMap, UserStatsMap};
+    use crate::test_utils::*;
+    use crate::test_utils::{create_account_info, get_pyth_price, get_spot_positions};
+    use crate::{create_account_info, get_orders};
+
+    use super::*;
+
+    fn get_market(market_index: u16, oracle: Pubkey, oracle_price: i64) -> PerpMarket {
+        PerpMarket {
+            market_index,
+            paused_operations: PerpOperation::AmmFill as u8,
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                terminal_quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_slippage_ratio: 100,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 1000,
+                order_tick_size: 1,
+                oracle,
+                historical_oracle_data: HistoricalOracleData {
+                    last_oracle_price_twap: oracle_price,
+                    last_oracle_price_twap_5min: oracle_price,
+                    last_oracle_price: oracle_price,
+                    ..HistoricalOracleData::default()
+                },
+                ..AMM::default()
+            },
+            margin_ratio_initial: 2000,
+            margin_ratio_maintenance: 1000,
+            status: MarketStatus::Active,
+            ..PerpMarket::default()
+        }
+    }
+
+    fn get_leg(order_id: u32, market_index: u16, direction: PositionDirection) -> Order {
+        Order {
+            market_index,
+            order_id,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            direction,
+            market_type: MarketType::Perp,
+            base_asset_amount: BASE_PRECISION_U64,
+            slot: 50,
+            price: match direction {
+                PositionDirection::Long => 101 * PRICE_PRECISION_U64,
+                PositionDirection::Short => 99 * PRICE_PRECISION_U64,
+            },
+            ..Order::default()
+        }
+    }
+
+    fn get_user_with_spread(max_net_quote_asset_amount: i64) -> User {
+        let mut perp_positions = [PerpPosition::default(); 8];
+        perp_positions[0] = PerpPosition {
+            market_index: 0,
+            open_orders: 1,
+            open_bids: BASE_PRECISION_I64,
+            ..PerpPosition::default()
+        };
+        perp_positions[1] = PerpPosition {
+            market_index: 1,
+            open_orders: 1,
+            open_asks: -BASE_PRECISION_I64,
+            ..PerpPosition::default()
+        };
+
+        let mut user = User {
+            authority: Pubkey::from_str("My11111111111111111111111111111111111111112").unwrap(),
+            orders: get_orders!(
+                get_leg(1, 0, PositionDirection::Long),
+                get_leg(2, 1, PositionDirection::Short)
+            ),
+            perp_positions,
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            open_orders: 2,
+            next_order_id: 3,
+            ..User::default()
+        };
+
+        link_spread_order_legs(&mut user, &[1, 2], max_net_quote_asset_amount).unwrap();
+
+        user
+    }
+
+    /// Fills the user's spread against a maker quoting both markets at 100, or only the first
+    fn fill_spread(
+        max_net_quote_asset_amount: i64,
+        maker_quotes_both_markets: bool,
+    ) -> (DriftResult<i64>, User) {
+        let clock = Clock {
+            slot: 56,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 0,
+        };
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let mut market_0 = get_market(0, oracle_price_key, oracle_price.agg.price);
+        create_anchor_account_info!(market_0, PerpMarket, market_0_account_info);
+        let mut market_1 = get_market(1, oracle_price_key, oracle_price.agg.price);
+        create_anchor_account_info!(market_1, PerpMarket, market_1_account_info);
+        let market_map = PerpMarketMap::load_multiple(
+            vec![&market_0_account_info, &market_1_account_info],
+            true,
+        )
+        .unwrap();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let mut user = get_user_with_spread(max_net_quote_asset_amount);
+        create_anchor_account_info!(user, User, user_account_info);
+        let user_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&user_account_info).unwrap();
+
+        create_anchor_account_info!(UserStats::default(), UserStats, user_stats_account_info);
+        let user_stats_account_loader: AccountLoader<UserStats> =
+            AccountLoader::try_from(&user_stats_account_info).unwrap();
+
+        let maker_ask = Order {
+            market_index: 0,
+            order_id: 1,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Short,
+            base_asset_amount: BASE_PRECISION_U64,
+            slot: clock.slot - 3,
+            price: 100 * PRICE_PRECISION_U64,
+            post_only: true,
+            ..Order::default()
+        };
+        let maker_bid = Order {
+            market_index: 1,
+            order_id: 2,
+            direction: PositionDirection::Long,
+            ..maker_ask
+        };
+        let mut maker_perp_positions = [PerpPosition::default(); 8];
+        maker_perp_positions[0] = PerpPosition {
+            market_index: 0,
+            open_orders: 1,
+            open_asks: -BASE_PRECISION_I64,
+            ..PerpPosition::default()
+        };
+        if maker_quotes_both_markets {
+            maker_perp_positions[1] = PerpPosition {
+                market_index: 1,
+                open_orders: 1,
+                open_bids: BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            };
+        }
+
+        let maker_key = Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
+        let mut maker = User {
+            authority: Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap(),
+            orders: if maker_quotes_both_markets {
+                get_orders!(maker_ask, maker_bid)
+            } else {
+                get_orders(maker_ask)
+            },
+            perp_positions: maker_perp_positions,
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
+        create_anchor_account_info!(maker, &maker_key, User, maker_account_info);
+        let makers_and_referrers = UserMap::load_one(&maker_account_info).unwrap();
+
+        create_anchor_account_info!(UserStats::default(), UserStats, maker_stats_account_info);
+        let maker_and_referrer_stats = UserStatsMap::load_one(&maker_stats_account_info).unwrap();
+
+        let filler_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
+        create_anchor_account_info!(User::default(), &filler_key, User, filler_account_info);
+        let filler_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&filler_account_info).unwrap();
+
+        create_anchor_account_info!(UserStats::default(), UserStats, filler_stats_account_info);
+        let filler_stats_account_loader: AccountLoader<UserStats> =
+            AccountLoader::try_from(&filler_stats_account_info).unwrap();
+
+        let state = State {
+            min_perp_auction_duration: 1,
+            default_market_order_time_in_force: 10,
+            ..State::default()
+        };
+
+        let result = fill_spread_order(
+            1,
+            &state,
+            &user_account_loader,
+            &user_stats_account_loader,
+            &spot_market_map,
+            &market_map,
+            &mut oracle_map,
+            &filler_account_loader,
+            &filler_stats_account_loader,
+            &makers_and_referrers,
+            &maker_and_referrer_stats,
+            &clock,
+            FillMode::Fill,
+            None,
+            &mut None,
+            false,
+            &[],
+        );
+
+        let user_after = *user_account_loader.load().unwrap();
+
+        (result, user_after)
+    }
+
+    #[test]
+    fn link_legs() {
+        let user = get_user_with_spread(QUOTE_PRECISION_I64);
+
+        for order in user.orders[..2].iter() {
+            assert!(order.is_spread_leg());
+            assert_eq!(order.oco_group_id(), 1);
+            assert_eq!(
+                order.get_spread_max_net_quote_asset_amount(),
+                QUOTE_PRECISION_I64
+            );
+        }
+    }
+
+    #[test]
+    fn fills_all_legs() {
+        let (result, user) = fill_spread(QUOTE_PRECISION_I64, true);
+
+        // bought at 100 and sold at 100
+        assert_eq!(result, Ok(0));
+        assert_eq!(user.orders[0].status, OrderStatus::Filled);
+        assert_eq!(user.orders[1].status, OrderStatus::Filled);
+        assert_eq!(user.perp_positions[0].base_asset_amount, BASE_PRECISION_I64);
+        assert_eq!(
+            user.perp_positions[1].base_asset_amount,
+            -BASE_PRECISION_I64
+        );
+    }
+
+    #[test]
+    fn fails_when_a_leg_is_not_filled() {
+        let (result, _) = fill_spread(QUOTE_PRECISION_I64, false);
+
+        assert_eq!(result, Err(ErrorCode::SpreadOrderNotFilled));
+    }
+
+    #[test]
+    fn fails_above_net_limit() {
+        let (result, _) = fill_spread(-QUOTE_PRECISION_I64, true);
+
+        assert_eq!(result, Err(ErrorCode::SpreadOrderNotFilled));
+    }
+
+    #[test]
+    fn legs_cant_be_filled_alone() {
+        let clock = Clock {
+            slot: 56,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 0,
+        };
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let mut market = get_market(0, oracle_price_key, oracle_price.agg.price);
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let spot_market_map = SpotMarketMap::empty();
+
+        let mut user = get_user_with_spread(QUOTE_PRECISION_I64);
+        create_anchor_account_info!(user, User, user_account_info);
+        let user_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&user_account_info).unwrap();
+
+        create_anchor_account_info!(UserStats::default(), UserStats, user_stats_account_info);
+        let user_stats_account_loader: AccountLoader<UserStats> =
+            AccountLoader::try_from(&user_stats_account_info).unwrap();
+
+        let filler_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
+        create_anchor_account_info!(User::default(), &filler_key, User, filler_account_info);
+        let filler_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&filler_account_info).unwrap();
+
+        create_anchor_account_info!(UserStats::default(), UserStats, filler_stats_account_info);
+        let filler_stats_account_loader: AccountLoader<UserStats> =
+            AccountLoader::try_from(&filler_stats_account_info).unwrap();
+
+        let result = fill_perp_order(
+            1,
+            &State::default(),
+            &user_account_loader,
+            &user_stats_account_loader,
+            &spot_market_map,
+            &market_map,
+            &mut oracle_map,
+            &filler_account_loader,
+            &filler_stats_account_loader,
+            &UserMap::empty(),
+            &UserStatsMap::empty(),
+            None,
+            &clock,
+            FillMode::Fill,
+            &mut None,
+            false,
+            &[],
+        );
+
+        assert_eq!(result, Err(ErrorCode::InvalidOrder));
+    }
+
+    #[test]
+    fn canceling_a_leg_cancels_the_spread() {
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, 56, None).unwrap();
+
+        let mut market_0 = get_market(0, oracle_price_key, oracle_price.agg.price);
+        create_anchor_account_info!(market_0, PerpMarket, market_0_account_info);
+        let mut market_1 = get_market(1, oracle_price_key, oracle_price.agg.price);
+        create_anchor_account_info!(market_1, PerpMarket, market_1_account_info);
+        let market_map = PerpMarketMap::load_multiple(
+            vec![&market_0_account_info, &market_1_account_info],
+            true,
+        )
+        .unwrap();
+
+        let mut user = get_user_with_spread(QUOTE_PRECISION_I64);
+
+        cancel_order(
+            0,
+            &mut user,
+            &Pubkey::default(),
+            &market_map,
+            &SpotMarketMap::empty(),
+            &mut oracle_map,
+            0,
+            56,
+            OrderActionExplanation::None,
+            None,
+            0,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(user.orders[0].status, OrderStatus::Canceled);
+        assert_eq!(user.orders[1].status, OrderStatus::Canceled);
+        assert_eq!(user.perp_positions[0].open_orders, 0);
+        assert_eq!(user.perp_positions[1].open_orders, 0);
+        assert_eq!(user.perp_positions[1].open_asks, 0);
+        assert_eq!(user.open_orders, 0);
+    }
+}
+
+pub mod cancel_orders_after_heartbeat_expired {
+    use std::str::FromStr;
+
+    use anchor_lang::prelude::{AccountLoader, Clock};
+
+    use crate::controller::orders::cancel_orders_after_heartbeat_expired;
+    use crate::controller::position::PositionDirection;
+    use crate::create_account_info;
+    use crate::create_anchor_account_info;
+    use crate::error::{DriftResult, ErrorCode};
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, BASE_PRECISION_I64, BASE_PRECISION_U64, PEG_PRECISION,
+        PRICE_PRECISION_U64, SPOT_BALANCE_PRECISION, SPOT_BALANCE_PRECISION_U64,
+        SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
+    };
+    use crate::state::oracle::HistoricalOracleData;
+    use crate::state::oracle::OracleSource;
+    use crate::state::perp_market::{PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::state::State;
+    use crate::state::user::{OrderStatus, OrderType, SpotPosition, User};
+    use crate::test_utils::*;
+    use crate::test_utils::{
+        create_account_info, get_positions, get_pyth_price, get_spot_positions,
+    };
+
+    use super::*;
+
+    fn cancel_after_heartbeat(
+        last_heartbeat_ts: u32,
+        now: i64,
+        extension_open_orders: u8,
+    ) -> (DriftResult, User, User) {
+        let clock = Clock {
+            slot: 6,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: now,
+        };
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                terminal_quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_slippage_ratio: 100,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 1000,
+                order_tick_size: 1,
+                oracle: oracle_price_key,
+                max_spread: 1000,
+                historical_oracle_data: HistoricalOracleData {
+                    last_oracle_price_twap: oracle_price.twap,
+                    last_oracle_price_twap_5min: oracle_price.twap,
+                    last_oracle_price: oracle_price.agg.price,
+                    ..HistoricalOracleData::default()
+                },
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Active,
+            ..PerpMarket::default()
+        };
+        market.amm.max_base_asset_reserve = u128::MAX;
+        market.amm.min_base_asset_reserve = 0;
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut usdc_spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            deposit_balance: SPOT_BALANCE_PRECISION,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(usdc_spot_market, SpotMarket, usdc_spot_market_account_info);
+        let spot_market_map =
+            SpotMarketMap::load_one(&usdc_spot_market_account_info, true).unwrap();
+
+        let mut orders = [Order::default(); 32];
+        orders[0] = Order {
+            market_index: 0,
+            order_id: 1,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Long,
+            base_asset_amount: BASE_PRECISION_U64,
+            price: 99 * PRICE_PRECISION_U64,
+            ..Order::default()
+        };
+        orders[1] = Order {
+            market_index: 0,
+            order_id: 2,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Short,
+            base_asset_amount: BASE_PRECISION_U64,
+            price: 101 * PRICE_PRECISION_U64,
+            ..Order::default()
+        };
+
+        let mut user = User {
+            authority: Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap(), // different authority than filler
+            orders,
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 2,
+                open_bids: BASE_PRECISION_I64,
+                open_asks: -BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            open_orders: 2 + extension_open_orders,
+            extension_open_orders,
+            last_heartbeat_ts,
+            heartbeat_timeout: 60,
+            ..User::default()
+        };
+        create_anchor_account_info!(user, User, user_account_info);
+        let user_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&user_account_info).unwrap();
+
+        let filler_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
+        create_anchor_account_info!(User::default(), &filler_key, User, filler_account_info);
+        let filler_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&filler_account_info).unwrap();
+
+        let state = State {
+            perp_fee_structure: FeeStructure {
+                flat_filler_fee: 10000,
+                ..FeeStructure::test_default()
+            },
+            ..State::default()
+        };
+
+        let result = cancel_orders_after_heartbeat_expired(
+            &state,
+            &user_account_loader,
+            &spot_market_map,
+            &market_map,
+            &mut oracle_map,
+            &filler_account_loader,
+            &clock,
+            &mut None,
+        );
+
+        let user = *user_account_loader.load().unwrap();
+        let filler = *filler_account_loader.load().unwrap();
+
+        (result, user, filler)
+    }
+
+    #[test]
+    fn cancels_orders_and_pays_keeper() {
+        let (result, user, filler) = cancel_after_heartbeat(100, 161, 0);
+        assert_eq!(result, Ok(()));
+
+        assert!(user.orders[0].is_available());
+        assert!(user.orders[1].is_available());
+        assert_eq!(user.open_orders, 0);
+        assert_eq!(user.perp_positions[0].open_orders, 0);
+        assert_eq!(user.perp_positions[0].open_bids, 0);
+        assert_eq!(user.perp_positions[0].open_asks, 0);
+
+        // flat filler fee for each canceled order
+        assert_eq!(filler.cumulative_spot_fees, 20000);
+        assert_eq!(user.cumulative_spot_fees, -20000);
+        assert_eq!(filler.spot_positions[0].market_index, 0);
+        assert_eq!(
+            filler.spot_positions[0].balance_type,
+            SpotBalanceType::Deposit
+        );
+        assert_eq!(filler.spot_positions[0].scaled_balance, 20000000);
+        assert_eq!(user.spot_positions[0].scaled_balance, 980000000);
+    }
+
+    #[test]
+    fn fails_before_expiry() {
+        let (result, user, filler) = cancel_after_heartbeat(100, 160, 0);
+        assert_eq!(result, Err(ErrorCode::HeartbeatNotExpired));
+
+        assert_eq!(user.orders[0].status, OrderStatus::Open);
+        assert_eq!(user.orders[1].status, OrderStatus::Open);
+        assert_eq!(filler.cumulative_spot_fees, 0);
+    }
+
+    #[test]
+    fn fails_without_orders_extension() {
+        let (result, user, filler) = cancel_after_heartbeat(100, 161, 1);
+        assert_eq!(result, Err(ErrorCode::UserOrdersExtensionRequired));
+
+        // orders in User.orders aren't canceled without the parked ones either
+        assert_eq!(user.orders[0].status, OrderStatus::Open);
+        assert_eq!(user.orders[1].status, OrderStatus::Open);
+        assert_eq!(filler.cumulative_spot_fees, 0);
+    }
+}
+
+pub mod cancel_orders_by_filter {
+    use std::cell::{RefCell, RefMut};
+    use std::str::FromStr;
+
+    use anchor_lang::prelude::Clock;
+
+    use crate::controller::orders::cancel_orders_by_filter;
+    use crate::controller::position::PositionDirection;
+    use crate::create_account_info;
+    use crate::create_anchor_account_info;
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, BASE_PRECISION_I64, BASE_PRECISION_U64, PEG_PRECISION,
+        PRICE_PRECISION_U64, SPOT_BALANCE_PRECISION, SPOT_CUMULATIVE_INTEREST_PRECISION,
+        SPOT_WEIGHT_PRECISION,
+    };
+    use crate::state::oracle::HistoricalOracleData;
+    use crate::state::oracle::OracleSource;
+    use crate::state::order_params::CancelOrdersParams;
+    use crate::state::perp_market::{PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::SpotMarket;
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::user::{OrderStatus, OrderTriggerCondition, OrderType, User};
+    use crate::state::user_orders_extension::{
+        UserOrdersExtensionFixed, UserOrdersExtensionZeroCopyMut,
+    };
+    use crate::test_utils::*;
+    use crate::test_utils::{create_account_info, get_positions, get_pyth_price};
+
+    use super::*;
+
+    #[test]
+    fn price_range_uses_effective_price() {
+        let clock = Clock {
+            slot: 6,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 0,
+        };
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                terminal_quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_slippage_ratio: 100,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 1000,
+                order_tick_size: 1,
+                oracle: oracle_price_key,
+                max_spread: 1000,
+                historical_oracle_data: HistoricalOracleData {
+                    last_oracle_price_twap: oracle_price.twap,
+                    last_oracle_price_twap_5min: oracle_price.twap,
+                    last_oracle_price: oracle_price.agg.price,
+                    ..HistoricalOracleData::default()
+                },
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Active,
+            ..PerpMarket::default()
+        };
+        market.amm.max_base_asset_reserve = u128::MAX;
+        market.amm.min_base_asset_reserve = 0;
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut usdc_spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            deposit_balance: SPOT_BALANCE_PRECISION,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(usdc_spot_market, SpotMarket, usdc_spot_market_account_info);
+        let spot_market_map =
+            SpotMarketMap::load_one(&usdc_spot_market_account_info, true).unwrap();
+
+        let order = Order {
+            market_index: 0,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            base_asset_amount: BASE_PRECISION_U64,
+            ..Order::default()
+        };
+
+        let mut orders = [Order::default(); 32];
+        orders[0] = Order {
+            order_id: 1,
+            direction: PositionDirection::Long,
+            price: 99 * PRICE_PRECISION_U64,
+            ..order
+        };
+        // oracle offset ask at 101
+        orders[1] = Order {
+            order_id: 2,
+            direction: PositionDirection::Short,
+            oracle_price_offset: PRICE_PRECISION_U64 as i32,
+            ..order
+        };
+        // stop that hasn't triggered is compared by its trigger price
+        orders[2] = Order {
+            order_id: 3,
+            order_type: OrderType::TriggerLimit,
+            direction: PositionDirection::Short,
+            price: 100 * PRICE_PRECISION_U64,
+            trigger_price: 90 * PRICE_PRECISION_U64,
+            trigger_condition: OrderTriggerCondition::Below,
+            ..order
+        };
+        orders[3] = Order {
+            order_id: 4,
+            direction: PositionDirection::Long,
+            price: 110 * PRICE_PRECISION_U64,
+            ..order
+        };
+
+        let mut user = User {
+            orders,
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 5,
+                open_bids: 2 * BASE_PRECISION_I64,
+                open_asks: -2 * BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            open_orders: 5,
+            extension_open_orders: 1,
+            ..User::default()
+        };
+
+        let fixed = RefCell::new(UserOrdersExtensionFixed {
+            len: 1,
+            ..UserOrdersExtensionFixed::default()
+        });
+        let data = RefCell::new([Order {
+            order_id: 5,
+            direction: PositionDirection::Short,
+            price: 102 * PRICE_PRECISION_U64,
+            ..order
+        }]);
+        let orders_extension = UserOrdersExtensionZeroCopyMut {
+            fixed: fixed.borrow_mut(),
+            data: RefMut::map(data.borrow_mut(), |orders| {
+                bytemuck::cast_slice_mut(&mut orders[..])
+            }),
+        };
+        let mut orders_extension = Some(orders_extension);
+
+        let params = CancelOrdersParams {
+            min_price: Some(95 * PRICE_PRECISION_U64),
+            max_price: Some(105 * PRICE_PRECISION_U64),
+            ..CancelOrdersParams::default()
+        };
+
+        let canceled_order_ids = cancel_orders_by_filter(
+            &mut user,
+            &Pubkey::default(),
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            clock.unix_timestamp,
+            clock.slot,
+            &params,
+            &mut orders_extension,
+        )
+        .unwrap();
+
+        assert_eq!(canceled_order_ids, vec![1, 2, 5]);
+        assert!(user.orders[0].is_available());
+        assert!(user.orders[1].is_available());
+        assert_eq!(user.orders[2].status, OrderStatus::Open);
+        assert_eq!(user.orders[3].status, OrderStatus::Open);
+
+        let orders_extension = orders_extension.unwrap();
+        assert!(orders_extension.get_order(0).unwrap().is_available());
+        assert_eq!(user.extension_open_orders, 0);
+
+        assert_eq!(user.open_orders, 2);
+        assert_eq!(user.perp_positions[0].open_orders, 2);
+        assert_eq!(user.perp_positions[0].open_bids, BASE_PRECISION_I64);
+        assert_eq!(user.perp_positions[0].open_asks, 0);
+    }
+}
+
+pub mod modify_order_amend {
+    use std::str::FromStr;
+
+    use anchor_lang::prelude::{AccountLoader, Clock};
+
+    use crate::controller::orders::{modify_order, ModifyOrderId};
+    use crate::controller::position::PositionDirection;
+    use crate::create_account_info;
+    use crate::create_anchor_account_info;
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, BASE_PRECISION_I64, BASE_PRECISION_U64, PEG_PRECISION,
+        PRICE_PRECISION_U64, SPOT_BALANCE_PRECISION, SPOT_CUMULATIVE_INTEREST_PRECISION,
+    };
+    use crate::state::oracle::HistoricalOracleData;
+    use crate::state::order_params::ModifyOrderParams;
+    use crate::state::perp_market::{PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::SpotMarket;
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::state::State;
+    use crate::state::user::{OrderStatus, OrderTriggerCondition, OrderType, SpotPosition, User};
+    use crate::test_utils::*;
+    use crate::test_utils::{
+        create_account_info, get_positions, get_pyth_price, get_spot_positions,
+    };
+
+    use super::*;
+
+    fn amend(
+        order: Order,
+        perp_position: PerpPosition,
+        spot_position: SpotPosition,
+        modify_order_params: ModifyOrderParams,
+    ) -> User {
+        let clock = Clock {
+            slot: 10,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 100,
+        };
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                terminal_quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_slippage_ratio: 100,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 1000,
+                order_tick_size: 1,
+                oracle: oracle_price_key,
+                max_spread: 1000,
+                historical_oracle_data: HistoricalOracleData {
+                    last_oracle_price_twap: oracle_price.twap,
+                    last_oracle_price_twap_5min: oracle_price.twap,
+                    last_oracle_price: oracle_price.agg.price,
+                    ..HistoricalOracleData::default()
+                },
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Active,
+            ..PerpMarket::default()
+        };
+        market.amm.max_base_asset_reserve = u128::MAX;
+        market.amm.min_base_asset_reserve = 0;
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut usdc_spot_market = SpotMarket {
+            deposit_balance: SPOT_BALANCE_PRECISION,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            ..SpotMarket::default_quote_market()
+        };
+        create_anchor_account_info!(usdc_spot_market, SpotMarket, usdc_spot_market_account_info);
+        let mut sol_spot_market = SpotMarket {
+            market_index: 1,
+            deposit_balance: SPOT_BALANCE_PRECISION,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            oracle: oracle_price_key,
+            ..SpotMarket::default_base_market()
+        };
+        create_anchor_account_info!(sol_spot_market, SpotMarket, sol_spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_multiple(
+            vec![
+                &usdc_spot_market_account_info,
+                &sol_spot_market_account_info,
+            ],
+            true,
+        )
+        .unwrap();
+
+        let mut user = User {
+            orders: get_orders(order),
+            perp_positions: get_positions(perp_position),
+            spot_positions: get_spot_positions(spot_position),
+            open_orders: 1,
+            ..User::default()
+        };
+        create_anchor_account_info!(user, User, user_account_info);
+        let user_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&user_account_info).unwrap();
+
+        modify_order(
+            ModifyOrderId::OrderId(1),
+            modify_order_params,
+            &user_account_loader,
+            &State::default(),
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &clock,
+        )
+        .unwrap();
+
+        let user = user_account_loader.load().unwrap();
+        *user
+    }
+
+    #[test]
+    fn perp_size_decrease_keeps_slot() {
+        let order = Order {
+            market_index: 0,
+            order_id: 1,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Long,
+            base_asset_amount: 3 * BASE_PRECISION_U64,
+            base_asset_amount_filled: BASE_PRECISION_U64,
+            price: 99 * PRICE_PRECISION_U64,
+            slot: 1,
+            ..Order::default()
+        };
+        let perp_position = PerpPosition {
+            market_index: 0,
+            base_asset_amount: BASE_PRECISION_I64,
+            open_orders: 1,
+            open_bids: 2 * BASE_PRECISION_I64,
+            ..PerpPosition::default()
+        };
+
+        let user = amend(
+            order,
+            perp_position,
+            SpotPosition::default(),
+            ModifyOrderParams {
+                base_asset_amount: Some(BASE_PRECISION_U64),
+                max_ts: Some(200),
+                ..ModifyOrderParams::default()
+            },
+        );
+
+        assert_eq!(user.orders[0].status, OrderStatus::Open);
+        assert_eq!(user.orders[0].slot, 1);
+        assert_eq!(user.orders[0].base_asset_amount, 2 * BASE_PRECISION_U64);
+        assert_eq!(user.orders[0].base_asset_amount_filled, BASE_PRECISION_U64);
+        assert_eq!(user.orders[0].max_ts, 200);
+        assert_eq!(user.open_orders, 1);
+        assert_eq!(user.perp_positions[0].open_orders, 1);
+        assert_eq!(user.perp_positions[0].open_bids, BASE_PRECISION_I64);
+        assert_eq!(user.perp_positions[0].open_asks, 0);
+    }
+
+    #[test]
+    fn untriggered_perp_order_leaves_open_bids_and_asks() {
+        let order = Order {
+            market_index: 0,
+            order_id: 1,
+            status: OrderStatus::Open,
+            order_type: OrderType::TriggerMarket,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Short,
+            base_asset_amount: 2 * BASE_PRECISION_U64,
+            trigger_price: 90 * PRICE_PRECISION_U64,
+            trigger_condition: OrderTriggerCondition::Below,
+            slot: 1,
+            ..Order::default()
+        };
+        let perp_position = PerpPosition {
+            market_index: 0,
+            base_asset_amount: 2 * BASE_PRECISION_I64,
+            open_orders: 1,
+            ..PerpPosition::default()
+        };
+
+        let user = amend(
+            order,
+            perp_position,
+            SpotPosition::default(),
+            ModifyOrderParams {
+                base_asset_amount: Some(BASE_PRECISION_U64),
+                reduce_only: Some(true),
+                ..ModifyOrderParams::default()
+            },
+        );
+
+        assert_eq!(user.orders[0].slot, 1);
+        assert_eq!(user.orders[0].base_asset_amount, BASE_PRECISION_U64);
+        assert!(user.orders[0].reduce_only);
+        assert_eq!(user.perp_positions[0].open_orders, 1);
+        assert_eq!(user.perp_positions[0].open_bids, 0);
+        assert_eq!(user.perp_positions[0].open_asks, 0);
+    }
+
+    #[test]
+    fn spot_size_decrease_keeps_slot() {
+        let order = Order {
+            market_index: 1,
+            order_id: 1,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            market_type: MarketType::Spot,
+            direction: PositionDirection::Short,
+            base_asset_amount: 2 * BASE_PRECISION_U64,
+            price: 101 * PRICE_PRECISION_U64,
+            slot: 1,
+            ..Order::default()
+        };
+        let spot_position = SpotPosition {
+            market_index: 1,
+            open_orders: 1,
+            open_asks: -2 * BASE_PRECISION_I64,
+            ..SpotPosition::default()
+        };
+
+        let user = amend(
+            order,
+            PerpPosition::default(),
+            spot_position,
+            ModifyOrderParams {
+                base_asset_amount: Some(BASE_PRECISION_U64),
+                ..ModifyOrderParams::default()
+            },
+        );
+
+        assert_eq!(user.orders[0].slot, 1);
+        assert_eq!(user.orders[0].base_asset_amount, BASE_PRECISION_U64);
+        assert_eq!(user.spot_positions[1].open_orders, 1);
+        assert_eq!(user.spot_positions[1].open_bids, 0);
+        assert_eq!(user.spot_positions[1].open_asks, -BASE_PRECISION_I64);
+    }
+}
+
+pub mod clear_perp_batch_auction {
+    use std::str::FromStr;
+
+    use anchor_lang::prelude::{AccountLoader, Clock};
+
+    use crate::controller::orders::clear_perp_batch_auction;
+    use crate::controller::position::PositionDirection;
+    use crate::create_anchor_account_info;
+    use crate::error::{DriftResult, ErrorCode};
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, BASE_PRECISION_I64, BASE_PRECISION_U64, PEG_PRECISION,
+        PRICE_PRECISION_U64, QUOTE_PRECISION_U64, SPOT_BALANCE_PRECISION_U64,
+        SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
+    };
+    use crate::state::oracle::HistoricalOracleData;
+    use crate::state::oracle::OracleSource;
+    use crate::state::perp_market::{PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::perp_order_book::{OrderBookEntry, PerpOrderBook};
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::state::State;
+    use crate::state::user::{
+        OrderExtBitFlag, OrderStatus, OrderType, SelfTradePreventionMode, SpotPosition, User,
+        UserStats,
+    };
+    use crate::state::user_map::{UserMap, UserStatsMap};
+    use crate::test_utils::*;
+    use crate::test_utils::{
+        create_account_info, get_positions, get_pyth_price, get_spot_positions,
+    };
+    use crate::{create_account_info, QUOTE_PRECISION_I64};
+
+    use super::*;
+    use bytemuck::Zeroable;
+
+    fn get_user(authority: Pubkey, orders: &[(PositionDirection, u64, u64)]) -> User {
+        let mut user = User {
+            authority,
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: orders.len() as u8,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
+
+        for (order_index, (direction, price, slot)) in orders.iter().enumerate() {
+            user.orders[order_index] = Order {
+                market_index: 0,
+                order_id: order_index as u32 + 1,
+                status: OrderStatus::Open,
+                order_type: OrderType::Limit,
+                direction: *direction,
+                base_asset_amount: BASE_PRECISION_U64,
+                price: *price,
+                slot: *slot,
+                ..Order::default()
+            };
+
+            match direction {
+                PositionDirection::Long => {
+                    user.perp_positions[0].open_bids += BASE_PRECISION_I64;
+                }
+                PositionDirection::Short => {
+                    user.perp_positions[0].open_asks -= BASE_PRECISION_I64;
+                }
+            }
+        }
+
+        user
+    }
+
+    fn clear(users: [(Pubkey, User); 3]) -> ((u64, u64), [User; 3]) {
+        try_clear(users, PerpOrderBook::zeroed()).unwrap()
+    }
+
+    fn try_clear(
+        users: [(Pubkey, User); 3],
+        mut perp_order_book: PerpOrderBook,
+    ) -> DriftResult<((u64, u64), [User; 3])> {
+        let clock = Clock {
+            slot: 56,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 0,
+        };
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                terminal_quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_slippage_ratio: 100,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 1000,
+                order_tick_size: 1,
+                oracle: oracle_price_key,
+                max_spread: 1000,
+                base_spread: 0,
+                long_spread: 0,
+                short_spread: 0,
+                historical_oracle_data: HistoricalOracleData {
+                    last_oracle_price_twap: oracle_price.twap,
+                    last_oracle_price_twap_5min: oracle_price.twap,
+                    last_oracle_price: oracle_price.agg.price,
+                    ..HistoricalOracleData::default()
+                },
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Active,
+            batch_auction_duration: 1,
+            last_batch_auction_slot: 0,
+            ..PerpMarket::default()
+        };
+        market.amm.max_base_asset_reserve = i128::MAX as u128;
+        market.amm.min_base_asset_reserve = 0;
+        let (new_ask_base_asset_reserve, new_ask_quote_asset_reserve) =
+            crate::math::amm_spread::calculate_spread_reserves(&market, PositionDirection::Long)
+                .unwrap();
+        let (new_bid_base_asset_reserve, new_bid_quote_asset_reserve) =
+            crate::math::amm_spread::calculate_spread_reserves(&market, PositionDirection::Short)
+                .unwrap();
+        market.amm.ask_base_asset_reserve = new_ask_base_asset_reserve;
+        market.amm.bid_base_asset_reserve = new_bid_base_asset_reserve;
+        market.amm.ask_quote_asset_reserve = new_ask_quote_asset_reserve;
+        market.amm.bid_quote_asset_reserve = new_bid_quote_asset_reserve;
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let [(user_a_key, mut user_a), (user_b_key, mut user_b), (user_c_key, mut user_c)] = users;
+        let authorities = [user_a.authority, user_b.authority, user_c.authority];
+
+        create_anchor_account_info!(user_a, &user_a_key, User, user_a_account_info);
+        create_anchor_account_info!(user_b, &user_b_key, User, user_b_account_info);
+        create_anchor_account_info!(user_c, &user_c_key, User, user_c_account_info);
+        let mut user_map = UserMap::load_one(&user_a_account_info).unwrap();
+        user_map
+            .insert(
+                user_b_key,
+                AccountLoader::try_from(&user_b_account_info).unwrap(),
+            )
+            .unwrap();
+        user_map
+            .insert(
+                user_c_key,
+                AccountLoader::try_from(&user_c_account_info).unwrap(),
+            )
+            .unwrap();
+
+        let mut user_a_stats = UserStats {
+            authority: authorities[0],
+            ..UserStats::default()
+        };
+        let mut user_b_stats = UserStats {
+            authority: authorities[1],
+            ..UserStats::default()
+        };
+        let mut user_c_stats = UserStats {
+            authority: authorities[2],
+            ..UserStats::default()
+        };
+        create_anchor_account_info!(user_a_stats, UserStats, user_a_stats_account_info);
+        create_anchor_account_info!(user_b_stats, UserStats, user_b_stats_account_info);
+        create_anchor_account_info!(user_c_stats, UserStats, user_c_stats_account_info);
+        let mut user_stats_map = UserStatsMap::load_one(&user_a_stats_account_info).unwrap();
+        for (authority, user_stats_account_info) in [
+            (authorities[1], &user_b_stats_account_info),
+            (authorities[2], &user_c_stats_account_info),
+        ] {
+            if !user_stats_map.0.contains_key(&authority) {
+                user_stats_map
+                    .insert(
+                        authority,
+                        AccountLoader::try_from(user_stats_account_info).unwrap(),
+                    )
+                    .unwrap();
+            }
+        }
+
+        let filler_key = Pubkey::from_str("My11111111111111111111111111111111111111114").unwrap();
+
+        let state = State {
+            perp_fee_structure: get_fee_structure(),
+            ..State::default()
+        };
+
+        let result = clear_perp_batch_auction(
+            0,
+            &state,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &user_map,
+            &user_stats_map,
+            &mut perp_order_book,
+            &filler_key,
+            &clock,
+        )?;
+
+        let users = [
+            *user_map.get_ref(&user_a_key).unwrap(),
+            *user_map.get_ref(&user_b_key).unwrap(),
+            *user_map.get_ref(&user_c_key).unwrap(),
+        ];
+
+        Ok((result, users))
+    }
+
+    #[test]
+    fn self_match_skipped_without_using_up_allocations() {
+        let user_a_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
+        let user_b_key = Pubkey::from_str("My11111111111111111111111111111111111111112").unwrap();
+        let user_c_key = Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
+
+        // user a's bid is the earliest order and crosses its own ask
+        let user_a = get_user(
+            user_a_key,
+            &[
+                (PositionDirection::Long, 101 * PRICE_PRECISION_U64, 1),
+                (PositionDirection::Short, 99 * PRICE_PRECISION_U64, 2),
+            ],
+        );
+        let user_b = get_user(
+            user_b_key,
+            &[(PositionDirection::Short, 100 * PRICE_PRECISION_U64, 3)],
+        );
+        let user_c = get_user(
+            user_c_key,
+            &[(PositionDirection::Long, 100 * PRICE_PRECISION_U64, 4)],
+        );
+
+        let ((base_asset_amount, quote_asset_amount), [user_a, user_b, user_c]) = clear([
+            (user_a_key, user_a),
+            (user_b_key, user_b),
+            (user_c_key, user_c),
+        ]);
+
+        assert_eq!(base_asset_amount, 2 * BASE_PRECISION_U64);
+        assert_eq!(quote_asset_amount, 200 * QUOTE_PRECISION_U64);
+
+        // user a's bid filled against user b and its ask against user c
+        assert!(user_a.orders[0].is_available());
+        assert!(user_a.orders[1].is_available());
+        assert_eq!(user_a.perp_positions[0].base_asset_amount, 0);
+        assert_eq!(user_a.perp_positions[0].open_orders, 0);
+
+        assert!(user_b.orders[0].is_available());
+        assert_eq!(
+            user_b.perp_positions[0].base_asset_amount,
+            -BASE_PRECISION_I64
+        );
+
+        assert!(user_c.orders[0].is_available());
+        assert_eq!(
+            user_c.perp_positions[0].base_asset_amount,
+            BASE_PRECISION_I64
+        );
+    }
+
+    #[test]
+    fn self_trade_prevention() {
+        let authority = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
+        let user_a_key = Pubkey::from_str("My11111111111111111111111111111111111111112").unwrap();
+        let user_b_key = Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
+        let user_c_key = Pubkey::from_str("My11111111111111111111111111111111111111115").unwrap();
+        let user_c_authority =
+            Pubkey::from_str("My11111111111111111111111111111111111111116").unwrap();
+
+        // users a and b share an authority and b's later bid would take a's ask
+        let user_a = get_user(
+            authority,
+            &[(PositionDirection::Short, 100 * PRICE_PRECISION_U64, 1)],
+        );
+        let mut user_b = get_user(
+            authority,
+            &[(PositionDirection::Long, 100 * PRICE_PRECISION_U64, 2)],
+        );
+        user_b.self_trade_prevention_mode = SelfTradePreventionMode::CancelTaker;
+        let user_c = get_user(
+            user_c_authority,
+            &[(PositionDirection::Long, 100 * PRICE_PRECISION_U64, 3)],
+        );
+
+        let ((base_asset_amount, _), [user_a, user_b, user_c]) = clear([
+            (user_a_key, user_a),
+            (user_b_key, user_b),
+            (user_c_key, user_c),
+        ]);
+
+        // the bids split the ask pro-rata, user b's share is dropped with its order
+        assert_eq!(base_asset_amount, BASE_PRECISION_U64 / 2);
+
+        assert_eq!(user_b.orders[0].status, OrderStatus::Canceled);
+        assert_eq!(user_b.perp_positions[0].base_asset_amount, 0);
+        assert_eq!(user_b.perp_positions[0].open_orders, 0);
+        assert_eq!(user_b.perp_positions[0].open_bids, 0);
+
+        assert_eq!(user_a.orders[0].status, OrderStatus::Open);
+        assert_eq!(
+            user_a.orders[0].base_asset_amount_filled,
+            BASE_PRECISION_U64 / 2
+        );
+        assert_eq!(
+            user_a.perp_positions[0].base_asset_amount,
+            -BASE_PRECISION_I64 / 2
+        );
+
+        assert_eq!(
+            user_c.perp_positions[0].base_asset_amount,
+            BASE_PRECISION_I64 / 2
+        );
+    }
+
+    #[test]
+    fn spread_legs_skipped() {
+        let user_a_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
+        let user_b_key = Pubkey::from_str("My11111111111111111111111111111111111111112").unwrap();
+        let user_c_key = Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
+
+        // user a's bid is a spread leg, which only fills with the rest of its spread
+        let mut user_a = get_user(
+            user_a_key,
+            &[(PositionDirection::Long, 101 * PRICE_PRECISION_U64, 1)],
+        );
+        user_a.orders[0].add_ext_bit_flag(OrderExtBitFlag::SpreadLeg);
+        let user_b = get_user(
+            user_b_key,
+            &[(PositionDirection::Short, 100 * PRICE_PRECISION_U64, 2)],
+        );
+        let user_c = get_user(
+            user_c_key,
+            &[(PositionDirection::Short, 102 * PRICE_PRECISION_U64, 3)],
+        );
+
+        let ((base_asset_amount, _), [user_a, user_b, _]) = clear([
+            (user_a_key, user_a),
+            (user_b_key, user_b),
+            (user_c_key, user_c),
+        ]);
+
+        assert_eq!(base_asset_amount, 0);
+        assert_eq!(user_a.orders[0].base_asset_amount_filled, 0);
+        assert_eq!(user_b.orders[0].base_asset_amount_filled, 0);
+    }
+
+    #[test]
+    fn order_book_users_must_be_passed_in() {
+        let user_a_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
+        let user_b_key = Pubkey::from_str("My11111111111111111111111111111111111111112").unwrap();
+        let user_c_key = Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
+
+        let user_a = get_user(
+            user_a_key,
+            &[(PositionDirection::Long, 100 * PRICE_PRECISION_U64, 1)],
+        );
+        let user_b = get_user(
+            user_b_key,
+            &[(PositionDirection::Short, 100 * PRICE_PRECISION_U64, 2)],
+        );
+        let user_c = get_user(user_c_key, &[]);
+
+        // a better priced ask rests in the book for a user the crank left out
+        let mut perp_order_book = PerpOrderBook::zeroed();
+        perp_order_book.insert(
+            PositionDirection::Short,
+            OrderBookEntry {
+                user: Pubkey::new_unique(),
+                price: 99 * PRICE_PRECISION_U64,
+                order_id: 1,
+                padding: [0; 4],
+            },
+        );
+
+        let result = try_clear(
+            [
+                (user_a_key, user_a),
+                (user_b_key, user_b),
+                (user_c_key, user_c),
+            ],
+            perp_order_book,
+        );
+
+        assert_eq!(result.err(), Some(ErrorCode::InvalidPerpOrderBook));
+    }
+}
+
+pub mod signed_msg_quote_order {
+    use std::str::FromStr;
+
+    use anchor_lang::prelude::{AccountLoader, Clock};
+
+    use crate::controller::orders::{
+        fill_perp_order, place_signed_msg_quote_order, settle_signed_msg_quote_order,
+    };
+    use crate::controller::position::PositionDirection;
+    use crate::create_anchor_account_info;
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, BASE_PRECISION_I64, BASE_PRECISION_U64, PEG_PRECISION,
+        PRICE_PRECISION_U64, SPOT_BALANCE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION,
+        SPOT_WEIGHT_PRECISION,
+    };
+    use crate::state::fill_mode::FillMode;
+    use crate::state::oracle::HistoricalOracleData;
+    use crate::state::oracle::OracleSource;
+    use crate::state::order_params::SignedMsgQuoteMessage;
+    use crate::state::perp_market::{PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::state::State;
+    use crate::state::user::{OrderStatus, OrderType, SpotPosition, User, UserStats};
+    use crate::state::user_map::{UserMap, UserStatsMap};
+    use crate::test_utils::*;
+    use crate::test_utils::{
+        create_account_info, get_orders, get_positions, get_pyth_price, get_spot_positions,
+    };
+    use crate::{create_account_info, QUOTE_PRECISION_I64};
+
+    use super::*;
+    use crate::error::{DriftResult, ErrorCode};
+
+    // places the quote for the maker, fills the taker's bid and settles the quote. Returns the
+    // base filled, the maker's quote order, the maker's last quote nonce and last heartbeat
+    fn place_fill_and_settle(
+        taker_price: u64,
+        last_signed_quote_nonce: u64,
+        quote: &SignedMsgQuoteMessage,
+    ) -> DriftResult<(u64, Order, u64, u32)> {
+        let clock = Clock {
+            slot: 56,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 100,
+        };
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        // the amm quotes around 99/101 so only the quote fills the taker
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                terminal_quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_slippage_ratio: 100,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 1000,
+                order_tick_size: 1,
+                oracle: oracle_price_key,
+                max_spread: 100000,
+                base_spread: 0,
+                long_spread: 20000,
+                short_spread: 20000,
+                historical_oracle_data: HistoricalOracleData {
+                    last_oracle_price_twap: oracle_price.twap,
+                    last_oracle_price_twap_5min: oracle_price.twap,
+                    last_oracle_price: oracle_price.agg.price,
+                    ..HistoricalOracleData::default()
+                },
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Active,
+            ..PerpMarket::default()
+        };
+        market.amm.max_base_asset_reserve = i128::MAX as u128;
+        market.amm.min_base_asset_reserve = 0;
+        let (new_ask_base_asset_reserve, new_ask_quote_asset_reserve) =
+            crate::math::amm_spread::calculate_spread_reserves(&market, PositionDirection::Long)
+                .unwrap();
+        let (new_bid_base_asset_reserve, new_bid_quote_asset_reserve) =
+            crate::math::amm_spread::calculate_spread_reserves(&market, PositionDirection::Short)
+                .unwrap();
+        market.amm.ask_base_asset_reserve = new_ask_base_asset_reserve;
+        market.amm.bid_base_asset_reserve = new_bid_base_asset_reserve;
+        market.amm.ask_quote_asset_reserve = new_ask_quote_asset_reserve;
+        market.amm.bid_quote_asset_reserve = new_bid_quote_asset_reserve;
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let taker_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
+        let mut taker = User {
+            authority: taker_key,
+            orders: get_orders(Order {
+                market_index: 0,
+                order_id: 1,
+                status: OrderStatus::Open,
+                order_type: OrderType::Limit,
+                direction: PositionDirection::Long,
+                base_asset_amount: BASE_PRECISION_U64,
+                slot: 0,
+                price: taker_price,
+                ..Order::default()
+            }),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 1,
+                open_bids: BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
+        create_anchor_account_info!(taker, &taker_key, User, taker_account_info);
+        let taker_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&taker_account_info).unwrap();
+
+        let mut taker_stats = UserStats {
+            authority: taker_key,
+            ..UserStats::default()
+        };
+        create_anchor_account_info!(taker_stats, UserStats, taker_stats_account_info);
+        let taker_stats_account_loader: AccountLoader<UserStats> =
+            AccountLoader::try_from(&taker_stats_account_info).unwrap();
+
+        let maker_key = Pubkey::from_str("My11111111111111111111111111111111111111112").unwrap();
+        let maker_authority =
+            Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
+        let mut maker = User {
+            authority: maker_authority,
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            last_heartbeat_ts: 50,
+            ..User::default()
+        };
+        create_anchor_account_info!(maker, &maker_key, User, maker_account_info);
+        let makers_and_referrers = UserMap::load_one(&maker_account_info).unwrap();
+
+        let mut maker_stats = UserStats {
+            authority: maker_authority,
+            last_signed_quote_nonce,
+            ..UserStats::default()
+        };
+        create_anchor_account_info!(maker_stats, UserStats, maker_stats_account_info);
+        let maker_and_referrer_stats = UserStatsMap::load_one(&maker_stats_account_info).unwrap();
+
+        let state = State {
+            min_perp_auction_duration: 10,
+            default_market_order_time_in_force: 10,
+            ..State::default()
+        };
+
+        let quote_order_id = place_signed_msg_quote_order(
+            &state,
+            &mut makers_and_referrers.get_ref_mut(&maker_key).unwrap(),
+            maker_key,
+            &maker_and_referrer_stats.get_ref(&maker_authority).unwrap(),
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &clock,
+            quote,
+        )?;
+
+        let (base_asset_amount, _) = fill_perp_order(
+            1,
+            &state,
+            &taker_account_loader,
+            &taker_stats_account_loader,
+            &spot_market_map,
+            &market_map,
+            &mut oracle_map,
+            &taker_account_loader,
+            &taker_stats_account_loader,
+            &makers_and_referrers,
+            &maker_and_referrer_stats,
+            None,
+            &clock,
+            FillMode::PlaceAndTake(true, 0),
+            &mut None,
+            false,
+            &[],
+        )
+        .unwrap();
+
+        let mut maker = makers_and_referrers.get_ref_mut(&maker_key).unwrap();
+        let mut maker_stats = maker_and_referrer_stats
+            .get_ref_mut(&maker_authority)
+            .unwrap();
+        settle_signed_msg_quote_order(
+            &mut maker,
+            &maker_key,
+            &mut maker_stats,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &clock,
+            quote_order_id,
+            quote.nonce,
+        )
+        .unwrap();
+
+        let quote_order = *maker
+            .orders
+            .iter()
+            .find(|order| order.order_id == quote_order_id)
+            .unwrap();
+
+        // the same quote can't be placed again once its nonce is used up
+        let place_again = place_signed_msg_quote_order(
+            &state,
+            &mut maker,
+            maker_key,
+            &maker_stats,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &clock,
+            quote,
+        );
+        assert_eq!(
+            place_again.is_ok(),
+            maker_stats.last_signed_quote_nonce < quote.nonce
+        );
+
+        Ok((
+            base_asset_amount,
+            quote_order,
+            maker_stats.last_signed_quote_nonce,
+            maker.last_heartbeat_ts,
+        ))
+    }
+
+    fn get_quote() -> SignedMsgQuoteMessage {
+        SignedMsgQuoteMessage {
+            sub_account_id: 0,
+            market_index: 0,
+            direction: PositionDirection::Short,
+            price: 100 * PRICE_PRECISION_U64,
+            base_asset_amount: 2 * BASE_PRECISION_U64,
+            max_slot: 60,
+            nonce: 5,
+        }
+    }
+
+    #[test]
+    fn filled_quote_uses_up_nonce() {
+        let (base_asset_amount, quote_order, last_signed_quote_nonce, _) =
+            place_fill_and_settle(100 * PRICE_PRECISION_U64, 4, &get_quote()).unwrap();
+
+        assert_eq!(base_asset_amount, BASE_PRECISION_U64);
+        assert_eq!(quote_order.base_asset_amount_filled, BASE_PRECISION_U64);
+        // rest of the quote is canceled after the fill
+        assert_eq!(quote_order.status, OrderStatus::Canceled);
+        assert_eq!(last_signed_quote_nonce, 5);
+    }
+
+    #[test]
+    fn filled_quote_keeps_maker_heartbeat() {
+        let (base_asset_amount, _, _, last_heartbeat_ts) =
+            place_fill_and_settle(100 * PRICE_PRECISION_U64, 4, &get_quote()).unwrap();
+
+        assert_eq!(base_asset_amount, BASE_PRECISION_U64);
+        // the taker placing the maker's quote doesn't keep the maker's dead man's switch alive
+        assert_eq!(last_heartbeat_ts, 50);
+    }
+
+    #[test]
+    fn unfilled_quote_keeps_nonce() {
+        let (base_asset_amount, quote_order, last_signed_quote_nonce, _) =
+            place_fill_and_settle(99 * PRICE_PRECISION_U64, 4, &get_quote()).unwrap();
+
+        assert_eq!(base_asset_amount, 0);
+        assert_eq!(quote_order.base_asset_amount_filled, 0);
+        assert_eq!(quote_order.status, OrderStatus::Canceled);
+        assert_eq!(last_signed_quote_nonce, 4);
+    }
+
+    #[test]
+    fn used_nonce() {
+        let result = place_fill_and_settle(100 * PRICE_PRECISION_U64, 5, &get_quote());
+
+        assert_eq!(result, Err(ErrorCode::InvalidSignedMsgQuote));
+    }
+
+    #[test]
+    fn expired_quote() {
+        let quote = SignedMsgQuoteMessage {
+            max_slot: 55,
+            ..get_quote()
+        };
+        let result = place_fill_and_settle(100 * PRICE_PRECISION_U64, 4, &quote);
+
+        assert_eq!(result, Err(ErrorCode::InvalidSignedMsgQuote));
+    }
+}
@@ -2405,6 +2405,8 @@ pub mod delisting_test {
                 clock.slot,
                 clock.unix_timestamp,
                 &state,
+                &mut None,
+                &[],
             )
             .unwrap();
 
@@ -2488,6 +2490,7 @@ pub mod delisting_test {
                 10,
                 PERCENTAGE_PRECISION,
                 150,
+                &mut None,
             )
             .unwrap();
 
@@ -2580,6 +2583,7 @@ pub mod delisting_test {
                 10,
                 PERCENTAGE_PRECISION,
                 150,
+                &mut None,
             )
             .unwrap();
 
@@ -249,6 +249,7 @@ fn amm_pred_expiry_price_yes_market_example() {
         &mut oracle_map,
         &state,
         &clock,
+        &[],
     )
     .unwrap();
 
@@ -362,6 +363,7 @@ fn amm_pred_expiry_price_market_example() {
         &mut oracle_map,
         &state,
         &clock,
+        &[],
     )
     .unwrap();
 
@@ -470,6 +472,7 @@ fn amm_pred_settle_market_example() {
         &mut oracle_map,
         &state,
         &clock,
+        &[],
     )
     .unwrap();
 
@@ -1,3 +1,4 @@
+use std::cell::RefMut;
 use std::cmp::min;
 
 use crate::math::oracle::LogMode;
@@ -6,10 +7,12 @@ use crate::state::oracle::MMOraclePriceData;
 use anchor_lang::prelude::AccountInfo;
 use anchor_lang::prelude::*;
 
-use crate::controller::amm::update_spreads;
+use crate::controller::amm::{update_oracle_quote_amm_peg, update_spreads};
 use crate::controller::spot_balance::update_spot_balances;
 use crate::error::ErrorCode;
 use crate::error::*;
+use crate::get_then_update_id;
+use crate::load;
 use crate::load_mut;
 use crate::math::amm;
 use crate::math::bn;
@@ -26,9 +29,13 @@ use crate::math::repeg;
 use crate::math::safe_math::SafeMath;
 use crate::math::spot_balance::get_token_amount;
 
+use crate::state::events::{CurveRecord, CurveUpdateReason};
 use crate::state::oracle::OracleSource;
 use crate::state::oracle_map::OracleMap;
-use crate::state::perp_market::{MarketStatus, PerpMarket};
+use crate::state::paused_operations::PerpOperation;
+use crate::state::perp_circuit_breaker::PerpCircuitBreaker;
+use crate::state::perp_curve_crank::PerpCurveCrank;
+use crate::state::perp_market::{AmmCircuitBreakerStatus, MarketStatus, PerpMarket};
 use crate::state::perp_market_map::PerpMarketMap;
 use crate::state::spot_market::SpotBalance;
 use crate::state::spot_market::SpotBalanceType;
@@ -131,6 +138,7 @@ pub fn update_amm(
     oracle_map: &mut OracleMap,
     state: &State,
     clock: &Clock,
+    circuit_breakers: &[AccountLoader<PerpCircuitBreaker>],
 ) -> DriftResult<i128> {
     let market = &mut perp_market_map.get_ref_mut(&market_index)?;
     let oracle_price_data = oracle_map.get_price_data(&market.oracle_id())?;
@@ -140,6 +148,15 @@ pub fn update_amm(
         &state.oracle_guard_rails.validity,
     )?;
 
+    if let Some(mut circuit_breaker) = load_perp_circuit_breaker(circuit_breakers, market)? {
+        update_amm_circuit_breaker(
+            market,
+            &mut circuit_breaker,
+            &mm_oracle_price_data,
+            clock.slot,
+        )?;
+    }
+
     let cost_of_update = _update_amm(
         market,
         &mm_oracle_price_data,
@@ -178,9 +195,20 @@ pub fn _update_amm(
         0,
     )?;
 
+    let last_oracle_price = market.amm.historical_oracle_data.last_oracle_price;
+
     let mut amm_update_cost = 0;
     let mut amm_not_successfully_updated = false;
-    if is_oracle_valid_for_action(oracle_validity, Some(DriftAction::UpdateAMMCurve))? {
+    if market.is_oracle_quote_amm() {
+        if is_oracle_valid_for_action(oracle_validity, Some(DriftAction::UpdateAMMCurve))? {
+            match update_oracle_quote_amm_peg(market, mm_oracle_price_data.get_price())? {
+                Some(cost) => amm_update_cost = cost,
+                None => amm_not_successfully_updated = true,
+            }
+        } else {
+            amm_not_successfully_updated = true;
+        }
+    } else if is_oracle_valid_for_action(oracle_validity, Some(DriftAction::UpdateAMMCurve))? {
         let curve_update_intensity =
             min(market.amm.curve_update_intensity, 100_u8).cast::<i128>()?;
 
@@ -228,9 +256,41 @@ pub fn _update_amm(
         )?;
     }
 
+    let is_oracle_valid_for_amm_fill =
+        is_oracle_valid_for_action(oracle_validity, Some(DriftAction::FillOrderAmm))?;
+
+    // sample the oracle return once per slot, on the updates that advance last_update_slot
+    if is_oracle_valid_for_amm_fill
+        && !amm_not_successfully_updated
+        && clock_slot > market.amm.last_update_slot
+    {
+        let oracle_price = mm_oracle_price_data.get_exchange_oracle_price_data().price;
+        let slots_since_last_sample = clock_slot.safe_sub(market.amm.last_update_slot)?;
+
+        if market.oracle_realized_vol_fast_half_life > 0 {
+            market.oracle_realized_vol_fast = amm::calculate_new_oracle_realized_vol(
+                market.oracle_realized_vol_fast,
+                last_oracle_price,
+                oracle_price,
+                slots_since_last_sample,
+                market.oracle_realized_vol_fast_half_life,
+            )?;
+        }
+
+        if market.oracle_realized_vol_slow_half_life > 0 {
+            market.oracle_realized_vol_slow = amm::calculate_new_oracle_realized_vol(
+                market.oracle_realized_vol_slow,
+                last_oracle_price,
+                oracle_price,
+                slots_since_last_sample,
+                market.oracle_realized_vol_slow_half_life,
+            )?;
+        }
+    }
+
     update_spreads(market, reserve_price_after, Some(clock_slot))?;
 
-    if is_oracle_valid_for_action(oracle_validity, Some(DriftAction::FillOrderAmm))? {
+    if is_oracle_valid_for_amm_fill {
         if !amm_not_successfully_updated {
             market.amm.last_update_slot = clock_slot;
         }
@@ -249,7 +309,20 @@ pub fn update_amm_and_check_validity(
     now: i64,
     clock_slot: u64,
     action: Option<DriftAction>,
+    circuit_breaker: Option<&mut PerpCircuitBreaker>,
 ) -> DriftResult {
+    match circuit_breaker {
+        Some(circuit_breaker) => {
+            update_amm_circuit_breaker(market, circuit_breaker, mm_oracle_price_data, clock_slot)?;
+        }
+        None => validate!(
+            !market.has_amm_circuit_breaker(),
+            ErrorCode::InvalidPerpCircuitBreaker,
+            "circuit breaker for perp market {} not passed in",
+            market.market_index
+        )?,
+    }
+
     _update_amm(market, mm_oracle_price_data, state, now, clock_slot)?;
 
     // 1 hour EMA
@@ -280,6 +353,249 @@ pub fn update_amm_and_check_validity(
     Ok(())
 }
 
+/// Trips the market's amm circuit breaker on a rapid oracle move or inventory change, pausing amm
+/// fills and widening spreads to max until the cooldown passes
+pub fn update_amm_circuit_breaker(
+    market: &mut PerpMarket,
+    circuit_breaker: &mut PerpCircuitBreaker,
+    mm_oracle_price_data: &MMOraclePriceData,
+    clock_slot: u64,
+) -> DriftResult<bool> {
+    validate!(
+        circuit_breaker.market_index == market.market_index,
+        ErrorCode::InvalidPerpCircuitBreaker,
+        "circuit breaker market index ({}) != market index ({})",
+        circuit_breaker.market_index,
+        market.market_index
+    )?;
+
+    let tripped = circuit_breaker.evaluate(
+        mm_oracle_price_data.get_price(),
+        market.amm.base_asset_amount_with_amm,
+        clock_slot,
+    )?;
+
+    if circuit_breaker.is_tripped(clock_slot) {
+        if tripped && !market.is_amm_circuit_breaker_tripped() {
+            msg!(
+                "amm circuit breaker tripped for perp market {} until slot {}",
+                market.market_index,
+                circuit_breaker.tripped_until_slot
+            );
+        }
+
+        market.amm_circuit_breaker_status = AmmCircuitBreakerStatus::Tripped;
+
+        if !market.is_operation_paused(PerpOperation::AmmFill) {
+            market.paused_operations |= PerpOperation::AmmFill as u8;
+            circuit_breaker.paused_amm_fill = 1;
+        }
+    } else {
+        if market.is_amm_circuit_breaker_tripped() {
+            msg!(
+                "amm circuit breaker reset for perp market {}",
+                market.market_index
+            );
+
+            if circuit_breaker.paused_amm_fill != 0 {
+                market.paused_operations &= !(PerpOperation::AmmFill as u8);
+                circuit_breaker.paused_amm_fill = 0;
+            }
+        }
+
+        market.amm_circuit_breaker_status = AmmCircuitBreakerStatus::Armed;
+    }
+
+    Ok(market.is_amm_circuit_breaker_tripped())
+}
+
+/// Finds the market's circuit breaker among the passed in accounts. Once a market has a circuit
+/// breaker it has to be passed in wherever the amm is updated
+pub fn load_perp_circuit_breaker<'a>(
+    circuit_breakers: &'a [AccountLoader<PerpCircuitBreaker>],
+    market: &PerpMarket,
+) -> DriftResult<Option<RefMut<'a, PerpCircuitBreaker>>> {
+    for circuit_breaker in circuit_breakers {
+        if load!(circuit_breaker)?.market_index == market.market_index {
+            return Ok(Some(load_mut!(circuit_breaker)?));
+        }
+    }
+
+    validate!(
+        !market.has_amm_circuit_breaker(),
+        ErrorCode::InvalidPerpCircuitBreaker,
+        "circuit breaker for perp market {} not passed in",
+        market.market_index
+    )?;
+
+    Ok(None)
+}
+
+/// Re-evaluates the market's circuit breaker after fills so the amm fills count toward the per
+/// slot inventory change
+pub fn update_amm_circuit_breaker_after_fill(
+    market_index: u16,
+    perp_market_map: &PerpMarketMap,
+    oracle_map: &mut OracleMap,
+    state: &State,
+    clock_slot: u64,
+    circuit_breakers: &[AccountLoader<PerpCircuitBreaker>],
+) -> DriftResult {
+    let market = &mut perp_market_map.get_ref_mut(&market_index)?;
+
+    if let Some(mut circuit_breaker) = load_perp_circuit_breaker(circuit_breakers, market)? {
+        let oracle_price_data = oracle_map.get_price_data(&market.oracle_id())?;
+        let mm_oracle_price_data = market.get_mm_oracle_price_data(
+            *oracle_price_data,
+            clock_slot,
+            &state.oracle_guard_rails.validity,
+        )?;
+
+        update_amm_circuit_breaker(
+            market,
+            &mut circuit_breaker,
+            &mm_oracle_price_data,
+            clock_slot,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Scheduled repeg toward the oracle (lowering k when the budget runs short), paid for out of the
+/// crank's epoch budget
+pub fn crank_curve(
+    market: &mut PerpMarket,
+    curve_crank: &mut PerpCurveCrank,
+    mm_oracle_price_data: &MMOraclePriceData,
+    state: &State,
+    now: i64,
+    clock_slot: u64,
+) -> DriftResult<i128> {
+    validate!(
+        market.status == MarketStatus::Active && !market.is_oracle_quote_amm(),
+        ErrorCode::InvalidPerpCurveCrank,
+        "perp market {} must be active and quote off its curve",
+        market.market_index
+    )?;
+
+    validate!(
+        curve_crank.is_crank_due(now)?,
+        ErrorCode::PerpCurveCrankNotReady,
+        "last crank ts = {} crank interval = {} now = {}",
+        curve_crank.last_crank_ts,
+        curve_crank.crank_interval,
+        now
+    )?;
+
+    let oracle_validity = oracle::oracle_validity(
+        MarketType::Perp,
+        market.market_index,
+        market.amm.historical_oracle_data.last_oracle_price_twap,
+        &mm_oracle_price_data.get_safe_oracle_price_data(),
+        &state.oracle_guard_rails.validity,
+        market.get_max_confidence_interval_multiplier()?,
+        &market.amm.oracle_source,
+        oracle::LogMode::SafeMMOracle,
+        0,
+    )?;
+
+    validate!(
+        is_oracle_valid_for_action(oracle_validity, Some(DriftAction::UpdateAMMCurve))?,
+        ErrorCode::InvalidOracle,
+        "oracle invalid for curve update ({:?})",
+        oracle_validity
+    )?;
+
+    curve_crank.update_epoch(now, repeg::calculate_fee_pool(market)?)?;
+    curve_crank.last_crank_ts = now;
+
+    let (optimal_peg, fee_budget, check_lower_bound) =
+        repeg::calculate_optimal_peg_and_budget(market, mm_oracle_price_data)?;
+
+    let remaining_epoch_budget = curve_crank.get_remaining_epoch_budget()?;
+    let budget = fee_budget.min(remaining_epoch_budget);
+
+    let (repegged_market, adjustment_cost) = repeg::adjust_amm(market, optimal_peg, budget, true)?;
+
+    if repegged_market.amm.peg_multiplier == market.amm.peg_multiplier
+        && repegged_market.amm.sqrt_k == market.amm.sqrt_k
+    {
+        msg!("perp market {} curve unchanged", market.market_index);
+        return Ok(0);
+    }
+
+    if adjustment_cost > remaining_epoch_budget.cast()? {
+        msg!(
+            "adjustment cost {} > remaining epoch budget {}",
+            adjustment_cost,
+            remaining_epoch_budget
+        );
+        return Ok(0);
+    }
+
+    let peg_multiplier_before = market.amm.peg_multiplier;
+    let base_asset_reserve_before = market.amm.base_asset_reserve;
+    let quote_asset_reserve_before = market.amm.quote_asset_reserve;
+    let sqrt_k_before = market.amm.sqrt_k;
+
+    if !apply_cost_to_market(market, adjustment_cost, check_lower_bound)? {
+        msg!(
+            "adjustment cost {} not applied for check_lower_bound={}",
+            adjustment_cost,
+            check_lower_bound
+        );
+        return Ok(0);
+    }
+
+    cp_curve::update_k(
+        market,
+        &UpdateKResult {
+            sqrt_k: repegged_market.amm.sqrt_k,
+            base_asset_reserve: repegged_market.amm.base_asset_reserve,
+            quote_asset_reserve: repegged_market.amm.quote_asset_reserve,
+        },
+    )?;
+    market.amm.peg_multiplier = repegged_market.amm.peg_multiplier;
+
+    curve_crank.epoch_cost = curve_crank.epoch_cost.safe_add(adjustment_cost.cast()?)?;
+
+    let reserve_price_after = market.amm.reserve_price()?;
+    update_spreads(market, reserve_price_after, Some(clock_slot))?;
+
+    let reason = if market.amm.peg_multiplier == optimal_peg {
+        CurveUpdateReason::ScheduledRepeg
+    } else {
+        CurveUpdateReason::ScheduledRepegBudgetLimited
+    };
+
+    emit!(CurveRecord {
+        ts: now,
+        record_id: get_then_update_id!(market, next_curve_record_id),
+        market_index: market.market_index,
+        peg_multiplier_before,
+        base_asset_reserve_before,
+        quote_asset_reserve_before,
+        sqrt_k_before,
+        peg_multiplier_after: market.amm.peg_multiplier,
+        base_asset_reserve_after: market.amm.base_asset_reserve,
+        quote_asset_reserve_after: market.amm.quote_asset_reserve,
+        sqrt_k_after: market.amm.sqrt_k,
+        base_asset_amount_long: market.amm.base_asset_amount_long.unsigned_abs(),
+        base_asset_amount_short: market.amm.base_asset_amount_short.unsigned_abs(),
+        base_asset_amount_with_amm: market.amm.base_asset_amount_with_amm,
+        number_of_users: market.number_of_users,
+        adjustment_cost,
+        total_fee: market.amm.total_fee,
+        total_fee_minus_distributions: market.amm.total_fee_minus_distributions,
+        oracle_price: mm_oracle_price_data.get_price(),
+        fill_record: market.next_fill_record_id as u128,
+        reason,
+    });
+
+    Ok(adjustment_cost)
+}
+
 pub fn apply_cost_to_market(
     market: &mut PerpMarket,
     cost: i128,
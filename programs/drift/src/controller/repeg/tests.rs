@@ -478,6 +478,7 @@ pub fn update_amm_larg_conf_w_neg_tfmd_test() {
         market.amm.last_oracle_conf_pct,
         market.amm.mark_std,
         market.amm.oracle_std,
+        0,
         market.amm.max_spread,
     )
     .unwrap();
@@ -576,6 +577,7 @@ pub fn update_amm_larg_conf_w_neg_tfmd_test() {
         market.amm.last_oracle_conf_pct,
         market.amm.mark_std,
         market.amm.oracle_std,
+        0,
         market.amm.max_spread,
     )
     .unwrap();
@@ -618,3 +620,366 @@ pub fn update_amm_larg_conf_w_neg_tfmd_test() {
     assert_eq!((oracle_price_data.price as u64) > bid, true);
     assert_eq!((oracle_price_data.price as u64) < ask, true);
 }
+
+#[test]
+pub fn update_amm_concentrated_band_test() {
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 65 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 63015384615,
+            terminal_quote_asset_reserve: 64 * AMM_RESERVE_PRECISION,
+            sqrt_k: 64 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 19_400 * PEG_PRECISION,
+            base_asset_amount_with_amm: -(AMM_RESERVE_PRECISION as i128),
+            mark_std: PRICE_PRECISION as u64,
+            last_mark_price_twap_ts: 0,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price_twap: 19_400 * PRICE_PRECISION_I64,
+                last_oracle_price_twap_5min: 19_400 * PRICE_PRECISION_I64,
+
+                ..HistoricalOracleData::default()
+            },
+            base_spread: 250,
+            curve_update_intensity: 100,
+            max_spread: 55500,
+            concentration_coef: 31020710,
+            concentrated_band_width: 20,
+            concentrated_liquidity_multiplier: 4,
+            ..AMM::default()
+        },
+        status: MarketStatus::Initialized,
+        contract_tier: ContractTier::B,
+        margin_ratio_initial: 555,
+        ..PerpMarket::default()
+    };
+    let (_, new_terminal_base_reserve) = amm::calculate_terminal_reserves(&market.amm).unwrap();
+    let (min_base_asset_reserve, max_base_asset_reserve) =
+        amm::calculate_bid_ask_bounds(market.amm.concentration_coef, new_terminal_base_reserve)
+            .unwrap();
+    market.amm.min_base_asset_reserve = min_base_asset_reserve;
+    market.amm.max_base_asset_reserve = max_base_asset_reserve;
+
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            price_divergence: PriceDivergenceGuardRails {
+                mark_oracle_percent_divergence: 1,
+                oracle_twap_5min_percent_divergence: 10,
+            },
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+        },
+        ..State::default()
+    };
+
+    let now = 10000;
+    let slot = 81680085;
+    let oracle_price_data = OraclePriceData {
+        price: (18_000 * PRICE_PRECISION) as i64,
+        confidence: 0,
+        delay: 2,
+        has_sufficient_number_of_data_points: true,
+        sequence_id: None,
+    };
+
+    let reserve_price_before = market.amm.reserve_price().unwrap();
+
+    let mm_oracle_price_data = market
+        .get_mm_oracle_price_data(oracle_price_data, slot, &state.oracle_guard_rails.validity)
+        .unwrap();
+    let cost_of_update =
+        _update_amm(&mut market, &mm_oracle_price_data, &state, now, slot).unwrap();
+
+    // the concentrated curve is repegged toward the oracle and the fee pool pays for it
+    assert!(cost_of_update > 0);
+    assert!(market.amm.peg_multiplier < 19_400 * PEG_PRECISION);
+    assert_eq!(market.amm.total_fee_minus_distributions, -cost_of_update);
+    assert_eq!(market.amm.last_update_slot, slot);
+
+    let reserve_price_after = market.amm.reserve_price().unwrap();
+    let oracle_price = oracle_price_data.price as u64;
+    assert!(
+        reserve_price_after.abs_diff(oracle_price) < reserve_price_before.abs_diff(oracle_price)
+    );
+    assert_eq!(
+        market.amm.historical_oracle_data.last_oracle_price,
+        18_000 * PRICE_PRECISION_I64
+    );
+}
+
+#[test]
+pub fn crank_curve_test() {
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 65 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 63015384615,
+            terminal_quote_asset_reserve: 64 * AMM_RESERVE_PRECISION,
+            sqrt_k: 64 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 19_400 * PEG_PRECISION,
+            base_asset_amount_with_amm: -(AMM_RESERVE_PRECISION as i128),
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price_twap: 18_700 * PRICE_PRECISION_I64,
+                last_oracle_price_twap_5min: 18_700 * PRICE_PRECISION_I64,
+                ..HistoricalOracleData::default()
+            },
+            total_fee_minus_distributions: (1_000 * QUOTE_PRECISION) as i128,
+            base_spread: 250,
+            curve_update_intensity: 100,
+            max_spread: 55500,
+            concentration_coef: 31020710,
+            ..AMM::default()
+        },
+        status: MarketStatus::Active,
+        contract_tier: ContractTier::B,
+        margin_ratio_initial: 555,
+        ..PerpMarket::default()
+    };
+    let (_, new_terminal_base_reserve) = amm::calculate_terminal_reserves(&market.amm).unwrap();
+    let (min_base_asset_reserve, max_base_asset_reserve) =
+        amm::calculate_bid_ask_bounds(market.amm.concentration_coef, new_terminal_base_reserve)
+            .unwrap();
+    market.amm.min_base_asset_reserve = min_base_asset_reserve;
+    market.amm.max_base_asset_reserve = max_base_asset_reserve;
+
+    let mut curve_crank = PerpCurveCrank {
+        crank_interval: 60,
+        epoch_duration: 3600,
+        epoch_budget_pct: 500_000, // 50%
+        ..PerpCurveCrank::default()
+    };
+
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,
+                slots_before_stale_for_margin: 120,
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+            ..OracleGuardRails::default()
+        },
+        ..State::default()
+    };
+
+    let now = 10000;
+    let slot = 81680085;
+    let oracle_price_data = OraclePriceData {
+        price: 18_700 * PRICE_PRECISION_I64,
+        confidence: 0,
+        delay: 2,
+        has_sufficient_number_of_data_points: true,
+        sequence_id: None,
+    };
+    let mm_oracle_price_data = market
+        .get_mm_oracle_price_data(oracle_price_data, slot, &state.oracle_guard_rails.validity)
+        .unwrap();
+
+    let reserve_price_before = market.amm.reserve_price().unwrap();
+    let peg_multiplier_before = market.amm.peg_multiplier;
+
+    let cost = crank_curve(
+        &mut market,
+        &mut curve_crank,
+        &mm_oracle_price_data,
+        &state,
+        now,
+        slot,
+    )
+    .unwrap();
+
+    assert_eq!(curve_crank.last_crank_ts, now);
+    assert_eq!(curve_crank.epoch_start_ts, now);
+    assert_eq!(curve_crank.epoch_budget, 500 * QUOTE_PRECISION as u64);
+    assert_eq!(curve_crank.epoch_cost, cost as i64);
+
+    // amm is long so moving the peg down toward the oracle costs the fee pool
+    assert!(cost > 0);
+    assert!(cost <= 500 * QUOTE_PRECISION as i128);
+    assert_eq!(
+        market.amm.total_fee_minus_distributions,
+        (1_000 * QUOTE_PRECISION) as i128 - cost
+    );
+    assert!(market.amm.peg_multiplier < peg_multiplier_before);
+
+    let reserve_price_after = market.amm.reserve_price().unwrap();
+    let oracle_price = oracle_price_data.price as u64;
+    assert!(
+        reserve_price_after.abs_diff(oracle_price) < reserve_price_before.abs_diff(oracle_price)
+    );
+
+    // not due until the interval passes
+    assert_eq!(
+        crank_curve(
+            &mut market,
+            &mut curve_crank,
+            &mm_oracle_price_data,
+            &state,
+            now + 30,
+            slot + 75,
+        ),
+        Err(ErrorCode::PerpCurveCrankNotReady)
+    );
+
+    // already centred, budget carries over within the epoch
+    let cost = crank_curve(
+        &mut market,
+        &mut curve_crank,
+        &mm_oracle_price_data,
+        &state,
+        now + 60,
+        slot + 150,
+    )
+    .unwrap();
+    assert_eq!(cost, 0);
+    assert_eq!(curve_crank.last_crank_ts, now + 60);
+    assert_eq!(curve_crank.epoch_start_ts, now);
+
+    // markets with a concentrated band are cranked with the band priced in
+    market.amm.concentrated_band_width = 20;
+    market.amm.concentrated_liquidity_multiplier = 4;
+    let oracle_price_data = OraclePriceData {
+        price: 18_500 * PRICE_PRECISION_I64,
+        ..oracle_price_data
+    };
+    let mm_oracle_price_data = market
+        .get_mm_oracle_price_data(oracle_price_data, slot, &state.oracle_guard_rails.validity)
+        .unwrap();
+    let total_fee_minus_distributions_before = market.amm.total_fee_minus_distributions;
+    let peg_multiplier_before = market.amm.peg_multiplier;
+
+    let cost = crank_curve(
+        &mut market,
+        &mut curve_crank,
+        &mm_oracle_price_data,
+        &state,
+        now + 3660,
+        slot + 9150,
+    )
+    .unwrap();
+
+    assert!(cost > 0);
+    assert_eq!(curve_crank.last_crank_ts, now + 3660);
+    assert_eq!(curve_crank.epoch_start_ts, now + 3660);
+    assert_eq!(
+        market.amm.total_fee_minus_distributions,
+        total_fee_minus_distributions_before - cost
+    );
+    assert!(market.amm.peg_multiplier < peg_multiplier_before);
+}
+
+#[test]
+pub fn update_amm_circuit_breaker_test() {
+    let mut market = PerpMarket {
+        status: MarketStatus::Active,
+        ..PerpMarket::default()
+    };
+
+    let mut circuit_breaker = PerpCircuitBreaker {
+        max_oracle_move_pct: 50_000, // 5%
+        oracle_move_window_slots: 10,
+        cooldown_slots: 20,
+        ..PerpCircuitBreaker::default()
+    };
+
+    let mm_oracle_price_data = |price: i64| {
+        let oracle_price_data = OraclePriceData {
+            price,
+            confidence: 0,
+            delay: 1,
+            has_sufficient_number_of_data_points: true,
+            sequence_id: None,
+        };
+        MMOraclePriceData::new(price, 1, 0, OracleValidity::default(), oracle_price_data).unwrap()
+    };
+
+    let tripped = update_amm_circuit_breaker(
+        &mut market,
+        &mut circuit_breaker,
+        &mm_oracle_price_data(100 * PRICE_PRECISION_I64),
+        100,
+    )
+    .unwrap();
+    assert!(!tripped);
+    assert_eq!(market.paused_operations, 0);
+
+    let tripped = update_amm_circuit_breaker(
+        &mut market,
+        &mut circuit_breaker,
+        &mm_oracle_price_data(106 * PRICE_PRECISION_I64),
+        105,
+    )
+    .unwrap();
+    assert!(tripped);
+    assert!(market.is_amm_circuit_breaker_tripped());
+    assert!(market.is_operation_paused(PerpOperation::AmmFill));
+    assert_eq!(circuit_breaker.paused_amm_fill, 1);
+    assert_eq!(circuit_breaker.tripped_until_slot, 125);
+
+    // oracle steady but still cooling down
+    let tripped = update_amm_circuit_breaker(
+        &mut market,
+        &mut circuit_breaker,
+        &mm_oracle_price_data(106 * PRICE_PRECISION_I64),
+        124,
+    )
+    .unwrap();
+    assert!(tripped);
+    assert!(market.is_operation_paused(PerpOperation::AmmFill));
+
+    let tripped = update_amm_circuit_breaker(
+        &mut market,
+        &mut circuit_breaker,
+        &mm_oracle_price_data(106 * PRICE_PRECISION_I64),
+        125,
+    )
+    .unwrap();
+    assert!(!tripped);
+    assert!(!market.is_amm_circuit_breaker_tripped());
+    assert_eq!(market.paused_operations, 0);
+    assert_eq!(circuit_breaker.paused_amm_fill, 0);
+
+    // amm fills paused by the admin stay paused after the breaker resets
+    market.paused_operations = PerpOperation::AmmFill as u8;
+    let tripped = update_amm_circuit_breaker(
+        &mut market,
+        &mut circuit_breaker,
+        &mm_oracle_price_data(112 * PRICE_PRECISION_I64),
+        130,
+    )
+    .unwrap();
+    assert!(tripped);
+    assert_eq!(circuit_breaker.paused_amm_fill, 0);
+
+    let tripped = update_amm_circuit_breaker(
+        &mut market,
+        &mut circuit_breaker,
+        &mm_oracle_price_data(112 * PRICE_PRECISION_I64),
+        150,
+    )
+    .unwrap();
+    assert!(!tripped);
+    assert!(!market.is_amm_circuit_breaker_tripped());
+    assert!(market.is_operation_paused(PerpOperation::AmmFill));
+
+    circuit_breaker.market_index = 1;
+    assert_eq!(
+        update_amm_circuit_breaker(
+            &mut market,
+            &mut circuit_breaker,
+            &mm_oracle_price_data(112 * PRICE_PRECISION_I64),
+            151,
+        ),
+        Err(ErrorCode::InvalidPerpCircuitBreaker)
+    );
+
+    // once armed the circuit breaker has to be passed in
+    assert!(load_perp_circuit_breaker(&[], &market).unwrap().is_none());
+    market.amm_circuit_breaker_status = AmmCircuitBreakerStatus::Armed;
+    assert!(matches!(
+        load_perp_circuit_breaker(&[], &market),
+        Err(ErrorCode::InvalidPerpCircuitBreaker)
+    ));
+}
@@ -655,6 +655,46 @@ pub enum ErrorCode {
     CannotRevokeBuilderWithOpenOrders,
     #[msg("Unable to load builder account")]
     UnableToLoadRevenueShareAccount,
+    #[msg("User heartbeat has not expired")]
+    HeartbeatNotExpired,
+    #[msg("User orders extension has no available order slots")]
+    UserOrdersExtensionFull,
+    #[msg("Invalid user orders extension resize")]
+    InvalidUserOrdersExtensionResize,
+    #[msg("User orders extension does not belong to user")]
+    UserOrdersExtensionUserMismatch,
+    #[msg("User orders extension still has open orders")]
+    UserOrdersExtensionHasOpenOrders,
+    #[msg("Order must be activated first")]
+    OrderNotActivated,
+    #[msg("Order is not pending activation")]
+    OrderNotPendingActivation,
+    #[msg("Order start ts/slot not reached")]
+    OrderActivationNotReached,
+    #[msg("Invalid order min fill size")]
+    InvalidOrderMinFillSize,
+    #[msg("Fill below order min fill size")]
+    FillBelowMinFillSize,
+    #[msg("Invalid perp order book")]
+    InvalidPerpOrderBook,
+    #[msg("Market fills clear in batch auctions")]
+    MarketFillsInBatchAuction,
+    #[msg("Batch auction not ready to clear")]
+    BatchAuctionNotReady,
+    #[msg("Invalid signed msg quote")]
+    InvalidSignedMsgQuote,
+    #[msg("Invalid concentrated curve")]
+    InvalidConcentratedCurve,
+    #[msg("Invalid perp curve crank")]
+    InvalidPerpCurveCrank,
+    #[msg("Perp curve crank not ready")]
+    PerpCurveCrankNotReady,
+    #[msg("Invalid perp circuit breaker")]
+    InvalidPerpCircuitBreaker,
+    #[msg("Spread order not filled")]
+    SpreadOrderNotFilled,
+    #[msg("User orders extension must be passed in")]
+    UserOrdersExtensionRequired,
 }
 
 #[macro_export]
@@ -14,16 +14,18 @@ use crate::controller::token::{close_vault, initialize_immutable_owner, initiali
 use crate::error::ErrorCode;
 use crate::ids::{admin_hot_wallet, amm_spread_adjust_wallet, mm_oracle_crank_wallet};
 use crate::instructions::constraints::*;
-use crate::instructions::optional_accounts::{load_maps, AccountMaps};
+use crate::instructions::optional_accounts::{
+    get_perp_circuit_breaker_accounts, load_maps, AccountMaps,
+};
 use crate::math::casting::Cast;
 use crate::math::constants::{
     AMM_TIMES_PEG_TO_QUOTE_PRECISION_RATIO, DEFAULT_LIQUIDATION_MARGIN_BUFFER_RATIO,
     FEE_POOL_TO_REVENUE_POOL_THRESHOLD, GOV_SPOT_MARKET_INDEX, IF_FACTOR_PRECISION,
     INSURANCE_A_MAX, INSURANCE_B_MAX, INSURANCE_C_MAX, INSURANCE_SPECULATIVE_MAX,
-    LIQUIDATION_FEE_PRECISION, MAX_CONCENTRATION_COEFFICIENT, MAX_SQRT_K,
-    MAX_UPDATE_K_PRICE_CHANGE, PERCENTAGE_PRECISION, PERCENTAGE_PRECISION_I64,
-    QUOTE_SPOT_MARKET_INDEX, SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_IMF_PRECISION,
-    SPOT_WEIGHT_PRECISION, THIRTEEN_DAY, TWENTY_FOUR_HOUR,
+    LIQUIDATION_FEE_PRECISION, MAX_CONCENTRATED_BAND_WIDTH, MAX_CONCENTRATED_LIQUIDITY_MULTIPLIER,
+    MAX_CONCENTRATION_COEFFICIENT, MAX_SQRT_K, MAX_UPDATE_K_PRICE_CHANGE, PERCENTAGE_PRECISION,
+    PERCENTAGE_PRECISION_I64, QUOTE_SPOT_MARKET_INDEX, SPOT_CUMULATIVE_INTEREST_PRECISION,
+    SPOT_IMF_PRECISION, SPOT_WEIGHT_PRECISION, THIRTEEN_DAY, TWENTY_FOUR_HOUR,
 };
 use crate::math::cp_curve::get_update_k_result;
 use crate::math::helpers::get_proportion_u128;
@@ -35,7 +37,8 @@ use crate::math::spot_withdraw::validate_spot_market_vault_amount;
 use crate::math::{amm, bn};
 use crate::optional_accounts::get_token_mint;
 use crate::state::events::{
-    CurveRecord, DepositDirection, DepositExplanation, DepositRecord, SpotMarketVaultDepositRecord,
+    CurveRecord, CurveUpdateReason, DepositDirection, DepositExplanation, DepositRecord,
+    SpotMarketVaultDepositRecord,
 };
 use crate::state::fulfillment_params::openbook_v2::{
     OpenbookV2Context, OpenbookV2FulfillmentConfig,
@@ -56,10 +59,14 @@ use crate::state::oracle::{
 };
 use crate::state::oracle_map::OracleMap;
 use crate::state::paused_operations::{InsuranceFundOperation, PerpOperation, SpotOperation};
+use crate::state::perp_circuit_breaker::PerpCircuitBreaker;
+use crate::state::perp_curve_crank::PerpCurveCrank;
 use crate::state::perp_market::{
-    ContractTier, ContractType, InsuranceClaim, MarketStatus, PerpMarket, PoolBalance, AMM,
+    AmmCircuitBreakerStatus, AmmQuoteMode, ContractTier, ContractType, InsuranceClaim,
+    MakerAllocationPolicy, MarketStatus, PerpMarket, PoolBalance, AMM,
 };
 use crate::state::perp_market_map::{get_writable_perp_market_set, MarketSet};
+use crate::state::perp_order_book::PerpOrderBook;
 use crate::state::protected_maker_mode_config::ProtectedMakerModeConfig;
 use crate::state::pyth_lazer_oracle::{PythLazerOracle, PYTH_LAZER_ORACLE_SEED};
 use crate::state::spot_market::{
@@ -981,9 +988,18 @@ pub fn handle_initialize_perp_market(
         high_leverage_margin_ratio_maintenance: 0,
         protected_maker_limit_price_divisor: 0,
         protected_maker_dynamic_divisor: 0,
-        padding1: 0,
+        oracle_realized_vol_fast: 0,
+        oracle_realized_vol_slow: 0,
         last_fill_price: 0,
-        padding: [0; 24],
+        last_batch_auction_slot: 0,
+        batch_auction_duration: 0,
+        maker_allocation_policy: MakerAllocationPolicy::PriceTime,
+        pro_rata_top_of_book_bonus: 0,
+        amm_quote_mode: AmmQuoteMode::Curve,
+        amm_circuit_breaker_status: AmmCircuitBreakerStatus::Disabled,
+        oracle_realized_vol_fast_half_life: 0,
+        oracle_realized_vol_slow_half_life: 0,
+        oracle_quote_inventory_budget: 0,
         amm: AMM {
             oracle: *ctx.accounts.oracle.key,
             oracle_source,
@@ -1079,7 +1095,9 @@ pub fn handle_initialize_perp_market(
             quote_asset_amount_with_unsettled_lp: 0,
             reference_price_offset: 0,
             amm_inventory_spread_adjustment: 0,
-            padding: [0; 3],
+            concentrated_band_width: 0,
+            concentrated_liquidity_multiplier: 0,
+            padding: [0; 1],
             last_funding_oracle_twap: 0,
         },
     };
@@ -2188,6 +2206,7 @@ pub fn handle_repeg_amm_curve(ctx: Context<RepegCurve>, new_peg_candidate: u128)
         adjustment_cost,
         oracle_price,
         fill_record: 0,
+        reason: CurveUpdateReason::AdminRepeg,
     });
 
     Ok(())
@@ -2476,6 +2495,7 @@ pub fn handle_update_k(ctx: Context<AdminUpdateK>, sqrt_k: u128) -> Result<()> {
         total_fee_minus_distributions,
         oracle_price,
         fill_record: 0,
+        reason: CurveUpdateReason::AdminUpdateK,
     });
 
     Ok(())
@@ -3439,6 +3459,59 @@ pub fn handle_update_perp_market_curve_update_intensity(
     Ok(())
 }
 
+#[access_control(
+    perp_market_valid(&ctx.accounts.perp_market)
+)]
+pub fn handle_update_perp_market_oracle_realized_vol_half_lives(
+    ctx: Context<HotAdminUpdatePerpMarket>,
+    oracle_realized_vol_fast_half_life: u8,
+    oracle_realized_vol_slow_half_life: u8,
+) -> Result<()> {
+    let perp_market = &mut load_mut!(ctx.accounts.perp_market)?;
+    msg!("perp market {}", perp_market.market_index);
+
+    validate!(
+        oracle_realized_vol_slow_half_life == 0
+            || oracle_realized_vol_fast_half_life <= oracle_realized_vol_slow_half_life,
+        ErrorCode::DefaultError,
+        "oracle_realized_vol_fast_half_life={} must be <= oracle_realized_vol_slow_half_life={}",
+        oracle_realized_vol_fast_half_life,
+        oracle_realized_vol_slow_half_life
+    )?;
+
+    msg!(
+        "perp_market.oracle_realized_vol_fast_half_life: {} -> {}",
+        perp_market.oracle_realized_vol_fast_half_life,
+        oracle_realized_vol_fast_half_life
+    );
+
+    msg!(
+        "perp_market.oracle_realized_vol_slow_half_life: {} -> {}",
+        perp_market.oracle_realized_vol_slow_half_life,
+        oracle_realized_vol_slow_half_life
+    );
+
+    if oracle_realized_vol_fast_half_life == 0 {
+        msg!(
+            "perp_market.oracle_realized_vol_fast: {} -> 0",
+            perp_market.oracle_realized_vol_fast
+        );
+        perp_market.oracle_realized_vol_fast = 0;
+    }
+
+    if oracle_realized_vol_slow_half_life == 0 {
+        msg!(
+            "perp_market.oracle_realized_vol_slow: {} -> 0",
+            perp_market.oracle_realized_vol_slow
+        );
+        perp_market.oracle_realized_vol_slow = 0;
+    }
+
+    perp_market.oracle_realized_vol_fast_half_life = oracle_realized_vol_fast_half_life;
+    perp_market.oracle_realized_vol_slow_half_life = oracle_realized_vol_slow_half_life;
+    Ok(())
+}
+
 pub fn handle_update_lp_cooldown_time(
     ctx: Context<AdminUpdateState>,
     lp_cooldown_time: u64,
@@ -4095,6 +4168,207 @@ pub fn handle_update_perp_market_protected_maker_params(
     Ok(())
 }
 
+#[access_control(
+    perp_market_valid(&ctx.accounts.perp_market)
+)]
+pub fn handle_update_perp_market_batch_auction_duration(
+    ctx: Context<AdminUpdatePerpMarket>,
+    batch_auction_duration: u16,
+) -> Result<()> {
+    let perp_market = &mut load_mut!(ctx.accounts.perp_market)?;
+    msg!("perp market {}", perp_market.market_index);
+
+    msg!(
+        "perp_market.batch_auction_duration: {:?} -> {:?}",
+        perp_market.batch_auction_duration,
+        batch_auction_duration
+    );
+
+    perp_market.batch_auction_duration = batch_auction_duration;
+    perp_market.last_batch_auction_slot = Clock::get()?.slot;
+    Ok(())
+}
+
+#[access_control(
+    perp_market_valid(&ctx.accounts.perp_market)
+)]
+pub fn handle_update_perp_market_maker_allocation_policy(
+    ctx: Context<AdminUpdatePerpMarket>,
+    maker_allocation_policy: MakerAllocationPolicy,
+    pro_rata_top_of_book_bonus: u8,
+) -> Result<()> {
+    let perp_market = &mut load_mut!(ctx.accounts.perp_market)?;
+    msg!("perp market {}", perp_market.market_index);
+
+    validate!(
+        pro_rata_top_of_book_bonus <= 100,
+        ErrorCode::DefaultError,
+        "pro_rata_top_of_book_bonus must be <= 100"
+    )?;
+
+    msg!(
+        "perp_market.maker_allocation_policy: {:?} -> {:?}",
+        perp_market.maker_allocation_policy,
+        maker_allocation_policy
+    );
+
+    msg!(
+        "perp_market.pro_rata_top_of_book_bonus: {:?} -> {:?}",
+        perp_market.pro_rata_top_of_book_bonus,
+        pro_rata_top_of_book_bonus
+    );
+
+    perp_market.maker_allocation_policy = maker_allocation_policy;
+    perp_market.pro_rata_top_of_book_bonus = pro_rata_top_of_book_bonus;
+    Ok(())
+}
+
+#[access_control(
+    perp_market_valid(&ctx.accounts.perp_market)
+)]
+pub fn handle_update_perp_market_concentrated_curve(
+    ctx: Context<AdminUpdatePerpMarket>,
+    concentrated_band_width: u8,
+    concentrated_liquidity_multiplier: u8,
+) -> Result<()> {
+    let perp_market = &mut load_mut!(ctx.accounts.perp_market)?;
+    msg!("perp market {}", perp_market.market_index);
+
+    validate!(
+        concentrated_band_width <= MAX_CONCENTRATED_BAND_WIDTH,
+        ErrorCode::InvalidConcentratedCurve,
+        "concentrated_band_width={} > {}",
+        concentrated_band_width,
+        MAX_CONCENTRATED_BAND_WIDTH
+    )?;
+
+    validate!(
+        concentrated_liquidity_multiplier <= MAX_CONCENTRATED_LIQUIDITY_MULTIPLIER,
+        ErrorCode::InvalidConcentratedCurve,
+        "concentrated_liquidity_multiplier={} > {}",
+        concentrated_liquidity_multiplier,
+        MAX_CONCENTRATED_LIQUIDITY_MULTIPLIER
+    )?;
+
+    // the curve changes the exit price for the net market position, so charge it like a k change
+    let net_market_value_before = math::position::calculate_base_asset_value(
+        perp_market.amm.base_asset_amount_with_amm,
+        &perp_market.amm,
+    )?;
+
+    msg!(
+        "perp_market.amm.concentrated_band_width: {:?} -> {:?}",
+        perp_market.amm.concentrated_band_width,
+        concentrated_band_width
+    );
+
+    msg!(
+        "perp_market.amm.concentrated_liquidity_multiplier: {:?} -> {:?}",
+        perp_market.amm.concentrated_liquidity_multiplier,
+        concentrated_liquidity_multiplier
+    );
+
+    perp_market.amm.concentrated_band_width = concentrated_band_width;
+    perp_market.amm.concentrated_liquidity_multiplier = concentrated_liquidity_multiplier;
+
+    let (_, adjustment_cost) = math::position::calculate_base_asset_value_and_pnl(
+        perp_market.amm.base_asset_amount_with_amm,
+        net_market_value_before,
+        &perp_market.amm,
+    )?;
+
+    if adjustment_cost > 0 {
+        let max_cost = perp_market
+            .amm
+            .total_fee_minus_distributions
+            .safe_sub(get_total_fee_lower_bound(perp_market)?.cast()?)?
+            .safe_sub(perp_market.amm.total_fee_withdrawn.cast()?)?;
+
+        validate!(
+            adjustment_cost <= max_cost,
+            ErrorCode::InvalidConcentratedCurve,
+            "adjustment_cost={} > max_cost={} for curve change",
+            adjustment_cost,
+            max_cost
+        )?;
+    }
+
+    perp_market.amm.total_fee_minus_distributions = perp_market
+        .amm
+        .total_fee_minus_distributions
+        .safe_sub(adjustment_cost)?;
+
+    perp_market.amm.net_revenue_since_last_funding = perp_market
+        .amm
+        .net_revenue_since_last_funding
+        .safe_sub(adjustment_cost.cast()?)?;
+
+    let (terminal_quote_asset_reserve, terminal_base_asset_reserve) =
+        math::amm::calculate_terminal_reserves(&perp_market.amm)?;
+    perp_market.amm.terminal_quote_asset_reserve = terminal_quote_asset_reserve;
+
+    let (min_base_asset_reserve, max_base_asset_reserve) = math::amm::calculate_bid_ask_bounds(
+        perp_market.amm.concentration_coef,
+        terminal_base_asset_reserve,
+    )?;
+    perp_market.amm.min_base_asset_reserve = min_base_asset_reserve;
+    perp_market.amm.max_base_asset_reserve = max_base_asset_reserve;
+
+    msg!("curve adjustment cost: {}", adjustment_cost);
+
+    validate_perp_market(perp_market)?;
+
+    Ok(())
+}
+
+#[access_control(
+    perp_market_valid(&ctx.accounts.perp_market)
+)]
+pub fn handle_update_perp_market_amm_quote_mode(
+    ctx: Context<AdminUpdatePerpMarket>,
+    amm_quote_mode: AmmQuoteMode,
+    oracle_quote_inventory_budget: u64,
+) -> Result<()> {
+    let perp_market = &mut load_mut!(ctx.accounts.perp_market)?;
+    msg!("perp market {}", perp_market.market_index);
+
+    if amm_quote_mode == AmmQuoteMode::Oracle {
+        // the reserves are kept balanced, so sqrt_k must cover the inventory
+        validate!(
+            oracle_quote_inventory_budget.cast::<u128>()? < perp_market.amm.sqrt_k,
+            ErrorCode::DefaultError,
+            "oracle_quote_inventory_budget={} must be < sqrt_k={}",
+            oracle_quote_inventory_budget,
+            perp_market.amm.sqrt_k
+        )?;
+
+        validate!(
+            perp_market.amm.base_asset_amount_with_amm.unsigned_abs()
+                <= oracle_quote_inventory_budget.cast()?,
+            ErrorCode::DefaultError,
+            "base_asset_amount_with_amm={} exceeds oracle_quote_inventory_budget={}",
+            perp_market.amm.base_asset_amount_with_amm,
+            oracle_quote_inventory_budget
+        )?;
+    }
+
+    msg!(
+        "perp_market.amm_quote_mode: {:?} -> {:?}",
+        perp_market.amm_quote_mode,
+        amm_quote_mode
+    );
+
+    msg!(
+        "perp_market.oracle_quote_inventory_budget: {:?} -> {:?}",
+        perp_market.oracle_quote_inventory_budget,
+        oracle_quote_inventory_budget
+    );
+
+    perp_market.amm_quote_mode = amm_quote_mode;
+    perp_market.oracle_quote_inventory_budget = oracle_quote_inventory_budget;
+    Ok(())
+}
+
 #[access_control(
     perp_market_valid(&ctx.accounts.perp_market)
 )]
@@ -4554,6 +4828,7 @@ pub fn handle_settle_expired_market<'c: 'info, 'info>(
         &mut oracle_map,
         state,
         &clock,
+        &get_perp_circuit_breaker_accounts(ctx.remaining_accounts)?,
     )?;
 
     controller::repeg::settle_expired_market(
@@ -4581,6 +4856,142 @@ pub fn handle_initialize_high_leverage_mode_config(
     Ok(())
 }
 
+pub fn handle_initialize_perp_order_book(
+    ctx: Context<InitializePerpOrderBook>,
+    market_index: u16,
+) -> Result<()> {
+    let mut order_book = ctx.accounts.perp_order_book.load_init()?;
+
+    order_book.market_index = market_index;
+
+    order_book.validate()?;
+
+    Ok(())
+}
+
+pub fn handle_initialize_perp_curve_crank(
+    ctx: Context<InitializePerpCurveCrank>,
+    market_index: u16,
+    crank_interval: i64,
+    epoch_duration: i64,
+    epoch_budget_pct: u32,
+) -> Result<()> {
+    let mut curve_crank = ctx.accounts.perp_curve_crank.load_init()?;
+
+    curve_crank.market_index = market_index;
+    curve_crank.crank_interval = crank_interval;
+    curve_crank.epoch_duration = epoch_duration;
+    curve_crank.epoch_budget_pct = epoch_budget_pct;
+
+    curve_crank.validate()?;
+
+    Ok(())
+}
+
+pub fn handle_update_perp_curve_crank(
+    ctx: Context<UpdatePerpCurveCrank>,
+    crank_interval: i64,
+    epoch_duration: i64,
+    epoch_budget_pct: u32,
+) -> Result<()> {
+    let mut curve_crank = load_mut!(ctx.accounts.perp_curve_crank)?;
+    msg!("perp market {}", curve_crank.market_index);
+
+    msg!(
+        "curve_crank.crank_interval: {} -> {}",
+        curve_crank.crank_interval,
+        crank_interval
+    );
+    msg!(
+        "curve_crank.epoch_duration: {} -> {}",
+        curve_crank.epoch_duration,
+        epoch_duration
+    );
+    msg!(
+        "curve_crank.epoch_budget_pct: {} -> {}",
+        curve_crank.epoch_budget_pct,
+        epoch_budget_pct
+    );
+
+    curve_crank.crank_interval = crank_interval;
+    curve_crank.epoch_duration = epoch_duration;
+    curve_crank.epoch_budget_pct = epoch_budget_pct;
+
+    curve_crank.validate()?;
+
+    Ok(())
+}
+
+pub fn handle_initialize_perp_circuit_breaker(
+    ctx: Context<InitializePerpCircuitBreaker>,
+    market_index: u16,
+    max_oracle_move_pct: u32,
+    oracle_move_window_slots: u32,
+    max_inventory_change_per_slot: u64,
+    cooldown_slots: u32,
+) -> Result<()> {
+    let mut circuit_breaker = ctx.accounts.perp_circuit_breaker.load_init()?;
+    let perp_market = &mut load_mut!(ctx.accounts.perp_market)?;
+
+    circuit_breaker.market_index = market_index;
+    circuit_breaker.max_oracle_move_pct = max_oracle_move_pct;
+    circuit_breaker.oracle_move_window_slots = oracle_move_window_slots;
+    circuit_breaker.max_inventory_change_per_slot = max_inventory_change_per_slot;
+    circuit_breaker.cooldown_slots = cooldown_slots;
+
+    circuit_breaker.validate()?;
+
+    msg!(
+        "perp_market.amm_circuit_breaker_status: {:?} -> {:?}",
+        perp_market.amm_circuit_breaker_status,
+        AmmCircuitBreakerStatus::Armed
+    );
+    perp_market.amm_circuit_breaker_status = AmmCircuitBreakerStatus::Armed;
+
+    Ok(())
+}
+
+pub fn handle_update_perp_circuit_breaker_config(
+    ctx: Context<UpdatePerpCircuitBreakerConfig>,
+    max_oracle_move_pct: u32,
+    oracle_move_window_slots: u32,
+    max_inventory_change_per_slot: u64,
+    cooldown_slots: u32,
+) -> Result<()> {
+    let mut circuit_breaker = load_mut!(ctx.accounts.perp_circuit_breaker)?;
+    msg!("perp market {}", circuit_breaker.market_index);
+
+    msg!(
+        "circuit_breaker.max_oracle_move_pct: {} -> {}",
+        circuit_breaker.max_oracle_move_pct,
+        max_oracle_move_pct
+    );
+    msg!(
+        "circuit_breaker.oracle_move_window_slots: {} -> {}",
+        circuit_breaker.oracle_move_window_slots,
+        oracle_move_window_slots
+    );
+    msg!(
+        "circuit_breaker.max_inventory_change_per_slot: {} -> {}",
+        circuit_breaker.max_inventory_change_per_slot,
+        max_inventory_change_per_slot
+    );
+    msg!(
+        "circuit_breaker.cooldown_slots: {} -> {}",
+        circuit_breaker.cooldown_slots,
+        cooldown_slots
+    );
+
+    circuit_breaker.max_oracle_move_pct = max_oracle_move_pct;
+    circuit_breaker.oracle_move_window_slots = oracle_move_window_slots;
+    circuit_breaker.max_inventory_change_per_slot = max_inventory_change_per_slot;
+    circuit_breaker.cooldown_slots = cooldown_slots;
+
+    circuit_breaker.validate()?;
+
+    Ok(())
+}
+
 pub fn handle_update_high_leverage_mode_config(
     ctx: Context<UpdateHighLeverageModeConfig>,
     max_users: u32,
@@ -5735,6 +6146,107 @@ pub struct InitializeHighLeverageModeConfig<'info> {
     pub system_program: Program<'info, System>,
 }
 
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct InitializePerpOrderBook<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(
+        init,
+        seeds = [b"perp_order_book".as_ref(), market_index.to_le_bytes().as_ref()],
+        space = PerpOrderBook::SIZE,
+        bump,
+        payer = admin
+    )]
+    pub perp_order_book: AccountLoader<'info, PerpOrderBook>,
+    #[account(
+        seeds = [b"perp_market", market_index.to_le_bytes().as_ref()],
+        bump,
+    )]
+    pub perp_market: AccountLoader<'info, PerpMarket>,
+    #[account(
+        has_one = admin
+    )]
+    pub state: Box<Account<'info, State>>,
+    pub rent: Sysvar<'info, Rent>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct InitializePerpCurveCrank<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(
+        init,
+        seeds = [b"perp_curve_crank".as_ref(), market_index.to_le_bytes().as_ref()],
+        space = PerpCurveCrank::SIZE,
+        bump,
+        payer = admin
+    )]
+    pub perp_curve_crank: AccountLoader<'info, PerpCurveCrank>,
+    #[account(
+        seeds = [b"perp_market", market_index.to_le_bytes().as_ref()],
+        bump,
+    )]
+    pub perp_market: AccountLoader<'info, PerpMarket>,
+    #[account(
+        has_one = admin
+    )]
+    pub state: Box<Account<'info, State>>,
+    pub rent: Sysvar<'info, Rent>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdatePerpCurveCrank<'info> {
+    pub admin: Signer<'info>,
+    #[account(mut)]
+    pub perp_curve_crank: AccountLoader<'info, PerpCurveCrank>,
+    #[account(
+        has_one = admin
+    )]
+    pub state: Box<Account<'info, State>>,
+}
+
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct InitializePerpCircuitBreaker<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(
+        init,
+        seeds = [b"perp_circuit_breaker".as_ref(), market_index.to_le_bytes().as_ref()],
+        space = PerpCircuitBreaker::SIZE,
+        bump,
+        payer = admin
+    )]
+    pub perp_circuit_breaker: AccountLoader<'info, PerpCircuitBreaker>,
+    #[account(
+        mut,
+        seeds = [b"perp_market", market_index.to_le_bytes().as_ref()],
+        bump,
+    )]
+    pub perp_market: AccountLoader<'info, PerpMarket>,
+    #[account(
+        has_one = admin
+    )]
+    pub state: Box<Account<'info, State>>,
+    pub rent: Sysvar<'info, Rent>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdatePerpCircuitBreakerConfig<'info> {
+    pub admin: Signer<'info>,
+    #[account(mut)]
+    pub perp_circuit_breaker: AccountLoader<'info, PerpCircuitBreaker>,
+    #[account(
+        has_one = admin
+    )]
+    pub state: Box<Account<'info, State>>,
+}
+
 #[derive(Accounts)]
 pub struct UpdateHighLeverageModeConfig<'info> {
     #[account(mut)]
@@ -24,12 +24,18 @@ use crate::ids::admin_hot_wallet;
 use crate::ids::dflow_mainnet_aggregator_4;
 use crate::ids::{jupiter_mainnet_3, jupiter_mainnet_4, jupiter_mainnet_6, serum_program};
 use crate::instructions::constraints::*;
+use crate::instructions::optional_accounts::get_perp_circuit_breaker_accounts;
+use crate::instructions::optional_accounts::get_perp_order_book_accounts;
 use crate::instructions::optional_accounts::get_revenue_share_escrow_account;
+use crate::instructions::optional_accounts::get_user_orders_extension_account;
+use crate::instructions::optional_accounts::load_maker_orders_from_extensions;
+use crate::instructions::optional_accounts::update_perp_order_books_after_fill;
 use crate::instructions::optional_accounts::{load_maps, AccountMaps};
 use crate::math::casting::Cast;
 use crate::math::constants::QUOTE_SPOT_MARKET_INDEX;
 use crate::math::margin::get_margin_calculation_for_disable_high_leverage_mode;
 use crate::math::margin::{calculate_user_equity, meets_settle_pnl_maintenance_margin_requirement};
+use crate::math::oracle::DriftAction;
 use crate::math::orders::{estimate_price_from_side, find_bids_and_asks_from_users};
 use crate::math::position::calculate_base_asset_value_and_pnl_with_oracle_price;
 use crate::math::safe_math::SafeMath;
@@ -46,11 +52,14 @@ use crate::state::insurance_fund_stake::InsuranceFundStake;
 use crate::state::oracle_map::OracleMap;
 use crate::state::order_params::{OrderParams, PlaceOrderOptions};
 use crate::state::paused_operations::{PerpOperation, SpotOperation};
+use crate::state::perp_circuit_breaker::PerpCircuitBreaker;
+use crate::state::perp_curve_crank::PerpCurveCrank;
 use crate::state::perp_market::{ContractType, MarketStatus, PerpMarket};
 use crate::state::perp_market_map::{
     get_market_set_for_spot_positions, get_market_set_for_user_positions, get_market_set_from_list,
     get_writable_perp_market_set, get_writable_perp_market_set_from_vec, MarketSet, PerpMarketMap,
 };
+use crate::state::perp_order_book::PerpOrderBook;
 use crate::state::revenue_share::RevenueShareEscrowZeroCopyMut;
 use crate::state::revenue_share::RevenueShareOrder;
 use crate::state::revenue_share::RevenueShareOrderBitFlag;
@@ -95,9 +104,16 @@ pub fn handle_fill_perp_order<'c: 'info, 'info>(
     order_id: Option<u32>,
 ) -> Result<()> {
     let (order_id, market_index) = {
-        let user = &load!(ctx.accounts.user)?;
+        let user = &mut load_mut!(ctx.accounts.user)?;
         // if there is no order id, use the users last order id
         let order_id = order_id.unwrap_or_else(|| user.get_last_order_id());
+        if let Some(orders_extension) =
+            get_user_orders_extension_account(ctx.remaining_accounts, &ctx.accounts.user.key())?
+                .as_mut()
+        {
+            controller::orders::load_order_from_extension(user, orders_extension, order_id)?;
+        }
+
         let market_index = match user.get_order(order_id) {
             Some(order) => order.market_index,
             None => {
@@ -157,14 +173,39 @@ fn fill_order<'c: 'info, 'info>(
         None
     };
 
+    let perp_circuit_breakers = get_perp_circuit_breaker_accounts(ctx.remaining_accounts)?;
+
     controller::repeg::update_amm(
         market_index,
         &perp_market_map,
         &mut oracle_map,
         &ctx.accounts.state,
         clock,
+        &perp_circuit_breakers,
+    )?;
+
+    let taker_direction = load!(ctx.accounts.user)?
+        .get_order(order_id)
+        .map(|order| order.direction)
+        .ok_or(ErrorCode::OrderDoesNotExist)?;
+    load_maker_orders_from_extensions(
+        ctx.remaining_accounts,
+        &makers_and_referrer,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        clock.slot,
+        MarketType::Perp,
+        market_index,
+        taker_direction,
     )?;
 
+    let perp_order_book_loaders = get_perp_order_book_accounts(ctx.remaining_accounts)?;
+    let perp_order_books = perp_order_book_loaders
+        .iter()
+        .map(|perp_order_book| perp_order_book.load())
+        .collect::<Result<Vec<_>>>()?;
+
     controller::orders::fill_perp_order(
         order_id,
         &ctx.accounts.state,
@@ -182,6 +223,69 @@ fn fill_order<'c: 'info, 'info>(
         FillMode::Fill,
         &mut escrow.as_mut(),
         builder_referral_enabled,
+        &perp_order_books
+            .iter()
+            .map(|perp_order_book| &**perp_order_book)
+            .collect::<Vec<_>>(),
+    )?;
+
+    drop(perp_order_books);
+
+    update_perp_order_books_after_fill(
+        ctx.remaining_accounts,
+        &ctx.accounts.user.key(),
+        &load!(ctx.accounts.user)?,
+        &makers_and_referrer,
+    )?;
+
+    controller::repeg::update_amm_circuit_breaker_after_fill(
+        market_index,
+        &perp_market_map,
+        &mut oracle_map,
+        state,
+        clock.slot,
+        &perp_circuit_breakers,
+    )?;
+
+    Ok(())
+}
+
+#[access_control(
+    fill_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_clear_perp_batch_auction<'c: 'info, 'info>(
+    ctx: Context<'_, '_, 'c, 'info, ClearPerpBatchAuction<'info>>,
+    market_index: u16,
+) -> Result<()> {
+    let clock = &Clock::get()?;
+    let state = &ctx.accounts.state;
+
+    let remaining_accounts_iter = &mut ctx.remaining_accounts.iter().peekable();
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        remaining_accounts_iter,
+        &get_writable_perp_market_set(market_index),
+        &MarketSet::new(),
+        clock.slot,
+        Some(state.oracle_guard_rails),
+    )?;
+
+    let (users, users_stats) = load_user_maps(remaining_accounts_iter, true)?;
+
+    controller::orders::clear_perp_batch_auction(
+        market_index,
+        state,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &users,
+        &users_stats,
+        &mut load_mut!(ctx.accounts.perp_order_book)?,
+        &ctx.accounts.authority.key(),
+        clock,
     )?;
 
     Ok(())
@@ -224,9 +328,16 @@ pub fn handle_fill_spot_order<'c: 'info, 'info>(
     _maker_order_id: Option<u32>,
 ) -> Result<()> {
     let (order_id, market_index) = {
-        let user = &load!(ctx.accounts.user)?;
+        let user = &mut load_mut!(ctx.accounts.user)?;
         // if there is no order id, use the users last order id
         let order_id = order_id.unwrap_or_else(|| user.get_last_order_id());
+        if let Some(orders_extension) =
+            get_user_orders_extension_account(ctx.remaining_accounts, &ctx.accounts.user.key())?
+                .as_mut()
+        {
+            controller::orders::load_order_from_extension(user, orders_extension, order_id)?;
+        }
+
         let market_index = user
             .get_order(order_id)
             .map(|order| order.market_index)
@@ -320,6 +431,24 @@ fn fill_spot_order<'c: 'info, 'info>(
         }
     };
 
+    if fulfillment_type == SpotFulfillmentType::Match {
+        let taker_direction = load!(ctx.accounts.user)?
+            .get_order(order_id)
+            .map(|order| order.direction)
+            .ok_or(ErrorCode::OrderDoesNotExist)?;
+        load_maker_orders_from_extensions(
+            ctx.remaining_accounts,
+            &makers_and_referrer,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            clock.slot,
+            MarketType::Spot,
+            market_index,
+            taker_direction,
+        )?;
+    }
+
     controller::orders::fill_spot_order(
         order_id,
         &ctx.accounts.state,
@@ -344,6 +473,162 @@ fn fill_spot_order<'c: 'info, 'info>(
     Ok(())
 }
 
+#[access_control(
+    fill_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_fill_spread_order<'c: 'info, 'info>(
+    ctx: Context<'_, '_, 'c, 'info, FillOrder<'info>>,
+    order_id: u32,
+) -> Result<()> {
+    let clock = Clock::get()?;
+    let state = &ctx.accounts.state;
+
+    let (perp_market_indexes, spot_market_index) = {
+        let user = load!(ctx.accounts.user)?;
+        let oco_group_id = user
+            .get_order(order_id)
+            .filter(|order| order.is_spread_leg())
+            .map(|order| order.oco_group_id())
+            .ok_or(ErrorCode::OrderDoesNotExist)?;
+
+        let legs = user.orders.iter().filter(|order| {
+            order.status == OrderStatus::Open
+                && order.is_spread_leg()
+                && order.oco_group_id() == oco_group_id
+        });
+
+        let mut perp_market_indexes = vec![];
+        let mut spot_market_index = None;
+        for leg in legs {
+            match leg.market_type {
+                MarketType::Perp => perp_market_indexes.push(leg.market_index),
+                MarketType::Spot => spot_market_index = Some(leg.market_index),
+            }
+        }
+
+        (perp_market_indexes, spot_market_index)
+    };
+
+    let remaining_accounts_iter = &mut ctx.remaining_accounts.iter().peekable();
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        remaining_accounts_iter,
+        &get_writable_perp_market_set_from_vec(&perp_market_indexes),
+        &match spot_market_index {
+            Some(market_index) => {
+                get_writable_spot_market_set_from_many(vec![QUOTE_SPOT_MARKET_INDEX, market_index])
+            }
+            None => MarketSet::new(),
+        },
+        clock.slot,
+        Some(state.oracle_guard_rails),
+    )?;
+
+    let (makers_and_referrer, makers_and_referrer_stats) =
+        load_user_maps(remaining_accounts_iter, true)?;
+
+    let builder_codes_enabled = state.builder_codes_enabled();
+    let builder_referral_enabled = state.builder_referral_enabled();
+    let mut escrow = if builder_codes_enabled || builder_referral_enabled {
+        get_revenue_share_escrow_account(
+            remaining_accounts_iter,
+            &load!(ctx.accounts.user)?.authority,
+        )?
+    } else {
+        None
+    };
+
+    let mut fulfillment_params = match spot_market_index {
+        Some(market_index) => {
+            let base_market = spot_market_map.get_ref(&market_index)?;
+            let quote_market = spot_market_map.get_quote_spot_market()?;
+            Some(MatchFulfillmentParams::new(
+                remaining_accounts_iter,
+                &base_market,
+                &quote_market,
+            )?)
+        }
+        None => None,
+    };
+
+    let perp_circuit_breakers = get_perp_circuit_breaker_accounts(ctx.remaining_accounts)?;
+
+    for market_index in perp_market_indexes.iter() {
+        controller::repeg::update_amm(
+            *market_index,
+            &perp_market_map,
+            &mut oracle_map,
+            state,
+            &clock,
+            &perp_circuit_breakers,
+        )?;
+    }
+
+    let perp_order_book_loaders = get_perp_order_book_accounts(ctx.remaining_accounts)?;
+    let perp_order_books = perp_order_book_loaders
+        .iter()
+        .map(|perp_order_book| perp_order_book.load())
+        .collect::<Result<Vec<_>>>()?;
+
+    controller::orders::fill_spread_order(
+        order_id,
+        state,
+        &ctx.accounts.user,
+        &ctx.accounts.user_stats,
+        &spot_market_map,
+        &perp_market_map,
+        &mut oracle_map,
+        &ctx.accounts.filler,
+        &ctx.accounts.filler_stats,
+        &makers_and_referrer,
+        &makers_and_referrer_stats,
+        &clock,
+        FillMode::Fill,
+        fulfillment_params
+            .as_mut()
+            .map(|params| params as &mut dyn SpotFulfillmentParams),
+        &mut escrow.as_mut(),
+        builder_referral_enabled,
+        &perp_order_books
+            .iter()
+            .map(|perp_order_book| &**perp_order_book)
+            .collect::<Vec<_>>(),
+    )?;
+
+    if let (Some(fulfillment_params), Some(market_index)) =
+        (fulfillment_params.as_ref(), spot_market_index)
+    {
+        let base_market = spot_market_map.get_ref(&market_index)?;
+        let quote_market = spot_market_map.get_quote_spot_market()?;
+        fulfillment_params.validate_vault_amounts(&base_market, &quote_market)?;
+    }
+
+    drop(perp_order_books);
+
+    update_perp_order_books_after_fill(
+        ctx.remaining_accounts,
+        &ctx.accounts.user.key(),
+        &load!(ctx.accounts.user)?,
+        &makers_and_referrer,
+    )?;
+
+    for market_index in perp_market_indexes.iter() {
+        controller::repeg::update_amm_circuit_breaker_after_fill(
+            *market_index,
+            &perp_market_map,
+            &mut oracle_map,
+            state,
+            clock.slot,
+            &perp_circuit_breakers,
+        )?;
+    }
+
+    Ok(())
+}
+
 #[access_control(
     exchange_not_paused(&ctx.accounts.state)
 )]
@@ -405,6 +690,55 @@ pub fn handle_trigger_order<'c: 'info, 'info>(
     Ok(())
 }
 
+#[access_control(
+    exchange_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_activate_order<'c: 'info, 'info>(
+    ctx: Context<'_, '_, 'c, 'info, TriggerOrder<'info>>,
+    order_id: u32,
+) -> Result<()> {
+    let (market_type, market_index) = match load!(ctx.accounts.user)?.get_order(order_id) {
+        Some(order) => (order.market_type, order.market_index),
+        None => {
+            msg!("order_id not found {}", order_id);
+            return Ok(());
+        }
+    };
+
+    let (writeable_perp_markets, writeable_spot_markets) = match market_type {
+        MarketType::Spot => (
+            MarketSet::new(),
+            get_writable_spot_market_set_from_many(vec![QUOTE_SPOT_MARKET_INDEX, market_index]),
+        ),
+        MarketType::Perp => (MarketSet::new(), MarketSet::new()),
+    };
+
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        &mut ctx.remaining_accounts.iter().peekable(),
+        &writeable_perp_markets,
+        &writeable_spot_markets,
+        Clock::get()?.slot,
+        None,
+    )?;
+
+    controller::orders::activate_order(
+        order_id,
+        &ctx.accounts.state,
+        &ctx.accounts.user,
+        &spot_market_map,
+        &perp_market_map,
+        &mut oracle_map,
+        &ctx.accounts.filler,
+        &Clock::get()?,
+    )?;
+
+    Ok(())
+}
+
 #[access_control(
     exchange_not_paused(&ctx.accounts.state)
 )]
@@ -423,6 +757,9 @@ pub fn handle_force_cancel_orders<'c: 'info, 'info>(
         None,
     )?;
 
+    let mut orders_extension =
+        get_user_orders_extension_account(ctx.remaining_accounts, &ctx.accounts.user.key())?;
+
     controller::orders::force_cancel_orders(
         &ctx.accounts.state,
         &ctx.accounts.user,
@@ -431,6 +768,42 @@ pub fn handle_force_cancel_orders<'c: 'info, 'info>(
         &mut oracle_map,
         &ctx.accounts.filler,
         &Clock::get()?,
+        &mut orders_extension,
+    )?;
+
+    Ok(())
+}
+
+#[access_control(
+    exchange_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_cancel_orders_after_heartbeat_expired<'c: 'info, 'info>(
+    ctx: Context<'_, '_, 'c, 'info, ForceCancelOrder>,
+) -> Result<()> {
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        &mut ctx.remaining_accounts.iter().peekable(),
+        &MarketSet::new(),
+        &get_writable_spot_market_set(QUOTE_SPOT_MARKET_INDEX),
+        Clock::get()?.slot,
+        None,
+    )?;
+
+    let mut orders_extension =
+        get_user_orders_extension_account(ctx.remaining_accounts, &ctx.accounts.user.key())?;
+
+    controller::orders::cancel_orders_after_heartbeat_expired(
+        &ctx.accounts.state,
+        &ctx.accounts.user,
+        &spot_market_map,
+        &perp_market_map,
+        &mut oracle_map,
+        &ctx.accounts.filler,
+        &Clock::get()?,
+        &mut orders_extension,
     )?;
 
     Ok(())
@@ -603,7 +976,8 @@ pub fn handle_update_user_stats_referrer_info<'c: 'info, 'info>(
 pub fn handle_update_user_open_orders_count<'info>(ctx: Context<UpdateUserIdle>) -> Result<()> {
     let mut user = load_mut!(ctx.accounts.user)?;
 
-    let mut open_orders = 0_u8;
+    // orders parked in the orders extension are resting limit orders without auctions
+    let mut open_orders = user.extension_open_orders;
     let mut open_auctions = 0_u8;
 
     for order in user.orders.iter() {
@@ -651,6 +1025,20 @@ pub fn handle_place_signed_msg_taker_order<'c: 'info, 'info>(
     let mut taker = load_mut!(ctx.accounts.user)?;
     let mut signed_msg_taker = ctx.accounts.signed_msg_user_orders.load_mut()?;
 
+    // room for the taker order and its optional stop loss and take profit orders
+    let clock = Clock::get()?;
+    controller::orders::expire_and_free_order_slots_with_extension(
+        &mut taker,
+        &taker_key,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        clock.unix_timestamp,
+        clock.slot,
+        &mut get_user_orders_extension_account(ctx.remaining_accounts, &taker_key)?,
+        3,
+    )?;
+
     let escrow = if state.builder_codes_enabled() {
         get_revenue_share_escrow_account(&mut remaining_accounts, &taker.authority)?
     } else {
@@ -839,6 +1227,19 @@ pub fn place_signed_msg_taker_order<'c: 'info, 'info>(
         return Ok(());
     }
 
+    // link the take profit and stop loss so that one cancels the other
+    let oco_group_id = if verified_message_and_signature
+        .stop_loss_order_params
+        .is_some()
+        && verified_message_and_signature
+            .take_profit_order_params
+            .is_some()
+    {
+        taker.get_next_oco_group_id()?
+    } else {
+        0
+    };
+
     // Good to place orders, do stop loss and take profit orders first
     if let Some(stop_loss_order_params) = verified_message_and_signature.stop_loss_order_params {
         taker_order_id_to_use += 1;
@@ -898,6 +1299,7 @@ pub fn place_signed_msg_taker_order<'c: 'info, 'info>(
             PlaceOrderOptions {
                 enforce_margin_check: false,
                 existing_position_direction_override: Some(matching_taker_order_params.direction),
+                oco_group_id,
                 ..PlaceOrderOptions::default()
             },
             &mut builder_order,
@@ -963,6 +1365,7 @@ pub fn place_signed_msg_taker_order<'c: 'info, 'info>(
             PlaceOrderOptions {
                 enforce_margin_check: false,
                 existing_position_direction_override: Some(matching_taker_order_params.direction),
+                oco_group_id,
                 ..PlaceOrderOptions::default()
             },
             &mut builder_order,
@@ -1104,6 +1507,7 @@ pub fn handle_settle_pnl<'c: 'info, 'info>(
             &mut oracle_map,
             state,
             &clock,
+            &get_perp_circuit_breaker_accounts(ctx.remaining_accounts)?,
         )?;
 
         controller::pnl::settle_pnl(
@@ -1218,6 +1622,7 @@ pub fn handle_settle_multiple_pnls<'c: 'info, 'info>(
                 &mut oracle_map,
                 state,
                 &clock,
+                &get_perp_circuit_breaker_accounts(ctx.remaining_accounts)?,
             )?;
 
             controller::pnl::settle_pnl(
@@ -1328,6 +1733,9 @@ pub fn handle_liquidate_perp<'c: 'info, 'info>(
         Some(state.oracle_guard_rails),
     )?;
 
+    let mut orders_extension =
+        get_user_orders_extension_account(ctx.remaining_accounts, &user_key)?;
+
     controller::liquidation::liquidate_perp(
         market_index,
         liquidator_max_base_asset_amount,
@@ -1344,6 +1752,8 @@ pub fn handle_liquidate_perp<'c: 'info, 'info>(
         slot,
         now,
         state,
+        &mut orders_extension,
+        &get_perp_circuit_breaker_accounts(ctx.remaining_accounts)?,
     )?;
 
     Ok(())
@@ -1383,6 +1793,31 @@ pub fn handle_liquidate_perp_with_fill<'c: 'info, 'info>(
     let (makers_and_referrer, makers_and_referrer_stats) =
         load_user_maps(remaining_accounts_iter, true)?;
 
+    // the liquidation order reduces the user's position
+    let taker_direction = if load!(ctx.accounts.user)?
+        .get_perp_position(market_index)?
+        .base_asset_amount
+        > 0
+    {
+        PositionDirection::Short
+    } else {
+        PositionDirection::Long
+    };
+    load_maker_orders_from_extensions(
+        ctx.remaining_accounts,
+        &makers_and_referrer,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        clock.slot,
+        MarketType::Perp,
+        market_index,
+        taker_direction,
+    )?;
+
+    let mut orders_extension =
+        get_user_orders_extension_account(ctx.remaining_accounts, &user_key)?;
+
     controller::liquidation::liquidate_perp_with_fill(
         market_index,
         &ctx.accounts.user,
@@ -1398,6 +1833,8 @@ pub fn handle_liquidate_perp_with_fill<'c: 'info, 'info>(
         &mut oracle_map,
         &clock,
         state,
+        &mut orders_extension,
+        &get_perp_circuit_breaker_accounts(ctx.remaining_accounts)?,
     )?;
 
     Ok(())
@@ -1442,6 +1879,9 @@ pub fn handle_liquidate_spot<'c: 'info, 'info>(
         Some(state.oracle_guard_rails),
     )?;
 
+    let mut orders_extension =
+        get_user_orders_extension_account(ctx.remaining_accounts, &user_key)?;
+
     controller::liquidation::liquidate_spot(
         asset_market_index,
         liability_market_index,
@@ -1459,6 +1899,7 @@ pub fn handle_liquidate_spot<'c: 'info, 'info>(
         now,
         clock.slot,
         state,
+        &mut orders_extension,
     )?;
 
     Ok(())
@@ -1552,6 +1993,9 @@ pub fn handle_liquidate_spot_with_swap_begin<'c: 'info, 'info>(
         "swap_amount cannot be zero"
     )?;
 
+    let mut orders_extension =
+        get_user_orders_extension_account(ctx.remaining_accounts, &user_key)?;
+
     liquidate_spot_with_swap_begin(
         asset_market_index,
         liability_market_index,
@@ -1568,6 +2012,7 @@ pub fn handle_liquidate_spot_with_swap_begin<'c: 'info, 'info>(
         now,
         clock.slot,
         state,
+        &mut orders_extension,
     )?;
 
     let mut asset_spot_market = spot_market_map.get_ref_mut(&asset_market_index)?;
@@ -1965,6 +2410,9 @@ pub fn handle_liquidate_borrow_for_perp_pnl<'c: 'info, 'info>(
         Some(state.oracle_guard_rails),
     )?;
 
+    let mut orders_extension =
+        get_user_orders_extension_account(ctx.remaining_accounts, &user_key)?;
+
     controller::liquidation::liquidate_borrow_for_perp_pnl(
         perp_market_index,
         spot_market_index,
@@ -1982,6 +2430,7 @@ pub fn handle_liquidate_borrow_for_perp_pnl<'c: 'info, 'info>(
         state.liquidation_margin_buffer_ratio,
         state.initial_pct_to_liquidate as u128,
         state.liquidation_duration as u128,
+        &mut orders_extension,
     )?;
 
     Ok(())
@@ -2024,6 +2473,9 @@ pub fn handle_liquidate_perp_pnl_for_deposit<'c: 'info, 'info>(
         Some(state.oracle_guard_rails),
     )?;
 
+    let mut orders_extension =
+        get_user_orders_extension_account(ctx.remaining_accounts, &user_key)?;
+
     controller::liquidation::liquidate_perp_pnl_for_deposit(
         perp_market_index,
         spot_market_index,
@@ -2041,6 +2493,7 @@ pub fn handle_liquidate_perp_pnl_for_deposit<'c: 'info, 'info>(
         state.liquidation_margin_buffer_ratio,
         state.initial_pct_to_liquidate as u128,
         state.liquidation_duration as u128,
+        &mut orders_extension,
     )?;
 
     Ok(())
@@ -2115,6 +2568,7 @@ pub fn handle_resolve_perp_pnl_deficit<'c: 'info, 'info>(
         &mut oracle_map,
         state,
         &clock,
+        &get_perp_circuit_breaker_accounts(ctx.remaining_accounts)?,
     )?;
 
     {
@@ -2815,6 +3269,106 @@ pub fn handle_update_spot_market_cumulative_interest(
     Ok(())
 }
 
+#[access_control(
+    perp_market_valid(&ctx.accounts.perp_market)
+    amm_not_paused(&ctx.accounts.state)
+    valid_oracle_for_perp_market(&ctx.accounts.oracle, &ctx.accounts.perp_market)
+)]
+pub fn handle_crank_perp_market_curve(ctx: Context<CrankPerpMarketCurve>) -> Result<()> {
+    let perp_market = &mut load_mut!(ctx.accounts.perp_market)?;
+    let curve_crank = &mut load_mut!(ctx.accounts.perp_curve_crank)?;
+    let clock = Clock::get()?;
+    let now = clock.unix_timestamp;
+    let clock_slot = clock.slot;
+    let state = &ctx.accounts.state;
+
+    validate!(
+        curve_crank.market_index == perp_market.market_index,
+        ErrorCode::InvalidPerpCurveCrank,
+        "curve crank market index ({}) != perp market index ({})",
+        curve_crank.market_index,
+        perp_market.market_index
+    )?;
+
+    let mut oracle_map = OracleMap::load_one(
+        &ctx.accounts.oracle,
+        clock_slot,
+        Some(state.oracle_guard_rails),
+    )?;
+    let oracle_price_data = oracle_map.get_price_data(&perp_market.oracle_id())?;
+    let mm_oracle_price_data = perp_market.get_mm_oracle_price_data(
+        *oracle_price_data,
+        clock_slot,
+        &state.oracle_guard_rails.validity,
+    )?;
+
+    controller::repeg::_update_amm(perp_market, &mm_oracle_price_data, state, now, clock_slot)?;
+
+    controller::repeg::crank_curve(
+        perp_market,
+        curve_crank,
+        &mm_oracle_price_data,
+        state,
+        now,
+        clock_slot,
+    )?;
+
+    Ok(())
+}
+
+#[access_control(
+    perp_market_valid(&ctx.accounts.perp_market)
+    valid_oracle_for_perp_market(&ctx.accounts.oracle, &ctx.accounts.perp_market)
+)]
+pub fn handle_update_perp_circuit_breaker(ctx: Context<UpdatePerpCircuitBreaker>) -> Result<()> {
+    let perp_market = &mut load_mut!(ctx.accounts.perp_market)?;
+    let mut circuit_breaker = load_mut!(ctx.accounts.perp_circuit_breaker)?;
+    let clock = Clock::get()?;
+    let now = clock.unix_timestamp;
+    let clock_slot = clock.slot;
+    let state = &ctx.accounts.state;
+
+    let mut oracle_map = OracleMap::load_one(
+        &ctx.accounts.oracle,
+        clock_slot,
+        Some(state.oracle_guard_rails),
+    )?;
+    let oracle_price_data = oracle_map.get_price_data(&perp_market.oracle_id())?;
+    let mm_oracle_price_data = perp_market.get_mm_oracle_price_data(
+        *oracle_price_data,
+        clock_slot,
+        &state.oracle_guard_rails.validity,
+    )?;
+
+    controller::repeg::update_amm_and_check_validity(
+        perp_market,
+        &mm_oracle_price_data,
+        state,
+        now,
+        clock_slot,
+        Some(DriftAction::UpdateTwap),
+        Some(&mut *circuit_breaker),
+    )?;
+
+    Ok(())
+}
+
+#[access_control(
+    exchange_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_sync_perp_order_book<'c: 'info, 'info>(
+    ctx: Context<'_, '_, 'c, 'info, SyncPerpOrderBook<'info>>,
+) -> Result<()> {
+    let user_map = load_user_map(&mut ctx.remaining_accounts.iter().peekable(), false)?;
+
+    let mut perp_order_book = load_mut!(ctx.accounts.perp_order_book)?;
+    for (user_key, user) in user_map.0.iter() {
+        perp_order_book.update_for_user(user_key, &load!(user)?)?;
+    }
+
+    Ok(())
+}
+
 #[access_control(
     exchange_not_paused(&ctx.accounts.state)
 )]
@@ -3319,6 +3873,19 @@ pub struct FillOrder<'info> {
     pub user_stats: AccountLoader<'info, UserStats>,
 }
 
+#[derive(Accounts)]
+#[instruction(market_index: u16,)]
+pub struct ClearPerpBatchAuction<'info> {
+    pub state: Box<Account<'info, State>>,
+    pub authority: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"perp_order_book".as_ref(), market_index.to_le_bytes().as_ref()],
+        bump,
+    )]
+    pub perp_order_book: AccountLoader<'info, PerpOrderBook>,
+}
+
 #[derive(Accounts)]
 pub struct RevertFill<'info> {
     pub state: Box<Account<'info, State>>,
@@ -3731,6 +4298,38 @@ pub struct UpdateAMM<'info> {
     pub authority: Signer<'info>,
 }
 
+#[derive(Accounts)]
+pub struct CrankPerpMarketCurve<'info> {
+    pub state: Box<Account<'info, State>>,
+    #[account(mut)]
+    pub perp_market: AccountLoader<'info, PerpMarket>,
+    #[account(mut)]
+    pub perp_curve_crank: AccountLoader<'info, PerpCurveCrank>,
+    /// CHECK: checked in `crank_perp_market_curve` ix constraint
+    pub oracle: AccountInfo<'info>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdatePerpCircuitBreaker<'info> {
+    pub state: Box<Account<'info, State>>,
+    #[account(mut)]
+    pub perp_market: AccountLoader<'info, PerpMarket>,
+    #[account(mut)]
+    pub perp_circuit_breaker: AccountLoader<'info, PerpCircuitBreaker>,
+    /// CHECK: checked in `update_perp_circuit_breaker` ix constraint
+    pub oracle: AccountInfo<'info>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SyncPerpOrderBook<'info> {
+    pub state: Box<Account<'info, State>>,
+    #[account(mut)]
+    pub perp_order_book: AccountLoader<'info, PerpOrderBook>,
+    pub authority: Signer<'info>,
+}
+
 #[derive(Accounts)]
 pub struct UpdateFundingRate<'info> {
     pub state: Box<Account<'info, State>>,
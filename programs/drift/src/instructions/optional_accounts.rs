@@ -6,18 +6,26 @@ use crate::state::revenue_share::{
 use std::cell::RefMut;
 use std::convert::TryFrom;
 
+use crate::controller::orders::load_maker_orders_from_extension;
+use crate::controller::position::PositionDirection;
 use crate::error::ErrorCode::UnableToLoadOracle;
 use crate::math::safe_unwrap::SafeUnwrap;
 use crate::msg;
 use crate::state::load_ref::load_ref_mut;
 use crate::state::oracle::PrelaunchOracle;
 use crate::state::oracle_map::OracleMap;
+use crate::state::perp_circuit_breaker::PerpCircuitBreaker;
 use crate::state::perp_market::PerpMarket;
 use crate::state::perp_market_map::{MarketSet, PerpMarketMap};
+use crate::state::perp_order_book::PerpOrderBook;
 use crate::state::spot_market_map::SpotMarketMap;
 use crate::state::state::OracleGuardRails;
 use crate::state::traits::Size;
-use crate::state::user::{User, UserStats};
+use crate::state::user::{MarketType, User, UserStats};
+use crate::state::user_map::UserMap;
+use crate::state::user_orders_extension::{
+    UserOrdersExtension, UserOrdersExtensionLoader, UserOrdersExtensionZeroCopyMut,
+};
 use crate::{validate, OracleSource};
 use anchor_lang::accounts::account::Account;
 use anchor_lang::prelude::{AccountInfo, Interface, Pubkey};
@@ -313,3 +321,147 @@ pub fn get_revenue_share_escrow_account<'a>(
 
     Ok(Some(escrow))
 }
+
+/// Finds the user's orders extension account anywhere in the remaining accounts
+pub fn get_user_orders_extension_account<'a>(
+    remaining_accounts: &'a [AccountInfo<'a>],
+    user_key: &Pubkey,
+) -> DriftResult<Option<UserOrdersExtensionZeroCopyMut<'a>>> {
+    let discriminator: [u8; 8] = UserOrdersExtension::discriminator();
+    let account_info = remaining_accounts.iter().find(|account_info| {
+        account_info.data_len() >= 48
+            && account_info.try_borrow_data().map_or(false, |data| {
+                array_ref![data, 0, 8] == &discriminator
+                    && array_ref![data, 8, 32] == &user_key.to_bytes()
+            })
+    });
+
+    let account_info = match account_info {
+        Some(account_info) => account_info,
+        None => return Ok(None),
+    };
+
+    let orders_extension: UserOrdersExtensionZeroCopyMut<'a> = account_info.load_mut()?;
+
+    validate!(
+        orders_extension.fixed.user_pubkey == *user_key,
+        ErrorCode::UserOrdersExtensionUserMismatch,
+        "invalid UserOrdersExtension user"
+    )?;
+
+    Ok(Some(orders_extension))
+}
+
+/// Moves the makers' resting orders on the other side of the taker's order out of any orders
+/// extension accounts passed in the remaining accounts, so they can be matched by the fill
+pub fn load_maker_orders_from_extensions<'a>(
+    remaining_accounts: &'a [AccountInfo<'a>],
+    makers_and_referrer: &UserMap,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    slot: u64,
+    market_type: MarketType,
+    market_index: u16,
+    taker_direction: PositionDirection,
+) -> DriftResult {
+    for maker_key in makers_and_referrer.0.keys() {
+        if let Some(orders_extension) =
+            get_user_orders_extension_account(remaining_accounts, maker_key)?.as_mut()
+        {
+            load_maker_orders_from_extension(
+                &mut makers_and_referrer.get_ref_mut(maker_key)?,
+                orders_extension,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                slot,
+                market_type,
+                market_index,
+                taker_direction.opposite(),
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Finds all perp order book accounts anywhere in the remaining accounts
+pub fn get_perp_order_book_accounts<'a>(
+    remaining_accounts: &'a [AccountInfo<'a>],
+) -> DriftResult<Vec<AccountLoader<'a, PerpOrderBook>>> {
+    let discriminator: [u8; 8] = PerpOrderBook::discriminator();
+
+    remaining_accounts
+        .iter()
+        .filter(|account_info| {
+            account_info.data_len() >= PerpOrderBook::SIZE
+                && account_info
+                    .try_borrow_data()
+                    .map_or(false, |data| array_ref![data, 0, 8] == &discriminator)
+        })
+        .map(|account_info| {
+            AccountLoader::try_from(account_info).or(Err(ErrorCode::InvalidPerpOrderBook))
+        })
+        .collect()
+}
+
+/// Keeps any perp order book accounts passed in the remaining accounts in sync with the user's orders
+pub fn update_perp_order_books<'a>(
+    remaining_accounts: &'a [AccountInfo<'a>],
+    user_key: &Pubkey,
+    user: &User,
+) -> DriftResult {
+    for perp_order_book in get_perp_order_book_accounts(remaining_accounts)? {
+        let mut perp_order_book = perp_order_book.load_mut().map_err(|e| {
+            msg!("{:?}", e);
+            ErrorCode::InvalidPerpOrderBook
+        })?;
+        perp_order_book.update_for_user(user_key, user)?;
+    }
+
+    Ok(())
+}
+
+/// Keeps any perp order book accounts passed in the remaining accounts in sync with the taker's and
+/// makers' orders after a fill
+pub fn update_perp_order_books_after_fill<'a>(
+    remaining_accounts: &'a [AccountInfo<'a>],
+    taker_key: &Pubkey,
+    taker: &User,
+    makers_and_referrer: &UserMap,
+) -> DriftResult {
+    for perp_order_book in get_perp_order_book_accounts(remaining_accounts)? {
+        let mut perp_order_book = perp_order_book.load_mut().map_err(|e| {
+            msg!("{:?}", e);
+            ErrorCode::InvalidPerpOrderBook
+        })?;
+        perp_order_book.update_for_user(taker_key, taker)?;
+        for (maker_key, maker) in makers_and_referrer.0.iter() {
+            perp_order_book.update_for_user(maker_key, &load!(maker)?)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Perp circuit breaker accounts passed in the remaining accounts. A market with a circuit breaker
+/// needs it passed in wherever its amm is updated
+pub fn get_perp_circuit_breaker_accounts<'a>(
+    remaining_accounts: &'a [AccountInfo<'a>],
+) -> DriftResult<Vec<AccountLoader<'a, PerpCircuitBreaker>>> {
+    let discriminator: [u8; 8] = PerpCircuitBreaker::discriminator();
+
+    remaining_accounts
+        .iter()
+        .filter(|account_info| {
+            account_info.data_len() >= PerpCircuitBreaker::SIZE
+                && account_info
+                    .try_borrow_data()
+                    .map_or(false, |data| array_ref![data, 0, 8] == &discriminator)
+        })
+        .map(|account_info| {
+            AccountLoader::try_from(account_info).or(Err(ErrorCode::InvalidPerpCircuitBreaker))
+        })
+        .collect()
+}
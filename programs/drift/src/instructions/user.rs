@@ -12,7 +12,7 @@ use solana_program::program::invoke;
 use solana_program::system_instruction::transfer;
 
 use crate::controller::funding::settle_funding_payment;
-use crate::controller::orders::{cancel_orders, ModifyOrderId};
+use crate::controller::orders::ModifyOrderId;
 use crate::controller::position::update_position_and_market;
 use crate::controller::position::PositionDirection;
 use crate::controller::spot_balance::update_revenue_pool_balances;
@@ -29,6 +29,12 @@ use crate::ids::{
 };
 use crate::instructions::constraints::*;
 use crate::instructions::optional_accounts::get_revenue_share_escrow_account;
+use crate::instructions::optional_accounts::get_user_orders_extension_account;
+use crate::instructions::optional_accounts::load_maker_orders_from_extensions;
+use crate::instructions::optional_accounts::{
+    get_perp_circuit_breaker_accounts, get_perp_order_book_accounts, update_perp_order_books,
+    update_perp_order_books_after_fill,
+};
 use crate::instructions::optional_accounts::{
     get_referrer_and_referrer_stats, get_whitelist_token, load_maps, AccountMaps,
 };
@@ -73,13 +79,16 @@ use crate::state::fulfillment_params::serum::SerumFulfillmentParams;
 use crate::state::high_leverage_mode_config::HighLeverageModeConfig;
 use crate::state::margin_calculation::MarginContext;
 use crate::state::oracle::StrictOraclePrice;
+use crate::state::oracle_map::OracleMap;
 use crate::state::order_params::{
-    parse_optional_params, ModifyOrderParams, OrderParams, PlaceAndTakeOrderSuccessCondition,
-    PlaceOrderOptions, PostOnlyParam,
+    parse_optional_params, BracketOrderParams, CancelOrdersParams, ModifyOrderParams, OrderParams,
+    PlaceAndTakeOrderSuccessCondition, PlaceOrderOptions, PostOnlyParam, ScaleOrderParams,
 };
 use crate::state::paused_operations::{PerpOperation, SpotOperation};
 use crate::state::perp_market::MarketStatus;
-use crate::state::perp_market_map::{get_writable_perp_market_set, MarketSet};
+use crate::state::perp_market_map::{
+    get_writable_perp_market_set, get_writable_perp_market_set_from_vec, MarketSet, PerpMarketMap,
+};
 use crate::state::protected_maker_mode_config::ProtectedMakerModeConfig;
 use crate::state::revenue_share::BuilderInfo;
 use crate::state::revenue_share::RevenueShare;
@@ -96,7 +105,7 @@ use crate::state::spot_fulfillment_params::SpotFulfillmentParams;
 use crate::state::spot_market::SpotBalanceType;
 use crate::state::spot_market::SpotMarket;
 use crate::state::spot_market_map::{
-    get_writable_spot_market_set, get_writable_spot_market_set_from_many,
+    get_writable_spot_market_set, get_writable_spot_market_set_from_many, SpotMarketMap,
 };
 use crate::state::state::State;
 use crate::state::traits::Size;
@@ -104,12 +113,14 @@ use crate::state::user::Order;
 use crate::state::user::OrderStatus;
 use crate::state::user::ReferrerStatus;
 use crate::state::user::{
-    FuelOverflow, FuelOverflowProvider, MarginMode, MarketType, OrderType, ReferrerName, User,
-    UserStats,
+    FuelOverflow, FuelOverflowProvider, MarginMode, MarketType, OrderType, ReferrerName,
+    SelfTradePreventionMode, User, UserStats,
 };
 use crate::state::user_map::{load_user_maps, UserMap, UserStatsMap};
+use crate::state::user_orders_extension::{UserOrdersExtension, USER_ORDERS_EXTENSION_PDA_SEED};
 use crate::validate;
 use crate::validation::position::validate_perp_position_with_perp_market;
+use crate::validation::sig_verification::verify_and_decode_ed25519_quote;
 use crate::validation::user::validate_user_deletion;
 use crate::validation::whitelist::validate_whitelist_token;
 use crate::{controller, math};
@@ -348,6 +359,37 @@ pub fn handle_resize_signed_msg_user_orders<'c: 'info, 'info>(
     Ok(())
 }
 
+pub fn handle_initialize_user_orders_extension<'c: 'info, 'info>(
+    ctx: Context<'_, '_, 'c, 'info, InitializeUserOrdersExtension<'info>>,
+    num_orders: u16,
+) -> Result<()> {
+    let user_orders_extension = &mut ctx.accounts.user_orders_extension;
+    user_orders_extension.user_pubkey = ctx.accounts.user.key();
+    user_orders_extension
+        .orders
+        .resize_with(num_orders as usize, Order::default);
+    user_orders_extension.validate()?;
+    Ok(())
+}
+
+pub fn handle_resize_user_orders_extension<'c: 'info, 'info>(
+    ctx: Context<'_, '_, 'c, 'info, ResizeUserOrdersExtension<'info>>,
+    num_orders: u16,
+) -> Result<()> {
+    let user_orders_extension = &mut ctx.accounts.user_orders_extension;
+    validate!(
+        num_orders as usize >= user_orders_extension.orders.len(),
+        ErrorCode::InvalidUserOrdersExtensionResize,
+        "Invalid shrinking resize for user orders extension"
+    )?;
+
+    user_orders_extension
+        .orders
+        .resize_with(num_orders as usize, Order::default);
+    user_orders_extension.validate()?;
+    Ok(())
+}
+
 pub fn handle_initialize_signed_msg_ws_delegates<'c: 'info, 'info>(
     ctx: Context<'_, '_, 'c, 'info, InitializeSignedMsgWsDelegates<'info>>,
     delegates: Vec<Pubkey>,
@@ -1757,6 +1799,7 @@ pub fn handle_transfer_perp_position<'c: 'info, 'info>(
         &mut oracle_map,
         &ctx.accounts.state,
         &clock,
+        &get_perp_circuit_breaker_accounts(ctx.remaining_accounts)?,
     )?;
 
     settle_funding_payment(
@@ -2079,6 +2122,18 @@ pub fn handle_place_perp_order<'c: 'info, 'info>(
     let user_key = ctx.accounts.user.key();
     let mut user = load_mut!(ctx.accounts.user)?;
 
+    controller::orders::expire_and_free_order_slots_with_extension(
+        &mut user,
+        &user_key,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        clock.unix_timestamp,
+        clock.slot,
+        &mut get_user_orders_extension_account(ctx.remaining_accounts, &user_key)?,
+        1,
+    )?;
+
     controller::orders::place_perp_order(
         &ctx.accounts.state,
         &mut user,
@@ -2093,6 +2148,73 @@ pub fn handle_place_perp_order<'c: 'info, 'info>(
         &mut None,
     )?;
 
+    update_perp_order_books(ctx.remaining_accounts, &user_key, &user)?;
+
+    Ok(())
+}
+
+#[access_control(
+    exchange_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_place_bracket_perp_order<'c: 'info, 'info>(
+    ctx: Context<'_, '_, 'c, 'info, PlaceOrder>,
+    params: OrderParams,
+    bracket_params: BracketOrderParams,
+) -> Result<()> {
+    let clock = &Clock::get()?;
+    let state = &ctx.accounts.state;
+
+    let mut remaining_accounts = ctx.remaining_accounts.iter().peekable();
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        &mut remaining_accounts,
+        &MarketSet::new(),
+        &MarketSet::new(),
+        clock.slot,
+        Some(state.oracle_guard_rails),
+    )?;
+
+    let high_leverage_mode_config = get_high_leverage_mode_config(&mut remaining_accounts)?;
+
+    if params.is_immediate_or_cancel() {
+        msg!("immediate_or_cancel order must be in place_and_make or place_and_take");
+        return Err(print_error!(ErrorCode::InvalidOrderIOC)().into());
+    }
+
+    let user_key = ctx.accounts.user.key();
+    let mut user = load_mut!(ctx.accounts.user)?;
+
+    controller::orders::expire_and_free_order_slots_with_extension(
+        &mut user,
+        &user_key,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        clock.unix_timestamp,
+        clock.slot,
+        &mut get_user_orders_extension_account(ctx.remaining_accounts, &user_key)?,
+        1 + bracket_params.num_legs(),
+    )?;
+
+    controller::orders::place_bracket_perp_order(
+        &ctx.accounts.state,
+        &mut user,
+        user_key,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &high_leverage_mode_config,
+        clock,
+        params,
+        bracket_params,
+        PlaceOrderOptions::default(),
+    )?;
+
+    update_perp_order_books(ctx.remaining_accounts, &user_key, &user)?;
+
     Ok(())
 }
 
@@ -2123,6 +2245,17 @@ pub fn handle_cancel_order<'c: 'info, 'info>(
         None => load!(ctx.accounts.user)?.get_last_order_id(),
     };
 
+    if let Some(orders_extension) =
+        get_user_orders_extension_account(ctx.remaining_accounts, &ctx.accounts.user.key())?
+            .as_mut()
+    {
+        controller::orders::load_order_from_extension(
+            &mut load_mut!(ctx.accounts.user)?,
+            orders_extension,
+            order_id,
+        )?;
+    }
+
     controller::orders::cancel_order_by_order_id(
         order_id,
         &ctx.accounts.user,
@@ -2132,6 +2265,12 @@ pub fn handle_cancel_order<'c: 'info, 'info>(
         clock,
     )?;
 
+    update_perp_order_books(
+        ctx.remaining_accounts,
+        &ctx.accounts.user.key(),
+        &load!(ctx.accounts.user)?,
+    )?;
+
     Ok(())
 }
 
@@ -2157,6 +2296,17 @@ pub fn handle_cancel_order_by_user_id<'c: 'info, 'info>(
         Some(state.oracle_guard_rails),
     )?;
 
+    if let Some(orders_extension) =
+        get_user_orders_extension_account(ctx.remaining_accounts, &ctx.accounts.user.key())?
+            .as_mut()
+    {
+        controller::orders::load_order_from_extension_by_user_order_id(
+            &mut load_mut!(ctx.accounts.user)?,
+            orders_extension,
+            user_order_id,
+        )?;
+    }
+
     controller::orders::cancel_order_by_user_order_id(
         user_order_id,
         &ctx.accounts.user,
@@ -2166,6 +2316,54 @@ pub fn handle_cancel_order_by_user_id<'c: 'info, 'info>(
         clock,
     )?;
 
+    update_perp_order_books(
+        ctx.remaining_accounts,
+        &ctx.accounts.user.key(),
+        &load!(ctx.accounts.user)?,
+    )?;
+
+    Ok(())
+}
+
+#[access_control(
+    exchange_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_cancel_orders_by_filter<'c: 'info, 'info>(
+    ctx: Context<'_, '_, 'c, 'info, CancelOrder<'info>>,
+    params: CancelOrdersParams,
+) -> Result<()> {
+    let clock = &Clock::get()?;
+    let state = &ctx.accounts.state;
+
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        &mut ctx.remaining_accounts.iter().peekable(),
+        &MarketSet::new(),
+        &MarketSet::new(),
+        clock.slot,
+        Some(state.oracle_guard_rails),
+    )?;
+
+    let user_key = ctx.accounts.user.key();
+    let mut user = load_mut!(ctx.accounts.user)?;
+
+    controller::orders::cancel_orders_by_filter(
+        &mut user,
+        &user_key,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        clock.unix_timestamp,
+        clock.slot,
+        &params,
+        &mut get_user_orders_extension_account(ctx.remaining_accounts, &user_key)?,
+    )?;
+
+    update_perp_order_books(ctx.remaining_accounts, &user_key, &user)?;
+
     Ok(())
 }
 
@@ -2191,7 +2389,18 @@ pub fn handle_cancel_orders_by_ids<'c: 'info, 'info>(
         Some(state.oracle_guard_rails),
     )?;
 
+    let mut orders_extension =
+        get_user_orders_extension_account(ctx.remaining_accounts, &ctx.accounts.user.key())?;
+
     for order_id in order_ids {
+        if let Some(orders_extension) = orders_extension.as_mut() {
+            controller::orders::load_order_from_extension(
+                &mut load_mut!(ctx.accounts.user)?,
+                orders_extension,
+                order_id,
+            )?;
+        }
+
         controller::orders::cancel_order_by_order_id(
             order_id,
             &ctx.accounts.user,
@@ -2202,6 +2411,12 @@ pub fn handle_cancel_orders_by_ids<'c: 'info, 'info>(
         )?;
     }
 
+    update_perp_order_books(
+        ctx.remaining_accounts,
+        &ctx.accounts.user.key(),
+        &load!(ctx.accounts.user)?,
+    )?;
+
     Ok(())
 }
 
@@ -2232,7 +2447,7 @@ pub fn handle_cancel_orders<'c: 'info, 'info>(
     let user_key = ctx.accounts.user.key();
     let mut user = load_mut!(ctx.accounts.user)?;
 
-    cancel_orders(
+    controller::orders::cancel_orders_with_extension(
         &mut user,
         &user_key,
         None,
@@ -2245,8 +2460,11 @@ pub fn handle_cancel_orders<'c: 'info, 'info>(
         market_type,
         market_index,
         direction,
+        &mut get_user_orders_extension_account(ctx.remaining_accounts, &user_key)?,
     )?;
 
+    update_perp_order_books(ctx.remaining_accounts, &user_key, &user)?;
+
     Ok(())
 }
 
@@ -2278,6 +2496,17 @@ pub fn handle_modify_order<'c: 'info, 'info>(
         None => load!(ctx.accounts.user)?.get_last_order_id(),
     };
 
+    if let Some(orders_extension) =
+        get_user_orders_extension_account(ctx.remaining_accounts, &ctx.accounts.user.key())?
+            .as_mut()
+    {
+        controller::orders::load_order_from_extension(
+            &mut load_mut!(ctx.accounts.user)?,
+            orders_extension,
+            order_id,
+        )?;
+    }
+
     controller::orders::modify_order(
         ModifyOrderId::OrderId(order_id),
         modify_order_params,
@@ -2289,6 +2518,12 @@ pub fn handle_modify_order<'c: 'info, 'info>(
         clock,
     )?;
 
+    update_perp_order_books(
+        ctx.remaining_accounts,
+        &ctx.accounts.user.key(),
+        &load!(ctx.accounts.user)?,
+    )?;
+
     Ok(())
 }
 
@@ -2315,6 +2550,17 @@ pub fn handle_modify_order_by_user_order_id<'c: 'info, 'info>(
         Some(state.oracle_guard_rails),
     )?;
 
+    if let Some(orders_extension) =
+        get_user_orders_extension_account(ctx.remaining_accounts, &ctx.accounts.user.key())?
+            .as_mut()
+    {
+        controller::orders::load_order_from_extension_by_user_order_id(
+            &mut load_mut!(ctx.accounts.user)?,
+            orders_extension,
+            user_order_id,
+        )?;
+    }
+
     controller::orders::modify_order(
         ModifyOrderId::UserOrderId(user_order_id),
         modify_order_params,
@@ -2326,6 +2572,12 @@ pub fn handle_modify_order_by_user_order_id<'c: 'info, 'info>(
         clock,
     )?;
 
+    update_perp_order_books(
+        ctx.remaining_accounts,
+        &ctx.accounts.user.key(),
+        &load!(ctx.accounts.user)?,
+    )?;
+
     Ok(())
 }
 
@@ -2335,6 +2587,40 @@ pub fn handle_modify_order_by_user_order_id<'c: 'info, 'info>(
 pub fn handle_place_orders<'c: 'info, 'info>(
     ctx: Context<'_, '_, 'c, 'info, PlaceOrder>,
     params: Vec<OrderParams>,
+) -> Result<()> {
+    place_orders(ctx, PlaceOrdersInput::Orders(params))
+}
+
+#[access_control(
+    exchange_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_place_bracket_orders<'c: 'info, 'info>(
+    ctx: Context<'_, '_, 'c, 'info, PlaceOrder>,
+    params: Vec<OrderParams>,
+    bracket_params: Vec<BracketOrderParams>,
+) -> Result<()> {
+    place_orders(ctx, PlaceOrdersInput::BracketOrders(params, bracket_params))
+}
+
+#[access_control(
+    exchange_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_place_scale_orders<'c: 'info, 'info>(
+    ctx: Context<'_, '_, 'c, 'info, PlaceOrder>,
+    params: ScaleOrderParams,
+) -> Result<()> {
+    place_orders(ctx, PlaceOrdersInput::ScaleOrders(params))
+}
+
+enum PlaceOrdersInput {
+    Orders(Vec<OrderParams>),
+    BracketOrders(Vec<OrderParams>, Vec<BracketOrderParams>),
+    ScaleOrders(ScaleOrderParams),
+}
+
+fn place_orders<'c: 'info, 'info>(
+    ctx: Context<'_, '_, 'c, 'info, PlaceOrder>,
+    input: PlaceOrdersInput,
 ) -> Result<()> {
     let clock = &Clock::get()?;
     let state = &ctx.accounts.state;
@@ -2354,6 +2640,40 @@ pub fn handle_place_orders<'c: 'info, 'info>(
 
     let high_leverage_mode_config = get_high_leverage_mode_config(&mut remaining_accounts)?;
 
+    let (params, bracket_params) = match input {
+        PlaceOrdersInput::Orders(params) => (params, vec![]),
+        PlaceOrdersInput::BracketOrders(params, bracket_params) => {
+            // bracket params are matched to order params by index
+            validate!(
+                bracket_params.len() == params.len(),
+                ErrorCode::InvalidOrder,
+                "bracket params must match order params"
+            )?;
+
+            (params, bracket_params)
+        }
+        PlaceOrdersInput::ScaleOrders(scale_order_params) => {
+            let (tick_size, step_size) = match scale_order_params.market_type {
+                MarketType::Perp => {
+                    let perp_market = perp_market_map.get_ref(&scale_order_params.market_index)?;
+                    (
+                        perp_market.amm.order_tick_size,
+                        perp_market.amm.order_step_size,
+                    )
+                }
+                MarketType::Spot => {
+                    let spot_market = spot_market_map.get_ref(&scale_order_params.market_index)?;
+                    (spot_market.order_tick_size, spot_market.order_step_size)
+                }
+            };
+
+            (
+                scale_order_params.get_order_params(tick_size, step_size)?,
+                vec![],
+            )
+        }
+    };
+
     validate!(
         params.len() <= 32,
         ErrorCode::DefaultError,
@@ -2363,6 +2683,39 @@ pub fn handle_place_orders<'c: 'info, 'info>(
     let user_key = ctx.accounts.user.key();
     let mut user = load_mut!(ctx.accounts.user)?;
 
+    let mut orders_extension =
+        get_user_orders_extension_account(ctx.remaining_accounts, &user_key)?;
+
+    if let Some(orders_extension) = orders_extension.as_mut() {
+        controller::orders::expire_extension_orders(
+            &mut user,
+            &user_key,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            clock.unix_timestamp,
+            clock.slot,
+            orders_extension,
+        )?;
+    }
+
+    // all orders flagged as one cancels other are linked into a single group
+    let num_oco_orders = params
+        .iter()
+        .filter(|params| params.is_one_cancels_other())
+        .count();
+    let oco_group_id = if num_oco_orders > 0 {
+        validate!(
+            num_oco_orders >= 2,
+            ErrorCode::InvalidOrder,
+            "oco group must contain at least 2 orders"
+        )?;
+
+        user.get_next_oco_group_id()?
+    } else {
+        0
+    };
+
     let num_orders = params.len();
     for (i, params) in params.iter().enumerate() {
         validate!(
@@ -2379,9 +2732,39 @@ pub fn handle_place_orders<'c: 'info, 'info>(
             risk_increasing: false,
             explanation: OrderActionExplanation::None,
             existing_position_direction_override: None,
+            oco_group_id: if params.is_one_cancels_other() {
+                oco_group_id
+            } else {
+                0
+            },
+            update_heartbeat: true,
         };
 
-        if params.market_type == MarketType::Perp {
+        let bracket_params = bracket_params.get(i).filter(|params| !params.is_empty());
+
+        if let Some(orders_extension) = orders_extension.as_mut() {
+            controller::orders::free_order_slots_with_extension(
+                &mut user,
+                orders_extension,
+                1 + bracket_params.map_or(0, |params| params.num_legs()),
+            )?;
+        }
+
+        if let Some(bracket_params) = bracket_params {
+            controller::orders::place_bracket_perp_order(
+                &ctx.accounts.state,
+                &mut user,
+                user_key,
+                &perp_market_map,
+                &spot_market_map,
+                &mut oracle_map,
+                &high_leverage_mode_config,
+                clock,
+                *params,
+                *bracket_params,
+                options,
+            )?;
+        } else if params.market_type == MarketType::Perp {
             controller::orders::place_perp_order(
                 &ctx.accounts.state,
                 &mut user,
@@ -2410,6 +2793,8 @@ pub fn handle_place_orders<'c: 'info, 'info>(
         }
     }
 
+    update_perp_order_books(ctx.remaining_accounts, &user_key, &user)?;
+
     Ok(())
 }
 
@@ -2420,6 +2805,46 @@ pub fn handle_place_and_take_perp_order<'c: 'info, 'info>(
     ctx: Context<'_, '_, 'c, 'info, PlaceAndTake<'info>>,
     params: OrderParams,
     optional_params: Option<u32>, // u32 for backwards compatibility
+) -> Result<()> {
+    place_and_take_perp_order(ctx, params, optional_params, None, None)
+}
+
+#[access_control(
+    fill_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_place_and_take_bracket_perp_order<'c: 'info, 'info>(
+    ctx: Context<'_, '_, 'c, 'info, PlaceAndTake<'info>>,
+    params: OrderParams,
+    bracket_params: BracketOrderParams,
+    optional_params: Option<u32>,
+) -> Result<()> {
+    place_and_take_perp_order(ctx, params, optional_params, Some(bracket_params), None)
+}
+
+#[access_control(
+    fill_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_place_and_take_perp_order_with_signed_quote<'c: 'info, 'info>(
+    ctx: Context<'_, '_, 'c, 'info, PlaceAndTake<'info>>,
+    params: OrderParams,
+    signed_quote_message_bytes: Vec<u8>,
+    optional_params: Option<u32>,
+) -> Result<()> {
+    place_and_take_perp_order(
+        ctx,
+        params,
+        optional_params,
+        None,
+        Some(signed_quote_message_bytes),
+    )
+}
+
+fn place_and_take_perp_order<'c: 'info, 'info>(
+    ctx: Context<'_, '_, 'c, 'info, PlaceAndTake<'info>>,
+    params: OrderParams,
+    optional_params: Option<u32>,
+    bracket_params: Option<BracketOrderParams>,
+    signed_quote_message_bytes: Option<Vec<u8>>,
 ) -> Result<()> {
     let clock = Clock::get()?;
     let state = &ctx.accounts.state;
@@ -2449,12 +2874,40 @@ pub fn handle_place_and_take_perp_order<'c: 'info, 'info>(
 
     let is_immediate_or_cancel = params.is_immediate_or_cancel();
 
+    let signed_quote = match signed_quote_message_bytes {
+        Some(signed_quote_message_bytes) => {
+            let ix_sysvar = ctx
+                .remaining_accounts
+                .iter()
+                .find(|account_info| account_info.key == &IX_ID)
+                .ok_or(ErrorCode::InvalidSignedMsgQuote)?;
+
+            Some(place_signed_msg_maker_quote(
+                &signed_quote_message_bytes,
+                ix_sysvar,
+                &params,
+                &ctx.accounts.user.key(),
+                &makers_and_referrer,
+                &makers_and_referrer_stats,
+                state,
+                &perp_market_map,
+                &spot_market_map,
+                &mut oracle_map,
+                &clock,
+            )?)
+        }
+        None => None,
+    };
+
+    let perp_circuit_breakers = get_perp_circuit_breaker_accounts(ctx.remaining_accounts)?;
+
     controller::repeg::update_amm(
         params.market_index,
         &perp_market_map,
         &mut oracle_map,
         &ctx.accounts.state,
         &Clock::get()?,
+        &perp_circuit_breakers,
     )?;
 
     let user_key = ctx.accounts.user.key();
@@ -2463,20 +2916,49 @@ pub fn handle_place_and_take_perp_order<'c: 'info, 'info>(
 
     let (success_condition, auction_duration_percentage) = parse_optional_params(optional_params);
 
-    controller::orders::place_perp_order(
-        &ctx.accounts.state,
+    controller::orders::expire_and_free_order_slots_with_extension(
         &mut user,
-        user_key,
+        &user_key,
         &perp_market_map,
         &spot_market_map,
         &mut oracle_map,
-        &high_leverage_mode_config,
-        &clock,
-        params,
-        PlaceOrderOptions::default(),
-        &mut None,
+        clock.unix_timestamp,
+        clock.slot,
+        &mut get_user_orders_extension_account(ctx.remaining_accounts, &user_key)?,
+        1 + bracket_params.map_or(0, |params| params.num_legs()),
     )?;
 
+    // the entry is placed after its protective orders so it remains the last order placed
+    if let Some(bracket_params) = bracket_params {
+        controller::orders::place_bracket_perp_order(
+            &ctx.accounts.state,
+            &mut user,
+            user_key,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &high_leverage_mode_config,
+            &clock,
+            params,
+            bracket_params,
+            PlaceOrderOptions::default(),
+        )?;
+    } else {
+        controller::orders::place_perp_order(
+            &ctx.accounts.state,
+            &mut user,
+            user_key,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &high_leverage_mode_config,
+            &clock,
+            params,
+            PlaceOrderOptions::default(),
+            &mut None,
+        )?;
+    }
+
     drop(user);
 
     let user = &mut ctx.accounts.user;
@@ -2490,6 +2972,24 @@ pub fn handle_place_and_take_perp_order<'c: 'info, 'info>(
         None
     };
 
+    load_maker_orders_from_extensions(
+        ctx.remaining_accounts,
+        &makers_and_referrer,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        clock.slot,
+        MarketType::Perp,
+        params.market_index,
+        params.direction,
+    )?;
+
+    let perp_order_book_loaders = get_perp_order_book_accounts(ctx.remaining_accounts)?;
+    let perp_order_books = perp_order_book_loaders
+        .iter()
+        .map(|perp_order_book| perp_order_book.load())
+        .collect::<Result<Vec<_>>>()?;
+
     let (base_asset_amount_filled, _) = controller::orders::fill_perp_order(
         order_id,
         &ctx.accounts.state,
@@ -2510,6 +3010,28 @@ pub fn handle_place_and_take_perp_order<'c: 'info, 'info>(
         ),
         &mut escrow.as_mut(),
         builder_referral_enabled,
+        &perp_order_books
+            .iter()
+            .map(|perp_order_book| &**perp_order_book)
+            .collect::<Vec<_>>(),
+    )?;
+
+    drop(perp_order_books);
+
+    update_perp_order_books_after_fill(
+        ctx.remaining_accounts,
+        &ctx.accounts.user.key(),
+        &load!(ctx.accounts.user)?,
+        &makers_and_referrer,
+    )?;
+
+    controller::repeg::update_amm_circuit_breaker_after_fill(
+        params.market_index,
+        &perp_market_map,
+        &mut oracle_map,
+        state,
+        clock.slot,
+        &perp_circuit_breakers,
     )?;
 
     let order_unfilled = load!(ctx.accounts.user)?
@@ -2528,6 +3050,22 @@ pub fn handle_place_and_take_perp_order<'c: 'info, 'info>(
         )?;
     }
 
+    if let Some((maker_key, quote_order_id, quote_nonce)) = signed_quote {
+        let mut maker = makers_and_referrer.get_ref_mut(&maker_key)?;
+        let mut maker_stats = makers_and_referrer_stats.get_ref_mut(&maker.authority)?;
+        controller::orders::settle_signed_msg_quote_order(
+            &mut maker,
+            &maker_key,
+            &mut maker_stats,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &clock,
+            quote_order_id,
+            quote_nonce,
+        )?;
+    }
+
     if success_condition == PlaceAndTakeOrderSuccessCondition::PartialFill as u8 {
         validate!(
             base_asset_amount_filled > 0,
@@ -2545,6 +3083,426 @@ pub fn handle_place_and_take_perp_order<'c: 'info, 'info>(
     Ok(())
 }
 
+/// Verifies a maker signed quote against the taker's order and places it in the maker's account.
+/// Returns the maker, the quote's order id and the quote's nonce
+fn place_signed_msg_maker_quote(
+    signed_quote_message_bytes: &[u8],
+    ix_sysvar: &AccountInfo,
+    taker_params: &OrderParams,
+    taker_key: &Pubkey,
+    makers_and_referrer: &UserMap,
+    makers_and_referrer_stats: &UserStatsMap,
+    state: &State,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    clock: &Clock,
+) -> Result<(Pubkey, u32, u64)> {
+    let ix_idx = instructions::load_current_index_checked(ix_sysvar)?;
+    validate!(
+        ix_idx > 0,
+        ErrorCode::InvalidVerificationIxIndex,
+        "instruction index must be greater than 0 for one sig verifies"
+    )?;
+
+    let ix = instructions::load_instruction_at_checked(ix_idx as usize - 1, ix_sysvar)?;
+
+    // the message starts with the 64 byte signature followed by the signer's pubkey
+    validate!(
+        signed_quote_message_bytes.len() >= 96,
+        ErrorCode::InvalidSignedMsgQuote,
+        "signed quote message too short"
+    )?;
+    let mut signer = [0_u8; 32];
+    signer.copy_from_slice(&signed_quote_message_bytes[64..96]);
+
+    let quote = verify_and_decode_ed25519_quote(
+        &ix,
+        ix_sysvar,
+        ix_idx,
+        &signer,
+        signed_quote_message_bytes,
+    )?;
+
+    let maker_key = Pubkey::find_program_address(
+        &[
+            "user".as_bytes(),
+            &signer,
+            &quote.sub_account_id.to_le_bytes(),
+        ],
+        &crate::ID,
+    )
+    .0;
+
+    validate!(
+        maker_key != *taker_key,
+        ErrorCode::InvalidSignedMsgQuote,
+        "taker cant fill its own quote"
+    )?;
+
+    validate!(
+        quote.market_index == taker_params.market_index
+            && taker_params.market_type == MarketType::Perp
+            && quote.direction == taker_params.direction.opposite(),
+        ErrorCode::InvalidSignedMsgQuote,
+        "quote doesnt match taker order"
+    )?;
+
+    let mut maker = makers_and_referrer.get_ref_mut(&maker_key)?;
+    let maker_stats = makers_and_referrer_stats.get_ref(&maker.authority)?;
+
+    let quote_order_id = controller::orders::place_signed_msg_quote_order(
+        state,
+        &mut maker,
+        maker_key,
+        &maker_stats,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        clock,
+        &quote,
+    )?;
+
+    Ok((maker_key, quote_order_id, quote.nonce))
+}
+
+fn validate_spread_order_params(params: &[OrderParams]) -> DriftResult {
+    validate!(
+        (2..=4).contains(&params.len()),
+        ErrorCode::InvalidOrder,
+        "spread order must have between 2 and 4 legs"
+    )?;
+
+    for (i, leg) in params.iter().enumerate() {
+        validate!(
+            leg.order_type == OrderType::Limit
+                && leg.post_only == PostOnlyParam::None
+                && leg.auction_duration.unwrap_or(0) == 0,
+            ErrorCode::InvalidOrder,
+            "spread leg must be a limit order without auction that isn't post only"
+        )?;
+
+        validate!(
+            !params[..i]
+                .iter()
+                .any(|other| other.market_type == leg.market_type
+                    && other.market_index == leg.market_index),
+            ErrorCode::InvalidOrder,
+            "spread legs must be in different markets"
+        )?;
+    }
+
+    // spot legs are matched against makers and need the market vaults, only one pair can be passed
+    validate!(
+        params
+            .iter()
+            .filter(|leg| leg.market_type == MarketType::Spot)
+            .count()
+            <= 1,
+        ErrorCode::InvalidOrder,
+        "spread order can have at most one spot leg"
+    )?;
+
+    Ok(())
+}
+
+fn place_spread_order_legs(
+    state: &State,
+    user: &mut User,
+    user_key: Pubkey,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    high_leverage_mode_config: &Option<AccountLoader<HighLeverageModeConfig>>,
+    clock: &Clock,
+    params: &[OrderParams],
+    max_net_quote_asset_amount: i64,
+) -> DriftResult {
+    let mut order_ids = Vec::with_capacity(params.len());
+    for leg in params.iter() {
+        let order_id_before = user.get_last_order_id();
+
+        if leg.market_type == MarketType::Perp {
+            controller::orders::place_perp_order(
+                state,
+                user,
+                user_key,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                high_leverage_mode_config,
+                clock,
+                *leg,
+                PlaceOrderOptions::default(),
+                &mut None,
+            )?;
+        } else {
+            controller::orders::place_spot_order(
+                state,
+                user,
+                user_key,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                clock,
+                *leg,
+                PlaceOrderOptions::default(),
+            )?;
+        }
+
+        let order_id = user.get_last_order_id();
+        validate!(
+            order_id != order_id_before,
+            ErrorCode::InvalidOrder,
+            "spread leg failed to be placed"
+        )?;
+
+        order_ids.push(order_id);
+    }
+
+    controller::orders::link_spread_order_legs(user, &order_ids, max_net_quote_asset_amount)
+}
+
+/// Places a spread: orders across perp and spot markets that rest until they can all be filled
+/// together by fill_spread_order. max_net_quote_asset_amount caps the quote paid for the legs going
+/// long less the quote received for the legs going short (precision: QUOTE_PRECISION), so a
+/// negative value requires the spread to be entered at a net credit
+#[access_control(
+    exchange_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_place_spread_order<'c: 'info, 'info>(
+    ctx: Context<'_, '_, 'c, 'info, PlaceOrder>,
+    params: Vec<OrderParams>,
+    max_net_quote_asset_amount: i64,
+) -> Result<()> {
+    let clock = &Clock::get()?;
+    let state = &ctx.accounts.state;
+
+    validate_spread_order_params(&params)?;
+
+    let mut remaining_accounts = ctx.remaining_accounts.iter().peekable();
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        &mut remaining_accounts,
+        &MarketSet::new(),
+        &MarketSet::new(),
+        clock.slot,
+        Some(state.oracle_guard_rails),
+    )?;
+
+    let high_leverage_mode_config = get_high_leverage_mode_config(&mut remaining_accounts)?;
+
+    let user_key = ctx.accounts.user.key();
+    let mut user = load_mut!(ctx.accounts.user)?;
+
+    controller::orders::expire_and_free_order_slots_with_extension(
+        &mut user,
+        &user_key,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        clock.unix_timestamp,
+        clock.slot,
+        &mut get_user_orders_extension_account(ctx.remaining_accounts, &user_key)?,
+        params.len(),
+    )?;
+
+    place_spread_order_legs(
+        state,
+        &mut user,
+        user_key,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &high_leverage_mode_config,
+        clock,
+        &params,
+        max_net_quote_asset_amount,
+    )?;
+
+    Ok(())
+}
+
+#[access_control(
+    fill_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_place_and_take_spread_order<'c: 'info, 'info>(
+    ctx: Context<'_, '_, 'c, 'info, PlaceAndTake<'info>>,
+    params: Vec<OrderParams>,
+    max_net_quote_asset_amount: i64,
+) -> Result<()> {
+    let clock = Clock::get()?;
+    let state = &ctx.accounts.state;
+
+    validate_spread_order_params(&params)?;
+
+    let spot_market_index = params
+        .iter()
+        .find(|leg| leg.market_type == MarketType::Spot)
+        .map(|leg| leg.market_index);
+
+    let perp_market_indexes = params
+        .iter()
+        .filter(|leg| leg.market_type == MarketType::Perp)
+        .map(|leg| leg.market_index)
+        .collect::<Vec<u16>>();
+
+    let remaining_accounts_iter = &mut ctx.remaining_accounts.iter().peekable();
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        remaining_accounts_iter,
+        &get_writable_perp_market_set_from_vec(&perp_market_indexes),
+        &match spot_market_index {
+            Some(market_index) => {
+                get_writable_spot_market_set_from_many(vec![QUOTE_SPOT_MARKET_INDEX, market_index])
+            }
+            None => MarketSet::new(),
+        },
+        clock.slot,
+        Some(state.oracle_guard_rails),
+    )?;
+
+    let (makers_and_referrer, makers_and_referrer_stats) =
+        load_user_maps(remaining_accounts_iter, true)?;
+
+    let high_leverage_mode_config = get_high_leverage_mode_config(remaining_accounts_iter)?;
+
+    let builder_referral_enabled = state.builder_referral_enabled();
+    let builder_codes_enabled = state.builder_codes_enabled();
+    let mut escrow = if builder_codes_enabled || builder_referral_enabled {
+        get_revenue_share_escrow_account(
+            remaining_accounts_iter,
+            &load!(ctx.accounts.user)?.authority,
+        )?
+    } else {
+        None
+    };
+
+    let mut fulfillment_params = match spot_market_index {
+        Some(market_index) => {
+            let base_market = spot_market_map.get_ref(&market_index)?;
+            let quote_market = spot_market_map.get_quote_spot_market()?;
+            Some(MatchFulfillmentParams::new(
+                remaining_accounts_iter,
+                &base_market,
+                &quote_market,
+            )?)
+        }
+        None => None,
+    };
+
+    let perp_circuit_breakers = get_perp_circuit_breaker_accounts(ctx.remaining_accounts)?;
+
+    for market_index in perp_market_indexes.iter() {
+        controller::repeg::update_amm(
+            *market_index,
+            &perp_market_map,
+            &mut oracle_map,
+            state,
+            &clock,
+            &perp_circuit_breakers,
+        )?;
+    }
+
+    let user_key = ctx.accounts.user.key();
+    let order_id = {
+        let mut user = load_mut!(ctx.accounts.user)?;
+        controller::orders::expire_and_free_order_slots_with_extension(
+            &mut user,
+            &user_key,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            clock.unix_timestamp,
+            clock.slot,
+            &mut get_user_orders_extension_account(ctx.remaining_accounts, &user_key)?,
+            params.len(),
+        )?;
+
+        place_spread_order_legs(
+            state,
+            &mut user,
+            user_key,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &high_leverage_mode_config,
+            &clock,
+            &params,
+            max_net_quote_asset_amount,
+        )?;
+        user.get_last_order_id()
+    };
+
+    let perp_order_book_loaders = get_perp_order_book_accounts(ctx.remaining_accounts)?;
+    let perp_order_books = perp_order_book_loaders
+        .iter()
+        .map(|perp_order_book| perp_order_book.load())
+        .collect::<Result<Vec<_>>>()?;
+
+    let user = &ctx.accounts.user;
+    controller::orders::fill_spread_order(
+        order_id,
+        state,
+        user,
+        &ctx.accounts.user_stats,
+        &spot_market_map,
+        &perp_market_map,
+        &mut oracle_map,
+        &user.clone(),
+        &ctx.accounts.user_stats.clone(),
+        &makers_and_referrer,
+        &makers_and_referrer_stats,
+        &clock,
+        FillMode::PlaceAndTake(true, 100),
+        fulfillment_params
+            .as_mut()
+            .map(|params| params as &mut dyn SpotFulfillmentParams),
+        &mut escrow.as_mut(),
+        builder_referral_enabled,
+        &perp_order_books
+            .iter()
+            .map(|perp_order_book| &**perp_order_book)
+            .collect::<Vec<_>>(),
+    )?;
+
+    if let (Some(fulfillment_params), Some(market_index)) =
+        (fulfillment_params.as_ref(), spot_market_index)
+    {
+        let base_market = spot_market_map.get_ref(&market_index)?;
+        let quote_market = spot_market_map.get_quote_spot_market()?;
+        fulfillment_params.validate_vault_amounts(&base_market, &quote_market)?;
+    }
+
+    drop(perp_order_books);
+
+    update_perp_order_books_after_fill(
+        ctx.remaining_accounts,
+        &ctx.accounts.user.key(),
+        &load!(ctx.accounts.user)?,
+        &makers_and_referrer,
+    )?;
+
+    for market_index in perp_market_indexes.iter() {
+        controller::repeg::update_amm_circuit_breaker_after_fill(
+            *market_index,
+            &perp_market_map,
+            &mut oracle_map,
+            state,
+            clock.slot,
+            &perp_circuit_breakers,
+        )?;
+    }
+
+    Ok(())
+}
+
 #[access_control(
     fill_not_paused(&ctx.accounts.state)
 )]
@@ -2577,17 +3535,32 @@ pub fn handle_place_and_make_perp_order<'c: 'info, 'info>(
         return Err(print_error!(ErrorCode::InvalidOrderIOCPostOnly)().into());
     }
 
+    let perp_circuit_breakers = get_perp_circuit_breaker_accounts(ctx.remaining_accounts)?;
+
     controller::repeg::update_amm(
         params.market_index,
         &perp_market_map,
         &mut oracle_map,
         state,
         clock,
+        &perp_circuit_breakers,
     )?;
 
     let user_key = ctx.accounts.user.key();
     let mut user = load_mut!(ctx.accounts.user)?;
 
+    controller::orders::expire_and_free_order_slots_with_extension(
+        &mut user,
+        &user_key,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        clock.unix_timestamp,
+        clock.slot,
+        &mut get_user_orders_extension_account(ctx.remaining_accounts, &user_key)?,
+        1,
+    )?;
+
     controller::orders::place_perp_order(
         state,
         &mut user,
@@ -2639,6 +3612,16 @@ pub fn handle_place_and_make_perp_order<'c: 'info, 'info>(
         FillMode::PlaceAndMake,
         &mut escrow.as_mut(),
         builder_referral_enabled,
+        &[],
+    )?;
+
+    controller::repeg::update_amm_circuit_breaker_after_fill(
+        params.market_index,
+        &perp_market_map,
+        &mut oracle_map,
+        state,
+        clock.slot,
+        &perp_circuit_breakers,
     )?;
 
     let order_exists = load!(ctx.accounts.user)?
@@ -2692,17 +3675,32 @@ pub fn handle_place_and_make_signed_msg_perp_order<'c: 'info, 'info>(
         return Err(print_error!(ErrorCode::InvalidOrderIOCPostOnly)().into());
     }
 
+    let perp_circuit_breakers = get_perp_circuit_breaker_accounts(ctx.remaining_accounts)?;
+
     controller::repeg::update_amm(
         params.market_index,
         &perp_market_map,
         &mut oracle_map,
         state,
         clock,
+        &perp_circuit_breakers,
     )?;
 
     let user_key = ctx.accounts.user.key();
     let mut user = load_mut!(ctx.accounts.user)?;
 
+    controller::orders::expire_and_free_order_slots_with_extension(
+        &mut user,
+        &user_key,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        clock.unix_timestamp,
+        clock.slot,
+        &mut get_user_orders_extension_account(ctx.remaining_accounts, &user_key)?,
+        1,
+    )?;
+
     controller::orders::place_perp_order(
         state,
         &mut user,
@@ -2761,6 +3759,16 @@ pub fn handle_place_and_make_signed_msg_perp_order<'c: 'info, 'info>(
         FillMode::PlaceAndMake,
         &mut escrow.as_mut(),
         builder_referral_enabled,
+        &[],
+    )?;
+
+    controller::repeg::update_amm_circuit_breaker_after_fill(
+        params.market_index,
+        &perp_market_map,
+        &mut oracle_map,
+        state,
+        clock.slot,
+        &perp_circuit_breakers,
     )?;
 
     let order_exists = load!(ctx.accounts.user)?
@@ -2805,6 +3813,19 @@ pub fn handle_place_spot_order<'c: 'info, 'info>(
 
     let user_key = ctx.accounts.user.key();
     let mut user = load_mut!(ctx.accounts.user)?;
+    let clock = Clock::get()?;
+
+    controller::orders::expire_and_free_order_slots_with_extension(
+        &mut user,
+        &user_key,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        clock.unix_timestamp,
+        clock.slot,
+        &mut get_user_orders_extension_account(ctx.remaining_accounts, &user_key)?,
+        1,
+    )?;
 
     controller::orders::place_spot_order(
         &ctx.accounts.state,
@@ -2813,7 +3834,7 @@ pub fn handle_place_spot_order<'c: 'info, 'info>(
         &perp_market_map,
         &spot_market_map,
         &mut oracle_map,
-        &Clock::get()?,
+        &clock,
         params,
         PlaceOrderOptions::default(),
     )?;
@@ -2907,6 +3928,18 @@ pub fn handle_place_and_take_spot_order<'c: 'info, 'info>(
 
     let order_id_before = user.get_last_order_id();
 
+    controller::orders::expire_and_free_order_slots_with_extension(
+        &mut user,
+        &user_key,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        clock.unix_timestamp,
+        clock.slot,
+        &mut get_user_orders_extension_account(ctx.remaining_accounts, &user_key)?,
+        1,
+    )?;
+
     controller::orders::place_spot_order(
         &ctx.accounts.state,
         &mut user,
@@ -2929,6 +3962,20 @@ pub fn handle_place_and_take_spot_order<'c: 'info, 'info>(
         return Err(print_error!(ErrorCode::InvalidOrder)().into());
     }
 
+    if fulfillment_type == SpotFulfillmentType::Match {
+        load_maker_orders_from_extensions(
+            ctx.remaining_accounts,
+            &makers_and_referrer,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            clock.slot,
+            MarketType::Spot,
+            market_index,
+            params.direction,
+        )?;
+    }
+
     controller::orders::fill_spot_order(
         order_id,
         &ctx.accounts.state,
@@ -3054,6 +4101,18 @@ pub fn handle_place_and_make_spot_order<'c: 'info, 'info>(
     let mut user = load_mut!(ctx.accounts.user)?;
     let authority = user.authority;
 
+    controller::orders::expire_and_free_order_slots_with_extension(
+        &mut user,
+        &user_key,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        clock.unix_timestamp,
+        clock.slot,
+        &mut get_user_orders_extension_account(ctx.remaining_accounts, &user_key)?,
+        1,
+    )?;
+
     controller::orders::place_spot_order(
         state,
         &mut user,
@@ -3227,6 +4286,38 @@ pub fn handle_update_user_reduce_only(
     Ok(())
 }
 
+pub fn handle_update_user_self_trade_prevention_mode(
+    ctx: Context<UpdateUser>,
+    _sub_account_id: u16,
+    self_trade_prevention_mode: SelfTradePreventionMode,
+) -> Result<()> {
+    let mut user = load_mut!(ctx.accounts.user)?;
+
+    validate!(!user.is_being_liquidated(), ErrorCode::LiquidationsOngoing)?;
+
+    user.self_trade_prevention_mode = self_trade_prevention_mode;
+    Ok(())
+}
+
+pub fn handle_update_user_heartbeat_timeout(
+    ctx: Context<UpdateUser>,
+    _sub_account_id: u16,
+    heartbeat_timeout: u32,
+) -> Result<()> {
+    let mut user = load_mut!(ctx.accounts.user)?;
+
+    user.heartbeat_timeout = heartbeat_timeout;
+    user.update_last_heartbeat_ts(Clock::get()?.unix_timestamp)?;
+    Ok(())
+}
+
+pub fn handle_heartbeat(ctx: Context<Heartbeat>) -> Result<()> {
+    let mut user = load_mut!(ctx.accounts.user)?;
+
+    user.update_last_heartbeat_ts(Clock::get()?.unix_timestamp)?;
+    Ok(())
+}
+
 pub fn handle_update_user_advanced_lp(
     ctx: Context<UpdateUser>,
     _sub_account_id: u16,
@@ -3306,6 +4397,17 @@ pub fn handle_delete_signed_msg_user_orders(
     Ok(())
 }
 
+pub fn handle_delete_user_orders_extension(ctx: Context<DeleteUserOrdersExtension>) -> Result<()> {
+    let user = load!(ctx.accounts.user)?;
+    validate!(
+        user.extension_open_orders == 0,
+        ErrorCode::UserOrdersExtensionHasOpenOrders,
+        "user has {} open orders in the extension",
+        user.extension_open_orders
+    )?;
+    Ok(())
+}
+
 pub fn handle_reclaim_rent(ctx: Context<ReclaimRent>) -> Result<()> {
     let user_size = ctx.accounts.user.to_account_info().data_len();
     let minimum_lamports = ctx.accounts.rent.minimum_balance(user_size);
@@ -4209,6 +5311,50 @@ pub struct ResizeSignedMsgUserOrders<'info> {
     pub system_program: Program<'info, System>,
 }
 
+#[derive(Accounts)]
+#[instruction(num_orders: u16)]
+pub struct InitializeUserOrdersExtension<'info> {
+    #[account(
+        init,
+        seeds = [USER_ORDERS_EXTENSION_PDA_SEED.as_ref(), user.key().as_ref()],
+        space = UserOrdersExtension::space(num_orders as usize),
+        bump,
+        payer = payer
+    )]
+    pub user_orders_extension: Box<Account<'info, UserOrdersExtension>>,
+    #[account(
+        constraint = can_sign_for_user(&user, &authority)?
+    )]
+    pub user: AccountLoader<'info, User>,
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub rent: Sysvar<'info, Rent>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(num_orders: u16)]
+pub struct ResizeUserOrdersExtension<'info> {
+    #[account(
+        mut,
+        seeds = [USER_ORDERS_EXTENSION_PDA_SEED.as_ref(), user.key().as_ref()],
+        bump,
+        realloc = UserOrdersExtension::space(num_orders as usize),
+        realloc::payer = payer,
+        realloc::zero = false,
+    )]
+    pub user_orders_extension: Box<Account<'info, UserOrdersExtension>>,
+    #[account(
+        constraint = can_sign_for_user(&user, &authority)?
+    )]
+    pub user: AccountLoader<'info, User>,
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
 #[derive(Accounts)]
 #[instruction(delegates: Vec<Pubkey>)]
 pub struct InitializeSignedMsgWsDelegates<'info> {
@@ -4639,6 +5785,16 @@ pub struct UpdateUser<'info> {
     pub authority: Signer<'info>,
 }
 
+#[derive(Accounts)]
+pub struct Heartbeat<'info> {
+    #[account(
+        mut,
+        constraint = can_sign_for_user(&user, &authority)?
+    )]
+    pub user: AccountLoader<'info, User>,
+    pub authority: Signer<'info>,
+}
+
 #[derive(Accounts)]
 pub struct UpdateUserPerpPositionCustomMarginRatio<'info> {
     #[account(
@@ -4682,6 +5838,23 @@ pub struct DeleteSignedMsgUserOrders<'info> {
     pub authority: Signer<'info>,
 }
 
+#[derive(Accounts)]
+pub struct DeleteUserOrdersExtension<'info> {
+    #[account(
+        mut,
+        close = authority,
+        seeds = [USER_ORDERS_EXTENSION_PDA_SEED.as_ref(), user.key().as_ref()],
+        bump,
+    )]
+    pub user_orders_extension: Box<Account<'info, UserOrdersExtension>>,
+    #[account(
+        has_one = authority
+    )]
+    pub user: AccountLoader<'info, User>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+}
+
 #[derive(Accounts)]
 pub struct ReclaimRent<'info> {
     #[account(
@@ -13,14 +13,16 @@ use state::oracle::OracleSource;
 use crate::controller::position::PositionDirection;
 use crate::state::if_rebalance_config::IfRebalanceConfigParams;
 use crate::state::oracle::PrelaunchOracleParams;
-use crate::state::order_params::{ModifyOrderParams, OrderParams};
-use crate::state::perp_market::{ContractTier, MarketStatus};
+use crate::state::order_params::{
+    BracketOrderParams, CancelOrdersParams, ModifyOrderParams, OrderParams, ScaleOrderParams,
+};
+use crate::state::perp_market::{AmmQuoteMode, ContractTier, MakerAllocationPolicy, MarketStatus};
 use crate::state::settle_pnl_mode::SettlePnlMode;
 use crate::state::spot_market::AssetTier;
 use crate::state::spot_market::SpotFulfillmentConfigStatus;
 use crate::state::state::FeeStructure;
 use crate::state::state::*;
-use crate::state::user::MarketType;
+use crate::state::user::{MarketType, SelfTradePreventionMode};
 
 pub mod controller;
 pub mod error;
@@ -101,6 +103,20 @@ pub mod drift {
         handle_resize_signed_msg_user_orders(ctx, num_orders)
     }
 
+    pub fn initialize_user_orders_extension<'c: 'info, 'info>(
+        ctx: Context<'_, '_, 'c, 'info, InitializeUserOrdersExtension<'info>>,
+        num_orders: u16,
+    ) -> Result<()> {
+        handle_initialize_user_orders_extension(ctx, num_orders)
+    }
+
+    pub fn resize_user_orders_extension<'c: 'info, 'info>(
+        ctx: Context<'_, '_, 'c, 'info, ResizeUserOrdersExtension<'info>>,
+        num_orders: u16,
+    ) -> Result<()> {
+        handle_resize_user_orders_extension(ctx, num_orders)
+    }
+
     pub fn initialize_signed_msg_ws_delegates<'c: 'info, 'info>(
         ctx: Context<'_, '_, 'c, 'info, InitializeSignedMsgWsDelegates<'info>>,
         delegates: Vec<Pubkey>,
@@ -202,6 +218,22 @@ pub mod drift {
         handle_place_perp_order(ctx, params)
     }
 
+    pub fn place_bracket_perp_order<'c: 'info, 'info>(
+        ctx: Context<'_, '_, 'c, 'info, PlaceOrder>,
+        params: OrderParams,
+        bracket_params: BracketOrderParams,
+    ) -> Result<()> {
+        handle_place_bracket_perp_order(ctx, params, bracket_params)
+    }
+
+    pub fn place_spread_order<'c: 'info, 'info>(
+        ctx: Context<'_, '_, 'c, 'info, PlaceOrder>,
+        params: Vec<OrderParams>,
+        max_net_quote_asset_amount: i64,
+    ) -> Result<()> {
+        handle_place_spread_order(ctx, params, max_net_quote_asset_amount)
+    }
+
     pub fn cancel_order<'c: 'info, 'info>(
         ctx: Context<'_, '_, 'c, 'info, CancelOrder>,
         order_id: Option<u32>,
@@ -225,6 +257,13 @@ pub mod drift {
         handle_cancel_orders(ctx, market_type, market_index, direction)
     }
 
+    pub fn cancel_orders_by_filter<'c: 'info, 'info>(
+        ctx: Context<'_, '_, 'c, 'info, CancelOrder<'info>>,
+        params: CancelOrdersParams,
+    ) -> Result<()> {
+        handle_cancel_orders_by_filter(ctx, params)
+    }
+
     pub fn cancel_orders_by_ids<'c: 'info, 'info>(
         ctx: Context<'_, '_, 'c, 'info, CancelOrder>,
         order_ids: Vec<u32>,
@@ -256,6 +295,37 @@ pub mod drift {
         handle_place_and_take_perp_order(ctx, params, success_condition)
     }
 
+    pub fn place_and_take_bracket_perp_order<'c: 'info, 'info>(
+        ctx: Context<'_, '_, 'c, 'info, PlaceAndTake<'info>>,
+        params: OrderParams,
+        bracket_params: BracketOrderParams,
+        success_condition: Option<u32>,
+    ) -> Result<()> {
+        handle_place_and_take_bracket_perp_order(ctx, params, bracket_params, success_condition)
+    }
+
+    pub fn place_and_take_perp_order_with_signed_quote<'c: 'info, 'info>(
+        ctx: Context<'_, '_, 'c, 'info, PlaceAndTake<'info>>,
+        params: OrderParams,
+        signed_quote_message_bytes: Vec<u8>,
+        success_condition: Option<u32>,
+    ) -> Result<()> {
+        handle_place_and_take_perp_order_with_signed_quote(
+            ctx,
+            params,
+            signed_quote_message_bytes,
+            success_condition,
+        )
+    }
+
+    pub fn place_and_take_spread_order<'c: 'info, 'info>(
+        ctx: Context<'_, '_, 'c, 'info, PlaceAndTake<'info>>,
+        params: Vec<OrderParams>,
+        max_net_quote_asset_amount: i64,
+    ) -> Result<()> {
+        handle_place_and_take_spread_order(ctx, params, max_net_quote_asset_amount)
+    }
+
     pub fn place_and_make_perp_order<'c: 'info, 'info>(
         ctx: Context<'_, '_, 'c, 'info, PlaceAndMake<'info>>,
         params: OrderParams,
@@ -326,6 +396,21 @@ pub mod drift {
         handle_place_orders(ctx, params)
     }
 
+    pub fn place_bracket_orders<'c: 'info, 'info>(
+        ctx: Context<'_, '_, 'c, 'info, PlaceOrder>,
+        params: Vec<OrderParams>,
+        bracket_params: Vec<BracketOrderParams>,
+    ) -> Result<()> {
+        handle_place_bracket_orders(ctx, params, bracket_params)
+    }
+
+    pub fn place_scale_orders<'c: 'info, 'info>(
+        ctx: Context<'_, '_, 'c, 'info, PlaceOrder>,
+        params: ScaleOrderParams,
+    ) -> Result<()> {
+        handle_place_scale_orders(ctx, params)
+    }
+
     pub fn begin_swap<'c: 'info, 'info>(
         ctx: Context<'_, '_, 'c, 'info, Swap<'info>>,
         in_market_index: u16,
@@ -413,6 +498,30 @@ pub mod drift {
         handle_update_user_reduce_only(ctx, _sub_account_id, reduce_only)
     }
 
+    pub fn update_user_self_trade_prevention_mode(
+        ctx: Context<UpdateUser>,
+        _sub_account_id: u16,
+        self_trade_prevention_mode: SelfTradePreventionMode,
+    ) -> Result<()> {
+        handle_update_user_self_trade_prevention_mode(
+            ctx,
+            _sub_account_id,
+            self_trade_prevention_mode,
+        )
+    }
+
+    pub fn update_user_heartbeat_timeout(
+        ctx: Context<UpdateUser>,
+        _sub_account_id: u16,
+        heartbeat_timeout: u32,
+    ) -> Result<()> {
+        handle_update_user_heartbeat_timeout(ctx, _sub_account_id, heartbeat_timeout)
+    }
+
+    pub fn heartbeat(ctx: Context<Heartbeat>) -> Result<()> {
+        handle_heartbeat(ctx)
+    }
+
     // pub fn update_user_advanced_lp(
     //     ctx: Context<UpdateUser>,
     //     _sub_account_id: u16,
@@ -447,6 +556,10 @@ pub mod drift {
         handle_delete_signed_msg_user_orders(ctx)
     }
 
+    pub fn delete_user_orders_extension(ctx: Context<DeleteUserOrdersExtension>) -> Result<()> {
+        handle_delete_user_orders_extension(ctx)
+    }
+
     pub fn reclaim_rent(ctx: Context<ReclaimRent>) -> Result<()> {
         handle_reclaim_rent(ctx)
     }
@@ -468,6 +581,13 @@ pub mod drift {
         handle_fill_perp_order(ctx, order_id)
     }
 
+    pub fn clear_perp_batch_auction<'c: 'info, 'info>(
+        ctx: Context<'_, '_, 'c, 'info, ClearPerpBatchAuction<'info>>,
+        market_index: u16,
+    ) -> Result<()> {
+        handle_clear_perp_batch_auction(ctx, market_index)
+    }
+
     pub fn revert_fill(ctx: Context<RevertFill>) -> Result<()> {
         handle_revert_fill(ctx)
     }
@@ -481,6 +601,13 @@ pub mod drift {
         handle_fill_spot_order(ctx, order_id, fulfillment_type, maker_order_id)
     }
 
+    pub fn fill_spread_order<'c: 'info, 'info>(
+        ctx: Context<'_, '_, 'c, 'info, FillOrder<'info>>,
+        order_id: u32,
+    ) -> Result<()> {
+        handle_fill_spread_order(ctx, order_id)
+    }
+
     pub fn trigger_order<'c: 'info, 'info>(
         ctx: Context<'_, '_, 'c, 'info, TriggerOrder<'info>>,
         order_id: u32,
@@ -488,12 +615,25 @@ pub mod drift {
         handle_trigger_order(ctx, order_id)
     }
 
+    pub fn activate_order<'c: 'info, 'info>(
+        ctx: Context<'_, '_, 'c, 'info, TriggerOrder<'info>>,
+        order_id: u32,
+    ) -> Result<()> {
+        handle_activate_order(ctx, order_id)
+    }
+
     pub fn force_cancel_orders<'c: 'info, 'info>(
         ctx: Context<'_, '_, 'c, 'info, ForceCancelOrder<'info>>,
     ) -> Result<()> {
         handle_force_cancel_orders(ctx)
     }
 
+    pub fn cancel_orders_after_heartbeat_expired<'c: 'info, 'info>(
+        ctx: Context<'_, '_, 'c, 'info, ForceCancelOrder<'info>>,
+    ) -> Result<()> {
+        handle_cancel_orders_after_heartbeat_expired(ctx)
+    }
+
     pub fn update_user_idle<'c: 'info, 'info>(
         ctx: Context<'_, '_, 'c, 'info, UpdateUserIdle<'info>>,
     ) -> Result<()> {
@@ -711,6 +851,20 @@ pub mod drift {
         handle_update_spot_market_cumulative_interest(ctx)
     }
 
+    pub fn crank_perp_market_curve(ctx: Context<CrankPerpMarketCurve>) -> Result<()> {
+        handle_crank_perp_market_curve(ctx)
+    }
+
+    pub fn update_perp_circuit_breaker(ctx: Context<UpdatePerpCircuitBreaker>) -> Result<()> {
+        handle_update_perp_circuit_breaker(ctx)
+    }
+
+    pub fn sync_perp_order_book<'c: 'info, 'info>(
+        ctx: Context<'_, '_, 'c, 'info, SyncPerpOrderBook<'info>>,
+    ) -> Result<()> {
+        handle_sync_perp_order_book(ctx)
+    }
+
     pub fn update_amms<'c: 'info, 'info>(
         ctx: Context<'_, '_, 'c, 'info, UpdateAMM<'info>>,
         market_indexes: Vec<u16>,
@@ -1387,6 +1541,18 @@ pub mod drift {
         handle_update_perp_market_curve_update_intensity(ctx, curve_update_intensity)
     }
 
+    pub fn update_perp_market_oracle_realized_vol_half_lives(
+        ctx: Context<HotAdminUpdatePerpMarket>,
+        oracle_realized_vol_fast_half_life: u8,
+        oracle_realized_vol_slow_half_life: u8,
+    ) -> Result<()> {
+        handle_update_perp_market_oracle_realized_vol_half_lives(
+            ctx,
+            oracle_realized_vol_fast_half_life,
+            oracle_realized_vol_slow_half_life,
+        )
+    }
+
     pub fn update_lp_cooldown_time(
         ctx: Context<AdminUpdateState>,
         lp_cooldown_time: u64,
@@ -1573,6 +1739,45 @@ pub mod drift {
         )
     }
 
+    pub fn update_perp_market_batch_auction_duration(
+        ctx: Context<AdminUpdatePerpMarket>,
+        batch_auction_duration: u16,
+    ) -> Result<()> {
+        handle_update_perp_market_batch_auction_duration(ctx, batch_auction_duration)
+    }
+
+    pub fn update_perp_market_concentrated_curve(
+        ctx: Context<AdminUpdatePerpMarket>,
+        concentrated_band_width: u8,
+        concentrated_liquidity_multiplier: u8,
+    ) -> Result<()> {
+        handle_update_perp_market_concentrated_curve(
+            ctx,
+            concentrated_band_width,
+            concentrated_liquidity_multiplier,
+        )
+    }
+
+    pub fn update_perp_market_amm_quote_mode(
+        ctx: Context<AdminUpdatePerpMarket>,
+        amm_quote_mode: AmmQuoteMode,
+        oracle_quote_inventory_budget: u64,
+    ) -> Result<()> {
+        handle_update_perp_market_amm_quote_mode(ctx, amm_quote_mode, oracle_quote_inventory_budget)
+    }
+
+    pub fn update_perp_market_maker_allocation_policy(
+        ctx: Context<AdminUpdatePerpMarket>,
+        maker_allocation_policy: MakerAllocationPolicy,
+        pro_rata_top_of_book_bonus: u8,
+    ) -> Result<()> {
+        handle_update_perp_market_maker_allocation_policy(
+            ctx,
+            maker_allocation_policy,
+            pro_rata_top_of_book_bonus,
+        )
+    }
+
     pub fn update_perp_market_taker_speed_bump_override(
         ctx: Context<HotAdminUpdatePerpMarket>,
         taker_speed_bump_override: i8,
@@ -1743,6 +1948,72 @@ pub mod drift {
         handle_initialize_high_leverage_mode_config(ctx, max_users)
     }
 
+    pub fn initialize_perp_order_book(
+        ctx: Context<InitializePerpOrderBook>,
+        market_index: u16,
+    ) -> Result<()> {
+        handle_initialize_perp_order_book(ctx, market_index)
+    }
+
+    pub fn initialize_perp_curve_crank(
+        ctx: Context<InitializePerpCurveCrank>,
+        market_index: u16,
+        crank_interval: i64,
+        epoch_duration: i64,
+        epoch_budget_pct: u32,
+    ) -> Result<()> {
+        handle_initialize_perp_curve_crank(
+            ctx,
+            market_index,
+            crank_interval,
+            epoch_duration,
+            epoch_budget_pct,
+        )
+    }
+
+    pub fn update_perp_curve_crank(
+        ctx: Context<UpdatePerpCurveCrank>,
+        crank_interval: i64,
+        epoch_duration: i64,
+        epoch_budget_pct: u32,
+    ) -> Result<()> {
+        handle_update_perp_curve_crank(ctx, crank_interval, epoch_duration, epoch_budget_pct)
+    }
+
+    pub fn initialize_perp_circuit_breaker(
+        ctx: Context<InitializePerpCircuitBreaker>,
+        market_index: u16,
+        max_oracle_move_pct: u32,
+        oracle_move_window_slots: u32,
+        max_inventory_change_per_slot: u64,
+        cooldown_slots: u32,
+    ) -> Result<()> {
+        handle_initialize_perp_circuit_breaker(
+            ctx,
+            market_index,
+            max_oracle_move_pct,
+            oracle_move_window_slots,
+            max_inventory_change_per_slot,
+            cooldown_slots,
+        )
+    }
+
+    pub fn update_perp_circuit_breaker_config(
+        ctx: Context<UpdatePerpCircuitBreakerConfig>,
+        max_oracle_move_pct: u32,
+        oracle_move_window_slots: u32,
+        max_inventory_change_per_slot: u64,
+        cooldown_slots: u32,
+    ) -> Result<()> {
+        handle_update_perp_circuit_breaker_config(
+            ctx,
+            max_oracle_move_pct,
+            oracle_move_window_slots,
+            max_inventory_change_per_slot,
+            cooldown_slots,
+        )
+    }
+
     pub fn update_high_leverage_mode_config(
         ctx: Context<UpdateHighLeverageModeConfig>,
         max_users: u32,
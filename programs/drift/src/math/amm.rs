@@ -10,6 +10,7 @@ use crate::math::casting::Cast;
 use crate::math::constants::{
     BID_ASK_SPREAD_PRECISION_I128, CONCENTRATION_PRECISION,
     DEFAULT_MAX_TWAP_UPDATE_PRICE_BAND_DENOMINATOR, FIVE_MINUTE, ONE_HOUR, ONE_MINUTE,
+    ORACLE_REALIZED_VOL_HALF_LIFE_SLOTS, ORACLE_REALIZED_VOL_HORIZON_SLOTS, PERCENTAGE_PRECISION,
     PRICE_TIMES_AMM_TO_QUOTE_PRECISION_RATIO, PRICE_TIMES_AMM_TO_QUOTE_PRECISION_RATIO_I128,
     PRICE_TO_PEG_PRECISION_RATIO,
 };
@@ -20,6 +21,7 @@ use crate::state::oracle::{MMOraclePriceData, OraclePriceData};
 use crate::state::perp_market::AMM;
 use crate::state::state::PriceDivergenceGuardRails;
 use crate::{validate, PERCENTAGE_PRECISION_U64};
+use num_integer::Roots;
 
 use super::helpers::get_proportion_u128;
 use crate::math::safe_math::SafeMath;
@@ -593,6 +595,72 @@ pub fn update_amm_mark_std(
     Ok(true)
 }
 
+/// Weight left on the prior ewma value after `slots` for a given half life, 2^(-slots / half_life)
+/// linearly interpolated between halvings
+/// precision: PERCENTAGE_PRECISION
+pub fn calculate_ewma_decay(slots: u64, half_life: u64) -> DriftResult<u128> {
+    let half_life = half_life.max(1);
+    let halvings = slots.safe_div(half_life)?;
+    if halvings >= 64 {
+        return Ok(0);
+    }
+
+    let decay = PERCENTAGE_PRECISION >> halvings;
+    let remainder = slots.safe_sub(halvings.safe_mul(half_life)?)?;
+
+    decay.safe_sub(
+        decay
+            .safe_mul(remainder.cast()?)?
+            .safe_div(half_life.safe_mul(2)?.cast()?)?,
+    )
+}
+
+/// Folds the oracle log return since the last sample into an ewma realized volatility, kept as
+/// the volatility over ORACLE_REALIZED_VOL_HORIZON_SLOTS so it's comparable to spreads
+/// half_life precision: 1 = ORACLE_REALIZED_VOL_HALF_LIFE_SLOTS
+/// precision: PERCENTAGE_PRECISION
+pub fn calculate_new_oracle_realized_vol(
+    oracle_realized_vol: u16,
+    last_oracle_price: i64,
+    oracle_price: i64,
+    slots_since_last_sample: u64,
+    half_life: u8,
+) -> DriftResult<u16> {
+    if last_oracle_price <= 0 || oracle_price <= 0 || slots_since_last_sample == 0 {
+        return Ok(oracle_realized_vol);
+    }
+
+    // ln(p1 / p0) ~= 2 * (p1 - p0) / (p1 + p0)
+    let log_return = oracle_price
+        .safe_sub(last_oracle_price)?
+        .unsigned_abs()
+        .cast::<u128>()?
+        .safe_mul(PERCENTAGE_PRECISION * 2)?
+        .safe_div(oracle_price.safe_add(last_oracle_price)?.cast()?)?;
+
+    // variance of the sample scaled to the horizon
+    let sample_variance = log_return
+        .safe_mul(log_return)?
+        .safe_mul(ORACLE_REALIZED_VOL_HORIZON_SLOTS.cast()?)?
+        .safe_div(slots_since_last_sample.cast()?)?;
+
+    let decay = calculate_ewma_decay(
+        slots_since_last_sample,
+        half_life
+            .cast::<u64>()?
+            .safe_mul(ORACLE_REALIZED_VOL_HALF_LIFE_SLOTS)?,
+    )?;
+
+    let oracle_realized_variance = oracle_realized_vol
+        .cast::<u128>()?
+        .safe_mul(oracle_realized_vol.cast()?)?
+        .safe_mul(decay)?
+        .safe_add(sample_variance.safe_mul(PERCENTAGE_PRECISION.safe_sub(decay)?)?)?
+        .safe_div(PERCENTAGE_PRECISION)?;
+
+    oracle_realized_variance.sqrt().min(u16::MAX as u128).cast()
+}
+
 pub fn update_amm_oracle_std(
     amm: &mut AMM,
     now: i64,
@@ -688,11 +756,23 @@ pub fn calculate_quote_asset_amount_swapped(
     swap_direction: SwapDirection,
     peg_multiplier: u128,
 ) -> DriftResult<u128> {
-    let mut quote_asset_reserve_change = match swap_direction {
+    let quote_asset_reserve_change = match swap_direction {
         SwapDirection::Add => quote_asset_reserve_before.safe_sub(quote_asset_reserve_after)?,
         SwapDirection::Remove => quote_asset_reserve_after.safe_sub(quote_asset_reserve_before)?,
     };
 
+    calculate_quote_asset_amount_for_reserve_change(
+        quote_asset_reserve_change,
+        swap_direction,
+        peg_multiplier,
+    )
+}
+
+pub fn calculate_quote_asset_amount_for_reserve_change(
+    mut quote_asset_reserve_change: u128,
+    swap_direction: SwapDirection,
+    peg_multiplier: u128,
+) -> DriftResult<u128> {
     // when a user goes long base asset, make the base asset slightly more expensive
     // by adding one unit of quote asset
     if swap_direction == SwapDirection::Remove {
@@ -711,12 +791,248 @@ pub fn calculate_quote_asset_amount_swapped(
     Ok(quote_asset_amount)
 }
 
+/// Base reserves at the upper and lower price edge of the concentrated band.
+/// Reserve price is quote / base * peg, so the band edges don't move when the amm is repegged
+pub fn calculate_concentrated_band_reserves(amm: &AMM) -> DriftResult<(u128, u128)> {
+    let invariant_sqrt_u192 = U192::from(amm.sqrt_k);
+    let invariant = invariant_sqrt_u192.safe_mul(invariant_sqrt_u192)?;
+
+    let band_width = U192::from(
+        PERCENTAGE_PRECISION_U64
+            .safe_mul(amm.concentrated_band_width.cast()?)?
+            .safe_div(1000)?,
+    );
+    let percentage_precision = U192::from(PERCENTAGE_PRECISION_U64);
+
+    let upper_price_base_asset_reserve = invariant
+        .safe_mul(percentage_precision)?
+        .safe_div(percentage_precision.safe_add(band_width)?)?
+        .integer_sqrt()
+        .try_to_u128()?;
+
+    let lower_price_base_asset_reserve = invariant
+        .safe_mul(percentage_precision)?
+        .safe_div(percentage_precision.safe_sub(band_width)?)?
+        .integer_sqrt()
+        .try_to_u128()?;
+
+    Ok((
+        upper_price_base_asset_reserve,
+        lower_price_base_asset_reserve,
+    ))
+}
+
+/// Splits a base amount traded against the amm into (base reserve change, liquidity multiplier)
+/// segments walking from the current base reserve. Inside the concentrated band the amm is
+/// concentrated_liquidity_multiplier times deeper, so the base reserve moves that much less
+pub fn calculate_base_reserve_segments(
+    amm: &AMM,
+    base_asset_amount: u128,
+    direction: SwapDirection,
+) -> DriftResult<Vec<(u128, u128)>> {
+    if !amm.has_concentrated_band() {
+        return Ok(vec![(base_asset_amount, 1)]);
+    }
+
+    let (band_start, band_end) = calculate_concentrated_band_reserves(amm)?;
+    let concentrated_liquidity_multiplier = amm.concentrated_liquidity_multiplier.cast::<u128>()?;
+
+    let mut segments = Vec::with_capacity(3);
+    let mut base_asset_reserve = amm.base_asset_reserve;
+    let mut base_asset_amount_remaining = base_asset_amount;
+    while base_asset_amount_remaining > 0 {
+        let (in_band, distance_to_edge) = match direction {
+            SwapDirection::Add => {
+                if base_asset_reserve < band_start {
+                    (false, band_start.safe_sub(base_asset_reserve)?)
+                } else if base_asset_reserve < band_end {
+                    (true, band_end.safe_sub(base_asset_reserve)?)
+                } else {
+                    (false, u128::MAX)
+                }
+            }
+            SwapDirection::Remove => {
+                if base_asset_reserve > band_end {
+                    (false, base_asset_reserve.safe_sub(band_end)?)
+                } else if base_asset_reserve > band_start {
+                    (true, base_asset_reserve.safe_sub(band_start)?)
+                } else {
+                    (false, u128::MAX)
+                }
+            }
+        };
+
+        let multiplier = if in_band {
+            concentrated_liquidity_multiplier
+        } else {
+            1
+        };
+
+        let segment_capacity = distance_to_edge.saturating_mul(multiplier);
+        if base_asset_amount_remaining <= segment_capacity {
+            // round the reserve change up so the amm isn't worse off
+            segments.push((
+                base_asset_amount_remaining.safe_div_ceil(multiplier)?,
+                multiplier,
+            ));
+            break;
+        }
+
+        segments.push((distance_to_edge, multiplier));
+        base_asset_amount_remaining = base_asset_amount_remaining.safe_sub(segment_capacity)?;
+        base_asset_reserve = match direction {
+            SwapDirection::Add => base_asset_reserve.safe_add(distance_to_edge)?,
+            SwapDirection::Remove => base_asset_reserve.safe_sub(distance_to_edge)?,
+        };
+    }
+
+    Ok(segments)
+}
+
+/// Swaps along the segments starting from base_asset_reserve.
+/// Returns the new quote and base reserve and the quote reserve amount the trade is worth,
+/// which is each segment's quote reserve change scaled by its liquidity multiplier
+pub fn calculate_swap_output_for_segments(
+    segments: &[(u128, u128)],
+    base_asset_reserve: u128,
+    direction: SwapDirection,
+    invariant_sqrt: u128,
+) -> DriftResult<(u128, u128, u128)> {
+    let (mut quote_asset_reserve, mut base_asset_reserve) =
+        calculate_swap_output(0, base_asset_reserve, direction, invariant_sqrt)?;
+
+    let mut quote_asset_reserve_amount = 0_u128;
+    for (base_asset_reserve_change, multiplier) in segments.iter() {
+        let (new_quote_asset_reserve, new_base_asset_reserve) = calculate_swap_output(
+            *base_asset_reserve_change,
+            base_asset_reserve,
+            direction,
+            invariant_sqrt,
+        )?;
+
+        let quote_asset_reserve_change = match direction {
+            SwapDirection::Add => quote_asset_reserve.safe_sub(new_quote_asset_reserve)?,
+            SwapDirection::Remove => new_quote_asset_reserve.safe_sub(quote_asset_reserve)?,
+        };
+
+        quote_asset_reserve_amount = quote_asset_reserve_amount
+            .safe_add(quote_asset_reserve_change.safe_mul(*multiplier)?)?;
+        quote_asset_reserve = new_quote_asset_reserve;
+        base_asset_reserve = new_base_asset_reserve;
+    }
+
+    Ok((
+        quote_asset_reserve,
+        base_asset_reserve,
+        quote_asset_reserve_amount,
+    ))
+}
+
+/// Base amount that moves the base reserve by base_asset_reserve_change from the current base reserve
+pub fn calculate_base_asset_amount_for_reserve_change(
+    amm: &AMM,
+    base_asset_reserve_change: u128,
+    direction: SwapDirection,
+) -> DriftResult<u128> {
+    if !amm.has_concentrated_band() {
+        return Ok(base_asset_reserve_change);
+    }
+
+    let (band_start, band_end) = calculate_concentrated_band_reserves(amm)?;
+
+    let (range_start, range_end) = match direction {
+        SwapDirection::Add => (
+            amm.base_asset_reserve,
+            amm.base_asset_reserve
+                .saturating_add(base_asset_reserve_change),
+        ),
+        SwapDirection::Remove => (
+            amm.base_asset_reserve
+                .saturating_sub(base_asset_reserve_change),
+            amm.base_asset_reserve,
+        ),
+    };
+
+    let overlap = range_end
+        .min(band_end)
+        .saturating_sub(range_start.max(band_start));
+
+    base_asset_reserve_change.safe_add(
+        overlap.safe_mul(
+            amm.concentrated_liquidity_multiplier
+                .cast::<u128>()?
+                .safe_sub(1)?,
+        )?,
+    )
+}
+
+/// Quote reserve amount the net user position is worth closed against the curve, signed like
+/// quote_asset_reserve - terminal_quote_asset_reserve. Inside the concentrated band the quote
+/// reserve change counts concentrated_liquidity_multiplier times, like it does for swaps
+pub fn calculate_net_quote_asset_reserve_amount(amm: &AMM) -> DriftResult<i128> {
+    if !amm.has_concentrated_band() {
+        return amm
+            .quote_asset_reserve
+            .cast::<i128>()?
+            .safe_sub(amm.terminal_quote_asset_reserve.cast()?);
+    }
+
+    if amm.base_asset_amount_with_amm == 0 {
+        return Ok(0);
+    }
+
+    let swap_direction = if amm.base_asset_amount_with_amm > 0 {
+        SwapDirection::Add
+    } else {
+        SwapDirection::Remove
+    };
+
+    let segments = calculate_base_reserve_segments(
+        amm,
+        amm.base_asset_amount_with_amm.unsigned_abs(),
+        swap_direction,
+    )?;
+
+    let (_, _, quote_asset_reserve_amount) = calculate_swap_output_for_segments(
+        &segments,
+        amm.base_asset_reserve,
+        swap_direction,
+        amm.sqrt_k,
+    )?;
+
+    let quote_asset_reserve_amount = quote_asset_reserve_amount.cast::<i128>()?;
+
+    Ok(match swap_direction {
+        SwapDirection::Add => quote_asset_reserve_amount,
+        SwapDirection::Remove => -quote_asset_reserve_amount,
+    })
+}
+
 pub fn calculate_terminal_reserves(amm: &AMM) -> DriftResult<(u128, u128)> {
     let swap_direction = if amm.base_asset_amount_with_amm > 0 {
         SwapDirection::Add
     } else {
         SwapDirection::Remove
     };
+
+    if amm.has_concentrated_band() {
+        let segments = calculate_base_reserve_segments(
+            amm,
+            amm.base_asset_amount_with_amm.unsigned_abs(),
+            swap_direction,
+        )?;
+
+        let (new_quote_asset_amount, new_base_asset_amount, _) =
+            calculate_swap_output_for_segments(
+                &segments,
+                amm.base_asset_reserve,
+                swap_direction,
+                amm.sqrt_k,
+            )?;
+
+        return Ok((new_quote_asset_amount, new_base_asset_amount));
+    }
+
     let (new_quote_asset_amount, new_base_asset_amount) = calculate_swap_output(
         amm.base_asset_amount_with_amm.unsigned_abs(),
         amm.base_asset_reserve,
@@ -844,6 +1160,10 @@ pub fn calculate_amm_available_liquidity(
     amm: &AMM,
     order_direction: &PositionDirection,
 ) -> DriftResult<u64> {
+    if amm.has_concentrated_band() {
+        return calculate_concentrated_amm_available_liquidity(amm, order_direction);
+    }
+
     let max_fill_size: u64 = (amm.base_asset_reserve / amm.max_fill_reserve_fraction as u128)
         .min(u64::MAX as u128)
         .cast()?;
@@ -869,6 +1189,40 @@ pub fn calculate_amm_available_liquidity(
     )
 }
 
+/// Same reserve limits as calculate_amm_available_liquidity, converted to the base amount
+/// that moves the reserves that far along the concentrated curve
+fn calculate_concentrated_amm_available_liquidity(
+    amm: &AMM,
+    order_direction: &PositionDirection,
+) -> DriftResult<u64> {
+    let max_fill_reserve_change = amm.base_asset_reserve / amm.max_fill_reserve_fraction as u128;
+
+    let (max_reserve_change_on_side, swap_direction) = match order_direction {
+        PositionDirection::Long => (
+            amm.base_asset_reserve
+                .saturating_sub(amm.min_base_asset_reserve)
+                / 2,
+            SwapDirection::Remove,
+        ),
+        PositionDirection::Short => (
+            amm.max_base_asset_reserve
+                .saturating_sub(amm.base_asset_reserve)
+                / 2,
+            SwapDirection::Add,
+        ),
+    };
+
+    let max_base_asset_amount = calculate_base_asset_amount_for_reserve_change(
+        amm,
+        max_fill_reserve_change.min(max_reserve_change_on_side),
+        swap_direction,
+    )?
+    .min(u64::MAX as u128)
+    .cast::<u64>()?;
+
+    standardize_base_asset_amount(max_base_asset_amount, amm.order_step_size)
+}
+
 pub fn calculate_net_user_cost_basis(amm: &AMM) -> DriftResult<i128> {
     amm.quote_asset_amount
         .safe_add(amm.quote_asset_amount_with_unsettled_lp.cast()?)?
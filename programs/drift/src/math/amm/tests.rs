@@ -1051,3 +1051,125 @@ fn test_last_oracle_conf_update() {
 
     assert_eq!(amm.last_oracle_conf_pct, 7307 - 7307 / 5 + 1); //5847
 }
+
+#[test]
+fn calculate_concentrated_band_reserves_test() {
+    let mut amm = AMM::default_test();
+    amm.concentrated_band_width = 100; // 10%
+    amm.concentrated_liquidity_multiplier = 4;
+
+    let (band_start, band_end) = calculate_concentrated_band_reserves(&amm).unwrap();
+    assert_eq!(band_start, 95346258924);
+    assert_eq!(band_end, 105409255338);
+
+    // reserve price at the edges is peg -/+ 10%
+    let upper_price = calculate_price(
+        amm.sqrt_k * amm.sqrt_k / band_start,
+        band_start,
+        amm.peg_multiplier,
+    )
+    .unwrap();
+    assert_eq!(upper_price, 11 * PRICE_PRECISION_U64 / 10);
+}
+
+#[test]
+fn calculate_base_reserve_segments_test() {
+    let mut amm = AMM::default_test();
+
+    let segments =
+        calculate_base_reserve_segments(&amm, 20 * AMM_RESERVE_PRECISION, SwapDirection::Remove)
+            .unwrap();
+    assert_eq!(segments, vec![(20 * AMM_RESERVE_PRECISION, 1)]);
+
+    amm.concentrated_band_width = 100; // 10%
+    amm.concentrated_liquidity_multiplier = 4;
+
+    // stays inside the band
+    let segments =
+        calculate_base_reserve_segments(&amm, AMM_RESERVE_PRECISION, SwapDirection::Remove)
+            .unwrap();
+    assert_eq!(segments, vec![(AMM_RESERVE_PRECISION / 4, 4)]);
+
+    // crosses the upper price edge
+    let segments =
+        calculate_base_reserve_segments(&amm, 20 * AMM_RESERVE_PRECISION, SwapDirection::Remove)
+            .unwrap();
+    assert_eq!(segments, vec![(4653741076, 4), (1385035696, 1)]);
+
+    let base_asset_amount = calculate_base_asset_amount_for_reserve_change(
+        &amm,
+        4653741076 + 1385035696,
+        SwapDirection::Remove,
+    )
+    .unwrap();
+    assert_eq!(base_asset_amount, 20 * AMM_RESERVE_PRECISION);
+}
+
+#[test]
+fn calculate_swap_output_for_segments_test() {
+    let amm = AMM::default_test();
+
+    let (new_quote_asset_reserve, new_base_asset_reserve, quote_asset_reserve_amount) =
+        calculate_swap_output_for_segments(
+            &[(AMM_RESERVE_PRECISION / 4, 4)],
+            amm.base_asset_reserve,
+            SwapDirection::Remove,
+            amm.sqrt_k,
+        )
+        .unwrap();
+
+    assert_eq!(new_base_asset_reserve, 99750000000);
+    assert_eq!(new_quote_asset_reserve, 100250626566);
+    // 4x the depth means 4x the quote for the same price move
+    assert_eq!(quote_asset_reserve_amount, 4 * 250626566);
+}
+
+#[test]
+fn calculate_ewma_decay_test() {
+    assert_eq!(calculate_ewma_decay(0, 100).unwrap(), 1_000_000);
+    assert_eq!(calculate_ewma_decay(50, 100).unwrap(), 750_000);
+    assert_eq!(calculate_ewma_decay(100, 100).unwrap(), 500_000);
+    assert_eq!(calculate_ewma_decay(150, 100).unwrap(), 375_000);
+    assert_eq!(calculate_ewma_decay(10_000, 100).unwrap(), 0);
+}
+
+#[test]
+fn calculate_new_oracle_realized_vol_test() {
+    let price = 100 * PRICE_PRECISION_I64;
+
+    // 10 bps move with a 150 slot half life
+    let vol = calculate_new_oracle_realized_vol(0, price, price + PRICE_PRECISION_I64 / 10, 1, 15)
+        .unwrap();
+    assert_eq!(vol, 706);
+
+    let vol =
+        calculate_new_oracle_realized_vol(vol, price + PRICE_PRECISION_I64 / 10, price, 1, 15)
+            .unwrap();
+    assert_eq!(vol, 997);
+
+    // quiet for two half lives
+    let vol = calculate_new_oracle_realized_vol(vol, price, price, 300, 15).unwrap();
+    assert_eq!(vol, 498);
+
+    // the same move with a 1500 slot half life
+    let slow_vol =
+        calculate_new_oracle_realized_vol(0, price, price + PRICE_PRECISION_I64 / 10, 1, 150)
+            .unwrap();
+    assert_eq!(slow_vol, 223);
+
+    // no sample
+    assert_eq!(
+        calculate_new_oracle_realized_vol(vol, price, price * 2, 0, 15).unwrap(),
+        vol
+    );
+    assert_eq!(
+        calculate_new_oracle_realized_vol(vol, 0, price, 1, 15).unwrap(),
+        vol
+    );
+
+    // saturates
+    assert_eq!(
+        calculate_new_oracle_realized_vol(0, 100, price * 2, 1, 1).unwrap(),
+        u16::MAX
+    );
+}
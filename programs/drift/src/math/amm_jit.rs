@@ -158,7 +158,8 @@ pub fn calculate_amm_jit_liquidity(
         !taker_has_limit_price && maker_base_asset_amount < taker_base_asset_amount;
 
     // return early
-    if amm_will_fill_next_round {
+    // oracle quote amms only fill at their own bid/ask
+    if amm_will_fill_next_round || market.is_oracle_quote_amm() {
         return Ok(jit_base_asset_amount);
     }
     let amm_wants_to_jit_make = market.amm.amm_wants_to_jit_make(taker_direction)?;
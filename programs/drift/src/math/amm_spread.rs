@@ -2,9 +2,12 @@ use std::cmp::{max, min};
 
 use crate::msg;
 
+use crate::controller::amm::SwapDirection;
 use crate::controller::position::PositionDirection;
 use crate::error::{DriftResult, ErrorCode};
-use crate::math::amm::_calculate_market_open_bids_asks;
+use crate::math::amm::{
+    _calculate_market_open_bids_asks, calculate_base_asset_amount_for_reserve_change,
+};
 use crate::math::bn::U192;
 use crate::math::casting::Cast;
 use crate::math::constants::{
@@ -54,16 +57,22 @@ pub fn calculate_base_asset_amount_to_trade_to_price(
     };
 
     if new_base_asset_reserve > base_asset_reserve_before {
-        let max_trade_amount = new_base_asset_reserve
-            .safe_sub(base_asset_reserve_before)?
-            .cast::<u64>()
-            .unwrap_or(u64::MAX);
+        let max_trade_amount = calculate_base_asset_amount_for_reserve_change(
+            amm,
+            new_base_asset_reserve.safe_sub(base_asset_reserve_before)?,
+            SwapDirection::Add,
+        )?
+        .cast::<u64>()
+        .unwrap_or(u64::MAX);
         Ok((max_trade_amount, PositionDirection::Short))
     } else {
-        let max_trade_amount = base_asset_reserve_before
-            .safe_sub(new_base_asset_reserve)?
-            .cast::<u64>()
-            .unwrap_or(u64::MAX);
+        let max_trade_amount = calculate_base_asset_amount_for_reserve_change(
+            amm,
+            base_asset_reserve_before.safe_sub(new_base_asset_reserve)?,
+            SwapDirection::Remove,
+        )?
+        .cast::<u64>()
+        .unwrap_or(u64::MAX);
         Ok((max_trade_amount, PositionDirection::Long))
     }
 }
@@ -107,6 +116,7 @@ pub fn calculate_long_short_vol_spread(
     reserve_price: u64,
     mark_std: u64,
     oracle_std: u64,
+    oracle_realized_vol_pct: u64,
     long_intensity_volume: u64,
     short_intensity_volume: u64,
     volume_24h: u64,
@@ -121,7 +131,8 @@ pub fn calculate_long_short_vol_spread(
 
     let vol_spread: u128 = last_oracle_conf_pct
         .cast::<u128>()?
-        .max(market_avg_std_pct.safe_div(4)?);
+        .max(market_avg_std_pct.safe_div(4)?)
+        .max(oracle_realized_vol_pct.cast()?);
 
     let factor_clamp_min: u128 = PERCENTAGE_PRECISION / 100; // .01
     let factor_clamp_max: u128 = PERCENTAGE_PRECISION; // 1
@@ -300,6 +311,7 @@ pub fn calculate_max_target_spread(
     last_oracle_conf_pct: u64,
     mark_std: u64,
     oracle_std: u64,
+    oracle_realized_vol_pct: u64,
     max_spread: u32,
 ) -> DriftResult<u64> {
     let max_spread_baseline = last_oracle_reserve_price_spread_pct.unsigned_abs().max(
@@ -311,6 +323,7 @@ pub fn calculate_max_target_spread(
                     .safe_mul(PERCENTAGE_PRECISION_U64)?
                     .safe_div(reserve_price)?,
             )
+            .max(oracle_realized_vol_pct.safe_mul(2)?)
             .min(BID_ASK_SPREAD_PRECISION),
     );
 
@@ -336,6 +349,8 @@ pub fn calculate_spread(
     max_base_asset_reserve: u128,
     mark_std: u64,
     oracle_std: u64,
+    oracle_realized_vol_fast: u64,
+    oracle_realized_vol_slow: u64,
     long_intensity_volume: u64,
     short_intensity_volume: u64,
     volume_24h: u64,
@@ -346,6 +361,7 @@ pub fn calculate_spread(
         reserve_price,
         mark_std,
         oracle_std,
+        oracle_realized_vol_fast,
         long_intensity_volume,
         short_intensity_volume,
         volume_24h,
@@ -362,6 +378,7 @@ pub fn calculate_spread(
         last_oracle_conf_pct,
         mark_std,
         oracle_std,
+        oracle_realized_vol_slow,
         max_spread,
     )?;
 
@@ -233,6 +233,8 @@ mod test {
             max_base_asset_reserve,
             mark_std,
             oracle_std,
+            0,
+            0,
             long_intensity_volume,
             short_intensity_volume,
             volume_24h,
@@ -263,6 +265,8 @@ mod test {
             max_base_asset_reserve,
             mark_std,
             oracle_std,
+            0,
+            0,
             long_intensity_volume,
             short_intensity_volume,
             volume_24h,
@@ -293,6 +297,8 @@ mod test {
             max_base_asset_reserve,
             mark_std,
             oracle_std,
+            0,
+            0,
             long_intensity_volume,
             short_intensity_volume,
             volume_24h,
@@ -329,6 +335,8 @@ mod test {
             max_base_asset_reserve,
             mark_std,
             oracle_std,
+            0,
+            0,
             long_intensity_volume,
             short_intensity_volume,
             volume_24h,
@@ -359,6 +367,8 @@ mod test {
             max_base_asset_reserve,
             mark_std,
             oracle_std,
+            0,
+            0,
             long_intensity_volume,
             short_intensity_volume,
             volume_24h,
@@ -488,6 +498,8 @@ mod test {
             max_base_asset_reserve,
             mark_std,
             oracle_std,
+            0,
+            0,
             long_intensity_volume,
             short_intensity_volume,
             volume_24h,
@@ -515,6 +527,8 @@ mod test {
             max_base_asset_reserve,
             mark_std,
             oracle_std,
+            0,
+            0,
             long_intensity_volume,
             short_intensity_volume,
             volume_24h,
@@ -569,6 +583,8 @@ mod test {
             max_base_asset_reserve,
             mark_std,
             oracle_std,
+            0,
+            0,
             long_intensity_volume,
             short_intensity_volume,
             volume_24h,
@@ -618,6 +634,8 @@ mod test {
             max_base_asset_reserve,
             mark_std,
             oracle_std,
+            0,
+            0,
             long_intensity_volume,
             short_intensity_volume,
             volume_24h,
@@ -646,6 +664,8 @@ mod test {
             max_base_asset_reserve,
             mark_std,
             oracle_std,
+            0,
+            0,
             long_intensity_volume,
             short_intensity_volume,
             volume_24h,
@@ -673,6 +693,8 @@ mod test {
             max_base_asset_reserve,
             mark_std,
             oracle_std,
+            0,
+            0,
             long_intensity_volume,
             short_intensity_volume,
             volume_24h,
@@ -686,8 +708,9 @@ mod test {
         let (d1, _) = calculate_long_short_vol_spread(
             last_oracle_conf_pct, // 0
             reserve_price,
-            mark_std,               // 0
-            oracle_std,             // 0
+            mark_std,   // 0
+            oracle_std, // 0
+            0,
             long_intensity_volume,  // 0
             short_intensity_volume, // 0
             volume_24h,             // 0
@@ -723,6 +746,8 @@ mod test {
             max_base_asset_reserve,
             mark_std,
             oracle_std,
+            0,
+            0,
             long_intensity_volume,
             short_intensity_volume,
             volume_24h,
@@ -749,6 +774,8 @@ mod test {
             max_base_asset_reserve,
             mark_std,
             oracle_std,
+            0,
+            0,
             long_intensity_volume,
             short_intensity_volume,
             volume_24h,
@@ -775,6 +802,8 @@ mod test {
             max_base_asset_reserve * 2,
             mark_std,
             oracle_std,
+            0,
+            0,
             long_intensity_volume,
             short_intensity_volume,
             volume_24h,
@@ -1073,6 +1102,7 @@ mod test {
             reserve_price,
             mark_std,
             oracle_std,
+            0,
             long_intensity_volume,
             short_intensity_volume,
             volume_24h,
@@ -1132,6 +1162,8 @@ mod test {
             max_base_asset_reserve,
             mark_std,
             oracle_std,
+            0,
+            0,
             long_intensity_volume,
             short_intensity_volume,
             volume_24h,
@@ -1173,6 +1205,8 @@ mod test {
             max_base_asset_reserve,
             mark_std,
             oracle_std,
+            0,
+            0,
             long_intensity_volume,
             short_intensity_volume,
             volume_24h,
@@ -1200,6 +1234,8 @@ mod test {
             max_base_asset_reserve,
             mark_std,
             oracle_std,
+            0,
+            0,
             long_intensity_volume,
             short_intensity_volume,
             volume_24h,
@@ -1227,6 +1263,8 @@ mod test {
             max_base_asset_reserve,
             mark_std,
             oracle_std,
+            0,
+            0,
             long_intensity_volume,
             short_intensity_volume,
             volume_24h,
@@ -1254,6 +1292,8 @@ mod test {
             max_base_asset_reserve,
             mark_std,
             oracle_std,
+            0,
+            0,
             long_intensity_volume,
             short_intensity_volume,
             volume_24h,
@@ -1280,6 +1320,8 @@ mod test {
             max_base_asset_reserve,
             mark_std,
             oracle_std,
+            0,
+            0,
             long_intensity_volume,
             short_intensity_volume,
             volume_24h,
@@ -1321,6 +1363,7 @@ mod test {
             reserve_price,
             mark_std,
             oracle_std,
+            0,
             long_intensity_volume,
             short_intensity_volume,
             volume_24h,
@@ -1380,6 +1423,8 @@ mod test {
             max_base_asset_reserve,
             mark_std,
             oracle_std,
+            0,
+            0,
             long_intensity_volume,
             short_intensity_volume,
             volume_24h,
@@ -1421,6 +1466,8 @@ mod test {
             max_base_asset_reserve,
             mark_std,
             oracle_std,
+            0,
+            0,
             long_intensity_volume,
             short_intensity_volume,
             volume_24h,
@@ -1448,6 +1495,8 @@ mod test {
             max_base_asset_reserve,
             mark_std,
             oracle_std,
+            0,
+            0,
             long_intensity_volume,
             short_intensity_volume,
             volume_24h,
@@ -1474,6 +1523,8 @@ mod test {
             max_base_asset_reserve,
             mark_std,
             oracle_std,
+            0,
+            0,
             long_intensity_volume,
             short_intensity_volume,
             volume_24h,
@@ -1505,6 +1556,8 @@ mod test {
             945977491145601,
             161188,
             1459632439,
+            0,
+            0,
             12358265776,
             72230366233,
             432067603632,
@@ -1532,6 +1585,8 @@ mod test {
             945977491145601,
             161188,
             1459632439,
+            0,
+            0,
             12358265776,
             72230366233,
             432067603632,
@@ -1559,6 +1614,8 @@ mod test {
             945977491145601,
             161188,
             1459632439,
+            0,
+            0,
             12358265776,
             72230366233,
             432067603632,
@@ -1587,6 +1644,8 @@ mod test {
             945977494085178,
             11581,
             54284474,
+            0,
+            0,
             9520659647,
             53979922148,
             427588331503,
@@ -1694,4 +1753,61 @@ mod test {
             949981 // under .95
         );
     }
+
+    #[test]
+    fn calculate_realized_vol_spread_tests() {
+        let reserve_price = 34562304;
+        let mark_std = 34000000 / 50; // 2% of price
+        let oracle_std = 34000000 / 150; // .66% of price
+        let long_intensity_volume = (QUOTE_PRECISION * 10000) as u64; //10k
+        let short_intensity_volume = (QUOTE_PRECISION * 30000) as u64; //30k
+        let volume_24h = (QUOTE_PRECISION * 40000) as u64; // 40k
+
+        let (long_vspread, short_vspread) = calculate_long_short_vol_spread(
+            0,
+            reserve_price,
+            mark_std,
+            oracle_std,
+            0,
+            long_intensity_volume,
+            short_intensity_volume,
+            volume_24h,
+        )
+        .unwrap();
+        assert_eq!(long_vspread, 819);
+        assert_eq!(short_vspread, 2459);
+
+        // 2% realized vol dominates the std based estimate
+        let oracle_realized_vol_pct = 20000;
+        let (long_vspread, short_vspread) = calculate_long_short_vol_spread(
+            0,
+            reserve_price,
+            mark_std,
+            oracle_std,
+            oracle_realized_vol_pct,
+            long_intensity_volume,
+            short_intensity_volume,
+            volume_24h,
+        )
+        .unwrap();
+        assert_eq!(long_vspread, 5000);
+        assert_eq!(short_vspread, 15000);
+
+        let max_target_spread =
+            calculate_max_target_spread(reserve_price, 0, 0, mark_std, oracle_std, 0, 1000)
+                .unwrap();
+        assert_eq!(max_target_spread, 19674);
+
+        let max_target_spread = calculate_max_target_spread(
+            reserve_price,
+            0,
+            0,
+            mark_std,
+            oracle_std,
+            oracle_realized_vol_pct,
+            1000,
+        )
+        .unwrap();
+        assert_eq!(max_target_spread, 40000);
+    }
 }
@@ -179,6 +179,10 @@ pub const MAX_APR_PER_REVENUE_SETTLE_TO_INSURANCE_FUND_VAULT_GOV: u128 =
     (PERCENTAGE_PRECISION_U64 / 22) as u128; // 4.6%
 
 pub const MAX_CONCENTRATION_COEFFICIENT: u128 = 1_414_200;
+pub const MAX_CONCENTRATED_BAND_WIDTH: u8 = 200; // 20%
+pub const MAX_CONCENTRATED_LIQUIDITY_MULTIPLIER: u8 = 20;
+pub const ORACLE_REALIZED_VOL_HORIZON_SLOTS: u64 = 150; // ~1 minute
+pub const ORACLE_REALIZED_VOL_HALF_LIFE_SLOTS: u64 = 10; // unit of the realized vol half lives
 pub const MAX_LIQUIDATION_MULTIPLIER: u32 = 3;
 pub const LIQUIDATION_FEE_INCREASE_PER_SLOT: u32 = LIQUIDATION_FEE_PRECISION / 1_000_000; // .01 bps per slot
 pub const MAX_LIQUIDATION_SLIPPAGE: i128 = 10_000; // expo = -2
@@ -40,6 +40,15 @@ pub fn calculate_budgeted_k_scale(
         K_BPS_UPDATE_SCALE - MAX_K_BPS_DECREASE
     )?;
 
+    if market.amm.has_concentrated_band() {
+        return calculate_concentrated_budgeted_k_scale(
+            market,
+            budget,
+            k_pct_upper_bound,
+            k_pct_lower_bound,
+        );
+    }
+
     let (numerator, denominator) = _calculate_budgeted_k_scale(
         market.amm.base_asset_reserve,
         market.amm.quote_asset_reserve,
@@ -53,6 +62,76 @@ pub fn calculate_budgeted_k_scale(
     Ok((numerator, denominator))
 }
 
+/// The k cost of a concentrated curve has no closed form, so the k scale is searched for: the
+/// largest scale within the bounds whose k change costs at most the budget. The cost of a k change
+/// only grows with k, so the search halves the range of scales each step
+fn calculate_concentrated_budgeted_k_scale(
+    market: &PerpMarket,
+    budget: i128,
+    k_pct_upper_bound: i128,
+    k_pct_lower_bound: i128,
+) -> DriftResult<(u128, u128)> {
+    let net_market_value =
+        calculate_base_asset_value(market.amm.base_asset_amount_with_amm, &market.amm)?;
+
+    let is_within_budget = |k_scale: i128| -> DriftResult<bool> {
+        let new_sqrt_k = U192::from(market.amm.sqrt_k)
+            .safe_mul(U192::from(k_scale.cast::<u128>()?))?
+            .safe_div(U192::from(K_BPS_UPDATE_SCALE.cast::<u128>()?))?;
+
+        let update_k_result = match get_update_k_result(market, new_sqrt_k, true) {
+            Ok(update_k_result) => update_k_result,
+            Err(_) => return Ok(false),
+        };
+
+        let mut amm = market.amm;
+        amm.sqrt_k = update_k_result.sqrt_k;
+        amm.base_asset_reserve = update_k_result.base_asset_reserve;
+        amm.quote_asset_reserve = update_k_result.quote_asset_reserve;
+
+        let (_, cost) = calculate_base_asset_value_and_pnl(
+            amm.base_asset_amount_with_amm,
+            net_market_value,
+            &amm,
+        )?;
+
+        Ok(cost <= budget)
+    };
+
+    if !is_within_budget(k_pct_lower_bound)? {
+        return Ok((
+            k_pct_lower_bound.cast::<u128>()?,
+            K_BPS_UPDATE_SCALE.cast::<u128>()?,
+        ));
+    }
+
+    if is_within_budget(k_pct_upper_bound)? {
+        return Ok((
+            k_pct_upper_bound.cast::<u128>()?,
+            K_BPS_UPDATE_SCALE.cast::<u128>()?,
+        ));
+    }
+
+    let mut within_budget_k_scale = k_pct_lower_bound;
+    let mut over_budget_k_scale = k_pct_upper_bound;
+    while over_budget_k_scale.safe_sub(within_budget_k_scale)? > 1 {
+        let k_scale = within_budget_k_scale
+            .safe_add(over_budget_k_scale)?
+            .safe_div(2)?;
+
+        if is_within_budget(k_scale)? {
+            within_budget_k_scale = k_scale;
+        } else {
+            over_budget_k_scale = k_scale;
+        }
+    }
+
+    Ok((
+        within_budget_k_scale.cast::<u128>()?,
+        K_BPS_UPDATE_SCALE.cast::<u128>()?,
+    ))
+}
+
 pub fn _calculate_budgeted_k_scale(
     x: u128,
     y: u128,
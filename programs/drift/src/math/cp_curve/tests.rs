@@ -309,6 +309,50 @@ fn calculate_k_tests_wrapper_fcn() {
     assert_eq!(pct_change_in_k, 10007); // k was increased .07%
 }
 
+#[test]
+fn calculate_budgeted_k_scale_concentrated_band() {
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 65 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 63015384615,
+            sqrt_k: 64 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 19_400 * PEG_PRECISION,
+            base_asset_amount_with_amm: -(AMM_RESERVE_PRECISION as i128),
+            concentration_coef: MAX_CONCENTRATION_COEFFICIENT,
+            concentrated_band_width: 20, // 2%
+            concentrated_liquidity_multiplier: 4,
+            ..AMM::default()
+        },
+        ..PerpMarket::default()
+    };
+
+    let budget = QUOTE_PRECISION as i128;
+    let k_pct_upper_bound = K_BPS_UPDATE_SCALE + K_BPS_UPDATE_SCALE / 100;
+
+    let (numerator, denominator) =
+        calculate_budgeted_k_scale(&mut market, budget, k_pct_upper_bound, K_BPS_UPDATE_SCALE)
+            .unwrap();
+
+    assert_eq!(denominator, K_BPS_UPDATE_SCALE as u128);
+    assert!(numerator > denominator);
+    assert!(numerator < k_pct_upper_bound as u128);
+
+    let cost_at_k_scale = |k_scale: u128| {
+        let new_sqrt_k = bn::U192::from(market.amm.sqrt_k)
+            .safe_mul(bn::U192::from(k_scale))
+            .unwrap()
+            .safe_div(bn::U192::from(denominator))
+            .unwrap();
+        let update_k_result = get_update_k_result(&market, new_sqrt_k, true).unwrap();
+        let mut market_clone = market;
+        adjust_k_cost(&mut market_clone, &update_k_result).unwrap()
+    };
+
+    // the largest k increase the budget covers on the concentrated curve
+    assert!(cost_at_k_scale(numerator) <= budget);
+    assert!(cost_at_k_scale(numerator + 1) > budget);
+}
+
 #[test]
 fn amm_spread_adj_logic() {
     let mut market = PerpMarket {
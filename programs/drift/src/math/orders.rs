@@ -6,7 +6,6 @@ use crate::msg;
 use crate::controller::position::PositionDelta;
 use crate::controller::position::PositionDirection;
 use crate::error::{DriftResult, ErrorCode};
-use crate::math::amm::calculate_amm_available_liquidity;
 use crate::math::casting::Cast;
 use crate::state::protected_maker_mode_config::ProtectedMakerParams;
 use crate::state::user::OrderBitFlag;
@@ -83,7 +82,7 @@ pub fn calculate_base_asset_amount_for_amm_to_fulfill(
         limit_price_with_buffer,
         Some(existing_base_asset_amount),
     )?;
-    let max_base_asset_amount = calculate_amm_available_liquidity(&market.amm, &order.direction)?;
+    let max_base_asset_amount = market.get_amm_available_liquidity(&order.direction)?;
 
     Ok((min(base_asset_amount, max_base_asset_amount), limit_price))
 }
@@ -133,6 +132,24 @@ pub fn calculate_base_asset_amount_to_fill_up_to_limit_price(
 
     let (max_trade_base_asset_amount, max_trade_direction) = if let Some(limit_price) = limit_price
     {
+        if market.is_oracle_quote_amm() {
+            // oracle quote amm fills any size at its bid/ask
+            let reserve_price = market.amm.reserve_price()?;
+            let amm_price_crosses = match order.direction {
+                PositionDirection::Long => market.amm.ask_price(reserve_price)? <= limit_price,
+                PositionDirection::Short => market.amm.bid_price(reserve_price)? >= limit_price,
+            };
+
+            if !amm_price_crosses {
+                return Ok(0);
+            }
+
+            return standardize_base_asset_amount(
+                base_asset_amount_unfilled,
+                market.amm.order_step_size,
+            );
+        }
+
         // buy to right below or sell up right above the limit price
         let adjusted_limit_price = match order.direction {
             PositionDirection::Long => limit_price.safe_sub(market.amm.order_tick_size)?,
@@ -585,6 +602,20 @@ pub fn is_oracle_too_divergent_with_twap_5min(
     Ok(too_divergent)
 }
 
+/// Fills smaller than the order's min fill size are skipped unless they fill the rest of the order
+pub fn is_fill_below_min_fill_size(
+    order: &Order,
+    base_asset_amount: u64,
+    existing_position: Option<i64>,
+) -> DriftResult<bool> {
+    let min_fill_size = order.get_min_fill_size();
+    if min_fill_size == 0 || base_asset_amount >= min_fill_size {
+        return Ok(false);
+    }
+
+    Ok(base_asset_amount < order.get_base_asset_amount_unfilled(existing_position)?)
+}
+
 pub fn order_satisfies_trigger_condition(order: &Order, oracle_price: u64) -> DriftResult<bool> {
     match order.trigger_condition {
         OrderTriggerCondition::Above => Ok(oracle_price > order.trigger_price),
@@ -593,6 +624,61 @@ pub fn order_satisfies_trigger_condition(order: &Order, oracle_price: u64) -> Dr
     }
 }
 
+pub fn order_satisfies_account_trigger_condition(
+    order: &Order,
+    margin_calculation: &MarginCalculation,
+) -> DriftResult<bool> {
+    match order.trigger_condition {
+        OrderTriggerCondition::MarginRatioBelow => {
+            Ok(margin_calculation.get_margin_ratio()? < order.trigger_price.cast()?)
+        }
+        OrderTriggerCondition::TotalCollateralBelow => {
+            Ok(margin_calculation.total_collateral < order.trigger_price.cast()?)
+        }
+        _ => Err(print_error!(ErrorCode::InvalidTriggerOrderCondition)()),
+    }
+}
+
+/// Returns the new trigger price for a trailing stop if the oracle has moved in the order's favor
+pub fn calculate_trailing_stop_trigger_price(
+    order: &Order,
+    oracle_price: u64,
+    tick_size: u64,
+) -> DriftResult<Option<u64>> {
+    let trail = if order.is_bit_flag_set(OrderBitFlag::TrailingStopPercentage) {
+        oracle_price
+            .cast::<u128>()?
+            .safe_mul(order.oracle_price_offset.unsigned_abs().cast()?)?
+            .safe_div(PERCENTAGE_PRECISION_U64.cast()?)?
+            .cast::<u64>()?
+    } else {
+        order.oracle_price_offset.unsigned_abs().cast::<u64>()?
+    };
+
+    let trigger_price = match order.trigger_condition {
+        OrderTriggerCondition::Above => standardize_price(
+            oracle_price.safe_add(trail)?,
+            tick_size,
+            PositionDirection::Short,
+        )?,
+        OrderTriggerCondition::Below => standardize_price(
+            oracle_price.saturating_sub(trail).max(tick_size),
+            tick_size,
+            PositionDirection::Long,
+        )?,
+        _ => return Err(print_error!(ErrorCode::InvalidTriggerOrderCondition)()),
+    };
+
+    let ratchets = match order.trigger_condition {
+        OrderTriggerCondition::Above => {
+            order.trigger_price == 0 || trigger_price < order.trigger_price
+        }
+        _ => trigger_price > order.trigger_price,
+    };
+
+    Ok(if ratchets { Some(trigger_price) } else { None })
+}
+
 pub fn is_new_order_risk_increasing(
     order: &Order,
     position_base_asset_amount: i64,
@@ -771,6 +857,15 @@ pub fn find_maker_orders(
             continue;
         }
 
+        if order.is_pending_activation() {
+            continue;
+        }
+
+        // spread legs only fill together, never as makers
+        if order.is_spread_leg() {
+            continue;
+        }
+
         let limit_price = order.force_get_limit_price(
             valid_oracle_price,
             None,
@@ -1280,6 +1375,15 @@ pub fn find_bids_and_asks_from_users(
                 continue;
             }
 
+            if order.is_pending_activation() {
+                continue;
+            }
+
+            // spread legs only fill together, never as makers
+            if order.is_spread_leg() {
+                continue;
+            }
+
             if !order.is_resting_limit_order(slot)? {
                 continue;
             }
@@ -1289,7 +1393,7 @@ pub fn find_bids_and_asks_from_users(
             }
 
             let existing_position = user.get_perp_position(market_index)?.base_asset_amount;
-            let base_amount = order.get_base_asset_amount_unfilled(Some(existing_position))?;
+            let base_amount = order.get_base_asset_amount_displayed(Some(existing_position))?;
             let limit_price = order.force_get_limit_price(
                 oracle_price,
                 None,
@@ -1401,3 +1505,135 @@ pub fn calculate_existing_position_fields_for_order_action(
         Ok((None, None))
     }
 }
+
+/// Finds the single price that matches the most base between bids and asks.
+/// Ties are broken by the smallest imbalance, then by the price closest to the oracle.
+/// Bids and asks are (limit price, base asset amount). Returns (price, base asset amount matched)
+pub fn calculate_batch_auction_clearing_price(
+    bids: &[(u64, u64)],
+    asks: &[(u64, u64)],
+    oracle_price: u64,
+) -> DriftResult<Option<(u64, u64)>> {
+    let mut best: Option<(u64, u64, u64, u64)> = None;
+
+    for (price, _) in bids.iter().chain(asks.iter()) {
+        let mut demand = 0_u64;
+        for (bid_price, base_asset_amount) in bids.iter() {
+            if bid_price >= price {
+                demand = demand.safe_add(*base_asset_amount)?;
+            }
+        }
+
+        let mut supply = 0_u64;
+        for (ask_price, base_asset_amount) in asks.iter() {
+            if ask_price <= price {
+                supply = supply.safe_add(*base_asset_amount)?;
+            }
+        }
+
+        let matched = demand.min(supply);
+        if matched == 0 {
+            continue;
+        }
+
+        let imbalance = demand.abs_diff(supply);
+        let oracle_distance = price.abs_diff(oracle_price);
+
+        let is_better = match best {
+            None => true,
+            Some((best_price, best_matched, best_imbalance, best_oracle_distance)) => {
+                (matched, best_imbalance, best_oracle_distance, best_price)
+                    > (best_matched, imbalance, oracle_distance, *price)
+            }
+        };
+
+        if is_better {
+            best = Some((*price, matched, imbalance, oracle_distance));
+        }
+    }
+
+    Ok(best.map(|(price, matched, _, _)| (price, matched)))
+}
+
+/// Splits total_fill across sizes in proportion to each size, rounded down to the step size.
+/// What's left from rounding goes to the sizes in the order given
+pub fn calculate_pro_rata_allocations(
+    sizes: &[u64],
+    total_fill: u64,
+    step_size: u64,
+) -> DriftResult<Vec<u64>> {
+    let mut total_size = 0_u64;
+    for size in sizes.iter() {
+        total_size = total_size.safe_add(*size)?;
+    }
+
+    validate!(
+        total_fill <= total_size,
+        ErrorCode::DefaultError,
+        "total fill {} > total size {}",
+        total_fill,
+        total_size
+    )?;
+
+    if total_fill == 0 {
+        return Ok(vec![0; sizes.len()]);
+    }
+
+    let mut allocations = Vec::with_capacity(sizes.len());
+    let mut total_allocated = 0_u64;
+    for size in sizes.iter() {
+        let allocation = size
+            .cast::<u128>()?
+            .safe_mul(total_fill.cast()?)?
+            .safe_div(total_size.cast()?)?
+            .cast::<u64>()?;
+        let allocation = standardize_base_asset_amount(allocation, step_size)?;
+        total_allocated = total_allocated.safe_add(allocation)?;
+        allocations.push(allocation);
+    }
+
+    let mut remainder = total_fill.safe_sub(total_allocated)?;
+    for (allocation, size) in allocations.iter_mut().zip(sizes.iter()) {
+        if remainder == 0 {
+            break;
+        }
+
+        let extra = remainder.min(size.safe_sub(*allocation)?);
+        *allocation = allocation.safe_add(extra)?;
+        remainder = remainder.safe_sub(extra)?;
+    }
+
+    Ok(allocations)
+}
+
+/// Pro-rata split where the first size (the oldest order at the price) is first given
+/// top_of_book_bonus percent of total_fill before the rest is split pro-rata
+pub fn calculate_pro_rata_maker_allocations(
+    sizes: &[u64],
+    total_fill: u64,
+    step_size: u64,
+    top_of_book_bonus: u8,
+) -> DriftResult<Vec<u64>> {
+    if sizes.is_empty() || top_of_book_bonus == 0 {
+        return calculate_pro_rata_allocations(sizes, total_fill, step_size);
+    }
+
+    let bonus = standardize_base_asset_amount(
+        total_fill
+            .cast::<u128>()?
+            .safe_mul(top_of_book_bonus.cast()?)?
+            .safe_div(100)?
+            .cast::<u64>()?,
+        step_size,
+    )?
+    .min(sizes[0]);
+
+    let mut remaining_sizes = sizes.to_vec();
+    remaining_sizes[0] = remaining_sizes[0].safe_sub(bonus)?;
+
+    let mut allocations =
+        calculate_pro_rata_allocations(&remaining_sizes, total_fill.safe_sub(bonus)?, step_size)?;
+    allocations[0] = allocations[0].safe_add(bonus)?;
+
+    Ok(allocations)
+}
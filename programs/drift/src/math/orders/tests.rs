@@ -3951,7 +3951,7 @@ pub mod find_bids_and_asks_from_users {
     use crate::math::orders::{find_bids_and_asks_from_users, Level};
     use crate::state::oracle::OraclePriceData;
     use crate::state::perp_market::PerpMarket;
-    use crate::state::user::{Order, OrderStatus, OrderType, PerpPosition, User};
+    use crate::state::user::{Order, OrderExtBitFlag, OrderStatus, OrderType, PerpPosition, User};
     use crate::state::user_map::UserMap;
     use crate::test_utils::*;
     use crate::test_utils::{create_account_info, get_positions};
@@ -4032,6 +4032,64 @@ pub mod find_bids_and_asks_from_users {
         }
         assert_eq!(asks, expected_asks);
     }
+
+    #[test]
+    fn spread_legs_skipped() {
+        let market = PerpMarket::default_test();
+
+        let oracle_price_data = OraclePriceData {
+            price: 100 * PRICE_PRECISION_I64,
+            ..OraclePriceData::default()
+        };
+
+        let bid = Order {
+            status: OrderStatus::Open,
+            market_index: 0,
+            market_type: MarketType::Perp,
+            order_type: OrderType::Limit,
+            direction: PositionDirection::Long,
+            base_asset_amount: BASE_PRECISION_U64,
+            price: 95 * PRICE_PRECISION_U64,
+            post_only: true,
+            ..Order::default()
+        };
+
+        let mut maker_orders = [Order::default(); 32];
+        maker_orders[0] = bid;
+        maker_orders[1] = Order {
+            price: 99 * PRICE_PRECISION_U64,
+            ..bid
+        };
+        maker_orders[1].add_ext_bit_flag(OrderExtBitFlag::SpreadLeg);
+
+        let mut maker = User {
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 2,
+                ..PerpPosition::default()
+            }),
+            orders: maker_orders,
+            ..User::default()
+        };
+        let maker_key = Pubkey::default();
+        create_anchor_account_info!(maker, &maker_key, User, maker_account_info);
+
+        let makers_and_referrers = UserMap::load_one(&maker_account_info).unwrap();
+
+        let (bids, asks) =
+            find_bids_and_asks_from_users(&market, &oracle_price_data, &makers_and_referrers, 0, 0)
+                .unwrap();
+
+        // the spread leg only fills with the rest of its spread so it isn't quoted
+        assert_eq!(
+            bids,
+            vec![Level {
+                price: 95 * PRICE_PRECISION_U64,
+                base_asset_amount: BASE_PRECISION_U64,
+            }]
+        );
+        assert!(asks.is_empty());
+    }
 }
 
 pub mod calculate_limit_price_with_buffer {
@@ -4482,3 +4540,424 @@ mod order_bit_flags {
         assert_eq!(flags, 8);
     }
 }
+
+mod calculate_trailing_stop_trigger_price {
+    use crate::controller::position::PositionDirection;
+    use crate::math::constants::{
+        PERCENTAGE_PRECISION_U64, PRICE_PRECISION_I64, PRICE_PRECISION_U64,
+    };
+    use crate::math::orders::calculate_trailing_stop_trigger_price;
+    use crate::state::user::{Order, OrderBitFlag, OrderTriggerCondition, OrderType};
+
+    #[test]
+    fn fixed_trail_below() {
+        let mut order = Order {
+            order_type: OrderType::TriggerMarket,
+            direction: PositionDirection::Short,
+            trigger_condition: OrderTriggerCondition::Below,
+            trigger_price: 95 * PRICE_PRECISION_U64,
+            oracle_price_offset: 5 * PRICE_PRECISION_I64 as i32,
+            ..Order::default()
+        };
+        order.add_bit_flag(OrderBitFlag::TrailingStop);
+
+        // oracle rises, stop ratchets up
+        let trigger_price =
+            calculate_trailing_stop_trigger_price(&order, 110 * PRICE_PRECISION_U64, 1).unwrap();
+        assert_eq!(trigger_price, Some(105 * PRICE_PRECISION_U64));
+
+        // oracle falls, stop stays put
+        let trigger_price =
+            calculate_trailing_stop_trigger_price(&order, 98 * PRICE_PRECISION_U64, 1).unwrap();
+        assert_eq!(trigger_price, None);
+    }
+
+    #[test]
+    fn fixed_trail_above() {
+        let mut order = Order {
+            order_type: OrderType::TriggerMarket,
+            direction: PositionDirection::Long,
+            trigger_condition: OrderTriggerCondition::Above,
+            trigger_price: 105 * PRICE_PRECISION_U64,
+            oracle_price_offset: 5 * PRICE_PRECISION_I64 as i32,
+            ..Order::default()
+        };
+        order.add_bit_flag(OrderBitFlag::TrailingStop);
+
+        // oracle falls, stop ratchets down
+        let trigger_price =
+            calculate_trailing_stop_trigger_price(&order, 90 * PRICE_PRECISION_U64, 1).unwrap();
+        assert_eq!(trigger_price, Some(95 * PRICE_PRECISION_U64));
+
+        // oracle rises, stop stays put
+        let trigger_price =
+            calculate_trailing_stop_trigger_price(&order, 102 * PRICE_PRECISION_U64, 1).unwrap();
+        assert_eq!(trigger_price, None);
+    }
+
+    #[test]
+    fn percentage_trail() {
+        let mut order = Order {
+            order_type: OrderType::TriggerMarket,
+            direction: PositionDirection::Short,
+            trigger_condition: OrderTriggerCondition::Below,
+            trigger_price: 90 * PRICE_PRECISION_U64,
+            oracle_price_offset: (PERCENTAGE_PRECISION_U64 / 10) as i32, // 10%
+            ..Order::default()
+        };
+        order.add_bit_flag(OrderBitFlag::TrailingStop);
+        order.add_bit_flag(OrderBitFlag::TrailingStopPercentage);
+
+        let trigger_price =
+            calculate_trailing_stop_trigger_price(&order, 200 * PRICE_PRECISION_U64, 1).unwrap();
+        assert_eq!(trigger_price, Some(180 * PRICE_PRECISION_U64));
+    }
+
+    #[test]
+    fn rounds_to_tick_size() {
+        let mut order = Order {
+            order_type: OrderType::TriggerMarket,
+            direction: PositionDirection::Short,
+            trigger_condition: OrderTriggerCondition::Below,
+            trigger_price: 1,
+            oracle_price_offset: 1_500,
+            ..Order::default()
+        };
+        order.add_bit_flag(OrderBitFlag::TrailingStop);
+
+        let trigger_price = calculate_trailing_stop_trigger_price(&order, 10_000, 1_000).unwrap();
+        assert_eq!(trigger_price, Some(8_000));
+    }
+}
+
+mod order_satisfies_account_trigger_condition {
+    use crate::error::ErrorCode;
+    use crate::math::margin::MarginRequirementType;
+    use crate::math::orders::order_satisfies_account_trigger_condition;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext};
+    use crate::state::user::{Order, OrderTriggerCondition, OrderType};
+    use crate::{MARGIN_PRECISION_U128, QUOTE_PRECISION_I128, QUOTE_PRECISION_U64};
+
+    fn get_margin_calculation(
+        total_collateral: i128,
+        margin_requirement: u128,
+    ) -> MarginCalculation {
+        let mut margin_calculation =
+            MarginCalculation::new(MarginContext::standard(MarginRequirementType::Maintenance));
+        margin_calculation.total_collateral = total_collateral;
+        margin_calculation.margin_requirement = margin_requirement;
+        margin_calculation
+    }
+
+    #[test]
+    fn margin_ratio_below() {
+        // trigger when collateral is less than 1.5x the maintenance requirement
+        let order = Order {
+            order_type: OrderType::TriggerMarket,
+            trigger_condition: OrderTriggerCondition::MarginRatioBelow,
+            trigger_price: 15000,
+            ..Order::default()
+        };
+
+        let margin_calculation = get_margin_calculation(
+            200 * QUOTE_PRECISION_I128,
+            100 * QUOTE_PRECISION_I128 as u128,
+        );
+        assert_eq!(
+            margin_calculation.get_margin_ratio().unwrap(),
+            2 * MARGIN_PRECISION_U128
+        );
+        assert!(!order_satisfies_account_trigger_condition(&order, &margin_calculation).unwrap());
+
+        let margin_calculation = get_margin_calculation(
+            149 * QUOTE_PRECISION_I128,
+            100 * QUOTE_PRECISION_I128 as u128,
+        );
+        assert!(order_satisfies_account_trigger_condition(&order, &margin_calculation).unwrap());
+
+        // no margin requirement never triggers
+        let margin_calculation = get_margin_calculation(-QUOTE_PRECISION_I128, 0);
+        assert!(!order_satisfies_account_trigger_condition(&order, &margin_calculation).unwrap());
+    }
+
+    #[test]
+    fn total_collateral_below() {
+        let order = Order {
+            order_type: OrderType::TriggerMarket,
+            trigger_condition: OrderTriggerCondition::TotalCollateralBelow,
+            trigger_price: 1000 * QUOTE_PRECISION_U64,
+            ..Order::default()
+        };
+
+        let margin_calculation = get_margin_calculation(1000 * QUOTE_PRECISION_I128, 0);
+        assert!(!order_satisfies_account_trigger_condition(&order, &margin_calculation).unwrap());
+
+        let margin_calculation = get_margin_calculation(-QUOTE_PRECISION_I128, 0);
+        assert!(order_satisfies_account_trigger_condition(&order, &margin_calculation).unwrap());
+    }
+
+    #[test]
+    fn oracle_trigger_condition() {
+        let order = Order {
+            order_type: OrderType::TriggerMarket,
+            trigger_condition: OrderTriggerCondition::Below,
+            ..Order::default()
+        };
+
+        let margin_calculation = get_margin_calculation(0, 0);
+        assert_eq!(
+            order_satisfies_account_trigger_condition(&order, &margin_calculation),
+            Err(ErrorCode::InvalidTriggerOrderCondition)
+        );
+    }
+}
+
+mod is_fill_below_min_fill_size {
+    use crate::math::orders::is_fill_below_min_fill_size;
+    use crate::state::user::{
+        Order, OrderExtBitFlag, OrderStatus, OrderTriggerCondition, OrderType,
+    };
+    use crate::BASE_PRECISION_U64;
+
+    #[test]
+    fn limit_order() {
+        let mut order = Order {
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            base_asset_amount: 10 * BASE_PRECISION_U64,
+            trigger_price: 2 * BASE_PRECISION_U64,
+            oco_group_id_and_bit_flags: OrderExtBitFlag::MinFillSize as u8,
+            ..Order::default()
+        };
+
+        assert!(is_fill_below_min_fill_size(&order, BASE_PRECISION_U64, None).unwrap());
+        assert!(!is_fill_below_min_fill_size(&order, 2 * BASE_PRECISION_U64, None).unwrap());
+
+        // remainder below min fill size can still fill
+        order.base_asset_amount_filled = 9 * BASE_PRECISION_U64;
+        assert!(!is_fill_below_min_fill_size(&order, BASE_PRECISION_U64, None).unwrap());
+    }
+
+    #[test]
+    fn no_min_fill_size() {
+        let order = Order {
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            base_asset_amount: 10 * BASE_PRECISION_U64,
+            ..Order::default()
+        };
+
+        assert!(!is_fill_below_min_fill_size(&order, 1, None).unwrap());
+
+        // a trigger price alone doesn't mark an order as having a min fill size
+        let order = Order {
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            base_asset_amount: 10 * BASE_PRECISION_U64,
+            trigger_price: 2 * BASE_PRECISION_U64,
+            ..Order::default()
+        };
+
+        assert!(!is_fill_below_min_fill_size(&order, 1, None).unwrap());
+    }
+
+    #[test]
+    fn trigger_price_is_not_min_fill_size() {
+        let trigger_order = Order {
+            status: OrderStatus::Open,
+            order_type: OrderType::TriggerMarket,
+            trigger_condition: OrderTriggerCondition::TriggeredAbove,
+            base_asset_amount: 10 * BASE_PRECISION_U64,
+            trigger_price: 2 * BASE_PRECISION_U64,
+            ..Order::default()
+        };
+
+        assert!(!is_fill_below_min_fill_size(&trigger_order, 1, None).unwrap());
+
+        let pending_order = Order {
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            trigger_condition: OrderTriggerCondition::ActivateAfterTs,
+            base_asset_amount: 10 * BASE_PRECISION_U64,
+            trigger_price: 2 * BASE_PRECISION_U64,
+            ..Order::default()
+        };
+
+        assert!(!is_fill_below_min_fill_size(&pending_order, 1, None).unwrap());
+    }
+}
+
+mod calculate_batch_auction_clearing_price {
+    use crate::math::constants::{BASE_PRECISION_U64, PRICE_PRECISION_U64};
+    use crate::math::orders::calculate_batch_auction_clearing_price;
+
+    #[test]
+    fn no_cross() {
+        let bids = [(99 * PRICE_PRECISION_U64, BASE_PRECISION_U64)];
+        let asks = [(100 * PRICE_PRECISION_U64, BASE_PRECISION_U64)];
+
+        let result =
+            calculate_batch_auction_clearing_price(&bids, &asks, 100 * PRICE_PRECISION_U64)
+                .unwrap();
+        assert_eq!(result, None);
+
+        let result =
+            calculate_batch_auction_clearing_price(&bids, &[], 100 * PRICE_PRECISION_U64).unwrap();
+        assert_eq!(result, None);
+    }
+
+    #[test]
+    fn maximizes_volume() {
+        let bids = [
+            (102 * PRICE_PRECISION_U64, BASE_PRECISION_U64),
+            (101 * PRICE_PRECISION_U64, BASE_PRECISION_U64),
+            (100 * PRICE_PRECISION_U64, BASE_PRECISION_U64),
+        ];
+        let asks = [
+            (99 * PRICE_PRECISION_U64, BASE_PRECISION_U64),
+            (100 * PRICE_PRECISION_U64, BASE_PRECISION_U64),
+            (103 * PRICE_PRECISION_U64, BASE_PRECISION_U64),
+        ];
+
+        // 101 and 100 both match 2, but at 100 there are 3 bids for 2 asks
+        let result =
+            calculate_batch_auction_clearing_price(&bids, &asks, 100 * PRICE_PRECISION_U64)
+                .unwrap();
+        assert_eq!(
+            result,
+            Some((101 * PRICE_PRECISION_U64, 2 * BASE_PRECISION_U64))
+        );
+    }
+
+    #[test]
+    fn tie_breaks_on_imbalance_then_oracle() {
+        let bids = [(102 * PRICE_PRECISION_U64, BASE_PRECISION_U64)];
+        let asks = [(98 * PRICE_PRECISION_U64, BASE_PRECISION_U64)];
+
+        // both candidate prices match the same size with no imbalance
+        let result =
+            calculate_batch_auction_clearing_price(&bids, &asks, 101 * PRICE_PRECISION_U64)
+                .unwrap();
+        assert_eq!(
+            result,
+            Some((102 * PRICE_PRECISION_U64, BASE_PRECISION_U64))
+        );
+
+        let result =
+            calculate_batch_auction_clearing_price(&bids, &asks, 99 * PRICE_PRECISION_U64).unwrap();
+        assert_eq!(result, Some((98 * PRICE_PRECISION_U64, BASE_PRECISION_U64)));
+
+        let bids = [(102 * PRICE_PRECISION_U64, 2 * BASE_PRECISION_U64)];
+        let asks = [
+            (98 * PRICE_PRECISION_U64, BASE_PRECISION_U64),
+            (102 * PRICE_PRECISION_U64, BASE_PRECISION_U64),
+        ];
+
+        // 102 matches 2 with no imbalance, 98 matches 1
+        let result =
+            calculate_batch_auction_clearing_price(&bids, &asks, 98 * PRICE_PRECISION_U64).unwrap();
+        assert_eq!(
+            result,
+            Some((102 * PRICE_PRECISION_U64, 2 * BASE_PRECISION_U64))
+        );
+    }
+}
+
+mod calculate_pro_rata_allocations {
+    use crate::math::constants::BASE_PRECISION_U64;
+    use crate::math::orders::calculate_pro_rata_allocations;
+
+    #[test]
+    fn proportional() {
+        let step_size = BASE_PRECISION_U64 / 10;
+        let sizes = [3 * BASE_PRECISION_U64, BASE_PRECISION_U64];
+
+        let allocations =
+            calculate_pro_rata_allocations(&sizes, 2 * BASE_PRECISION_U64, step_size).unwrap();
+        assert_eq!(
+            allocations,
+            vec![3 * BASE_PRECISION_U64 / 2, BASE_PRECISION_U64 / 2]
+        );
+
+        let allocations =
+            calculate_pro_rata_allocations(&sizes, 4 * BASE_PRECISION_U64, step_size).unwrap();
+        assert_eq!(allocations, sizes.to_vec());
+
+        let allocations = calculate_pro_rata_allocations(&sizes, 0, step_size).unwrap();
+        assert_eq!(allocations, vec![0, 0]);
+    }
+
+    #[test]
+    fn rounding_remainder_goes_to_first() {
+        let step_size = BASE_PRECISION_U64 / 10;
+        let sizes = [BASE_PRECISION_U64; 3];
+
+        // 1/3 of 1 rounds down to 0.3, remaining 0.1 goes to the first size
+        let allocations =
+            calculate_pro_rata_allocations(&sizes, BASE_PRECISION_U64, step_size).unwrap();
+        assert_eq!(
+            allocations,
+            vec![
+                4 * BASE_PRECISION_U64 / 10,
+                3 * BASE_PRECISION_U64 / 10,
+                3 * BASE_PRECISION_U64 / 10
+            ]
+        );
+        assert_eq!(allocations.iter().sum::<u64>(), BASE_PRECISION_U64);
+    }
+
+    #[test]
+    fn fill_larger_than_sizes() {
+        let sizes = [BASE_PRECISION_U64];
+        assert!(calculate_pro_rata_allocations(&sizes, 2 * BASE_PRECISION_U64, 1).is_err());
+    }
+}
+
+mod calculate_pro_rata_maker_allocations {
+    use crate::math::constants::BASE_PRECISION_U64;
+    use crate::math::orders::calculate_pro_rata_maker_allocations;
+
+    #[test]
+    fn no_bonus() {
+        let step_size = BASE_PRECISION_U64 / 10;
+        let sizes = [3 * BASE_PRECISION_U64, BASE_PRECISION_U64];
+
+        let allocations =
+            calculate_pro_rata_maker_allocations(&sizes, 2 * BASE_PRECISION_U64, step_size, 0)
+                .unwrap();
+        assert_eq!(
+            allocations,
+            vec![3 * BASE_PRECISION_U64 / 2, BASE_PRECISION_U64 / 2]
+        );
+    }
+
+    #[test]
+    fn top_of_book_bonus() {
+        let step_size = BASE_PRECISION_U64 / 10;
+        let sizes = [BASE_PRECISION_U64, BASE_PRECISION_U64];
+
+        // first gets 0.5 bonus, remaining 0.5 split across 0.5 and 1
+        let allocations =
+            calculate_pro_rata_maker_allocations(&sizes, BASE_PRECISION_U64, step_size, 50)
+                .unwrap();
+        assert_eq!(
+            allocations,
+            vec![7 * BASE_PRECISION_U64 / 10, 3 * BASE_PRECISION_U64 / 10]
+        );
+        assert_eq!(allocations.iter().sum::<u64>(), BASE_PRECISION_U64);
+    }
+
+    #[test]
+    fn bonus_capped_at_size() {
+        let step_size = BASE_PRECISION_U64 / 10;
+        let sizes = [2 * BASE_PRECISION_U64 / 10, 2 * BASE_PRECISION_U64];
+
+        let allocations =
+            calculate_pro_rata_maker_allocations(&sizes, BASE_PRECISION_U64, step_size, 50)
+                .unwrap();
+        assert_eq!(
+            allocations,
+            vec![2 * BASE_PRECISION_U64 / 10, 8 * BASE_PRECISION_U64 / 10]
+        );
+    }
+}
@@ -37,6 +37,27 @@ pub fn calculate_base_asset_value(base_asset_amount: i128, amm: &AMM) -> DriftRe
 
     let swap_direction = swap_direction_to_close_position(base_asset_amount);
 
+    if amm.has_concentrated_band() {
+        let segments = amm::calculate_base_reserve_segments(
+            amm,
+            base_asset_amount.unsigned_abs(),
+            swap_direction,
+        )?;
+
+        let (_, _, quote_asset_reserve_amount) = amm::calculate_swap_output_for_segments(
+            &segments,
+            amm.base_asset_reserve,
+            swap_direction,
+            amm.sqrt_k,
+        )?;
+
+        return amm::calculate_quote_asset_amount_for_reserve_change(
+            quote_asset_reserve_amount,
+            swap_direction,
+            amm.peg_multiplier,
+        );
+    }
+
     let (base_asset_reserve, quote_asset_reserve) =
         (amm.base_asset_reserve, amm.quote_asset_reserve);
 
@@ -202,9 +202,7 @@ pub fn adjust_peg_cost(
 }
 
 pub fn calculate_repeg_cost(amm: &AMM, new_peg: u128) -> DriftResult<i128> {
-    amm.quote_asset_reserve
-        .cast::<i128>()?
-        .safe_sub(amm.terminal_quote_asset_reserve.cast()?)?
+    amm::calculate_net_quote_asset_reserve_amount(amm)?
         .safe_mul(
             new_peg
                 .cast::<i128>()?
@@ -213,17 +211,14 @@ pub fn calculate_repeg_cost(amm: &AMM, new_peg: u128) -> DriftResult<i128> {
         .safe_div(AMM_RESERVE_PRECISION_I128)
 }
 
-pub fn calculate_per_peg_cost(
-    quote_asset_reserve: u128,
-    terminal_quote_asset_reserve: u128,
-) -> DriftResult<i128> {
+pub fn calculate_per_peg_cost(amm: &AMM) -> DriftResult<i128> {
     // returns a signed per_peg_cost relative to delta peg
     // signed means that "cost" to amm is influenced whether delta_peg is the same sign
 
-    let per_peg_cost = if quote_asset_reserve != terminal_quote_asset_reserve {
-        quote_asset_reserve
-            .cast::<i128>()?
-            .safe_sub(terminal_quote_asset_reserve.cast::<i128>()?)?
+    let net_quote_asset_reserve_amount = amm::calculate_net_quote_asset_reserve_amount(amm)?;
+
+    let per_peg_cost = if net_quote_asset_reserve_amount != 0 {
+        net_quote_asset_reserve_amount
             .safe_div_ceil(AMM_RESERVE_PRECISION_I128 / PEG_PRECISION_I128)?
     } else {
         0
@@ -256,10 +251,7 @@ pub fn adjust_amm(
         .cast::<i128>()?
         .safe_sub(market.amm.peg_multiplier.cast()?)?; // PEG_PRECISION
 
-    let mut per_peg_cost = calculate_per_peg_cost(
-        market.amm.quote_asset_reserve,
-        market.amm.terminal_quote_asset_reserve,
-    )?; // PEG_PRECISION
+    let mut per_peg_cost = calculate_per_peg_cost(&market.amm)?; // PEG_PRECISION
 
     let budget_i128 = budget.cast::<i128>()?;
 
@@ -307,10 +299,7 @@ pub fn adjust_amm(
 
             let adjustment_cost =
                 cp_curve::adjust_k_cost_and_update(&mut market_clone, &update_k_result)?;
-            per_peg_cost = calculate_per_peg_cost(
-                market_clone.amm.quote_asset_reserve,
-                market_clone.amm.terminal_quote_asset_reserve,
-            )?;
+            per_peg_cost = calculate_per_peg_cost(&market_clone.amm)?;
 
             adjustment_cost
         } else {
@@ -403,3 +403,42 @@ fn calc_adjust_amm_tests_sufficent_fee_for_repeg() {
     assert_eq!(new_peg, 34657283);
     assert_eq!(_amm_update_cost, 304289);
 }
+
+#[test]
+fn calculate_repeg_cost_concentrated_band_test() {
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 65 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 63015384615,
+            sqrt_k: 64 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 19_400_000_000,
+            base_asset_amount_with_amm: -(AMM_RESERVE_PRECISION as i128),
+            concentrated_band_width: 20, // 2%
+            concentrated_liquidity_multiplier: 4,
+            ..AMM::default()
+        },
+        ..PerpMarket::default()
+    };
+    let (terminal_quote_asset_reserve, _) = amm::calculate_terminal_reserves(&market.amm).unwrap();
+    market.amm.terminal_quote_asset_reserve = terminal_quote_asset_reserve;
+
+    let new_peg = 18_400_000_000;
+
+    // priced like the change in the net market value on the concentrated curve
+    let cost = calculate_repeg_cost(&market.amm, new_peg).unwrap();
+    let (_, net_market_value_cost) = adjust_peg_cost(&market, new_peg).unwrap();
+    assert!(cost > 0);
+    assert!((cost - net_market_value_cost).abs() <= 2);
+
+    let per_peg_cost = calculate_per_peg_cost(&market.amm).unwrap();
+    assert!(per_peg_cost < 0);
+
+    // the band is deeper than the plain curve, so closing the net position slips less
+    let mut plain_market = market;
+    plain_market.amm.concentrated_liquidity_multiplier = 0;
+    let (terminal_quote_asset_reserve, _) =
+        amm::calculate_terminal_reserves(&plain_market.amm).unwrap();
+    plain_market.amm.terminal_quote_asset_reserve = terminal_quote_asset_reserve;
+    let plain_cost = calculate_repeg_cost(&plain_market.amm, new_peg).unwrap();
+    assert!(cost < plain_cost);
+}
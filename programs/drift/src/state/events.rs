@@ -164,6 +164,19 @@ pub struct CurveRecord {
     pub fill_record: u128,
     pub number_of_users: u32,
     pub market_index: u16,
+    pub reason: CurveUpdateReason,
+}
+
+#[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Eq, Debug, Default)]
+pub enum CurveUpdateReason {
+    #[default]
+    AdminRepeg,
+    AdminUpdateK,
+    FormulaicUpdateK,
+    /// scheduled crank moved the peg to the optimal peg
+    ScheduledRepeg,
+    /// scheduled crank moved the peg part way to the optimal peg, limited by the epoch budget
+    ScheduledRepegBudgetLimited,
 }
 
 #[event]
@@ -361,6 +374,7 @@ pub enum OrderAction {
     Fill,
     Trigger,
     Expire,
+    Amend,
 }
 
 #[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Eq)]
@@ -386,6 +400,12 @@ pub enum OrderActionExplanation {
     DeriskLp,
     OrderFilledWithOpenbookV2,
     TransferPerpPosition,
+    OcoOrderFilledOrTriggered,
+    BracketEntryCanceled,
+    SelfTradePrevention,
+    HeartbeatExpired,
+    OrderActivated,
+    SpreadLegCanceled,
 }
 
 #[event]
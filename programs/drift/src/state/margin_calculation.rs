@@ -409,6 +409,19 @@ impl MarginCalculation {
             .safe_div(self.margin_requirement)
     }
 
+    /// Total collateral relative to the margin requirement. precision: MARGIN_PRECISION
+    pub fn get_margin_ratio(&self) -> DriftResult<u128> {
+        if self.margin_requirement == 0 {
+            return Ok(u128::MAX);
+        }
+
+        self.total_collateral
+            .max(0)
+            .cast::<u128>()?
+            .safe_mul(MARGIN_PRECISION_U128)?
+            .safe_div(self.margin_requirement)
+    }
+
     pub fn get_free_collateral(&self) -> DriftResult<u128> {
         self.total_collateral
             .safe_sub(self.margin_requirement.cast::<i128>()?)?
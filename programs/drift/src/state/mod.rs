@@ -11,8 +11,11 @@ pub mod oracle;
 pub mod oracle_map;
 pub mod order_params;
 pub mod paused_operations;
+pub mod perp_circuit_breaker;
+pub mod perp_curve_crank;
 pub mod perp_market;
 pub mod perp_market_map;
+pub mod perp_order_book;
 pub mod protected_maker_mode_config;
 pub mod pyth_lazer_oracle;
 pub mod revenue_share;
@@ -27,3 +30,4 @@ pub mod state;
 pub mod traits;
 pub mod user;
 pub mod user_map;
+pub mod user_orders_extension;
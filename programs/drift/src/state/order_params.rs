@@ -1,11 +1,16 @@
 use crate::controller::position::PositionDirection;
-use crate::error::DriftResult;
+use crate::error::{DriftResult, ErrorCode};
 use crate::math::casting::Cast;
+use crate::math::constants::MAX_OPEN_ORDERS;
+use crate::math::orders::{
+    is_multiple_of_step_size, standardize_base_asset_amount, standardize_price,
+};
 use crate::math::safe_math::SafeMath;
 use crate::math::safe_unwrap::SafeUnwrap;
 use crate::state::events::OrderActionExplanation;
 use crate::state::perp_market::{ContractTier, PerpMarket};
-use crate::state::user::{MarketType, OrderTriggerCondition, OrderType};
+use crate::state::user::{MarketType, Order, OrderTriggerCondition, OrderType};
+use crate::validate;
 use crate::{
     MAX_PREDICTION_MARKET_PRICE_I64, ONE_HUNDRED_THOUSAND_QUOTE, PERCENTAGE_PRECISION_I64,
     PERCENTAGE_PRECISION_U64, PRICE_PRECISION_I64,
@@ -42,6 +47,12 @@ pub struct OrderParams {
 pub enum OrderParamsBitFlag {
     ImmediateOrCancel = 0b00000001,
     UpdateHighLeverageMode = 0b00000010,
+    TrailingStop = 0b00000100,
+    TrailingStopPercentage = 0b00001000,
+    OneCancelsOther = 0b00010000,
+    Iceberg = 0b00100000,
+    MinFillSize = 0b01000000,
+    ClosePosition = 0b10000000,
 }
 
 impl OrderParams {
@@ -855,6 +866,33 @@ impl OrderParams {
         self.bit_flags & OrderParamsBitFlag::UpdateHighLeverageMode as u8 != 0
     }
 
+    pub fn is_trailing_stop(&self) -> bool {
+        self.bit_flags & OrderParamsBitFlag::TrailingStop as u8 != 0
+    }
+
+    pub fn is_trailing_stop_percentage(&self) -> bool {
+        self.bit_flags & OrderParamsBitFlag::TrailingStopPercentage as u8 != 0
+    }
+
+    pub fn is_one_cancels_other(&self) -> bool {
+        self.bit_flags & OrderParamsBitFlag::OneCancelsOther as u8 != 0
+    }
+
+    /// Iceberg orders carry their display size in auction_start_price
+    pub fn is_iceberg(&self) -> bool {
+        self.bit_flags & OrderParamsBitFlag::Iceberg as u8 != 0
+    }
+
+    /// Min fill size orders carry their min fill size in trigger_price
+    pub fn has_min_fill_size(&self) -> bool {
+        self.bit_flags & OrderParamsBitFlag::MinFillSize as u8 != 0
+    }
+
+    /// Close position orders ignore base_asset_amount and close the whole position at fill time
+    pub fn is_close_position(&self) -> bool {
+        self.bit_flags & OrderParamsBitFlag::ClosePosition as u8 != 0
+    }
+
     pub fn is_max_leverage_order(&self) -> bool {
         self.base_asset_amount == u64::MAX
     }
@@ -862,6 +900,13 @@ impl OrderParams {
     pub fn is_trigger_order(&self) -> bool {
         self.order_type == OrderType::TriggerMarket || self.order_type == OrderType::TriggerLimit
     }
+
+    pub fn is_pending_activation(&self) -> bool {
+        matches!(
+            self.trigger_condition,
+            OrderTriggerCondition::ActivateAfterTs | OrderTriggerCondition::ActivateAfterSlot
+        )
+    }
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Eq, PartialEq, Debug)]
@@ -890,12 +935,206 @@ pub struct SignedMsgOrderParamsDelegateMessage {
     pub builder_fee_tenth_bps: Option<u16>,
 }
 
+/// A firm quote signed off-chain by a maker's authority that a taker can fill in place_and_take
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Eq, PartialEq, Debug)]
+pub struct SignedMsgQuoteMessage {
+    pub sub_account_id: u16,
+    pub market_index: u16,
+    /// The maker's side of the quote
+    pub direction: PositionDirection,
+    /// precision: PRICE_PRECISION
+    pub price: u64,
+    /// precision: BASE_PRECISION
+    pub base_asset_amount: u64,
+    /// The last slot the quote can be filled in
+    pub max_slot: u64,
+    /// Must be greater than the last quote nonce used by the maker's authority
+    pub nonce: u64,
+}
+
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Eq, PartialEq, Debug)]
 pub struct SignedMsgTriggerOrderParams {
     pub trigger_price: u64,
     pub base_asset_amount: u64,
 }
 
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, Eq, PartialEq, Debug)]
+pub struct BracketOrderParams {
+    pub take_profit_trigger_price: Option<u64>,
+    pub stop_loss_trigger_price: Option<u64>,
+}
+
+impl BracketOrderParams {
+    pub fn is_empty(&self) -> bool {
+        self.take_profit_trigger_price.is_none() && self.stop_loss_trigger_price.is_none()
+    }
+
+    pub fn num_legs(&self) -> usize {
+        self.take_profit_trigger_price.is_some() as usize
+            + self.stop_loss_trigger_price.is_some() as usize
+    }
+
+    /// Reduce only trigger orders protecting the entry. They are sized to the entry's
+    /// base amount so they pass validation and then emptied until the entry fills
+    pub fn get_order_params(&self, entry_params: &OrderParams) -> Vec<OrderParams> {
+        let (take_profit_trigger_condition, stop_loss_trigger_condition) =
+            if entry_params.direction == PositionDirection::Long {
+                (OrderTriggerCondition::Above, OrderTriggerCondition::Below)
+            } else {
+                (OrderTriggerCondition::Below, OrderTriggerCondition::Above)
+            };
+
+        [
+            (self.stop_loss_trigger_price, stop_loss_trigger_condition),
+            (
+                self.take_profit_trigger_price,
+                take_profit_trigger_condition,
+            ),
+        ]
+        .iter()
+        .filter_map(|(trigger_price, trigger_condition)| {
+            trigger_price.map(|trigger_price| OrderParams {
+                order_type: OrderType::TriggerMarket,
+                direction: entry_params.direction.opposite(),
+                trigger_price: Some(trigger_price),
+                base_asset_amount: entry_params.base_asset_amount,
+                trigger_condition: *trigger_condition,
+                market_index: entry_params.market_index,
+                market_type: MarketType::Perp,
+                reduce_only: true,
+                ..OrderParams::default()
+            })
+        })
+        .collect()
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, Eq, PartialEq, Debug)]
+pub enum SizeDistribution {
+    /// Every order has the same size
+    #[default]
+    Flat,
+    /// Sizes grow linearly from the order at start_price to the order at end_price
+    Linear,
+    /// Each order is double the size of the one before it
+    Exponential,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, Eq, PartialEq, Debug)]
+pub struct ScaleOrderParams {
+    pub market_type: MarketType,
+    pub direction: PositionDirection,
+    pub market_index: u16,
+    /// precision: BASE_PRECISION for perps, token mint precision for spot
+    pub total_base_asset_amount: u64,
+    /// precision: PRICE_PRECISION
+    pub start_price: u64,
+    /// precision: PRICE_PRECISION
+    pub end_price: u64,
+    pub num_orders: u8,
+    pub size_distribution: SizeDistribution,
+    pub reduce_only: bool,
+    pub post_only: PostOnlyParam,
+    pub max_ts: Option<i64>,
+}
+
+impl ScaleOrderParams {
+    /// Limit orders spaced evenly from start_price to end_price. Prices are rounded to the tick size
+    /// away from the market and sizes down to the step size, with the leftover going to the last order.
+    /// The total size must be a step size multiple so none of it is dropped
+    pub fn get_order_params(
+        &self,
+        tick_size: u64,
+        step_size: u64,
+    ) -> DriftResult<Vec<OrderParams>> {
+        validate!(
+            self.num_orders >= 2 && self.num_orders <= MAX_OPEN_ORDERS,
+            ErrorCode::InvalidOrder,
+            "num_orders must be between 2 and {}",
+            MAX_OPEN_ORDERS
+        )?;
+
+        validate!(
+            self.start_price > 0 && self.end_price > 0,
+            ErrorCode::InvalidOrder,
+            "start_price and end_price must be greater than 0"
+        )?;
+
+        validate!(
+            is_multiple_of_step_size(self.total_base_asset_amount, step_size)?,
+            ErrorCode::InvalidOrderNotStepSizeMultiple,
+            "total_base_asset_amount ({}) must be a multiple of the step size ({})",
+            self.total_base_asset_amount,
+            step_size
+        )?;
+
+        let num_orders = self.num_orders as u64;
+        let size_weights: Vec<u64> = (0..num_orders)
+            .map(|i| match self.size_distribution {
+                SizeDistribution::Flat => 1,
+                SizeDistribution::Linear => i + 1,
+                SizeDistribution::Exponential => 1 << i,
+            })
+            .collect();
+        let total_size_weight = size_weights.iter().sum::<u64>().cast::<u128>()?;
+
+        let price_range = self
+            .end_price
+            .cast::<i128>()?
+            .safe_sub(self.start_price.cast()?)?;
+
+        let mut base_asset_amount_remaining = self.total_base_asset_amount;
+        let mut order_params = Vec::with_capacity(size_weights.len());
+        for (i, size_weight) in size_weights.iter().enumerate() {
+            let base_asset_amount = if i == size_weights.len() - 1 {
+                base_asset_amount_remaining
+            } else {
+                self.total_base_asset_amount
+                    .cast::<u128>()?
+                    .safe_mul(size_weight.cast()?)?
+                    .safe_div(total_size_weight)?
+                    .cast::<u64>()?
+            };
+            let base_asset_amount = standardize_base_asset_amount(base_asset_amount, step_size)?;
+
+            validate!(
+                base_asset_amount > 0,
+                ErrorCode::InvalidOrderSizeTooSmall,
+                "scale order {} rounds to zero size",
+                i
+            )?;
+
+            base_asset_amount_remaining =
+                base_asset_amount_remaining.safe_sub(base_asset_amount)?;
+
+            let price = self
+                .start_price
+                .cast::<i128>()?
+                .safe_add(
+                    price_range
+                        .safe_mul(i.cast()?)?
+                        .safe_div((num_orders - 1).cast()?)?,
+                )?
+                .cast::<u64>()?;
+
+            order_params.push(OrderParams {
+                order_type: OrderType::Limit,
+                market_type: self.market_type,
+                direction: self.direction,
+                base_asset_amount,
+                price: standardize_price(price, tick_size, self.direction)?,
+                market_index: self.market_index,
+                reduce_only: self.reduce_only,
+                post_only: self.post_only,
+                max_ts: self.max_ts,
+                ..OrderParams::default()
+            });
+        }
+
+        Ok(order_params)
+    }
+}
+
 fn get_auction_duration(
     price_diff: u64,
     price: u64,
@@ -950,6 +1189,30 @@ impl ModifyOrderParams {
     pub fn exclude_previous_fill(&self) -> bool {
         self.policy.unwrap_or(0) & ModifyOrderPolicy::ExcludePreviousFill as u8 != 0
     }
+
+    /// Whether the modification only touches fields that can be amended in place without
+    /// resetting the order's slot: size, max_ts and reduce_only
+    pub fn only_amends(&self, order: &Order) -> bool {
+        self.direction
+            .map_or(true, |direction| direction == order.direction)
+            && self.price.map_or(true, |price| price == order.price)
+            && self.post_only.is_none()
+            && self.bit_flags.is_none()
+            && self
+                .trigger_price
+                .map_or(true, |trigger_price| trigger_price == order.trigger_price)
+            && self.trigger_condition.map_or(true, |trigger_condition| {
+                trigger_condition == order.trigger_condition
+            })
+            && self
+                .oracle_price_offset
+                .map_or(true, |oracle_price_offset| {
+                    oracle_price_offset == order.oracle_price_offset
+                })
+            && self.auction_duration.is_none()
+            && self.auction_start_price.is_none()
+            && self.auction_end_price.is_none()
+    }
 }
 
 pub enum ModifyOrderPolicy {
@@ -957,6 +1220,86 @@ pub enum ModifyOrderPolicy {
     ExcludePreviousFill = 2,
 }
 
+/// Filters for cancel_orders_by_filter. Orders are canceled if they match every filter that is set
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Eq, PartialEq, Debug)]
+pub struct CancelOrdersParams {
+    pub market_type: Option<MarketType>,
+    pub market_index: Option<u16>,
+    pub direction: Option<PositionDirection>,
+    /// inclusive range for the order's price. Trigger orders that haven't triggered are compared by
+    /// trigger price, other orders by their limit price including oracle offsets and auctions
+    pub min_price: Option<u64>,
+    pub max_price: Option<u64>,
+    pub order_types: Option<Vec<OrderType>>,
+    /// only cancel orders placed at least this many slots ago
+    pub min_age_slots: Option<u64>,
+    /// inclusive range for order.user_order_id
+    pub min_user_order_id: Option<u8>,
+    pub max_user_order_id: Option<u8>,
+    /// only cancel orders priced off the oracle. Trigger orders that haven't triggered don't match
+    pub oracle_price_offset_only: bool,
+}
+
+impl CancelOrdersParams {
+    pub fn matches(&self, order: &Order, slot: u64) -> bool {
+        if let Some(market_type) = self.market_type {
+            if order.market_type != market_type {
+                return false;
+            }
+        }
+
+        if let Some(market_index) = self.market_index {
+            if order.market_index != market_index {
+                return false;
+            }
+        }
+
+        if let Some(direction) = self.direction {
+            if order.direction != direction {
+                return false;
+            }
+        }
+
+        if let Some(order_types) = &self.order_types {
+            if !order_types.contains(&order.order_type) {
+                return false;
+            }
+        }
+
+        if let Some(min_age_slots) = self.min_age_slots {
+            if slot.saturating_sub(order.slot) < min_age_slots {
+                return false;
+            }
+        }
+
+        if order.user_order_id < self.min_user_order_id.unwrap_or(0)
+            || order.user_order_id > self.max_user_order_id.unwrap_or(u8::MAX)
+        {
+            return false;
+        }
+
+        // untriggered trailing stops keep their trail in oracle_price_offset, it isn't a price offset
+        // until they trigger
+        if self.oracle_price_offset_only
+            && (!order.has_oracle_price_offset()
+                || (order.must_be_triggered() && !order.triggered()))
+        {
+            return false;
+        }
+
+        true
+    }
+
+    pub fn has_price_filter(&self) -> bool {
+        self.min_price.is_some() || self.max_price.is_some()
+    }
+
+    /// Checked separately from `matches` since the order's price depends on the oracle
+    pub fn matches_price(&self, price: u64) -> bool {
+        price >= self.min_price.unwrap_or(0) && price <= self.max_price.unwrap_or(u64::MAX)
+    }
+}
+
 #[derive(Clone)]
 pub struct PlaceOrderOptions {
     pub signed_msg_taker_order_slot: Option<u64>,
@@ -965,6 +1308,10 @@ pub struct PlaceOrderOptions {
     pub risk_increasing: bool,
     pub explanation: OrderActionExplanation,
     pub existing_position_direction_override: Option<PositionDirection>,
+    pub oco_group_id: u8,
+    /// Orders placed on the user's behalf by someone else (e.g. a maker's signed quote) must not
+    /// refresh the user's heartbeat
+    pub update_heartbeat: bool,
 }
 
 impl Default for PlaceOrderOptions {
@@ -976,6 +1323,8 @@ impl Default for PlaceOrderOptions {
             risk_increasing: false,
             explanation: OrderActionExplanation::None,
             existing_position_direction_override: None,
+            oco_group_id: 0,
+            update_heartbeat: true,
         }
     }
 }
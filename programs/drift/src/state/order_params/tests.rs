@@ -1666,7 +1666,7 @@ mod get_close_perp_params {
             max_ts: 100,
             posted_slot_tail: get_posted_slot_from_clock_slot(slot),
             bit_flags: 0,
-            padding: [0; 1],
+            oco_group_id_and_bit_flags: 0,
         }
     }
 
@@ -1738,3 +1738,381 @@ fn test_parse_optional_params() {
     assert_eq!(success_condition, 0x34);
     assert_eq!(auction_duration_percentage, 0x12);
 }
+
+mod bracket_order_params {
+    use crate::controller::position::PositionDirection;
+    use crate::state::order_params::{BracketOrderParams, OrderParams};
+    use crate::state::user::{MarketType, OrderTriggerCondition, OrderType};
+    use crate::{BASE_PRECISION_U64, PRICE_PRECISION_U64};
+
+    #[test]
+    fn long_entry() {
+        let entry_params = OrderParams {
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Long,
+            base_asset_amount: BASE_PRECISION_U64,
+            price: 100 * PRICE_PRECISION_U64,
+            market_index: 1,
+            ..OrderParams::default()
+        };
+
+        let bracket_params = BracketOrderParams {
+            take_profit_trigger_price: Some(110 * PRICE_PRECISION_U64),
+            stop_loss_trigger_price: Some(90 * PRICE_PRECISION_U64),
+        };
+
+        let order_params = bracket_params.get_order_params(&entry_params);
+        assert_eq!(order_params.len(), 2);
+
+        let stop_loss = &order_params[0];
+        assert_eq!(stop_loss.order_type, OrderType::TriggerMarket);
+        assert_eq!(stop_loss.direction, PositionDirection::Short);
+        assert_eq!(stop_loss.trigger_condition, OrderTriggerCondition::Below);
+        assert_eq!(stop_loss.trigger_price, Some(90 * PRICE_PRECISION_U64));
+        assert_eq!(stop_loss.base_asset_amount, BASE_PRECISION_U64);
+        assert_eq!(stop_loss.market_index, 1);
+        assert!(stop_loss.reduce_only);
+
+        let take_profit = &order_params[1];
+        assert_eq!(take_profit.direction, PositionDirection::Short);
+        assert_eq!(take_profit.trigger_condition, OrderTriggerCondition::Above);
+        assert_eq!(take_profit.trigger_price, Some(110 * PRICE_PRECISION_U64));
+        assert!(take_profit.reduce_only);
+    }
+
+    #[test]
+    fn short_entry_stop_loss_only() {
+        let entry_params = OrderParams {
+            order_type: OrderType::Market,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Short,
+            base_asset_amount: BASE_PRECISION_U64,
+            ..OrderParams::default()
+        };
+
+        let bracket_params = BracketOrderParams {
+            take_profit_trigger_price: None,
+            stop_loss_trigger_price: Some(110 * PRICE_PRECISION_U64),
+        };
+
+        let order_params = bracket_params.get_order_params(&entry_params);
+        assert_eq!(order_params.len(), 1);
+        assert_eq!(order_params[0].direction, PositionDirection::Long);
+        assert_eq!(
+            order_params[0].trigger_condition,
+            OrderTriggerCondition::Above
+        );
+
+        assert!(BracketOrderParams::default().is_empty());
+        assert!(BracketOrderParams::default()
+            .get_order_params(&entry_params)
+            .is_empty());
+    }
+}
+
+mod scale_order_params {
+    use crate::controller::position::PositionDirection;
+    use crate::error::ErrorCode;
+    use crate::state::order_params::{ScaleOrderParams, SizeDistribution};
+    use crate::state::user::{MarketType, OrderType};
+    use crate::{BASE_PRECISION_U64, PRICE_PRECISION_U64};
+
+    const TICK_SIZE: u64 = PRICE_PRECISION_U64 / 100;
+    const STEP_SIZE: u64 = BASE_PRECISION_U64 / 10;
+
+    #[test]
+    fn flat() {
+        let params = ScaleOrderParams {
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Long,
+            market_index: 0,
+            total_base_asset_amount: 10 * BASE_PRECISION_U64,
+            start_price: 100 * PRICE_PRECISION_U64,
+            end_price: 96 * PRICE_PRECISION_U64,
+            num_orders: 5,
+            size_distribution: SizeDistribution::Flat,
+            ..ScaleOrderParams::default()
+        };
+
+        let order_params = params.get_order_params(TICK_SIZE, STEP_SIZE).unwrap();
+
+        assert_eq!(order_params.len(), 5);
+        for (i, order_params) in order_params.iter().enumerate() {
+            assert_eq!(order_params.order_type, OrderType::Limit);
+            assert_eq!(order_params.direction, PositionDirection::Long);
+            assert_eq!(order_params.base_asset_amount, 2 * BASE_PRECISION_U64);
+            assert_eq!(order_params.price, (100 - i as u64) * PRICE_PRECISION_U64);
+        }
+    }
+
+    #[test]
+    fn linear() {
+        let params = ScaleOrderParams {
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Long,
+            market_index: 0,
+            total_base_asset_amount: 10 * BASE_PRECISION_U64,
+            start_price: 100 * PRICE_PRECISION_U64,
+            end_price: 96 * PRICE_PRECISION_U64,
+            num_orders: 5,
+            size_distribution: SizeDistribution::Linear,
+            ..ScaleOrderParams::default()
+        };
+
+        let order_params = params.get_order_params(TICK_SIZE, STEP_SIZE).unwrap();
+
+        let sizes: Vec<u64> = order_params
+            .iter()
+            .map(|params| params.base_asset_amount)
+            .collect();
+        // rounding dust ends up in the last order
+        assert_eq!(
+            sizes,
+            vec![600000000, 1300000000, 2000000000, 2600000000, 3500000000]
+        );
+    }
+
+    #[test]
+    fn exponential() {
+        let params = ScaleOrderParams {
+            market_type: MarketType::Spot,
+            direction: PositionDirection::Short,
+            market_index: 1,
+            total_base_asset_amount: 7 * BASE_PRECISION_U64,
+            start_price: 100 * PRICE_PRECISION_U64 + TICK_SIZE / 2,
+            end_price: 101 * PRICE_PRECISION_U64 + TICK_SIZE / 2,
+            num_orders: 3,
+            size_distribution: SizeDistribution::Exponential,
+            ..ScaleOrderParams::default()
+        };
+
+        let order_params = params.get_order_params(TICK_SIZE, STEP_SIZE).unwrap();
+
+        let sizes: Vec<u64> = order_params
+            .iter()
+            .map(|params| params.base_asset_amount)
+            .collect();
+        assert_eq!(
+            sizes,
+            vec![
+                BASE_PRECISION_U64,
+                2 * BASE_PRECISION_U64,
+                4 * BASE_PRECISION_U64
+            ]
+        );
+
+        // asks round up to the tick size
+        let prices: Vec<u64> = order_params.iter().map(|params| params.price).collect();
+        assert_eq!(prices, vec![100010000, 100510000, 101010000]);
+        assert!(order_params
+            .iter()
+            .all(|params| params.market_type == MarketType::Spot && params.market_index == 1));
+    }
+
+    #[test]
+    fn invalid() {
+        let params = ScaleOrderParams {
+            total_base_asset_amount: 10 * BASE_PRECISION_U64,
+            start_price: 100 * PRICE_PRECISION_U64,
+            end_price: 96 * PRICE_PRECISION_U64,
+            num_orders: 1,
+            ..ScaleOrderParams::default()
+        };
+        assert_eq!(
+            params.get_order_params(TICK_SIZE, STEP_SIZE),
+            Err(ErrorCode::InvalidOrder)
+        );
+
+        let params = ScaleOrderParams {
+            num_orders: 33,
+            ..params
+        };
+        assert_eq!(
+            params.get_order_params(TICK_SIZE, STEP_SIZE),
+            Err(ErrorCode::InvalidOrder)
+        );
+
+        let params = ScaleOrderParams {
+            num_orders: 5,
+            total_base_asset_amount: STEP_SIZE,
+            ..params
+        };
+        assert_eq!(
+            params.get_order_params(TICK_SIZE, STEP_SIZE),
+            Err(ErrorCode::InvalidOrderSizeTooSmall)
+        );
+
+        // size that isn't a step size multiple would be dropped from the last order
+        let params = ScaleOrderParams {
+            total_base_asset_amount: 10 * BASE_PRECISION_U64 + STEP_SIZE / 2,
+            ..params
+        };
+        assert_eq!(
+            params.get_order_params(TICK_SIZE, STEP_SIZE),
+            Err(ErrorCode::InvalidOrderNotStepSizeMultiple)
+        );
+    }
+}
+
+mod cancel_orders_params {
+    use crate::controller::position::PositionDirection;
+    use crate::state::order_params::CancelOrdersParams;
+    use crate::state::user::{MarketType, Order, OrderBitFlag, OrderTriggerCondition, OrderType};
+    use crate::PRICE_PRECISION_U64;
+
+    #[test]
+    fn matches() {
+        let limit_order = Order {
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Long,
+            price: 100 * PRICE_PRECISION_U64,
+            user_order_id: 5,
+            slot: 100,
+            ..Order::default()
+        };
+
+        let stop_order = Order {
+            order_type: OrderType::TriggerMarket,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Short,
+            trigger_price: 90 * PRICE_PRECISION_U64,
+            slot: 100,
+            ..Order::default()
+        };
+
+        let oracle_offset_order = Order {
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Short,
+            oracle_price_offset: PRICE_PRECISION_U64 as i32,
+            slot: 150,
+            ..Order::default()
+        };
+
+        let slot = 200;
+
+        // default matches everything
+        let params = CancelOrdersParams::default();
+        assert!(params.matches(&limit_order, slot));
+        assert!(params.matches(&stop_order, slot));
+        assert!(params.matches(&oracle_offset_order, slot));
+
+        // limit orders only leaves the protective stop
+        let params = CancelOrdersParams {
+            order_types: Some(vec![OrderType::Limit, OrderType::Oracle]),
+            ..CancelOrdersParams::default()
+        };
+        assert!(params.matches(&limit_order, slot));
+        assert!(!params.matches(&stop_order, slot));
+        assert!(params.matches(&oracle_offset_order, slot));
+
+        assert!(!params.has_price_filter());
+
+        let params = CancelOrdersParams {
+            min_price: Some(95 * PRICE_PRECISION_U64),
+            max_price: Some(105 * PRICE_PRECISION_U64),
+            ..CancelOrdersParams::default()
+        };
+        assert!(params.has_price_filter());
+        assert!(params.matches_price(95 * PRICE_PRECISION_U64));
+        assert!(params.matches_price(105 * PRICE_PRECISION_U64));
+        assert!(!params.matches_price(90 * PRICE_PRECISION_U64));
+        assert!(!params.matches_price(0));
+
+        let params = CancelOrdersParams {
+            min_age_slots: Some(100),
+            ..CancelOrdersParams::default()
+        };
+        assert!(params.matches(&limit_order, slot));
+        assert!(!params.matches(&oracle_offset_order, slot));
+
+        let params = CancelOrdersParams {
+            min_user_order_id: Some(1),
+            max_user_order_id: Some(10),
+            ..CancelOrdersParams::default()
+        };
+        assert!(params.matches(&limit_order, slot));
+        assert!(!params.matches(&stop_order, slot));
+
+        let params = CancelOrdersParams {
+            oracle_price_offset_only: true,
+            ..CancelOrdersParams::default()
+        };
+        assert!(!params.matches(&limit_order, slot));
+        assert!(params.matches(&oracle_offset_order, slot));
+
+        // an untriggered trailing stop keeps its trail in oracle_price_offset
+        let mut trailing_stop_order = Order {
+            order_type: OrderType::TriggerMarket,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Short,
+            trigger_price: 90 * PRICE_PRECISION_U64,
+            trigger_condition: OrderTriggerCondition::Below,
+            oracle_price_offset: 5 * PRICE_PRECISION_U64 as i32,
+            slot: 100,
+            ..Order::default()
+        };
+        trailing_stop_order.add_bit_flag(OrderBitFlag::TrailingStop);
+        assert!(!params.matches(&trailing_stop_order, slot));
+        assert!(CancelOrdersParams::default().matches(&trailing_stop_order, slot));
+    }
+}
+
+mod modify_order_params_only_amends {
+    use crate::controller::position::PositionDirection;
+    use crate::state::order_params::{ModifyOrderParams, PostOnlyParam};
+    use crate::state::user::{Order, OrderType};
+    use crate::{BASE_PRECISION_U64, PRICE_PRECISION_U64};
+
+    #[test]
+    fn test() {
+        let order = Order {
+            order_type: OrderType::Limit,
+            direction: PositionDirection::Long,
+            price: 100 * PRICE_PRECISION_U64,
+            base_asset_amount: 10 * BASE_PRECISION_U64,
+            ..Order::default()
+        };
+
+        let params = ModifyOrderParams {
+            base_asset_amount: Some(5 * BASE_PRECISION_U64),
+            max_ts: Some(100),
+            reduce_only: Some(true),
+            ..ModifyOrderParams::default()
+        };
+        assert!(params.only_amends(&order));
+
+        // unchanged price is still an amendment
+        let params = ModifyOrderParams {
+            price: Some(100 * PRICE_PRECISION_U64),
+            ..ModifyOrderParams::default()
+        };
+        assert!(params.only_amends(&order));
+
+        let params = ModifyOrderParams {
+            price: Some(101 * PRICE_PRECISION_U64),
+            ..ModifyOrderParams::default()
+        };
+        assert!(!params.only_amends(&order));
+
+        let params = ModifyOrderParams {
+            direction: Some(PositionDirection::Short),
+            ..ModifyOrderParams::default()
+        };
+        assert!(!params.only_amends(&order));
+
+        let params = ModifyOrderParams {
+            post_only: Some(PostOnlyParam::MustPostOnly),
+            ..ModifyOrderParams::default()
+        };
+        assert!(!params.only_amends(&order));
+
+        let params = ModifyOrderParams {
+            oracle_price_offset: Some(100),
+            ..ModifyOrderParams::default()
+        };
+        assert!(!params.only_amends(&order));
+    }
+}
@@ -0,0 +1,150 @@
+use crate::error::DriftResult;
+use crate::error::ErrorCode;
+use crate::math::casting::Cast;
+use crate::math::constants::PERCENTAGE_PRECISION;
+use crate::math::safe_math::SafeMath;
+use crate::state::traits::Size;
+use crate::validate;
+use anchor_lang::prelude::*;
+
+#[cfg(test)]
+mod tests;
+
+/// Thresholds and rolling state for the amm circuit breaker of a perp market.
+/// While tripped, amm fills are paused and the amm quotes at max spread
+#[account(zero_copy(unsafe))]
+#[derive(Default, Eq, PartialEq, Debug)]
+#[repr(C)]
+pub struct PerpCircuitBreaker {
+    /// oracle price at the start of the current window
+    /// precision: PRICE_PRECISION
+    pub window_start_oracle_price: i64,
+    /// the slot the current oracle window started
+    pub window_start_slot: u64,
+    /// base_asset_amount_with_amm at the first evaluation in last_slot
+    /// precision: BASE_PRECISION
+    pub last_base_asset_amount_with_amm: i64,
+    /// the last slot the breaker was evaluated
+    pub last_slot: u64,
+    /// amm fills stay paused and spreads at max until this slot
+    pub tripped_until_slot: u64,
+    /// the most the amm's inventory can change by per slot, 0 is no limit
+    /// precision: BASE_PRECISION
+    pub max_inventory_change_per_slot: u64,
+    /// the most the oracle can move within a window, 0 is no limit
+    /// precision: PERCENTAGE_PRECISION
+    pub max_oracle_move_pct: u32,
+    /// number of slots the oracle move is measured over
+    pub oracle_move_window_slots: u32,
+    /// number of slots the breaker stays tripped
+    pub cooldown_slots: u32,
+    pub market_index: u16,
+    /// whether the breaker (rather than the admin) paused amm fills
+    pub paused_amm_fill: u8,
+    pub padding: [u8; 9],
+}
+
+impl Size for PerpCircuitBreaker {
+    const SIZE: usize = 80;
+}
+
+impl PerpCircuitBreaker {
+    pub fn validate(&self) -> DriftResult {
+        validate!(
+            self.max_oracle_move_pct == 0 || self.oracle_move_window_slots > 0,
+            ErrorCode::InvalidPerpCircuitBreaker,
+            "oracle move window slots must be > 0 when max oracle move pct ({}) is set",
+            self.max_oracle_move_pct
+        )?;
+
+        validate!(
+            self.cooldown_slots > 0,
+            ErrorCode::InvalidPerpCircuitBreaker,
+            "cooldown slots must be > 0"
+        )?;
+
+        Ok(())
+    }
+
+    pub fn is_tripped(&self, slot: u64) -> bool {
+        slot < self.tripped_until_slot
+    }
+
+    /// Rolls the oracle window and inventory snapshot forward and returns whether either threshold
+    /// was breached, extending the cooldown if so. The oracle move is measured from the start of the
+    /// current window, which restarts at the first evaluation after it expires
+    pub fn evaluate(
+        &mut self,
+        oracle_price: i64,
+        base_asset_amount_with_amm: i128,
+        slot: u64,
+    ) -> DriftResult<bool> {
+        if slot
+            >= self
+                .window_start_slot
+                .safe_add(self.oracle_move_window_slots.cast()?)?
+            || self.window_start_oracle_price <= 0
+        {
+            self.window_start_oracle_price = oracle_price;
+            self.window_start_slot = slot;
+        }
+        let oracle_move_breached = self.is_oracle_move_breached(oracle_price)?;
+
+        let base_asset_amount_with_amm = base_asset_amount_with_amm.cast::<i64>()?;
+        let inventory_change_per_slot = if self.last_slot == 0 {
+            0
+        } else {
+            base_asset_amount_with_amm
+                .safe_sub(self.last_base_asset_amount_with_amm)?
+                .unsigned_abs()
+                .safe_div(slot.saturating_sub(self.last_slot).max(1))?
+        };
+        if slot > self.last_slot {
+            self.last_base_asset_amount_with_amm = base_asset_amount_with_amm;
+            self.last_slot = slot;
+        }
+
+        let inventory_change_breached = self.max_inventory_change_per_slot > 0
+            && inventory_change_per_slot > self.max_inventory_change_per_slot;
+
+        if inventory_change_breached {
+            msg!(
+                "amm inventory change per slot {} > max {}",
+                inventory_change_per_slot,
+                self.max_inventory_change_per_slot
+            );
+        }
+
+        let breached = oracle_move_breached || inventory_change_breached;
+        if breached {
+            self.tripped_until_slot = slot.safe_add(self.cooldown_slots.cast()?)?;
+        }
+
+        Ok(breached)
+    }
+
+    fn is_oracle_move_breached(&self, oracle_price: i64) -> DriftResult<bool> {
+        if self.max_oracle_move_pct == 0 || self.window_start_oracle_price <= 0 {
+            return Ok(false);
+        }
+
+        let oracle_move_pct = oracle_price
+            .safe_sub(self.window_start_oracle_price)?
+            .unsigned_abs()
+            .cast::<u128>()?
+            .safe_mul(PERCENTAGE_PRECISION)?
+            .safe_div(self.window_start_oracle_price.cast()?)?;
+
+        let breached = oracle_move_pct > self.max_oracle_move_pct.cast()?;
+        if breached {
+            msg!(
+                "oracle move {} > max {} since slot {}",
+                oracle_move_pct,
+                self.max_oracle_move_pct,
+                self.window_start_slot
+            );
+        }
+
+        Ok(breached)
+    }
+}
@@ -0,0 +1,116 @@
+use crate::error::ErrorCode;
+use crate::math::constants::{BASE_PRECISION_I128, BASE_PRECISION_I64, PRICE_PRECISION_I64};
+use crate::state::perp_circuit_breaker::PerpCircuitBreaker;
+
+#[test]
+fn validate() {
+    let mut circuit_breaker = PerpCircuitBreaker {
+        max_oracle_move_pct: 50_000, // 5%
+        oracle_move_window_slots: 10,
+        cooldown_slots: 20,
+        ..PerpCircuitBreaker::default()
+    };
+    assert!(circuit_breaker.validate().is_ok());
+
+    circuit_breaker.oracle_move_window_slots = 0;
+    assert_eq!(
+        circuit_breaker.validate(),
+        Err(ErrorCode::InvalidPerpCircuitBreaker)
+    );
+
+    circuit_breaker.max_oracle_move_pct = 0;
+    assert!(circuit_breaker.validate().is_ok());
+
+    circuit_breaker.cooldown_slots = 0;
+    assert_eq!(
+        circuit_breaker.validate(),
+        Err(ErrorCode::InvalidPerpCircuitBreaker)
+    );
+}
+
+#[test]
+fn evaluate_oracle_move() {
+    let mut circuit_breaker = PerpCircuitBreaker {
+        max_oracle_move_pct: 50_000, // 5%
+        oracle_move_window_slots: 10,
+        cooldown_slots: 20,
+        ..PerpCircuitBreaker::default()
+    };
+
+    assert!(!circuit_breaker
+        .evaluate(100 * PRICE_PRECISION_I64, 0, 100)
+        .unwrap());
+    assert_eq!(
+        circuit_breaker.window_start_oracle_price,
+        100 * PRICE_PRECISION_I64
+    );
+    assert_eq!(circuit_breaker.window_start_slot, 100);
+
+    assert!(!circuit_breaker
+        .evaluate(104 * PRICE_PRECISION_I64, 0, 105)
+        .unwrap());
+
+    assert!(circuit_breaker
+        .evaluate(106 * PRICE_PRECISION_I64, 0, 108)
+        .unwrap());
+    assert_eq!(circuit_breaker.tripped_until_slot, 128);
+    assert!(circuit_breaker.is_tripped(127));
+    assert!(!circuit_breaker.is_tripped(128));
+
+    // window restarts
+    assert!(!circuit_breaker
+        .evaluate(104 * PRICE_PRECISION_I64, 0, 110)
+        .unwrap());
+    assert_eq!(
+        circuit_breaker.window_start_oracle_price,
+        104 * PRICE_PRECISION_I64
+    );
+    assert_eq!(circuit_breaker.window_start_slot, 110);
+    assert_eq!(circuit_breaker.tripped_until_slot, 128);
+
+    assert!(!circuit_breaker
+        .evaluate(109 * PRICE_PRECISION_I64, 0, 115)
+        .unwrap());
+
+    assert!(circuit_breaker
+        .evaluate(110 * PRICE_PRECISION_I64, 0, 116)
+        .unwrap());
+    assert_eq!(circuit_breaker.tripped_until_slot, 136);
+}
+
+#[test]
+fn evaluate_inventory_change() {
+    let mut circuit_breaker = PerpCircuitBreaker {
+        max_inventory_change_per_slot: 10 * BASE_PRECISION_I64 as u64,
+        cooldown_slots: 5,
+        ..PerpCircuitBreaker::default()
+    };
+    let oracle_price = 100 * PRICE_PRECISION_I64;
+
+    assert!(!circuit_breaker.evaluate(oracle_price, 0, 100).unwrap());
+    assert_eq!(circuit_breaker.last_slot, 100);
+
+    // measured against the first snapshot in the slot
+    assert!(circuit_breaker
+        .evaluate(oracle_price, 15 * BASE_PRECISION_I128, 100)
+        .unwrap());
+    assert_eq!(circuit_breaker.last_base_asset_amount_with_amm, 0);
+    assert_eq!(circuit_breaker.tripped_until_slot, 105);
+
+    // 12.5 per slot
+    assert!(circuit_breaker
+        .evaluate(oracle_price, 25 * BASE_PRECISION_I128, 102)
+        .unwrap());
+    assert_eq!(
+        circuit_breaker.last_base_asset_amount_with_amm,
+        25 * BASE_PRECISION_I64
+    );
+    assert_eq!(circuit_breaker.last_slot, 102);
+    assert_eq!(circuit_breaker.tripped_until_slot, 107);
+
+    // 7.5 per slot
+    assert!(!circuit_breaker
+        .evaluate(oracle_price, 40 * BASE_PRECISION_I128, 104)
+        .unwrap());
+    assert_eq!(circuit_breaker.tripped_until_slot, 107);
+}
@@ -0,0 +1,93 @@
+use crate::error::DriftResult;
+use crate::error::ErrorCode;
+use crate::math::casting::Cast;
+use crate::math::constants::PERCENTAGE_PRECISION;
+use crate::math::safe_math::SafeMath;
+use crate::state::traits::Size;
+use crate::validate;
+use anchor_lang::prelude::*;
+
+#[cfg(test)]
+mod tests;
+
+/// Schedule and budget for the permissionless repeg and k crank of a perp market
+#[account(zero_copy(unsafe))]
+#[derive(Default, Eq, PartialEq, Debug)]
+#[repr(C)]
+pub struct PerpCurveCrank {
+    /// the last unix_timestamp the crank ran
+    pub last_crank_ts: i64,
+    /// min seconds between cranks
+    pub crank_interval: i64,
+    /// unix_timestamp the current budget epoch started
+    pub epoch_start_ts: i64,
+    /// seconds in a budget epoch
+    pub epoch_duration: i64,
+    /// what the crank can spend in the current epoch, set when the epoch starts
+    /// precision: QUOTE_PRECISION
+    pub epoch_budget: u64,
+    /// net cost of the crank's curve updates in the current epoch, revenue is credited back
+    /// precision: QUOTE_PRECISION
+    pub epoch_cost: i64,
+    /// share of the market's fee pool (above the protocol reserved lower bound) given to each epoch
+    /// precision: PERCENTAGE_PRECISION
+    pub epoch_budget_pct: u32,
+    pub market_index: u16,
+    pub padding: [u8; 10],
+}
+
+impl Size for PerpCurveCrank {
+    const SIZE: usize = 72;
+}
+
+impl PerpCurveCrank {
+    pub fn validate(&self) -> DriftResult {
+        validate!(
+            self.crank_interval >= 0 && self.epoch_duration > 0,
+            ErrorCode::InvalidPerpCurveCrank,
+            "crank interval ({}) < 0 or epoch duration ({}) <= 0",
+            self.crank_interval,
+            self.epoch_duration
+        )?;
+
+        validate!(
+            self.epoch_budget_pct.cast::<u128>()? <= PERCENTAGE_PRECISION,
+            ErrorCode::InvalidPerpCurveCrank,
+            "epoch budget pct ({}) > 100%",
+            self.epoch_budget_pct
+        )?;
+
+        Ok(())
+    }
+
+    pub fn is_crank_due(&self, now: i64) -> DriftResult<bool> {
+        Ok(now >= self.last_crank_ts.safe_add(self.crank_interval)?)
+    }
+
+    /// Starts a new epoch once the current one is over, sizing its budget off the fee pool
+    pub fn update_epoch(&mut self, now: i64, fee_pool: u128) -> DriftResult {
+        if now < self.epoch_start_ts.safe_add(self.epoch_duration)? {
+            return Ok(());
+        }
+
+        self.epoch_start_ts = now;
+        self.epoch_budget = fee_pool
+            .safe_mul(self.epoch_budget_pct.cast()?)?
+            .safe_div(PERCENTAGE_PRECISION)?
+            .min(u64::MAX as u128)
+            .cast()?;
+        self.epoch_cost = 0;
+
+        Ok(())
+    }
+
+    /// precision: QUOTE_PRECISION
+    pub fn get_remaining_epoch_budget(&self) -> DriftResult<u128> {
+        Ok(self
+            .epoch_budget
+            .cast::<i128>()?
+            .safe_sub(self.epoch_cost.cast()?)?
+            .max(0)
+            .unsigned_abs())
+    }
+}
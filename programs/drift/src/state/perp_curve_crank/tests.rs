@@ -0,0 +1,83 @@
+use crate::error::ErrorCode;
+use crate::math::constants::{PERCENTAGE_PRECISION_U64, QUOTE_PRECISION, QUOTE_PRECISION_I64};
+use crate::state::perp_curve_crank::PerpCurveCrank;
+
+#[test]
+fn validate() {
+    let mut curve_crank = PerpCurveCrank {
+        crank_interval: 60,
+        epoch_duration: 3600,
+        epoch_budget_pct: PERCENTAGE_PRECISION_U64 as u32,
+        ..PerpCurveCrank::default()
+    };
+    assert!(curve_crank.validate().is_ok());
+
+    curve_crank.epoch_budget_pct += 1;
+    assert_eq!(
+        curve_crank.validate(),
+        Err(ErrorCode::InvalidPerpCurveCrank)
+    );
+
+    curve_crank.epoch_budget_pct -= 1;
+    curve_crank.epoch_duration = 0;
+    assert_eq!(
+        curve_crank.validate(),
+        Err(ErrorCode::InvalidPerpCurveCrank)
+    );
+}
+
+#[test]
+fn is_crank_due() {
+    let curve_crank = PerpCurveCrank {
+        last_crank_ts: 100,
+        crank_interval: 60,
+        ..PerpCurveCrank::default()
+    };
+
+    assert!(!curve_crank.is_crank_due(159).unwrap());
+    assert!(curve_crank.is_crank_due(160).unwrap());
+}
+
+#[test]
+fn update_epoch() {
+    let mut curve_crank = PerpCurveCrank {
+        epoch_start_ts: 100,
+        epoch_duration: 3600,
+        epoch_budget: 10 * QUOTE_PRECISION_I64 as u64,
+        epoch_cost: 4 * QUOTE_PRECISION_I64,
+        epoch_budget_pct: 100_000, // 10%
+        ..PerpCurveCrank::default()
+    };
+
+    // mid epoch
+    curve_crank
+        .update_epoch(3699, 1_000 * QUOTE_PRECISION)
+        .unwrap();
+    assert_eq!(curve_crank.epoch_start_ts, 100);
+    assert_eq!(
+        curve_crank.get_remaining_epoch_budget().unwrap(),
+        6 * QUOTE_PRECISION
+    );
+
+    // revenue is credited back
+    curve_crank.epoch_cost = -5 * QUOTE_PRECISION_I64;
+    assert_eq!(
+        curve_crank.get_remaining_epoch_budget().unwrap(),
+        15 * QUOTE_PRECISION
+    );
+
+    // overspent
+    curve_crank.epoch_cost = 11 * QUOTE_PRECISION_I64;
+    assert_eq!(curve_crank.get_remaining_epoch_budget().unwrap(), 0);
+
+    curve_crank
+        .update_epoch(3700, 1_000 * QUOTE_PRECISION)
+        .unwrap();
+    assert_eq!(curve_crank.epoch_start_ts, 3700);
+    assert_eq!(curve_crank.epoch_budget, 100 * QUOTE_PRECISION_I64 as u64);
+    assert_eq!(curve_crank.epoch_cost, 0);
+    assert_eq!(
+        curve_crank.get_remaining_epoch_budget().unwrap(),
+        100 * QUOTE_PRECISION
+    );
+}
@@ -26,6 +26,7 @@ use crate::math::margin::{
     calculate_size_discount_asset_weight, calculate_size_premium_liability_weight,
     MarginRequirementType,
 };
+use crate::math::orders::standardize_base_asset_amount;
 use crate::math::safe_math::SafeMath;
 use crate::math::stats;
 use num_integer::Roots;
@@ -135,6 +136,38 @@ impl ContractTier {
     }
 }
 
+/// How a taker fill is split between makers resting at the same price
+#[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Debug, Eq, Default)]
+pub enum MakerAllocationPolicy {
+    /// makers are filled in the order they are passed in
+    #[default]
+    PriceTime,
+    /// makers share the fill in proportion to their order size
+    ProRata,
+}
+
+/// How the amm prices its bid and ask
+#[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Debug, Eq, Default)]
+pub enum AmmQuoteMode {
+    /// bid and ask come from swapping against the virtual reserves
+    #[default]
+    Curve,
+    /// bid and ask are the oracle plus/minus the spread, fills are bounded by the inventory budget
+    Oracle,
+}
+
+/// Whether the market's amm circuit breaker is set up and whether it's tripped
+#[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Debug, Eq, Default)]
+pub enum AmmCircuitBreakerStatus {
+    /// the market has no circuit breaker account
+    #[default]
+    Disabled,
+    /// the circuit breaker account has to be passed in wherever the amm is updated
+    Armed,
+    /// amm fills are paused and the amm quotes at max spread
+    Tripped,
+}
+
 #[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Debug, Eq, PartialOrd, Ord)]
 pub enum AMMAvailability {
     Immediate,
@@ -234,9 +267,36 @@ pub struct PerpMarket {
     pub high_leverage_margin_ratio_maintenance: u16,
     pub protected_maker_limit_price_divisor: u8,
     pub protected_maker_dynamic_divisor: u8,
-    pub padding1: u32,
+    /// EWMA realized oracle volatility over ORACLE_REALIZED_VOL_HORIZON_SLOTS with the fast half life,
+    /// feeds the vol spread
+    /// precision: PERCENTAGE_PRECISION
+    pub oracle_realized_vol_fast: u16,
+    /// EWMA realized oracle volatility over ORACLE_REALIZED_VOL_HORIZON_SLOTS with the slow half life,
+    /// feeds the max target spread
+    /// precision: PERCENTAGE_PRECISION
+    pub oracle_realized_vol_slow: u16,
     pub last_fill_price: u64,
-    pub padding: [u8; 24],
+    /// The slot the last batch auction was cleared
+    pub last_batch_auction_slot: u64,
+    /// Number of slots orders collect for before they clear in a batch auction
+    /// 0 means orders fill continuously
+    pub batch_auction_duration: u16,
+    pub maker_allocation_policy: MakerAllocationPolicy,
+    /// Share of a pro-rata fill given first to the oldest maker order at the price
+    /// precision: 1 = 1%
+    pub pro_rata_top_of_book_bonus: u8,
+    pub amm_quote_mode: AmmQuoteMode,
+    /// Armed once the market has a circuit breaker, Tripped while it's tripped and the amm quotes at max spread
+    pub amm_circuit_breaker_status: AmmCircuitBreakerStatus,
+    /// Number of slots for the weight of an oracle return in oracle_realized_vol_fast to halve
+    /// precision: 1 = ORACLE_REALIZED_VOL_HALF_LIFE_SLOTS. 0 means it's not tracked
+    pub oracle_realized_vol_fast_half_life: u8,
+    /// Number of slots for the weight of an oracle return in oracle_realized_vol_slow to halve
+    /// precision: 1 = ORACLE_REALIZED_VOL_HALF_LIFE_SLOTS. 0 means it's not tracked
+    pub oracle_realized_vol_slow_half_life: u8,
+    /// The most base the amm will hold long or short when quoting off the oracle
+    /// precision: BASE_PRECISION
+    pub oracle_quote_inventory_budget: u64,
 }
 
 impl Default for PerpMarket {
@@ -278,9 +338,18 @@ impl Default for PerpMarket {
             high_leverage_margin_ratio_maintenance: 0,
             protected_maker_limit_price_divisor: 0,
             protected_maker_dynamic_divisor: 0,
-            padding1: 0,
+            oracle_realized_vol_fast: 0,
+            oracle_realized_vol_slow: 0,
             last_fill_price: 0,
-            padding: [0; 24],
+            last_batch_auction_slot: 0,
+            batch_auction_duration: 0,
+            maker_allocation_policy: MakerAllocationPolicy::default(),
+            pro_rata_top_of_book_bonus: 0,
+            amm_quote_mode: AmmQuoteMode::default(),
+            amm_circuit_breaker_status: AmmCircuitBreakerStatus::default(),
+            oracle_realized_vol_fast_half_life: 0,
+            oracle_realized_vol_slow_half_life: 0,
+            oracle_quote_inventory_budget: 0,
         }
     }
 }
@@ -315,6 +384,60 @@ impl PerpMarket {
         PerpOperation::is_operation_paused(self.paused_operations, operation)
     }
 
+    pub fn is_batch_auction_enabled(&self) -> bool {
+        self.batch_auction_duration > 0
+    }
+
+    pub fn can_clear_batch_auction(&self, slot: u64) -> DriftResult<bool> {
+        Ok(self.is_batch_auction_enabled()
+            && slot
+                >= self
+                    .last_batch_auction_slot
+                    .safe_add(self.batch_auction_duration.cast()?)?)
+    }
+
+    pub fn is_pro_rata_allocation_enabled(&self) -> bool {
+        self.maker_allocation_policy == MakerAllocationPolicy::ProRata
+    }
+
+    pub fn is_oracle_quote_amm(&self) -> bool {
+        self.amm_quote_mode == AmmQuoteMode::Oracle
+    }
+
+    pub fn has_amm_circuit_breaker(&self) -> bool {
+        self.amm_circuit_breaker_status != AmmCircuitBreakerStatus::Disabled
+    }
+
+    pub fn is_amm_circuit_breaker_tripped(&self) -> bool {
+        self.amm_circuit_breaker_status == AmmCircuitBreakerStatus::Tripped
+    }
+
+    /// Max base the amm can fill for a taker in order_direction
+    pub fn get_amm_available_liquidity(
+        &self,
+        order_direction: &PositionDirection,
+    ) -> DriftResult<u64> {
+        if !self.is_oracle_quote_amm() {
+            return amm::calculate_amm_available_liquidity(&self.amm, order_direction);
+        }
+
+        // users long means the amm is short
+        let inventory_budget = self.oracle_quote_inventory_budget.cast::<i128>()?;
+        let available_liquidity = match order_direction {
+            PositionDirection::Long => {
+                inventory_budget.safe_sub(self.amm.base_asset_amount_with_amm)?
+            }
+            PositionDirection::Short => {
+                inventory_budget.safe_add(self.amm.base_asset_amount_with_amm)?
+            }
+        }
+        .max(0)
+        .min(u64::MAX as i128)
+        .cast::<u64>()?;
+
+        standardize_base_asset_amount(available_liquidity, self.amm.order_step_size)
+    }
+
     pub fn can_skip_auction_duration(
         &self,
         state: &State,
@@ -1167,7 +1290,13 @@ pub struct AMM {
     pub reference_price_offset: i32,
     /// signed scale amm_spread similar to fee_adjustment logic (-100 = 0, 100 = double)
     pub amm_inventory_spread_adjustment: i8,
-    pub padding: [u8; 3],
+    /// half width of the price band around the peg where the amm is more concentrated
+    /// precision: 1 = 0.1%
+    pub concentrated_band_width: u8,
+    /// how many times deeper the amm is inside the concentrated band than in the tails
+    /// 0 or 1 is a plain constant product curve
+    pub concentrated_liquidity_multiplier: u8,
+    pub padding: [u8; 1],
     pub last_funding_oracle_twap: i64,
 }
 
@@ -1258,7 +1387,9 @@ impl Default for AMM {
             quote_asset_amount_with_unsettled_lp: 0,
             reference_price_offset: 0,
             amm_inventory_spread_adjustment: 0,
-            padding: [0; 3],
+            concentrated_band_width: 0,
+            concentrated_liquidity_multiplier: 0,
+            padding: [0; 1],
             last_funding_oracle_twap: 0,
         }
     }
@@ -1621,6 +1752,9 @@ impl AMM {
         self.mm_oracle_slot = mm_oracle_slot;
         Ok(())
     }
+    pub fn has_concentrated_band(&self) -> bool {
+        self.concentrated_band_width > 0 && self.concentrated_liquidity_multiplier > 1
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,226 @@
+use crate::controller::position::PositionDirection;
+use crate::error::DriftResult;
+use crate::error::ErrorCode;
+use crate::state::traits::Size;
+use crate::state::user::{MarketType, Order, OrderStatus, OrderType, User};
+use crate::validate;
+use anchor_lang::prelude::*;
+
+#[cfg(test)]
+mod tests;
+
+pub const PERP_ORDER_BOOK_SIDE_CAPACITY: usize = 96;
+
+/// Index of the resting post only limit orders for a perp market, sorted by price then time.
+/// Fillers read it to pick the best makers to pass to fill_perp_order, and fills that pass it in
+/// can't skip a maker in it with a better price
+#[account(zero_copy(unsafe))]
+#[derive(Eq, PartialEq, Debug)]
+#[repr(C)]
+pub struct PerpOrderBook {
+    pub market_index: u16,
+    pub num_bids: u16,
+    pub num_asks: u16,
+    pub padding: [u8; 10],
+    /// sorted by price descending
+    pub bids: [OrderBookEntry; PERP_ORDER_BOOK_SIDE_CAPACITY],
+    /// sorted by price ascending
+    pub asks: [OrderBookEntry; PERP_ORDER_BOOK_SIDE_CAPACITY],
+}
+
+impl Size for PerpOrderBook {
+    const SIZE: usize = 9240;
+}
+
+#[zero_copy(unsafe)]
+#[derive(Default, Eq, PartialEq, Debug)]
+#[repr(C)]
+pub struct OrderBookEntry {
+    pub user: Pubkey,
+    /// precision: PRICE_PRECISION
+    pub price: u64,
+    pub order_id: u32,
+    pub padding: [u8; 4],
+}
+
+impl OrderBookEntry {
+    pub fn new(user: Pubkey, order: &Order) -> Self {
+        OrderBookEntry {
+            user,
+            price: order.price,
+            order_id: order.order_id,
+            padding: [0; 4],
+        }
+    }
+}
+
+impl PerpOrderBook {
+    pub fn validate(&self) -> DriftResult {
+        validate!(
+            self.num_bids as usize <= PERP_ORDER_BOOK_SIDE_CAPACITY
+                && self.num_asks as usize <= PERP_ORDER_BOOK_SIDE_CAPACITY,
+            ErrorCode::InvalidPerpOrderBook,
+            "num bids ({}) or num asks ({}) > capacity ({})",
+            self.num_bids,
+            self.num_asks,
+            PERP_ORDER_BOOK_SIDE_CAPACITY
+        )?;
+
+        Ok(())
+    }
+
+    pub fn bids(&self) -> &[OrderBookEntry] {
+        &self.bids[..self.num_bids as usize]
+    }
+
+    pub fn asks(&self) -> &[OrderBookEntry] {
+        &self.asks[..self.num_asks as usize]
+    }
+
+    pub fn get_side(&self, direction: PositionDirection) -> &[OrderBookEntry] {
+        match direction {
+            PositionDirection::Long => self.bids(),
+            PositionDirection::Short => self.asks(),
+        }
+    }
+
+    fn get_side_mut(
+        &mut self,
+        direction: PositionDirection,
+    ) -> (
+        &mut [OrderBookEntry; PERP_ORDER_BOOK_SIDE_CAPACITY],
+        &mut u16,
+    ) {
+        match direction {
+            PositionDirection::Long => (&mut self.bids, &mut self.num_bids),
+            PositionDirection::Short => (&mut self.asks, &mut self.num_asks),
+        }
+    }
+
+    /// Inserts the entry behind all entries at the same or a better price.
+    /// If the side is full, the worst entry is evicted when the new entry is strictly better.
+    /// Returns whether the entry was added
+    pub fn insert(&mut self, direction: PositionDirection, entry: OrderBookEntry) -> bool {
+        let (entries, num_entries) = self.get_side_mut(direction);
+        let len = *num_entries as usize;
+
+        let is_better = |a: u64, b: u64| match direction {
+            PositionDirection::Long => a > b,
+            PositionDirection::Short => a < b,
+        };
+
+        let index = entries[..len]
+            .iter()
+            .position(|existing| is_better(entry.price, existing.price))
+            .unwrap_or(len);
+
+        if index >= PERP_ORDER_BOOK_SIDE_CAPACITY {
+            return false;
+        }
+
+        let new_len = (len + 1).min(PERP_ORDER_BOOK_SIDE_CAPACITY);
+        entries.copy_within(index..new_len - 1, index + 1);
+        entries[index] = entry;
+        *num_entries = new_len as u16;
+
+        true
+    }
+
+    /// Returns whether an entry was removed
+    pub fn remove(&mut self, direction: PositionDirection, user: &Pubkey, order_id: u32) -> bool {
+        let (entries, num_entries) = self.get_side_mut(direction);
+        let len = *num_entries as usize;
+
+        let index = match entries[..len]
+            .iter()
+            .position(|entry| entry.user == *user && entry.order_id == order_id)
+        {
+            Some(index) => index,
+            None => return false,
+        };
+
+        entries.copy_within(index + 1..len, index);
+        entries[len - 1] = OrderBookEntry::default();
+        *num_entries = (len - 1) as u16;
+
+        true
+    }
+
+    /// The price of the best entry on a side whose user isn't loaded. Fills that pass the book in
+    /// can't go past it, so fillers can't skip a better priced maker
+    pub fn get_best_unloaded_price(
+        &self,
+        direction: PositionDirection,
+        is_loaded: impl Fn(&Pubkey) -> bool,
+    ) -> Option<u64> {
+        self.get_side(direction)
+            .iter()
+            .find(|entry| !is_loaded(&entry.user))
+            .map(|entry| entry.price)
+    }
+
+    /// A full side may have turned away or evicted resting orders
+    pub fn has_full_side(&self) -> bool {
+        self.num_bids as usize == PERP_ORDER_BOOK_SIDE_CAPACITY
+            || self.num_asks as usize == PERP_ORDER_BOOK_SIDE_CAPACITY
+    }
+
+    pub fn contains(&self, direction: PositionDirection, entry: &OrderBookEntry) -> bool {
+        self.get_side(direction)
+            .iter()
+            .any(|existing| existing == entry)
+    }
+
+    pub fn is_eligible_order(&self, order: &Order) -> bool {
+        order.status == OrderStatus::Open
+            && order.market_type == MarketType::Perp
+            && order.market_index == self.market_index
+            && order.order_type == OrderType::Limit
+            && order.post_only
+            && !order.must_be_triggered()
+            && !order.is_pending_activation()
+            && !order.has_oracle_price_offset()
+            && !order.has_auction()
+    }
+
+    /// Brings the book in line with the user's orders: entries for orders that are no longer
+    /// resting (or whose price changed) are removed and missing resting orders are added
+    pub fn update_for_user(&mut self, user_key: &Pubkey, user: &User) -> DriftResult {
+        self.validate()?;
+
+        let is_resting = |entry: &OrderBookEntry, direction: PositionDirection| {
+            user.orders.iter().any(|order| {
+                order.order_id == entry.order_id
+                    && order.direction == direction
+                    && order.price == entry.price
+                    && self.is_eligible_order(order)
+            })
+        };
+
+        let mut stale_entries = vec![];
+        for direction in [PositionDirection::Long, PositionDirection::Short] {
+            for entry in self.get_side(direction).iter() {
+                if entry.user == *user_key && !is_resting(entry, direction) {
+                    stale_entries.push((direction, entry.order_id));
+                }
+            }
+        }
+
+        for (direction, order_id) in stale_entries {
+            self.remove(direction, user_key, order_id);
+        }
+
+        for order in user.orders.iter() {
+            if !self.is_eligible_order(order) {
+                continue;
+            }
+
+            let entry = OrderBookEntry::new(*user_key, order);
+            if !self.contains(order.direction, &entry) {
+                self.insert(order.direction, entry);
+            }
+        }
+
+        Ok(())
+    }
+}
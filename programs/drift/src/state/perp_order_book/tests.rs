@@ -0,0 +1,202 @@
+mod insert {
+    use crate::controller::position::PositionDirection;
+    use crate::state::perp_order_book::{
+        OrderBookEntry, PerpOrderBook, PERP_ORDER_BOOK_SIDE_CAPACITY,
+    };
+    use anchor_lang::prelude::Pubkey;
+    use bytemuck::Zeroable;
+
+    fn entry(price: u64, order_id: u32) -> OrderBookEntry {
+        OrderBookEntry {
+            user: Pubkey::default(),
+            price,
+            order_id,
+            padding: [0; 4],
+        }
+    }
+
+    fn order_ids(entries: &[OrderBookEntry]) -> Vec<u32> {
+        entries.iter().map(|entry| entry.order_id).collect()
+    }
+
+    #[test]
+    fn price_time_priority() {
+        let mut book = PerpOrderBook::zeroed();
+
+        assert!(book.insert(PositionDirection::Long, entry(100, 1)));
+        assert!(book.insert(PositionDirection::Long, entry(101, 2)));
+        assert!(book.insert(PositionDirection::Long, entry(100, 3)));
+        assert!(book.insert(PositionDirection::Long, entry(99, 4)));
+        assert_eq!(order_ids(book.bids()), vec![2, 1, 3, 4]);
+
+        assert!(book.insert(PositionDirection::Short, entry(100, 5)));
+        assert!(book.insert(PositionDirection::Short, entry(101, 6)));
+        assert!(book.insert(PositionDirection::Short, entry(100, 7)));
+        assert!(book.insert(PositionDirection::Short, entry(99, 8)));
+        assert_eq!(order_ids(book.asks()), vec![8, 5, 7, 6]);
+    }
+
+    #[test]
+    fn full_side() {
+        let mut book = PerpOrderBook::zeroed();
+
+        for i in 0..PERP_ORDER_BOOK_SIDE_CAPACITY {
+            assert!(book.insert(PositionDirection::Long, entry(100 + i as u64, i as u32)));
+        }
+        assert_eq!(book.bids().len(), PERP_ORDER_BOOK_SIDE_CAPACITY);
+
+        // not better than the worst bid
+        assert!(!book.insert(PositionDirection::Long, entry(100, 1000)));
+        assert!(!book.insert(PositionDirection::Long, entry(99, 1001)));
+
+        // evicts the worst bid
+        assert!(book.insert(PositionDirection::Long, entry(1000, 1002)));
+        assert_eq!(book.bids().len(), PERP_ORDER_BOOK_SIDE_CAPACITY);
+        assert_eq!(book.bids()[0].order_id, 1002);
+        assert_eq!(book.bids()[PERP_ORDER_BOOK_SIDE_CAPACITY - 1].price, 101);
+    }
+}
+
+mod remove {
+    use crate::controller::position::PositionDirection;
+    use crate::state::perp_order_book::{OrderBookEntry, PerpOrderBook};
+    use anchor_lang::prelude::Pubkey;
+    use bytemuck::Zeroable;
+
+    #[test]
+    fn test() {
+        let mut book = PerpOrderBook::zeroed();
+        let user = Pubkey::new_unique();
+
+        for (price, order_id) in [(100, 1), (99, 2), (98, 3)] {
+            book.insert(
+                PositionDirection::Long,
+                OrderBookEntry {
+                    user,
+                    price,
+                    order_id,
+                    padding: [0; 4],
+                },
+            );
+        }
+
+        assert!(!book.remove(PositionDirection::Short, &user, 2));
+        assert!(!book.remove(PositionDirection::Long, &Pubkey::new_unique(), 2));
+        assert!(book.remove(PositionDirection::Long, &user, 2));
+
+        assert_eq!(book.num_bids, 2);
+        assert_eq!(book.bids()[0].order_id, 1);
+        assert_eq!(book.bids()[1].order_id, 3);
+        assert_eq!(book.bids[2], OrderBookEntry::default());
+    }
+}
+
+mod update_for_user {
+    use crate::controller::position::PositionDirection;
+    use crate::state::perp_order_book::PerpOrderBook;
+    use crate::state::user::{MarketType, Order, OrderStatus, OrderType, User};
+    use crate::PRICE_PRECISION_U64;
+    use anchor_lang::prelude::Pubkey;
+    use bytemuck::Zeroable;
+
+    fn resting_order(order_id: u32, direction: PositionDirection, price: u64) -> Order {
+        Order {
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            market_index: 0,
+            order_id,
+            direction,
+            price,
+            post_only: true,
+            ..Order::default()
+        }
+    }
+
+    #[test]
+    fn test() {
+        let mut book = PerpOrderBook::zeroed();
+        let user_key = Pubkey::new_unique();
+
+        let mut user = User::default();
+        user.orders[0] = resting_order(1, PositionDirection::Long, 99 * PRICE_PRECISION_U64);
+        user.orders[1] = resting_order(2, PositionDirection::Short, 101 * PRICE_PRECISION_U64);
+        user.orders[2] = Order {
+            post_only: false,
+            ..resting_order(3, PositionDirection::Long, 100 * PRICE_PRECISION_U64)
+        };
+        user.orders[3] = Order {
+            oracle_price_offset: 1,
+            ..resting_order(4, PositionDirection::Long, 0)
+        };
+        user.orders[4] = Order {
+            market_index: 1,
+            ..resting_order(5, PositionDirection::Long, 100 * PRICE_PRECISION_U64)
+        };
+
+        book.update_for_user(&user_key, &user).unwrap();
+        assert_eq!(book.num_bids, 1);
+        assert_eq!(book.bids()[0].order_id, 1);
+        assert_eq!(book.num_asks, 1);
+        assert_eq!(book.asks()[0].order_id, 2);
+
+        // syncing again is a no op
+        book.update_for_user(&user_key, &user).unwrap();
+        assert_eq!(book.num_bids, 1);
+        assert_eq!(book.num_asks, 1);
+
+        // filled/canceled orders are removed, new orders are added
+        user.orders[0] = Order::default();
+        user.orders[5] = resting_order(6, PositionDirection::Short, 102 * PRICE_PRECISION_U64);
+        book.update_for_user(&user_key, &user).unwrap();
+        assert_eq!(book.num_bids, 0);
+        assert_eq!(book.num_asks, 2);
+        assert_eq!(book.asks()[0].order_id, 2);
+        assert_eq!(book.asks()[1].order_id, 6);
+
+        // other users entries are untouched
+        let other_user_key = Pubkey::new_unique();
+        book.update_for_user(&other_user_key, &User::default())
+            .unwrap();
+        assert_eq!(book.num_asks, 2);
+    }
+}
+
+mod get_best_unloaded_price {
+    use crate::controller::position::PositionDirection;
+    use crate::state::perp_order_book::{OrderBookEntry, PerpOrderBook};
+    use anchor_lang::prelude::Pubkey;
+    use bytemuck::Zeroable;
+
+    #[test]
+    fn test() {
+        let mut book = PerpOrderBook::zeroed();
+
+        let loaded_user = Pubkey::new_unique();
+        let unloaded_user = Pubkey::new_unique();
+        let entry = |user: Pubkey, price: u64, order_id: u32| OrderBookEntry {
+            user,
+            price,
+            order_id,
+            padding: [0; 4],
+        };
+
+        book.insert(PositionDirection::Short, entry(loaded_user, 100, 1));
+        book.insert(PositionDirection::Short, entry(unloaded_user, 101, 2));
+        book.insert(PositionDirection::Short, entry(loaded_user, 102, 3));
+
+        let is_loaded = |user: &Pubkey| *user == loaded_user;
+        assert_eq!(
+            book.get_best_unloaded_price(PositionDirection::Short, is_loaded),
+            Some(101)
+        );
+        assert_eq!(
+            book.get_best_unloaded_price(PositionDirection::Long, is_loaded),
+            None
+        );
+        assert_eq!(
+            book.get_best_unloaded_price(PositionDirection::Short, |_| true),
+            None
+        );
+    }
+}
@@ -129,9 +129,17 @@ pub struct User {
     pub has_open_auction: bool,
     pub margin_mode: MarginMode,
     pub pool_id: u8,
-    pub padding1: [u8; 3],
+    /// What happens when this user's taker order would fill against a maker order from the same authority
+    pub self_trade_prevention_mode: SelfTradePreventionMode,
+    /// number of open orders parked in the user's orders extension account. Included in open_orders
+    pub extension_open_orders: u8,
+    pub padding1: [u8; 1],
     pub last_fuel_bonus_update_ts: u32,
-    pub padding: [u8; 12],
+    /// The last time the authority or delegate refreshed the dead man's switch
+    pub last_heartbeat_ts: u32,
+    /// Seconds without a heartbeat after which any keeper can cancel the user's orders. 0 disables it
+    pub heartbeat_timeout: u32,
+    pub padding: [u8; 4],
 }
 
 impl User {
@@ -280,6 +288,33 @@ impl User {
             .find(|order| order.order_id == order_id && order.status == OrderStatus::Open)
     }
 
+    pub fn get_next_oco_group_id(&self) -> DriftResult<u8> {
+        (1..=OCO_GROUP_ID_MASK)
+            .find(|oco_group_id| {
+                !self
+                    .orders
+                    .iter()
+                    .any(|order| !order.is_available() && order.oco_group_id() == *oco_group_id)
+            })
+            .ok_or_else(|| {
+                msg!("No oco group id available");
+                ErrorCode::InvalidOrder
+            })
+    }
+
+    /// The take profit/stop loss orders of a bracket are empty until their entry fills
+    pub fn is_bracket_order_waiting_for_entry(&self, order_index: usize) -> bool {
+        let order = &self.orders[order_index];
+        order.base_asset_amount == 0
+            && order.has_oco_group()
+            && !order.is_bracket_entry()
+            && self.orders.iter().any(|entry| {
+                entry.status == OrderStatus::Open
+                    && entry.oco_group_id() == order.oco_group_id()
+                    && entry.is_bracket_entry()
+            })
+    }
+
     pub fn get_last_order_id(&self) -> u32 {
         if self.next_order_id == 1 {
             u32::MAX
@@ -369,6 +404,24 @@ impl User {
         Ok(())
     }
 
+    pub fn is_heartbeat_expired(&self, now: i64) -> DriftResult<bool> {
+        if self.heartbeat_timeout == 0 {
+            return Ok(false);
+        }
+
+        let heartbeat_expiry = self
+            .last_heartbeat_ts
+            .cast::<i64>()?
+            .safe_add(self.heartbeat_timeout.cast()?)?;
+
+        Ok(now > heartbeat_expiry)
+    }
+
+    pub fn update_last_heartbeat_ts(&mut self, now: i64) -> DriftResult {
+        self.last_heartbeat_ts = now.cast()?;
+        Ok(())
+    }
+
     pub fn update_last_active_slot(&mut self, slot: u64) {
         if !self.is_being_liquidated() {
             self.last_active_slot = slot;
@@ -1211,15 +1264,18 @@ pub struct Order {
     /// precision: PRICE_PRECISION
     pub trigger_price: u64,
     /// The start price for the auction. Only relevant for market/oracle orders
+    /// For iceberg orders, the display size (precision: BASE_PRECISION)
     /// precision: PRICE_PRECISION
     pub auction_start_price: i64,
     /// The end price for the auction. Only relevant for market/oracle orders
+    /// For spread legs, the most quote the spread can cost net (precision: QUOTE_PRECISION)
     /// precision: PRICE_PRECISION
     pub auction_end_price: i64,
     /// The time when the order will expire
     pub max_ts: i64,
     /// If set, the order limit price is the oracle price + this offset
-    /// precision: PRICE_PRECISION
+    /// For trailing stop orders, the distance the trigger price trails the oracle price
+    /// precision: PRICE_PRECISION (PERCENTAGE_PRECISION for percentage trailing stops)
     pub oracle_price_offset: i32,
     /// The id for the order. Each users has their own order id space
     pub order_id: u32,
@@ -1252,7 +1308,10 @@ pub struct Order {
     /// Bitflags for further classification
     /// 0: is_signed_message
     pub bit_flags: u8,
-    pub padding: [u8; 1],
+    /// The low 4 bits are the oco group id. Orders sharing a non-zero oco group id are canceled
+    /// once one of them is filled or triggered, unless they're legs of the same spread
+    /// The high 4 bits are [`OrderExtBitFlag`]s
+    pub oco_group_id_and_bit_flags: u8,
 }
 
 #[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Eq, Debug)]
@@ -1270,6 +1329,23 @@ impl Order {
         self.oracle_price_offset != 0
     }
 
+    /// Oracle offset limit orders can carry an absolute price cap in price. It's a ceiling for bids
+    /// and a floor for asks
+    pub fn has_oracle_price_offset_cap(&self) -> bool {
+        self.order_type == OrderType::Limit && self.has_oracle_price_offset() && self.price > 0
+    }
+
+    pub fn apply_oracle_price_offset_cap(&self, price: u64) -> u64 {
+        if !self.has_oracle_price_offset_cap() {
+            return price;
+        }
+
+        match self.direction {
+            PositionDirection::Long => price.min(self.price),
+            PositionDirection::Short => price.max(self.price),
+        }
+    }
+
     pub fn get_limit_price(
         &self,
         valid_oracle_price: Option<i64>,
@@ -1280,13 +1356,13 @@ impl Order {
         pmm_params: Option<ProtectedMakerParams>,
     ) -> DriftResult<Option<u64>> {
         let price = if self.has_auction_price(self.slot, self.auction_duration, slot)? {
-            Some(calculate_auction_price(
+            Some(self.apply_oracle_price_offset_cap(calculate_auction_price(
                 self,
                 slot,
                 tick_size,
                 valid_oracle_price,
                 is_prediction_market,
-            )?)
+            )?))
         } else if self.has_oracle_price_offset() {
             let oracle_price = valid_oracle_price.ok_or_else(|| {
                 msg!("Could not find oracle too calculate oracle offset limit price");
@@ -1298,6 +1374,8 @@ impl Order {
                 .max(tick_size.cast()?)
                 .cast::<u64>()?;
 
+            limit_price = self.apply_oracle_price_offset_cap(limit_price);
+
             if is_prediction_market {
                 limit_price = limit_price.min(MAX_PREDICTION_MARKET_PRICE)
             }
@@ -1392,6 +1470,46 @@ impl Order {
         Ok(!auction_complete && has_auction_prices)
     }
 
+    pub fn is_iceberg(&self) -> bool {
+        self.is_ext_bit_flag_set(OrderExtBitFlag::Iceberg)
+    }
+
+    pub fn get_iceberg_display_size(&self) -> DriftResult<u64> {
+        self.auction_start_price.cast()
+    }
+
+    /// Non trigger orders that aren't pending activation carry their min fill size in trigger_price
+    pub fn has_min_fill_size(&self) -> bool {
+        self.is_ext_bit_flag_set(OrderExtBitFlag::MinFillSize)
+    }
+
+    pub fn get_min_fill_size(&self) -> u64 {
+        if self.has_min_fill_size() {
+            self.trigger_price
+        } else {
+            0
+        }
+    }
+
+    /// The amount shown to takers. Iceberg orders show one display size slice at a time,
+    /// replenished from the hidden remainder as each slice fills
+    pub fn get_base_asset_amount_displayed(
+        &self,
+        existing_position: Option<i64>,
+    ) -> DriftResult<u64> {
+        let base_asset_amount_unfilled = self.get_base_asset_amount_unfilled(existing_position)?;
+
+        if !self.is_iceberg() {
+            return Ok(base_asset_amount_unfilled);
+        }
+
+        let display_size = self.get_iceberg_display_size()?;
+        let slice_remaining =
+            display_size.safe_sub(self.base_asset_amount_filled % display_size)?;
+
+        Ok(base_asset_amount_unfilled.min(slice_remaining))
+    }
+
     /// Passing in an existing_position forces the function to consider the order's reduce only status
     pub fn get_base_asset_amount_unfilled(
         &self,
@@ -1457,10 +1575,39 @@ impl Order {
     pub fn triggered(&self) -> bool {
         matches!(
             self.trigger_condition,
-            OrderTriggerCondition::TriggeredAbove | OrderTriggerCondition::TriggeredBelow
+            OrderTriggerCondition::TriggeredAbove
+                | OrderTriggerCondition::TriggeredBelow
+                | OrderTriggerCondition::TriggeredMarginRatioBelow
+                | OrderTriggerCondition::TriggeredTotalCollateralBelow
         )
     }
 
+    /// Whether the order triggers on the user's own margin health instead of the oracle price
+    pub fn has_account_trigger_condition(&self) -> bool {
+        matches!(
+            self.trigger_condition,
+            OrderTriggerCondition::MarginRatioBelow | OrderTriggerCondition::TotalCollateralBelow
+        )
+    }
+
+    /// Whether the order is waiting for a keeper to activate it at its start ts/slot
+    pub fn is_pending_activation(&self) -> bool {
+        matches!(
+            self.trigger_condition,
+            OrderTriggerCondition::ActivateAfterTs | OrderTriggerCondition::ActivateAfterSlot
+        )
+    }
+
+    pub fn can_be_activated(&self, now: i64, slot: u64) -> DriftResult<bool> {
+        let can_be_activated = match self.trigger_condition {
+            OrderTriggerCondition::ActivateAfterTs => now.cast::<u64>()? >= self.trigger_price,
+            OrderTriggerCondition::ActivateAfterSlot => slot >= self.trigger_price,
+            _ => false,
+        };
+
+        Ok(can_be_activated)
+    }
+
     pub fn is_jit_maker(&self) -> bool {
         self.post_only && self.immediate_or_cancel
     }
@@ -1507,6 +1654,37 @@ impl Order {
         self.is_bit_flag_set(OrderBitFlag::HasBuilder)
     }
 
+    pub fn is_trailing_stop(&self) -> bool {
+        self.is_bit_flag_set(OrderBitFlag::TrailingStop)
+    }
+
+    pub fn oco_group_id(&self) -> u8 {
+        self.oco_group_id_and_bit_flags & OCO_GROUP_ID_MASK
+    }
+
+    pub fn has_oco_group(&self) -> bool {
+        self.oco_group_id() != 0
+    }
+
+    pub fn is_bracket_entry(&self) -> bool {
+        self.is_bit_flag_set(OrderBitFlag::BracketEntry)
+    }
+
+    /// Spread legs share an oco group id and only fill together, all-or-none
+    pub fn is_spread_leg(&self) -> bool {
+        self.is_ext_bit_flag_set(OrderExtBitFlag::SpreadLeg)
+    }
+
+    pub fn get_spread_max_net_quote_asset_amount(&self) -> i64 {
+        self.auction_end_price
+    }
+
+    /// Turns a spread leg back into a plain limit order so it can be filled on its own
+    pub fn unlink_spread_leg(&mut self) {
+        self.oco_group_id_and_bit_flags &= !(OCO_GROUP_ID_MASK | OrderExtBitFlag::SpreadLeg as u8);
+        self.auction_end_price = 0;
+    }
+
     pub fn add_bit_flag(&mut self, flag: OrderBitFlag) {
         self.bit_flags |= flag as u8;
     }
@@ -1515,15 +1693,43 @@ impl Order {
         (self.bit_flags & flag as u8) != 0
     }
 
+    pub fn add_ext_bit_flag(&mut self, flag: OrderExtBitFlag) {
+        self.oco_group_id_and_bit_flags |= flag as u8;
+    }
+
+    pub fn is_ext_bit_flag_set(&self, flag: OrderExtBitFlag) -> bool {
+        (self.oco_group_id_and_bit_flags & flag as u8) != 0
+    }
+
     pub fn is_available(&self) -> bool {
         self.status != OrderStatus::Open
     }
 
     pub fn update_open_bids_and_asks(&self) -> bool {
+        if self.is_close_position() {
+            return false;
+        }
+
         !self.must_be_triggered()
             || (self.triggered()
                 && !(self.reduce_only && self.is_bit_flag_set(OrderBitFlag::NewTriggerReduceOnly)))
     }
+
+    /// Close position orders are sized from the position at every fill
+    pub fn is_close_position(&self) -> bool {
+        self.is_ext_bit_flag_set(OrderExtBitFlag::ClosePosition)
+    }
+
+    /// Close position orders are filled once the position is closed
+    pub fn is_filled(&self, existing_position: i64) -> DriftResult<bool> {
+        let existing_position = if self.is_close_position() {
+            Some(existing_position)
+        } else {
+            None
+        };
+
+        Ok(self.get_base_asset_amount_unfilled(existing_position)? == 0)
+    }
 }
 
 impl Default for Order {
@@ -1554,7 +1760,7 @@ impl Default for Order {
             max_ts: 0,
             posted_slot_tail: 0,
             bit_flags: 0,
-            padding: [0; 1],
+            oco_group_id_and_bit_flags: 0,
         }
     }
 }
@@ -1589,6 +1795,16 @@ pub enum OrderTriggerCondition {
     Below,
     TriggeredAbove, // above condition has been triggered
     TriggeredBelow, // below condition has been triggered
+    /// user's total collateral over maintenance margin requirement (MARGIN_PRECISION) is below trigger_price
+    MarginRatioBelow,
+    /// user's total collateral (QUOTE_PRECISION) is below trigger_price
+    TotalCollateralBelow,
+    TriggeredMarginRatioBelow, // margin ratio below condition has been triggered
+    TriggeredTotalCollateralBelow, // total collateral below condition has been triggered
+    /// order can't be filled or make until it's activated at or after the unix timestamp in trigger_price
+    ActivateAfterTs,
+    /// order can't be filled or make until it's activated at or after the slot in trigger_price
+    ActivateAfterSlot,
 }
 
 #[derive(Default, Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Debug, Eq)]
@@ -1617,6 +1833,20 @@ pub enum OrderBitFlag {
     SafeTriggerOrder = 0b00000100,
     NewTriggerReduceOnly = 0b00001000,
     HasBuilder = 0b00010000,
+    TrailingStop = 0b00100000,
+    TrailingStopPercentage = 0b01000000,
+    BracketEntry = 0b10000000,
+}
+
+pub const OCO_GROUP_ID_MASK: u8 = 0b00001111;
+
+/// Flags stored in the high bits of [`Order::oco_group_id_and_bit_flags`] once [`OrderBitFlag`] ran out
+#[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Debug, Eq)]
+pub enum OrderExtBitFlag {
+    SpreadLeg = 0b00010000,
+    Iceberg = 0b00100000,
+    MinFillSize = 0b01000000,
+    ClosePosition = 0b10000000,
 }
 
 #[account(zero_copy(unsafe))]
@@ -1685,7 +1915,9 @@ pub struct UserStats {
     /// last unix ts user stats data was used to update if fuel (u32 to save space)
     pub last_fuel_if_bonus_update_ts: u32,
 
-    pub padding: [u8; 12],
+    pub padding: [u8; 4],
+    /// The nonce of the last signed quote filled for the authority
+    pub last_signed_quote_nonce: u64,
 }
 
 #[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Debug, Eq)]
@@ -2053,6 +2285,17 @@ pub enum MarginMode {
     HighLeverageMaintenance,
 }
 
+#[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Debug, Eq, Default)]
+pub enum SelfTradePreventionMode {
+    #[default]
+    None,
+    CancelMaker,
+    CancelTaker,
+    CancelBoth,
+    /// Decrement both orders by the smaller remaining size and cancel whichever is emptied
+    DecrementAndCancel,
+}
+
 #[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Debug, Eq)]
 #[repr(u8)]
 pub enum FuelOverflowStatus {
@@ -2277,6 +2277,52 @@ mod get_limit_price {
 
         assert_eq!(limit_price, Some(1));
     }
+
+    #[test]
+    fn oracle_offset_price_cap() {
+        let long_order = Order {
+            direction: PositionDirection::Long,
+            order_type: OrderType::Limit,
+            oracle_price_offset: PRICE_PRECISION as i32,
+            price: 100 * PRICE_PRECISION_U64,
+            ..Order::default()
+        };
+
+        // pegged price below the ceiling
+        let oracle_price: Option<i64> = Some((98 * PRICE_PRECISION) as i64);
+        let limit_price = long_order
+            .get_limit_price(oracle_price, None, 0, 1, false, None)
+            .unwrap();
+        assert_eq!(limit_price, Some(99 * PRICE_PRECISION_U64));
+
+        // oracle spikes, bid rests at the ceiling
+        let oracle_price: Option<i64> = Some((120 * PRICE_PRECISION) as i64);
+        let limit_price = long_order
+            .get_limit_price(oracle_price, None, 0, 1, false, None)
+            .unwrap();
+        assert_eq!(limit_price, Some(100 * PRICE_PRECISION_U64));
+
+        let short_order = Order {
+            direction: PositionDirection::Short,
+            order_type: OrderType::Limit,
+            oracle_price_offset: -(PRICE_PRECISION as i32),
+            price: 100 * PRICE_PRECISION_U64,
+            ..Order::default()
+        };
+
+        // oracle drops, ask rests at the floor
+        let oracle_price: Option<i64> = Some((80 * PRICE_PRECISION) as i64);
+        let limit_price = short_order
+            .get_limit_price(oracle_price, None, 0, 1, false, None)
+            .unwrap();
+        assert_eq!(limit_price, Some(100 * PRICE_PRECISION_U64));
+
+        let oracle_price: Option<i64> = Some((102 * PRICE_PRECISION) as i64);
+        let limit_price = short_order
+            .get_limit_price(oracle_price, None, 0, 1, false, None)
+            .unwrap();
+        assert_eq!(limit_price, Some(101 * PRICE_PRECISION_U64));
+    }
 }
 
 mod update_referrer_status {
@@ -2531,3 +2577,257 @@ mod force_get_user_perp_position_mut {
         assert_eq!(user.perp_positions[0].max_margin_ratio, 0);
     }
 }
+
+mod get_next_oco_group_id {
+    use crate::state::user::{Order, OrderStatus, User};
+
+    #[test]
+    fn test() {
+        let mut user = User::default();
+
+        assert_eq!(user.get_next_oco_group_id().unwrap(), 1);
+
+        user.orders[0] = Order {
+            status: OrderStatus::Open,
+            oco_group_id_and_bit_flags: 1,
+            ..Order::default()
+        };
+        user.orders[1] = Order {
+            status: OrderStatus::Open,
+            oco_group_id_and_bit_flags: 1,
+            ..Order::default()
+        };
+        user.orders[2] = Order {
+            status: OrderStatus::Open,
+            oco_group_id_and_bit_flags: 3,
+            ..Order::default()
+        };
+
+        assert_eq!(user.get_next_oco_group_id().unwrap(), 2);
+
+        // ids of closed orders can be reused
+        user.orders[0].status = OrderStatus::Filled;
+        user.orders[1].status = OrderStatus::Canceled;
+
+        assert_eq!(user.get_next_oco_group_id().unwrap(), 1);
+    }
+}
+
+mod get_base_asset_amount_displayed {
+    use crate::state::user::{Order, OrderExtBitFlag, OrderStatus, OrderType};
+    use crate::BASE_PRECISION_U64;
+
+    #[test]
+    fn iceberg() {
+        let mut order = Order {
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            post_only: true,
+            base_asset_amount: 10 * BASE_PRECISION_U64,
+            auction_start_price: 3 * BASE_PRECISION_U64 as i64,
+            oco_group_id_and_bit_flags: OrderExtBitFlag::Iceberg as u8,
+            ..Order::default()
+        };
+
+        assert!(order.is_iceberg());
+        assert_eq!(
+            order.get_base_asset_amount_displayed(None).unwrap(),
+            3 * BASE_PRECISION_U64
+        );
+
+        // partially filled slice only shows what's left of it
+        order.base_asset_amount_filled = BASE_PRECISION_U64;
+        assert_eq!(
+            order.get_base_asset_amount_displayed(None).unwrap(),
+            2 * BASE_PRECISION_U64
+        );
+
+        // filled slice is replenished from the reserve
+        order.base_asset_amount_filled = 3 * BASE_PRECISION_U64;
+        assert_eq!(
+            order.get_base_asset_amount_displayed(None).unwrap(),
+            3 * BASE_PRECISION_U64
+        );
+
+        // last slice is capped by what's unfilled
+        order.base_asset_amount_filled = 9 * BASE_PRECISION_U64;
+        assert_eq!(
+            order.get_base_asset_amount_displayed(None).unwrap(),
+            BASE_PRECISION_U64
+        );
+    }
+
+    #[test]
+    fn not_iceberg() {
+        let order = Order {
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            base_asset_amount: 10 * BASE_PRECISION_U64,
+            auction_start_price: 3 * BASE_PRECISION_U64 as i64,
+            auction_duration: 10,
+            ..Order::default()
+        };
+
+        assert!(!order.is_iceberg());
+        assert_eq!(
+            order.get_base_asset_amount_displayed(None).unwrap(),
+            10 * BASE_PRECISION_U64
+        );
+
+        // post only orders placed before the iceberg flag aren't icebergs
+        let order = Order {
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            post_only: true,
+            base_asset_amount: 10 * BASE_PRECISION_U64,
+            auction_start_price: 3 * BASE_PRECISION_U64 as i64,
+            ..Order::default()
+        };
+
+        assert!(!order.is_iceberg());
+        assert_eq!(
+            order.get_base_asset_amount_displayed(None).unwrap(),
+            10 * BASE_PRECISION_U64
+        );
+    }
+}
+
+mod oco_group_id_and_bit_flags {
+    use crate::state::user::{Order, OrderExtBitFlag, OCO_GROUP_ID_MASK};
+
+    #[test]
+    fn test() {
+        let mut order = Order {
+            oco_group_id_and_bit_flags: OCO_GROUP_ID_MASK,
+            ..Order::default()
+        };
+
+        order.add_ext_bit_flag(OrderExtBitFlag::Iceberg);
+
+        assert_eq!(order.oco_group_id(), OCO_GROUP_ID_MASK);
+        assert!(order.is_iceberg());
+    }
+}
+
+mod is_heartbeat_expired {
+    use crate::state::user::User;
+
+    #[test]
+    fn test() {
+        let mut user = User {
+            last_heartbeat_ts: 100,
+            ..User::default()
+        };
+
+        // disabled without a timeout
+        assert!(!user.is_heartbeat_expired(1000).unwrap());
+
+        user.heartbeat_timeout = 60;
+        assert!(!user.is_heartbeat_expired(160).unwrap());
+        assert!(user.is_heartbeat_expired(161).unwrap());
+
+        user.update_last_heartbeat_ts(161).unwrap();
+        assert!(!user.is_heartbeat_expired(221).unwrap());
+        assert!(user.is_heartbeat_expired(222).unwrap());
+    }
+}
+
+mod can_be_activated {
+    use crate::state::user::{Order, OrderTriggerCondition};
+
+    #[test]
+    fn after_ts() {
+        let order = Order {
+            trigger_condition: OrderTriggerCondition::ActivateAfterTs,
+            trigger_price: 100,
+            ..Order::default()
+        };
+
+        assert!(order.is_pending_activation());
+        assert!(!order.can_be_activated(99, 1000).unwrap());
+        assert!(order.can_be_activated(100, 0).unwrap());
+    }
+
+    #[test]
+    fn after_slot() {
+        let order = Order {
+            trigger_condition: OrderTriggerCondition::ActivateAfterSlot,
+            trigger_price: 100,
+            ..Order::default()
+        };
+
+        assert!(order.is_pending_activation());
+        assert!(!order.can_be_activated(1000, 99).unwrap());
+        assert!(order.can_be_activated(0, 100).unwrap());
+    }
+
+    #[test]
+    fn not_pending() {
+        let order = Order {
+            trigger_condition: OrderTriggerCondition::Above,
+            trigger_price: 100,
+            ..Order::default()
+        };
+
+        assert!(!order.is_pending_activation());
+        assert!(!order.can_be_activated(1000, 1000).unwrap());
+    }
+}
+
+mod is_filled {
+    use crate::state::user::{Order, OrderExtBitFlag, OrderStatus, OrderType};
+    use crate::{PositionDirection, BASE_PRECISION_I64, BASE_PRECISION_U64};
+
+    #[test]
+    fn close_position() {
+        let mut order = Order {
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            direction: PositionDirection::Short,
+            reduce_only: true,
+            base_asset_amount: u64::MAX,
+            oco_group_id_and_bit_flags: OrderExtBitFlag::ClosePosition as u8,
+            ..Order::default()
+        };
+
+        assert!(order.is_close_position());
+        assert!(!order.update_open_bids_and_asks());
+
+        // sized from the position at fill time
+        assert_eq!(
+            order
+                .get_base_asset_amount_unfilled(Some(5 * BASE_PRECISION_I64))
+                .unwrap(),
+            5 * BASE_PRECISION_U64
+        );
+
+        order.base_asset_amount_filled = 2 * BASE_PRECISION_U64;
+        assert!(!order.is_filled(3 * BASE_PRECISION_I64).unwrap());
+        assert!(order.is_filled(0).unwrap());
+    }
+
+    #[test]
+    fn fixed_size() {
+        let mut order = Order {
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            direction: PositionDirection::Short,
+            reduce_only: true,
+            base_asset_amount: 2 * BASE_PRECISION_U64,
+            ..Order::default()
+        };
+
+        assert!(!order.is_close_position());
+        assert!(!order.is_filled(0).unwrap());
+
+        // reduce only trigger orders keep a base_asset_amount of u64::MAX without closing the position
+        let trigger_order = Order {
+            base_asset_amount: u64::MAX,
+            ..order
+        };
+        assert!(!trigger_order.is_close_position());
+
+        order.base_asset_amount_filled = 2 * BASE_PRECISION_U64;
+        assert!(order.is_filled(BASE_PRECISION_I64).unwrap());
+    }
+}
@@ -0,0 +1,208 @@
+use std::cell::{Ref, RefMut};
+
+use crate::error::{DriftResult, ErrorCode};
+use crate::math::safe_unwrap::SafeUnwrap;
+use crate::msg;
+use crate::state::user::{Order, OrderStatus};
+use crate::{validate, ID};
+use anchor_lang::prelude::Pubkey;
+use anchor_lang::*;
+use anchor_lang::{account, zero_copy};
+use prelude::AccountInfo;
+
+pub const USER_ORDERS_EXTENSION_PDA_SEED: &str = "USER_ORDERS_EXT";
+pub const MAX_USER_ORDERS_EXTENSION_ORDERS: usize = 128;
+
+#[cfg(test)]
+mod tests;
+
+/**
+ * Extra order slots for a user account, for users that need more than the 32 orders in User.orders.
+ * Only resting limit orders are parked here. They are swapped back into User.orders when they need
+ * to be filled, modified or canceled, and their position's open order accounting stays on the User.
+ *
+ * This struct is a duplicate of UserOrdersExtensionZeroCopy
+ * It is used to give anchor an struct to generate the idl for clients
+ * The struct UserOrdersExtensionZeroCopy is used to load the data in efficiently
+ */
+#[account]
+#[derive(Default, Eq, PartialEq, Debug)]
+#[repr(C)]
+pub struct UserOrdersExtension {
+    /// the user account these orders belong to
+    pub user_pubkey: Pubkey,
+    pub padding: u32, // align with [`UserOrdersExtension::orders`] 4 bytes len prefix
+    pub orders: Vec<Order>,
+}
+
+impl UserOrdersExtension {
+    pub fn space(num_orders: usize) -> usize {
+        8 + // discriminator
+        std::mem::size_of::<UserOrdersExtensionFixed>() + // fixed header
+        num_orders * std::mem::size_of::<Order>() // orders data
+    }
+
+    pub fn validate(&self) -> DriftResult<()> {
+        validate!(
+            !self.orders.is_empty() && self.orders.len() <= MAX_USER_ORDERS_EXTENSION_ORDERS,
+            ErrorCode::InvalidUserOrdersExtensionResize,
+            "UserOrdersExtension len must be between 1 and {}",
+            MAX_USER_ORDERS_EXTENSION_ORDERS
+        )?;
+        Ok(())
+    }
+}
+
+#[zero_copy]
+#[derive(Default, Eq, PartialEq, Debug)]
+pub struct UserOrdersExtensionFixed {
+    pub user_pubkey: Pubkey,
+    pub padding: u32,
+    pub len: u32,
+}
+
+pub struct UserOrdersExtensionZeroCopy<'a> {
+    pub fixed: Ref<'a, UserOrdersExtensionFixed>,
+    pub data: Ref<'a, [u8]>,
+}
+
+impl<'a> UserOrdersExtensionZeroCopy<'a> {
+    pub fn len(&self) -> u32 {
+        self.fixed.len
+    }
+
+    pub fn get_order(&self, index: u32) -> DriftResult<&Order> {
+        validate!(
+            index < self.len(),
+            ErrorCode::DefaultError,
+            "Order index out of bounds"
+        )?;
+        let size = std::mem::size_of::<Order>();
+        let start = index as usize * size;
+        Ok(bytemuck::from_bytes(&self.data[start..start + size]))
+    }
+
+    pub fn iter_orders(&self) -> impl Iterator<Item = DriftResult<&Order>> + '_ {
+        (0..self.len()).map(move |i| self.get_order(i))
+    }
+}
+
+pub struct UserOrdersExtensionZeroCopyMut<'a> {
+    pub fixed: RefMut<'a, UserOrdersExtensionFixed>,
+    pub data: RefMut<'a, [u8]>,
+}
+
+impl<'a> UserOrdersExtensionZeroCopyMut<'a> {
+    pub fn len(&self) -> u32 {
+        self.fixed.len
+    }
+
+    pub fn get_order(&self, index: u32) -> DriftResult<&Order> {
+        validate!(
+            index < self.len(),
+            ErrorCode::DefaultError,
+            "Order index out of bounds"
+        )?;
+        let size = std::mem::size_of::<Order>();
+        let start = index as usize * size;
+        Ok(bytemuck::from_bytes(&self.data[start..start + size]))
+    }
+
+    pub fn get_order_mut(&mut self, index: u32) -> DriftResult<&mut Order> {
+        validate!(
+            index < self.len(),
+            ErrorCode::DefaultError,
+            "Order index out of bounds"
+        )?;
+        let size = std::mem::size_of::<Order>();
+        let start = index as usize * size;
+        Ok(bytemuck::from_bytes_mut(
+            &mut self.data[start..start + size],
+        ))
+    }
+
+    /// Returns the index of the open order with `order_id`, if it is parked in the extension
+    pub fn get_order_index(&self, order_id: u32) -> Option<u32> {
+        (0..self.len()).find(|i| {
+            self.get_order(*i).map_or(false, |order| {
+                order.order_id == order_id && order.status == OrderStatus::Open
+            })
+        })
+    }
+
+    pub fn get_order_index_by_user_order_id(&self, user_order_id: u8) -> Option<u32> {
+        (0..self.len()).find(|i| {
+            self.get_order(*i).map_or(false, |order| {
+                order.user_order_id == user_order_id && order.status == OrderStatus::Open
+            })
+        })
+    }
+
+    pub fn get_available_order_index(&self) -> DriftResult<u32> {
+        (0..self.len())
+            .find(|i| {
+                self.get_order(*i)
+                    .map_or(false, |order| order.is_available())
+            })
+            .ok_or(ErrorCode::UserOrdersExtensionFull)
+    }
+}
+
+pub trait UserOrdersExtensionLoader<'a> {
+    fn load(&self) -> DriftResult<UserOrdersExtensionZeroCopy>;
+    fn load_mut(&self) -> DriftResult<UserOrdersExtensionZeroCopyMut>;
+}
+
+impl<'a> UserOrdersExtensionLoader<'a> for AccountInfo<'a> {
+    fn load(&self) -> DriftResult<UserOrdersExtensionZeroCopy> {
+        let owner = self.owner;
+
+        validate!(
+            owner == &ID,
+            ErrorCode::DefaultError,
+            "invalid user orders extension owner",
+        )?;
+
+        let data = self.try_borrow_data().safe_unwrap()?;
+
+        let (discriminator, data) = Ref::map_split(data, |d| d.split_at(8));
+        validate!(
+            *discriminator == UserOrdersExtension::discriminator(),
+            ErrorCode::DefaultError,
+            "invalid user orders extension discriminator",
+        )?;
+
+        let hdr_size = std::mem::size_of::<UserOrdersExtensionFixed>();
+        let (fixed, data) = Ref::map_split(data, |d| d.split_at(hdr_size));
+        Ok(UserOrdersExtensionZeroCopy {
+            fixed: Ref::map(fixed, |b| bytemuck::from_bytes(b)),
+            data,
+        })
+    }
+
+    fn load_mut(&self) -> DriftResult<UserOrdersExtensionZeroCopyMut> {
+        let owner = self.owner;
+
+        validate!(
+            owner == &ID,
+            ErrorCode::DefaultError,
+            "invalid user orders extension owner",
+        )?;
+
+        let data = self.try_borrow_mut_data().safe_unwrap()?;
+
+        let (discriminator, data) = RefMut::map_split(data, |d| d.split_at_mut(8));
+        validate!(
+            *discriminator == UserOrdersExtension::discriminator(),
+            ErrorCode::DefaultError,
+            "invalid user orders extension discriminator",
+        )?;
+
+        let hdr_size = std::mem::size_of::<UserOrdersExtensionFixed>();
+        let (fixed, data) = RefMut::map_split(data, |d| d.split_at_mut(hdr_size));
+        Ok(UserOrdersExtensionZeroCopyMut {
+            fixed: RefMut::map(fixed, |b| bytemuck::from_bytes_mut(b)),
+            data,
+        })
+    }
+}
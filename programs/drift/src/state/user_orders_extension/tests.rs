@@ -0,0 +1,56 @@
+mod get_order_index {
+    use std::cell::{RefCell, RefMut};
+
+    use crate::error::ErrorCode;
+    use crate::state::user::{Order, OrderStatus};
+    use crate::state::user_orders_extension::{
+        UserOrdersExtensionFixed, UserOrdersExtensionZeroCopyMut,
+    };
+
+    #[test]
+    fn test() {
+        let fixed = RefCell::new(UserOrdersExtensionFixed {
+            len: 3,
+            ..UserOrdersExtensionFixed::default()
+        });
+        let data = RefCell::new([Order::default(); 3]);
+        let mut orders_extension = UserOrdersExtensionZeroCopyMut {
+            fixed: fixed.borrow_mut(),
+            data: RefMut::map(data.borrow_mut(), |orders| {
+                bytemuck::cast_slice_mut(&mut orders[..])
+            }),
+        };
+
+        *orders_extension.get_order_mut(0).unwrap() = Order {
+            status: OrderStatus::Open,
+            order_id: 1,
+            ..Order::default()
+        };
+        *orders_extension.get_order_mut(1).unwrap() = Order {
+            status: OrderStatus::Canceled,
+            order_id: 2,
+            ..Order::default()
+        };
+
+        assert_eq!(orders_extension.get_order_index(1), Some(0));
+        assert_eq!(orders_extension.get_order_index(2), None);
+        assert_eq!(orders_extension.get_available_order_index(), Ok(1));
+
+        *orders_extension.get_order_mut(1).unwrap() = Order {
+            status: OrderStatus::Open,
+            order_id: 2,
+            ..Order::default()
+        };
+        *orders_extension.get_order_mut(2).unwrap() = Order {
+            status: OrderStatus::Open,
+            order_id: 3,
+            ..Order::default()
+        };
+
+        assert_eq!(
+            orders_extension.get_available_order_index(),
+            Err(ErrorCode::UserOrdersExtensionFull)
+        );
+        assert!(orders_extension.get_order(3).is_err());
+    }
+}
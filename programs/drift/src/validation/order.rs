@@ -9,8 +9,8 @@ use crate::math::orders::{
 };
 use crate::state::paused_operations::PerpOperation;
 use crate::state::perp_market::PerpMarket;
-use crate::state::user::{Order, OrderTriggerCondition, OrderType};
-use crate::{validate, MAX_PREDICTION_MARKET_PRICE};
+use crate::state::user::{Order, OrderBitFlag, OrderTriggerCondition, OrderType};
+use crate::{validate, MAX_PREDICTION_MARKET_PRICE, PERCENTAGE_PRECISION_U64};
 
 #[cfg(test)]
 mod test;
@@ -41,6 +41,10 @@ pub fn validate_order(
         }
     }
 
+    if order.is_pending_activation() {
+        validate_pending_activation_order(order)?;
+    }
+
     if market.is_prediction_market() {
         validate!(
             order.price <= MAX_PREDICTION_MARKET_PRICE,
@@ -81,10 +85,7 @@ fn validate_market_order(order: &Order, step_size: u64, min_order_size: u64) ->
 
     validate_auction_params(order)?;
 
-    if order.trigger_price > 0 {
-        msg!("Market should not have trigger price");
-        return Err(ErrorCode::InvalidOrderTrigger);
-    }
+    validate_min_fill_size(order, step_size)?;
 
     if order.post_only {
         msg!("Market order can not be post only");
@@ -109,10 +110,7 @@ fn validate_oracle_order(order: &Order, step_size: u64, min_order_size: u64) ->
 
     validate_oracle_auction_params(order)?;
 
-    if order.trigger_price > 0 {
-        msg!("Oracle order should not have trigger price");
-        return Err(ErrorCode::InvalidOrderTrigger);
-    }
+    validate_min_fill_size(order, step_size)?;
 
     if order.post_only {
         msg!("Oracle order can not be post only");
@@ -150,15 +148,7 @@ fn validate_limit_order(
         return Err(ErrorCode::InvalidOrderLimitPrice);
     }
 
-    if order.has_oracle_price_offset() && order.price != 0 {
-        msg!("Limit order price must be 0 for taker oracle offset order");
-        return Err(ErrorCode::InvalidOrderLimitPrice);
-    }
-
-    if order.trigger_price > 0 {
-        msg!("Limit order should not have trigger price");
-        return Err(ErrorCode::InvalidOrderTrigger);
-    }
+    validate_min_fill_size(order, market.amm.order_step_size)?;
 
     if order.post_only {
         validate!(
@@ -170,6 +160,10 @@ fn validate_limit_order(
         validate_post_only_order(order, market, valid_oracle_price, slot)?;
     }
 
+    if order.is_iceberg() {
+        validate_iceberg_order(order, market.amm.order_step_size)?;
+    }
+
     validate_limit_order_auction_params(order)?;
 
     Ok(())
@@ -184,7 +178,7 @@ fn validate_limit_order_auction_params(order: &Order) -> DriftResult {
         }
     } else {
         validate!(
-            order.auction_start_price == 0,
+            order.auction_start_price == 0 || order.is_iceberg(),
             ErrorCode::InvalidOrder,
             "limit order without auction can not have an auction start price"
         )?;
@@ -199,6 +193,36 @@ fn validate_limit_order_auction_params(order: &Order) -> DriftResult {
     Ok(())
 }
 
+fn validate_iceberg_order(order: &Order, step_size: u64) -> DriftResult {
+    let display_size = order.get_iceberg_display_size()?;
+
+    validate!(
+        display_size >= step_size,
+        ErrorCode::InvalidOrder,
+        "iceberg display size ({}) must be at least the step size ({})",
+        display_size,
+        step_size
+    )?;
+
+    validate!(
+        is_multiple_of_step_size(display_size, step_size)?,
+        ErrorCode::InvalidOrderNotStepSizeMultiple,
+        "iceberg display size ({}) not a multiple of the step size ({})",
+        display_size,
+        step_size
+    )?;
+
+    validate!(
+        display_size < order.base_asset_amount,
+        ErrorCode::InvalidOrder,
+        "iceberg display size ({}) must be below base asset amount ({})",
+        display_size,
+        order.base_asset_amount
+    )?;
+
+    Ok(())
+}
+
 fn validate_post_only_order(
     order: &Order,
     market: &PerpMarket,
@@ -269,8 +293,9 @@ fn validate_trigger_limit_order(order: &Order, step_size: u64, min_order_size: u
     if !matches!(
         order.trigger_condition,
         OrderTriggerCondition::Above | OrderTriggerCondition::Below
-    ) {
-        msg!("Invalid trigger condition, must be Above or Below");
+    ) && !order.has_account_trigger_condition()
+    {
+        msg!("Invalid trigger condition, must be Above, Below, MarginRatioBelow or TotalCollateralBelow");
         return Err(ErrorCode::InvalidTriggerOrderCondition);
     }
 
@@ -289,7 +314,9 @@ fn validate_trigger_limit_order(order: &Order, step_size: u64, min_order_size: u
         return Err(ErrorCode::InvalidOrderPostOnly);
     }
 
-    if order.has_oracle_price_offset() {
+    if order.is_trailing_stop() {
+        validate_trailing_stop_order(order)?;
+    } else if order.has_oracle_price_offset() {
         msg!("Trigger limit can not have oracle offset");
         return Err(ErrorCode::InvalidOrderOracleOffset);
     }
@@ -307,8 +334,9 @@ fn validate_trigger_market_order(
     if !matches!(
         order.trigger_condition,
         OrderTriggerCondition::Above | OrderTriggerCondition::Below
-    ) {
-        msg!("Invalid trigger condition, must be Above or Below");
+    ) && !order.has_account_trigger_condition()
+    {
+        msg!("Invalid trigger condition, must be Above, Below, MarginRatioBelow or TotalCollateralBelow");
         return Err(ErrorCode::InvalidTriggerOrderCondition);
     }
 
@@ -327,7 +355,9 @@ fn validate_trigger_market_order(
         return Err(ErrorCode::InvalidOrderPostOnly);
     }
 
-    if order.has_oracle_price_offset() {
+    if order.is_trailing_stop() {
+        validate_trailing_stop_order(order)?;
+    } else if order.has_oracle_price_offset() {
         msg!("Trigger market order can not have oracle offset");
         return Err(ErrorCode::InvalidOrderOracleOffset);
     }
@@ -335,6 +365,88 @@ fn validate_trigger_market_order(
     Ok(())
 }
 
+fn validate_trailing_stop_order(order: &Order) -> DriftResult {
+    validate!(
+        !order.has_account_trigger_condition(),
+        ErrorCode::InvalidTriggerOrderCondition,
+        "Trailing stop must trigger on the oracle price"
+    )?;
+
+    validate!(
+        order.oracle_price_offset > 0,
+        ErrorCode::InvalidOrderOracleOffset,
+        "Trailing stop must have a positive trail ({})",
+        order.oracle_price_offset
+    )?;
+
+    if order.is_bit_flag_set(OrderBitFlag::TrailingStopPercentage) {
+        validate!(
+            order.oracle_price_offset.unsigned_abs() < PERCENTAGE_PRECISION_U64.cast()?,
+            ErrorCode::InvalidOrderOracleOffset,
+            "Trailing stop percentage ({}) must be less than 100%",
+            order.oracle_price_offset
+        )?;
+    }
+
+    Ok(())
+}
+
+fn validate_min_fill_size(order: &Order, step_size: u64) -> DriftResult {
+    if !order.has_min_fill_size() {
+        return Ok(());
+    }
+
+    let min_fill_size = order.get_min_fill_size();
+
+    validate!(
+        min_fill_size <= order.base_asset_amount,
+        ErrorCode::InvalidOrderMinFillSize,
+        "min fill size ({}) > base asset amount ({})",
+        min_fill_size,
+        order.base_asset_amount
+    )?;
+
+    validate!(
+        is_multiple_of_step_size(min_fill_size, step_size)?,
+        ErrorCode::InvalidOrderMinFillSize,
+        "min fill size ({}) not a multiple of step size ({})",
+        min_fill_size,
+        step_size
+    )?;
+
+    validate!(
+        !order.is_iceberg(),
+        ErrorCode::InvalidOrderMinFillSize,
+        "iceberg order can not have a min fill size"
+    )?;
+
+    Ok(())
+}
+
+fn validate_pending_activation_order(order: &Order) -> DriftResult {
+    if order.trigger_price == 0 {
+        msg!("Order pending activation must have a start ts/slot in trigger_price");
+        return Err(ErrorCode::InvalidOrderTrigger);
+    }
+
+    if order.immediate_or_cancel {
+        msg!("Order pending activation can not be immediate or cancel");
+        return Err(ErrorCode::InvalidOrderIOC);
+    }
+
+    if order.trigger_condition == OrderTriggerCondition::ActivateAfterTs && order.max_ts != 0 {
+        validate!(
+            order.max_ts > order.trigger_price.cast::<i64>()?,
+            ErrorCode::InvalidOrder,
+            "max_ts ({}) must be after start ts ({})",
+            order.max_ts,
+            order.trigger_price
+        )?;
+    }
+
+    Ok(())
+}
+
 fn validate_base_asset_amount(
     order: &Order,
     step_size: u64,
@@ -346,8 +458,9 @@ fn validate_base_asset_amount(
         return Err(ErrorCode::InvalidOrderSizeTooSmall);
     }
 
+    // close position orders are sized from the position at fill time
     validate!(
-        is_multiple_of_step_size(order.base_asset_amount, step_size)?,
+        order.is_close_position() || is_multiple_of_step_size(order.base_asset_amount, step_size)?,
         ErrorCode::InvalidOrderNotStepSizeMultiple,
         "Order base asset amount ({}) not a multiple of the step size ({})",
         order.base_asset_amount,
@@ -482,6 +595,10 @@ pub fn validate_spot_order(order: &Order, step_size: u64, min_order_size: u64) -
         OrderType::Oracle => validate_oracle_order(order, step_size, min_order_size)?,
     }
 
+    if order.is_pending_activation() {
+        validate_pending_activation_order(order)?;
+    }
+
     Ok(())
 }
 
@@ -493,15 +610,7 @@ fn validate_spot_limit_order(order: &Order, step_size: u64, min_order_size: u64)
         return Err(ErrorCode::InvalidOrderLimitPrice);
     }
 
-    if order.has_oracle_price_offset() && order.price != 0 {
-        msg!("Limit order price must be 0 for taker oracle offset order");
-        return Err(ErrorCode::InvalidOrderOracleOffset);
-    }
-
-    if order.trigger_price > 0 {
-        msg!("Limit order should not have trigger price");
-        return Err(ErrorCode::InvalidOrderTrigger);
-    }
+    validate_min_fill_size(order, step_size)?;
 
     if order.post_only {
         validate!(
@@ -109,3 +109,51 @@ mod prediction_markets {
         assert_eq!(res, Err(ErrorCode::InvalidPredictionMarketOrder));
     }
 }
+
+mod iceberg {
+    use crate::error::ErrorCode;
+    use crate::state::paused_operations::PerpOperation;
+    use crate::state::perp_market::{PerpMarket, AMM};
+    use crate::state::user::{Order, OrderExtBitFlag, OrderType};
+    use crate::validation::order::validate_order;
+    use crate::{MarketType, PositionDirection, BASE_PRECISION_U64, PRICE_PRECISION_U64};
+
+    #[test]
+    fn display_size_below_step_size() {
+        let perp_market = PerpMarket {
+            amm: AMM {
+                order_step_size: BASE_PRECISION_U64 / 10,
+                ..PerpMarket::default_test().amm
+            },
+            paused_operations: PerpOperation::AmmFill as u8,
+            ..PerpMarket::default_test()
+        };
+
+        // iceberg placed without a display size
+        let mut order = Order {
+            market_type: MarketType::Perp,
+            order_type: OrderType::Limit,
+            base_asset_amount: 10 * BASE_PRECISION_U64,
+            direction: PositionDirection::Long,
+            price: PRICE_PRECISION_U64,
+            post_only: true,
+            auction_start_price: 0,
+            ..Order::default()
+        };
+        order.add_ext_bit_flag(OrderExtBitFlag::Iceberg);
+
+        let oracle_price = None;
+
+        let slot = 0;
+
+        let res = validate_order(&order, &perp_market, oracle_price, slot);
+
+        assert_eq!(res, Err(ErrorCode::InvalidOrder));
+
+        order.auction_start_price = BASE_PRECISION_U64 as i64 / 10;
+
+        let res = validate_order(&order, &perp_market, oracle_price, slot);
+
+        assert_eq!(res, Ok(()));
+    }
+}
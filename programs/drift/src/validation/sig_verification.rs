@@ -1,7 +1,7 @@
 use crate::error::ErrorCode;
 use crate::state::order_params::{
     OrderParams, SignedMsgOrderParamsDelegateMessage, SignedMsgOrderParamsMessage,
-    SignedMsgTriggerOrderParams,
+    SignedMsgQuoteMessage, SignedMsgTriggerOrderParams,
 };
 use anchor_lang::prelude::*;
 use bytemuck::try_cast_slice;
@@ -9,6 +9,7 @@ use bytemuck::{Pod, Zeroable};
 use byteorder::ByteOrder;
 use byteorder::LE;
 use solana_program::ed25519_program::ID as ED25519_ID;
+use solana_program::hash::hash;
 use solana_program::instruction::Instruction;
 use solana_program::program_memory::sol_memcmp;
 use solana_program::sysvar;
@@ -134,6 +135,29 @@ pub fn deserialize_into_verified_message(
     }
 }
 
+pub fn deserialize_into_verified_quote(payload: Vec<u8>) -> Result<SignedMsgQuoteMessage> {
+    if payload.len() < 8 {
+        return Err(SignatureVerificationError::InvalidMessageDataSize.into());
+    }
+
+    if payload[..8] != signed_msg_quote_discriminator() {
+        msg!("Invalid discriminator for signed msg quote");
+        return Err(SignatureVerificationError::InvalidMessageData.into());
+    }
+
+    SignedMsgQuoteMessage::deserialize(&mut &payload[8..]).map_err(|_| {
+        msg!("Invalid signed msg quote encoding");
+        SignatureVerificationError::InvalidMessageDataSize.into()
+    })
+}
+
+/// Quotes have their own discriminator so a signed taker message can't be used as a quote
+pub fn signed_msg_quote_discriminator() -> [u8; 8] {
+    let mut discriminator = [0_u8; 8];
+    discriminator.copy_from_slice(&hash(b"global:SignedMsgQuoteMessage").to_bytes()[..8]);
+    discriminator
+}
+
 /// Check Ed25519Program instruction data verifies the given msg
 ///
 /// `ix` an Ed25519Program instruction [see](https://github.com/solana-labs/solana/blob/master/sdk/src/ed25519_instruction.rs))
@@ -150,6 +174,44 @@ pub fn verify_and_decode_ed25519_msg(
     msg: &[u8],
     is_delegate_signer: bool,
 ) -> Result<VerifiedMessage> {
+    let (payload, signature) = verify_ed25519_msg(
+        ed25519_ix,
+        instructions_sysvar,
+        current_ix_index,
+        signer,
+        msg,
+    )?;
+
+    deserialize_into_verified_message(payload, &signature, is_delegate_signer)
+}
+
+/// Same checks as `verify_and_decode_ed25519_msg` for a maker signed quote
+pub fn verify_and_decode_ed25519_quote(
+    ed25519_ix: &Instruction,
+    instructions_sysvar: &AccountInfo,
+    current_ix_index: u16,
+    signer: &[u8; 32],
+    msg: &[u8],
+) -> Result<SignedMsgQuoteMessage> {
+    let (payload, _) = verify_ed25519_msg(
+        ed25519_ix,
+        instructions_sysvar,
+        current_ix_index,
+        signer,
+        msg,
+    )?;
+
+    deserialize_into_verified_quote(payload)
+}
+
+/// Returns the hex decoded payload and the signature
+fn verify_ed25519_msg(
+    ed25519_ix: &Instruction,
+    instructions_sysvar: &AccountInfo,
+    current_ix_index: u16,
+    signer: &[u8; 32],
+    msg: &[u8],
+) -> Result<(Vec<u8>, [u8; 64])> {
     if ed25519_ix.program_id != ED25519_ID || ed25519_ix.accounts.len() != 0 {
         msg!("Invalid Ix: program ID: {:?}", ed25519_ix.program_id);
         msg!("Invalid Ix: accounts: {:?}", ed25519_ix.accounts.len());
@@ -300,13 +362,13 @@ pub fn verify_and_decode_ed25519_msg(
         let end = start
             .checked_add(SIGNATURE_LEN.into())
             .ok_or(SignatureVerificationError::InvalidSignatureOffset)?;
-        &msg[start..end].try_into().unwrap()
+        msg[start..end].try_into().unwrap()
     };
 
     let payload =
         hex::decode(payload).map_err(|_| SignatureVerificationError::InvalidMessageHex)?;
 
-    deserialize_into_verified_message(payload, signature, is_delegate_signer)
+    Ok((payload, signature))
 }
 
 #[error_code]
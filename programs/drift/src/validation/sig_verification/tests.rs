@@ -353,4 +353,35 @@ mod sig_verification {
         assert_eq!(order_params.auction_start_price, Some(230000000i64));
         assert_eq!(order_params.auction_end_price, Some(237000000i64));
     }
+
+    #[test]
+    fn test_deserialize_into_verified_quote() {
+        use crate::state::order_params::SignedMsgQuoteMessage;
+        use crate::validation::sig_verification::{
+            deserialize_into_verified_quote, signed_msg_quote_discriminator,
+        };
+        use anchor_lang::AnchorSerialize;
+
+        let quote = SignedMsgQuoteMessage {
+            sub_account_id: 1,
+            market_index: 0,
+            direction: PositionDirection::Short,
+            price: 101000000,
+            base_asset_amount: 1000000000,
+            max_slot: 100,
+            nonce: 7,
+        };
+
+        let mut payload = signed_msg_quote_discriminator().to_vec();
+        payload.extend(quote.try_to_vec().unwrap());
+
+        let verified_quote = deserialize_into_verified_quote(payload.clone()).unwrap();
+        assert_eq!(verified_quote, quote);
+
+        // taker message discriminator
+        payload[..8].copy_from_slice(&[200, 213, 166, 94, 34, 52, 245, 93]);
+        assert!(deserialize_into_verified_quote(payload).is_err());
+
+        assert!(deserialize_into_verified_quote(vec![0; 4]).is_err());
+    }
 }
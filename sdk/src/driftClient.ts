@@ -25,6 +25,7 @@ import {
 	resolveExtraAccountMeta,
 } from '@solana/spl-token';
 import {
+	BracketOrderParams,
 	DriftClientMetricsEvents,
 	HighLeverageModeConfig,
 	isVariant,
@@ -4525,6 +4526,66 @@ export class DriftClient {
 		});
 	}
 
+	public async placeBracketPerpOrder(
+		orderParams: OptionalOrderParams,
+		bracketOrderParams: BracketOrderParams,
+		txParams?: TxParams,
+		subAccountId?: number
+	): Promise<TransactionSignature> {
+		const { txSig, slot } = await this.sendTransaction(
+			await this.buildTransaction(
+				await this.getPlaceBracketPerpOrderIx(
+					orderParams,
+					bracketOrderParams,
+					subAccountId
+				),
+				txParams
+			),
+			[],
+			this.opts
+		);
+		this.perpMarketLastSlotCache.set(orderParams.marketIndex, slot);
+		return txSig;
+	}
+
+	public async getPlaceBracketPerpOrderIx(
+		orderParams: OptionalOrderParams,
+		bracketOrderParams: BracketOrderParams,
+		subAccountId?: number
+	): Promise<TransactionInstruction> {
+		orderParams = getOrderParams(orderParams, { marketType: MarketType.PERP });
+
+		const user = await this.getUserAccountPublicKey(subAccountId);
+
+		const remainingAccounts = this.getRemainingAccounts({
+			userAccounts: [this.getUserAccount(subAccountId)],
+			useMarketLastSlotCache: false,
+			readablePerpMarketIndex: orderParams.marketIndex,
+		});
+
+		if (isUpdateHighLeverageMode(orderParams.bitFlags)) {
+			remainingAccounts.push({
+				pubkey: getHighLeverageModeConfigPublicKey(this.program.programId),
+				isWritable: true,
+				isSigner: false,
+			});
+		}
+
+		return await this.program.instruction.placeBracketPerpOrder(
+			orderParams,
+			bracketOrderParams,
+			{
+				accounts: {
+					state: await this.getStatePublicKey(),
+					user,
+					userStats: this.getUserStatsAccountPublicKey(),
+					authority: this.wallet.publicKey,
+				},
+				remainingAccounts,
+			}
+		);
+	}
+
 	public async updateAMMs(
 		marketIndexes: number[],
 		txParams?: TxParams
@@ -4924,7 +4985,8 @@ export class DriftClient {
 
 	public async getPlaceOrdersIx(
 		params: OptionalOrderParams[],
-		subAccountId?: number
+		subAccountId?: number,
+		bracketOrderParams?: BracketOrderParams[]
 	): Promise<TransactionInstruction> {
 		const user = await this.getUserAccountPublicKey(subAccountId);
 
@@ -4960,13 +5022,26 @@ export class DriftClient {
 
 		const formattedParams = params.map((item) => getOrderParams(item));
 
+		const accounts = {
+			state: await this.getStatePublicKey(),
+			user,
+			userStats: this.getUserStatsAccountPublicKey(),
+			authority: this.wallet.publicKey,
+		};
+
+		if (bracketOrderParams) {
+			return await this.program.instruction.placeBracketOrders(
+				formattedParams,
+				bracketOrderParams,
+				{
+					accounts,
+					remainingAccounts,
+				}
+			);
+		}
+
 		return await this.program.instruction.placeOrders(formattedParams, {
-			accounts: {
-				state: await this.getStatePublicKey(),
-				user,
-				userStats: this.getUserStatsAccountPublicKey(),
-				authority: this.wallet.publicKey,
-			},
+			accounts,
 			remainingAccounts,
 		});
 	}
@@ -6669,7 +6744,8 @@ export class DriftClient {
 		referrerInfo?: ReferrerInfo,
 		successCondition?: PlaceAndTakeOrderSuccessCondition,
 		auctionDurationPercentage?: number,
-		subAccountId?: number
+		subAccountId?: number,
+		bracketOrderParams?: BracketOrderParams
 	): Promise<TransactionInstruction> {
 		orderParams = getOrderParams(orderParams, { marketType: MarketType.PERP });
 		const userStatsPublicKey = await this.getUserStatsAccountPublicKey();
@@ -6737,16 +6813,30 @@ export class DriftClient {
 				((auctionDurationPercentage ?? 100) << 8) | (successCondition ?? 0);
 		}
 
+		const accounts = {
+			state: await this.getStatePublicKey(),
+			user,
+			userStats: userStatsPublicKey,
+			authority: this.wallet.publicKey,
+		};
+
+		if (bracketOrderParams) {
+			return await this.program.instruction.placeAndTakeBracketPerpOrder(
+				orderParams,
+				bracketOrderParams,
+				optionalParams,
+				{
+					accounts,
+					remainingAccounts,
+				}
+			);
+		}
+
 		return await this.program.instruction.placeAndTakePerpOrder(
 			orderParams,
 			optionalParams,
 			{
-				accounts: {
-					state: await this.getStatePublicKey(),
-					user,
-					userStats: userStatsPublicKey,
-					authority: this.wallet.publicKey,
-				},
+				accounts,
 				remainingAccounts,
 			}
 		);
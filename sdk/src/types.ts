@@ -201,12 +201,22 @@ export class OrderBitFlag {
 	static readonly NewTriggerReduceOnly = 8;
 }
 
+export const OCO_GROUP_ID_MASK = 0b00001111;
+
+export class OrderExtBitFlag {
+	static readonly SpreadLeg = 0b00010000;
+	static readonly Iceberg = 0b00100000;
+	static readonly MinFillSize = 0b01000000;
+	static readonly ClosePosition = 0b10000000;
+}
+
 export class OrderAction {
 	static readonly PLACE = { place: {} };
 	static readonly CANCEL = { cancel: {} };
 	static readonly EXPIRE = { expire: {} };
 	static readonly FILL = { fill: {} };
 	static readonly TRIGGER = { trigger: {} };
+	static readonly AMEND = { amend: {} };
 }
 
 export class OrderActionExplanation {
@@ -1230,6 +1240,7 @@ export type Order = {
 	maxTs: BN;
 	bitFlags: number;
 	postedSlotTail: number;
+	ocoGroupIdAndBitFlags: number;
 };
 
 export type OrderParams = {
@@ -1333,6 +1344,11 @@ export type SignedMsgTriggerOrderParams = {
 	baseAssetAmount: BN;
 };
 
+export type BracketOrderParams = {
+	takeProfitTriggerPrice: BN | null;
+	stopLossTriggerPrice: BN | null;
+};
+
 export type MakerInfo = {
 	maker: PublicKey;
 	makerStats: PublicKey;